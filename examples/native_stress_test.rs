@@ -3,12 +3,11 @@ extern crate remoteprocess;
 extern crate env_logger;
 #[macro_use]
 extern crate log;
-extern crate failure;
 extern crate py_spy;
 
-fn native_stress_test(pid: remoteprocess::Pid) -> Result<(), failure::Error> {
+fn native_stress_test(pid: remoteprocess::Pid) -> Result<(), py_spy::Error> {
 
-    let config = py_spy::Config{native: true, ..Default::default() };
+    let config = py_spy::ConfigBuilder::new().native(true).build()?;
     let mut spy = py_spy::PythonSpy::retry_new(pid, &config, 3)?;
 
 
@@ -23,11 +22,7 @@ fn native_stress_test(pid: remoteprocess::Pid) -> Result<(), failure::Error> {
                 }
             },
             Err(e) => {
-                error!("Failed to get stack traces: {:#?}", e);
-                for (_i, suberror) in e.iter_chain().enumerate() {
-                    eprintln!("Reason: {:?}", suberror);
-                }
-
+                error!("Failed to get stack traces: {:?}", e);
                 failed += 1;
                 info!("Success {} fail {}", success, failed);
            }
@@ -0,0 +1,10 @@
+use std::env;
+
+fn main() {
+    // python extensions get the python symbols from the interpreter that loads them, rather than
+    // linking to libpython
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() == "macos" {
+        println!("cargo:rustc-cdylib-link-arg=-undefined");
+        println!("cargo:rustc-cdylib-link-arg=dynamic_lookup");
+    }
+}
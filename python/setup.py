@@ -0,0 +1,45 @@
+import os
+import shutil
+import sys
+
+from setuptools import Extension, setup
+from setuptools.command.build_ext import build_ext
+
+
+class CargoBuildExt(build_ext):
+    """Builds the py_spy extension module with cargo, rather than compiling any C sources"""
+    def build_extension(self, ext):
+        source_dir = os.path.dirname(os.path.abspath(__file__))
+        # build against the python that's running this, rather than whichever one is on the path
+        os.environ["PYO3_PYTHON"] = sys.executable
+        if os.system("cargo build --release --manifest-path %s" % os.path.join(source_dir, "Cargo.toml")):
+            raise ValueError("Failed to compile!")
+
+        if sys.platform.startswith("darwin"):
+            library = "libpy_spy_module.dylib"
+        else:
+            library = "libpy_spy_module.so"
+
+        target = self.get_ext_fullpath(ext.name)
+        if not os.path.isdir(os.path.dirname(target)):
+            os.makedirs(os.path.dirname(target))
+        shutil.copyfile(os.path.join(source_dir, "target", "release", library), target)
+
+
+setup(name='py-spy-module',
+      author="Ben Frederickson",
+      author_email="ben@benfrederickson.com",
+      url='https://github.com/benfred/py-spy',
+      description="Profiles the python process that imports it with py-spy",
+      version="0.2.0.dev3",
+      license="GPL",
+      ext_modules=[Extension("py_spy", sources=[])],
+      cmdclass={'build_ext': CargoBuildExt},
+      classifiers=[
+        "Development Status :: 3 - Alpha",
+        "Programming Language :: Python :: 3",
+        "Intended Audience :: Developers",
+        "License :: OSI Approved :: GNU General Public License v3 (GPLv3)",
+        "Topic :: Software Development :: Libraries",
+        "Topic :: Utilities"],
+      zip_safe=False)
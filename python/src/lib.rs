@@ -0,0 +1,78 @@
+//! The py_spy python extension module, which profiles the process it's imported into:
+//!
+//! ```python
+//! import py_spy
+//! py_spy.start("profile.svg", format="flamegraph", rate=100)
+//! ...
+//! py_spy.stop()
+//! ```
+//!
+//! Since this reads the memory of its own process, it doesn't need ptrace or the SYS_PTRACE
+//! capability. Only python 3 is supported.
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate lazy_static;
+extern crate py_spy;
+extern crate pyo3;
+
+use std::sync::Mutex;
+
+use failure::Error;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use py_spy::{ConfigBuilder, FileFormat, Profiler};
+
+lazy_static! {
+    static ref PROFILER: Mutex<Option<Profiler>> = Mutex::new(None);
+}
+
+fn runtime_error(err: Error) -> PyErr {
+    let message = err.iter_chain().map(|e| e.to_string()).collect::<Vec<String>>().join(": ");
+    PyRuntimeError::new_err(message)
+}
+
+/// start(filename, format='flamegraph', rate=100, gil=False, idle=False, memory=False)
+/// --
+///
+/// Starts sampling this process, writing out the profile to filename when stopped.
+#[pyfunction(format = "\"flamegraph\"", rate = "100", gil = "false", idle = "false", memory = "false")]
+fn start(py: Python, filename: &str, format: &str, rate: i64, gil: bool, idle: bool, memory: bool) -> PyResult<()> {
+    // the GIL is released so that python threads keep running while we wait on the sampling thread
+    py.allow_threads(|| -> Result<(), Error> {
+        let mut profiler = PROFILER.lock().unwrap();
+        if profiler.is_some() {
+            return Err(format_err!("py-spy is already profiling this process, call py_spy.stop() first"));
+        }
+        if rate <= 0 {
+            return Err(format_err!("rate should be a positive number of samples per second"));
+        }
+        let format: FileFormat = format.parse().map_err(|_| format_err!("Unknown format {}", format))?;
+        let config = ConfigBuilder::new().format(format).sampling_rate(rate as u64).gil_only(gil)
+            .include_idle(idle).memory(memory).build()?;
+        *profiler = Some(Profiler::start(filename, &config)?);
+        Ok(())
+    }).map_err(runtime_error)
+}
+
+/// stop()
+/// --
+///
+/// Stops sampling and writes out the profile.
+#[pyfunction]
+fn stop(py: Python) -> PyResult<()> {
+    py.allow_threads(|| match PROFILER.lock().unwrap().take() {
+        Some(profiler) => profiler.stop().map_err(Error::from),
+        None => Err(format_err!("py-spy isn't profiling this process, call py_spy.start() first"))
+    }).map_err(runtime_error)
+}
+
+/// Profiles the python process that imports it with py-spy
+#[pymodule]
+#[pyo3(name = "py_spy")]
+fn py_spy_module(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(start, module)?)?;
+    module.add_function(wrap_pyfunction!(stop, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,53 @@
+"""Smoke tests for the py_spy extension module, run with the module installed ('pip install .')"""
+import os
+import shutil
+import tempfile
+import time
+import unittest
+
+import py_spy
+
+
+def busy(duration):
+    end = time.time() + duration
+    while time.time() < end:
+        pass
+
+
+class ModuleTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.mkdtemp()
+
+    def tearDown(self):
+        shutil.rmtree(self.directory)
+
+    def test_start_stop(self):
+        filename = os.path.join(self.directory, "profile.txt")
+        py_spy.start(filename, format="folded", rate=200)
+        busy(0.5)
+        py_spy.stop()
+
+        with open(filename) as f:
+            self.assertIn("busy (test_module.py", f.read())
+
+    def test_errors(self):
+        filename = os.path.join(self.directory, "profile.svg")
+        with self.assertRaises(RuntimeError):
+            py_spy.stop()
+        with self.assertRaises(RuntimeError):
+            py_spy.start(filename, format="unknown")
+        with self.assertRaises(RuntimeError):
+            py_spy.start(filename, rate=0)
+
+        py_spy.start(filename)
+        try:
+            with self.assertRaises(RuntimeError):
+                py_spy.start(filename)
+            busy(0.1)
+        finally:
+            py_spy.stop()
+        self.assertTrue(os.path.exists(filename))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -16,13 +16,12 @@ use std;
 /// Contains dwarf debugging information for a single binary
 #[derive(Debug)]
 pub struct UnwindInfo {
-    // eh_frame_hdr sections only exist on linux
+    // eh_frame_hdr sections only exist on linux, and aren't in every binary there
     #[cfg(target_os="linux")]
-    pub eh_frame_hdr: gimli::ParsedEhFrameHdr<RcReader>,
+    pub eh_frame_hdr: Option<gimli::ParsedEhFrameHdr<RcReader>>,
 
-    // mach binaries don't contain an eh_frame_hdr section, so instead we
-    // build a table of address:fde from the eh_frame section
-    #[cfg(target_os="macos")]
+    // mach binaries (and linux binaries linked without --eh-frame-hdr) don't contain an
+    // eh_frame_hdr section, so instead we build a table of address:fde from the eh_frame section
     pub frame_descriptions: Vec<(u64, FrameDescriptionEntry)>,
 
     pub eh_frame: gimli::EhFrame<RcReader>,
@@ -93,8 +92,12 @@ impl UnwindInfo {
                 gimli::RegisterRule::ValExpression(ref e) => {
                     evaluate_dwarf_expression(e, Some(cfa), reg, process)?
                 },
-                gimli::RegisterRule::Architectural => unimplemented!("Unhandled dwarf rule: Architectural"),
-                gimli::RegisterRule::Undefined => unimplemented!("Unhandled dwarf rule: Undefined"),
+                gimli::RegisterRule::Architectural => {
+                    return Err(Error::Other(format!("Unhandled dwarf rule: Architectural for register {:?}", register)));
+                },
+                // an undefined return address marks the outermost frame (like _start or the start of a thread)
+                gimli::RegisterRule::Undefined if register.0 == RETURN_ADDRESS_REGISTER => return Ok(false),
+                gimli::RegisterRule::Undefined => continue,
             };
             set_register(reg, register, value);
         }
@@ -110,7 +113,20 @@ impl UnwindInfo {
 
     #[cfg(target_os="macos")]
     fn get_fde(&self, pc: u64) -> gimli::Result<&FrameDescriptionEntry> {
-        // Binary search frame description table to get the FDE on osx
+        self.lookup_fde(pc)
+    }
+
+    #[cfg(target_os="linux")]
+    fn get_fde(&self, pc: u64) -> gimli::Result<FrameDescriptionEntry> {
+        // lookup FDE inside the eh_frame_hdr section on linux, when the binary has one
+        match self.eh_frame_hdr.as_ref().and_then(|hdr| hdr.table()) {
+            Some(table) => table.fde_for_address(&self.eh_frame, &self.bases, pc, gimli::EhFrame::cie_from_offset),
+            None => self.lookup_fde(pc).map(|fde| fde.clone())
+        }
+    }
+
+    // Binary search the frame description table to get the FDE
+    fn lookup_fde(&self, pc: u64) -> gimli::Result<&FrameDescriptionEntry> {
         if self.frame_descriptions.is_empty() {
             return Err(gimli::Error::NoUnwindInfoForAddress);
         }
@@ -121,13 +137,6 @@ impl UnwindInfo {
         Ok(fde)
     }
 
-    #[cfg(target_os="linux")]
-    fn get_fde(&self, pc: u64) -> gimli::Result<FrameDescriptionEntry> {
-        // lookup FDE inside the eh_frame_hdr section on linux
-        self.eh_frame_hdr.table().unwrap()
-            .fde_for_address(&self.eh_frame, &self.bases, pc, gimli::EhFrame::cie_from_offset)
-    }
-
     fn get_unwind_row(&self, pc: u64, ctx: &mut UninitializedUnwindContext, fde: &FrameDescriptionEntry)
             -> gimli::Result<gimli::UnwindTableRow<RcReader>> {
         let mut table = gimli::UnwindTable::new(&self.eh_frame, &self.bases, ctx, &fde)?;
@@ -149,29 +158,40 @@ impl UnwindInfo {
         let buf = std::rc::Rc::from(eh_frame);
         let eh_frame = gimli::EhFrame::from(RcReader::new(buf, gimli::NativeEndian));
         let bases = gimli::BaseAddresses::default().set_eh_frame(eh_frame_address);
+        let frame_descriptions = get_frame_descriptions(&eh_frame, &bases)?;
+        Ok(UnwindInfo{eh_frame, bases, frame_descriptions})
+    }
 
-        // Get a vector of all the frame description entries
-        let frame_descriptions = {
-            let mut frame_descriptions = Vec::new();
-            let mut iter = eh_frame.entries(&bases);
-            while let Some(entry) = iter.next()? {
-                match entry {
-                    gimli::CieOrFde::Cie(_) => continue,
-                    gimli::CieOrFde::Fde(partial) => {
-
-                        let fde = partial.parse(|_, bases, offset| eh_frame.cie_from_offset(bases, offset))?;
-                        frame_descriptions.push((fde.initial_address(), fde));
-                    }
-                }
-            }
-            frame_descriptions.sort_unstable_by(|a, b| a.0.cmp(&b.0));
-            frame_descriptions
-        };
+    /// Creates a new UnwindInfo object on linux for a binary without an eh_frame_hdr section,
+    /// by building a lookup table of the frame description entries in the eh_frame section
+    #[cfg(target_os="linux")]
+    pub fn from_eh_frame(eh_frame: gimli::EhFrame<RcReader>, bases: gimli::BaseAddresses) -> gimli::Result<UnwindInfo> {
+        let frame_descriptions = get_frame_descriptions(&eh_frame, &bases)?;
+        Ok(UnwindInfo{eh_frame_hdr: None, eh_frame, bases, frame_descriptions})
+    }
+}
 
-        Ok(UnwindInfo{eh_frame, bases, frame_descriptions})
+// Gets all the frame description entries in an eh_frame section, sorted by their starting address
+fn get_frame_descriptions(eh_frame: &gimli::EhFrame<RcReader>, bases: &gimli::BaseAddresses)
+        -> gimli::Result<Vec<(u64, FrameDescriptionEntry)>> {
+    let mut frame_descriptions = Vec::new();
+    let mut iter = eh_frame.entries(bases);
+    while let Some(entry) = iter.next()? {
+        match entry {
+            gimli::CieOrFde::Cie(_) => continue,
+            gimli::CieOrFde::Fde(partial) => {
+                let fde = partial.parse(|_, bases, offset| eh_frame.cie_from_offset(bases, offset))?;
+                frame_descriptions.push((fde.initial_address(), fde));
+            }
+        }
     }
+    frame_descriptions.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(frame_descriptions)
 }
 
+// the dwarf register number of the return address on x86_64
+const RETURN_ADDRESS_REGISTER: u16 = 16;
+
 fn evaluate_dwarf_expression(e: &gimli::Expression<RcReader>,
                              initial: Option<u64>, registers: &Registers, process: &Process) -> Result<u64, Error> {
     // TODO: this will require different code for 32bit
@@ -329,6 +349,7 @@ fn set_register(regs: &mut Registers, register: gimli::Register, value: u64) {
         14 => regs.r14 = value,
         15 => regs.r15 = value,
         16 => regs.rip = value,
-        _ => panic!("unknown reg")
+        // registers we don't track (like the xmm registers) aren't used to find the caller
+        _ => debug!("ignoring value for register {:?}", register)
     }
 }
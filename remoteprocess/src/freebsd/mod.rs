@@ -24,6 +24,11 @@ pub struct Process {
     lock: Rc<RefCell<Weak<ProcessLock>>>,
 }
 
+/// Returns the pids of all the processes running on the system
+pub fn processes() -> Result<Vec<Pid>, Error> {
+    Err(Error::Other("Listing processes isn't supported on FreeBSD yet".to_owned()))
+}
+
 pub struct Thread {
     pub tid: lwpid_t,
     pid: pid_t,
@@ -53,6 +58,31 @@ impl Process {
         Ok(procstat::exe(self.pid)?)
     }
 
+    /// Returns the command line arguments this process was started with
+    pub fn cmdline(&self) -> Result<Vec<String>, Error> {
+        Err(Error::Other("Getting the command line of a process isn't supported on FreeBSD yet".to_owned()))
+    }
+
+    /// Returns the pids of all the descendants of this process (children, grandchildren etc)
+    pub fn child_processes(&self) -> Result<Vec<Pid>, Error> {
+        Err(Error::Other("Finding child processes isn't supported on FreeBSD yet".to_owned()))
+    }
+
+    /// Returns the exit code of this process once it has exited, or None if it's still running
+    pub fn exit_code(&self) -> Result<Option<i32>, Error> {
+        Err(Error::Other("Getting the exit code of a process isn't supported on FreeBSD yet".to_owned()))
+    }
+
+    /// Returns the total amount of CPU time used by all the threads in this process
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        Err(Error::Other("Getting process cpu time isn't supported on FreeBSD yet".to_owned()))
+    }
+
+    /// Returns the resident set size of this process in bytes
+    pub fn memory_usage(&self) -> Result<u64, Error> {
+        Err(Error::Other("Getting process memory usage isn't supported on FreeBSD yet".to_owned()))
+    }
+
     pub fn cwd(&self) -> Result<String, Error> {
         Ok(procstat::cwd(self.pid)?)
     }
@@ -75,6 +105,11 @@ impl Process {
         process_lock(self.pid, &self.lock)
     }
 
+    /// Stops every thread at the same instant - which lock already does here
+    pub fn freeze(&self) -> Result<Rc<ProcessLock>, Error> {
+        self.lock()
+    }
+
     pub fn unwinder(&self) -> Result<(), Error> {
         unimplemented!("No unwinding yet!")
     }
@@ -89,6 +124,16 @@ impl Thread {
         Ok(self.active)
     }
 
+    /// Returns the name of this thread
+    pub fn name(&self) -> Result<String, Error> {
+        Err(Error::Other("Getting thread names isn't supported on FreeBSD yet".to_owned()))
+    }
+
+    /// Returns the total amount of time this thread has spent running on a CPU
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        Err(Error::Other("Getting thread cpu time isn't supported on FreeBSD yet".to_owned()))
+    }
+
     pub fn lock(&self) -> Result<Rc<ProcessLock>, Error> {
         process_lock(self.pid, &self.lock)
     }
@@ -107,7 +152,7 @@ impl Thread {
 impl ProcessMemory for Process {
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
         let handle: ProcessHandle = self.pid.try_into()?;
-        Ok(handle.copy_address(addr, buf)?)
+        handle.copy_address(addr, buf).map_err(|e| Error::MemoryReadError(addr, e))
     }
 }
 
@@ -109,6 +109,8 @@ pub enum Error {
     GimliError(gimli::Error),
     GoblinError(::goblin::error::Error),
     IOError(std::io::Error),
+    /// Copying memory at an address from the other process failed
+    MemoryReadError(usize, std::io::Error),
     Other(String),
     #[cfg(all(target_os="linux", unwind))]
     LibunwindError(linux::libunwind::Error),
@@ -127,6 +129,7 @@ impl std::fmt::Display for Error {
             Error::GimliError(ref e) => e.fmt(f),
             Error::GoblinError(ref e) => e.fmt(f),
             Error::IOError(ref e) => e.fmt(f),
+            Error::MemoryReadError(addr, ref e) => write!(f, "Failed to read memory at 0x{:016x}: {}", addr, e),
             Error::Other(ref e) => write!(f, "{}", e),
             #[cfg(all(target_os="linux", unwind))]
             Error::LibunwindError(ref e) => e.fmt(f),
@@ -145,6 +148,7 @@ impl std::error::Error for Error {
             Error::GimliError(ref e) => e.description(),
             Error::GoblinError(ref e) => e.description(),
             Error::IOError(ref e) => e.description(),
+            Error::MemoryReadError(_, _) => "Failed to read memory",
             #[cfg(all(target_os="linux", unwind))]
             Error::LibunwindError(ref e) => e.description(),
             #[cfg(target_os="linux")]
@@ -160,6 +164,7 @@ impl std::error::Error for Error {
             Error::GimliError(ref e) => Some(e),
             Error::GoblinError(ref e) => Some(e),
             Error::IOError(ref e) => Some(e),
+            Error::MemoryReadError(_, ref e) => Some(e),
             #[cfg(all(target_os="linux", unwind))]
             Error::LibunwindError(ref e) => Some(e),
             #[cfg(target_os="linux")]
@@ -235,6 +240,15 @@ pub trait ProcessMemory {
     /// byte buffer
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error>;
 
+    /// Copies several regions of memory from another process. Platforms that support it read all
+    /// the regions with a single call to the OS, otherwise this reads each region in turn
+    fn read_many(&self, reads: &mut [(usize, &mut [u8])]) -> Result<(), Error> {
+        for (addr, buf) in reads.iter_mut() {
+            self.read(*addr, buf)?;
+        }
+        Ok(())
+    }
+
     /// Copies a series of bytes from another process. Main difference
     /// with 'read' is that this will allocate memory for you
     fn copy(&self, addr: usize, length: usize) -> Result<Vec<u8>, Error> {
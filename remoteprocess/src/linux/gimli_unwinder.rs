@@ -1,5 +1,4 @@
 use std::fs::File;
-use std::path::Path;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
@@ -10,6 +9,7 @@ use proc_maps;
 
 use gimli::{EhFrame, BaseAddresses, Pointer, NativeEndian, EhFrameHdr};
 use goblin::elf::program_header::*;
+use goblin::elf::note::NT_GNU_BUILD_ID;
 
 use gimli::EndianRcSlice;
 type RcReader = EndianRcSlice<NativeEndian>;
@@ -18,13 +18,16 @@ use super::super::{ProcessMemory, Error};
 use crate::dwarf_unwind::{UnwindInfo, Registers};
 
 use crate::linux::symbolication::{SymbolData};
+use crate::linux::symbol_cache::SymbolCache;
 use super::super::StackFrame;
-use super::{Pid, Thread, Process};
+use super::{Pid, Thread, Process, namespace_path};
 
 pub struct Unwinder {
     binaries: BTreeMap<u64, BinaryInfo>,
     process: Process,
-    pid: Pid
+    pid: Pid,
+    symbol_cache: Option<SymbolCache>,
+    debuginfod: bool
 }
 
 pub struct Cursor<'a> {
@@ -36,7 +39,7 @@ pub struct Cursor<'a> {
 impl Unwinder {
     pub fn new(pid: Pid) -> Result<Unwinder, Error> {
         let process = Process::new(pid)?;
-        let mut ret = Unwinder{binaries: BTreeMap::new(), process, pid};
+        let mut ret = Unwinder{binaries: BTreeMap::new(), process, pid, symbol_cache: None, debuginfod: false};
         ret.reload()?;
         Ok(ret)
     }
@@ -69,8 +72,10 @@ impl Unwinder {
             let mmapped_file;
             let vdso_data;
 
-            let buffer = if Path::new(filename).exists() {
-                file = File::open(Path::new(filename))?;
+            // binaries of processes in containers are opened through /proc/<pid>/root
+            let path = namespace_path(self.pid, filename);
+            let buffer = if path.exists() {
+                file = File::open(&path)?;
                 mmapped_file = unsafe { Mmap::map(&file)? };
                 &mmapped_file[..]
             } else if filename != "[vsyscall]" {
@@ -85,7 +90,7 @@ impl Unwinder {
                 // insert a stub for [vsyscall] so that we don't continually try to load it etc
                 self.binaries.insert(address_key,
                         BinaryInfo{unwind_info: None, offset: 0, address: m.start() as u64, size: m.size() as u64,
-                                   filename: filename.to_string(), symbols: RefCell::new(None)});
+                                   filename: filename.to_string(), build_id: None, symbols: RefCell::new(None)});
                 continue;
             };
 
@@ -114,11 +119,15 @@ impl Unwinder {
                         }
                     };
 
+                    let build_id = elf.iter_note_headers(buffer)
+                        .and_then(|notes| notes.filter_map(|note| note.ok()).find(|note| note.n_type == NT_GNU_BUILD_ID))
+                        .map(|note| note.desc.to_vec());
+
                     // the map key is the end address of this filename, which lets us do a relatively efficent range
                     // based lookup of the binary
                     self.binaries.insert(address_key,
                         BinaryInfo{unwind_info, offset: obj_base, address: m.start() as u64, size: m.size() as u64,
-                                   filename: filename.to_string(), symbols: RefCell::new(None)});
+                                   filename: filename.to_string(), build_id, symbols: RefCell::new(None)});
                 },
                 Ok(_) => {
                     warn!("unknown binary type for {}", filename);
@@ -133,6 +142,18 @@ impl Unwinder {
         Ok(())
     }
 
+    /// Sets where the symbol tables of binaries are cached between runs. This only applies to
+    /// binaries that haven't been symbolicated yet
+    pub fn set_symbol_cache(&mut self, cache: Option<SymbolCache>) {
+        self.symbol_cache = cache;
+    }
+
+    /// Sets whether to download the debug info of stripped binaries from debuginfod servers.
+    /// This only applies to binaries that haven't been symbolicated yet
+    pub fn set_debuginfod(&mut self, debuginfod: bool) {
+        self.debuginfod = debuginfod;
+    }
+
     fn get_unwind_info(&self, filename: &str, elf: &goblin::elf::Elf, buffer: &[u8], obj_base: u64) -> Result<UnwindInfo, Error> {
         // get the eh_frame_hdr from the program headers
         let eh_frame_hdr_addr;
@@ -144,7 +165,8 @@ impl Unwinder {
                 EhFrameHdr::from(RcReader::new(data, NativeEndian)).parse(&bases, 8)?
             },
             None => {
-                return Err(Error::Other("Failed to find eh_frame_hdr section in binary".to_owned()));
+                debug!("no eh_frame_hdr in {}, building frame description table from eh_frame", filename);
+                return self.get_unwind_info_without_hdr(filename, elf, buffer, obj_base);
             }
         };
 
@@ -171,7 +193,22 @@ impl Unwinder {
             .set_eh_frame(eh_frame_addr)
             .set_eh_frame_hdr(eh_frame_hdr_addr);
 
-        Ok(UnwindInfo{eh_frame_hdr, eh_frame, bases})
+        Ok(UnwindInfo{eh_frame_hdr: Some(eh_frame_hdr), eh_frame, bases, frame_descriptions: Vec::new()})
+    }
+
+    // Some binaries (like python interpreters that are linked statically or with unusual linker
+    // flags) have an eh_frame section without an eh_frame_hdr to index it. For these we find the
+    // eh_frame section by name and build our own index of it, which is slower to load
+    fn get_unwind_info_without_hdr(&self, filename: &str, elf: &goblin::elf::Elf, buffer: &[u8], obj_base: u64)
+            -> Result<UnwindInfo, Error> {
+        let hdr = elf.section_headers.iter()
+            .find(|x| match elf.shdr_strtab.get(x.sh_name) { Some(Ok(name)) => name == ".eh_frame", _ => false })
+            .ok_or_else(|| Error::Other(format!("Failed to find eh_frame or eh_frame_hdr section in {}", filename)))?;
+
+        let data = Rc::from(&buffer[hdr.sh_offset as usize..][..hdr.sh_size as usize]);
+        let eh_frame = EhFrame::from(RcReader::new(data, NativeEndian));
+        let bases = BaseAddresses::default().set_eh_frame(obj_base + hdr.sh_addr);
+        Ok(UnwindInfo::from_eh_frame(eh_frame, bases)?)
     }
 
     pub fn cursor(&self, thread: &Thread) -> Result<Cursor, Error> {
@@ -189,7 +226,8 @@ impl Unwinder {
             let mut symbols = binary.symbols.borrow_mut();
             if symbols.is_none() {
                 info!("loading symbols from {}", binary.filename);
-                *symbols = Some(SymbolData::new(&binary.filename, binary.offset));
+                let path = namespace_path(self.pid, &binary.filename);
+                *symbols = Some(SymbolData::new(&path.to_string_lossy(), binary.offset, self.symbol_cache.as_ref(), self.debuginfod));
             }
             match symbols.as_ref() {
                 Some(Ok(symbols)) => symbols.symbolicate(addr, line_info, callback),
@@ -207,6 +245,14 @@ impl Unwinder {
         }
     }
 
+    /// Gets the filename and build id of the binary containing an address, along with the address
+    /// before the binary was relocated. This is enough to symbolicate the address later on from a
+    /// copy of the binary (or its debug info) with SymbolData
+    pub fn binary_address(&self, addr: u64) -> Option<(&str, Option<&[u8]>, u64)> {
+        self.get_binary(addr)
+            .map(|binary| (binary.filename.as_str(), binary.build_id.as_ref().map(|id| id.as_slice()), addr - binary.offset))
+    }
+
     fn get_binary(&self, addr: u64) -> Option<&BinaryInfo> {
         match self.binaries.range(addr..).next() {
             Some((_, binary)) if binary.contains(addr) => Some(&binary),
@@ -277,6 +323,7 @@ struct BinaryInfo {
     size: u64,
     offset: u64,
     filename: String,
+    build_id: Option<Vec<u8>>,
     unwind_info: Option<UnwindInfo>,
     symbols: RefCell<Option<Result<SymbolData, Error>>>
 }
@@ -4,6 +4,7 @@ pub mod libunwind;
 mod gimli_unwinder;
 #[cfg(unwind)]
 mod symbolication;
+mod symbol_cache;
 use libc::pid_t;
 #[cfg(unwind)]
 use libc::c_void;
@@ -13,6 +14,7 @@ use std::convert::TryInto;
 use std::io::Read;
 use std::os::unix::io::AsRawFd;
 use std::fs::File;
+use std::path::PathBuf;
 
 #[cfg(unwind)]
 use crate::dwarf_unwind::Registers;
@@ -22,6 +24,7 @@ use super::Error;
 pub use self::gimli_unwinder::*;
 #[cfg(unwind)]
 pub use self::symbolication::*;
+pub use self::symbol_cache::SymbolCache;
 #[cfg(unwind)]
 pub use self::libunwind::{LibUnwind};
 
@@ -54,6 +57,87 @@ impl Process {
         Ok(path.to_string_lossy().to_string())
     }
 
+    /// Returns the id of this process in its own pid namespace, which is the pid it knows itself by
+    /// when running in a container (and the one it puts in the names of files like /tmp/perf-<pid>.map)
+    pub fn namespace_pid(&self) -> Result<Pid, Error> {
+        let mut contents = String::new();
+        File::open(format!("/proc/{}/status", self.pid))?.read_to_string(&mut contents)?;
+        Ok(get_namespace_pid(&contents).unwrap_or(self.pid))
+    }
+
+    /// Returns true if the process is in a frozen cgroup, like the processes of a paused container.
+    /// Frozen processes can't be attached to until they're thawed, and trying blocks forever
+    pub fn frozen(&self) -> Result<bool, Error> {
+        let cgroups = std::fs::read_to_string(format!("/proc/{}/cgroup", self.pid))?;
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+        Ok(get_freeze_controls(&cgroups, &mountinfo).iter().any(|control| control.frozen().unwrap_or(false)))
+    }
+
+    /// Returns the command line arguments this process was started with
+    pub fn cmdline(&self) -> Result<Vec<String>, Error> {
+        let mut contents = Vec::new();
+        File::open(format!("/proc/{}/cmdline", self.pid))?.read_to_end(&mut contents)?;
+        Ok(contents.split(|&b| b == 0).filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).to_string()).collect())
+    }
+
+    /// Returns the pids of all the descendants of this process (children, grandchildren etc)
+    pub fn child_processes(&self) -> Result<Vec<Pid>, Error> {
+        let mut children: std::collections::HashMap<Pid, Vec<Pid>> = std::collections::HashMap::new();
+        for pid in processes()? {
+            // processes can exit while we're iterating, so ignore errors reading them
+            let mut contents = String::new();
+            if File::open(format!("/proc/{}/stat", pid)).and_then(|mut f| f.read_to_string(&mut contents)).is_err() {
+                continue;
+            }
+            if let Some(ppid) = get_parent_pid(&contents) {
+                children.entry(ppid).or_default().push(pid);
+            }
+        }
+
+        let mut ret = Vec::new();
+        let mut pending = vec![self.pid];
+        while let Some(pid) = pending.pop() {
+            if let Some(pids) = children.get(&pid) {
+                ret.extend(pids);
+                pending.extend(pids);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Returns the total amount of CPU time used by all the threads in this process
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        let mut contents = String::new();
+        File::open(format!("/proc/{}/stat", self.pid))?.read_to_string(&mut contents)?;
+        match get_cpu_ticks(&contents) {
+            Some(ticks) => Ok(ticks_to_duration(ticks)),
+            None => Err(Error::Other(format!("Failed to parse /proc/{}/stat", self.pid)))
+        }
+    }
+
+    /// Returns the exit code of this process once it has exited, or None if it's still running.
+    /// Processes killed by a signal return 128 plus the signal number, like shells do. This can only
+    /// be read until the parent of the process reaps it, so is mostly useful for child processes
+    pub fn exit_code(&self) -> Result<Option<i32>, Error> {
+        let mut contents = String::new();
+        File::open(format!("/proc/{}/stat", self.pid))?.read_to_string(&mut contents)?;
+        match get_exit_code(&contents) {
+            Some(code) => Ok(code),
+            None => Err(Error::Other(format!("Failed to parse /proc/{}/stat", self.pid)))
+        }
+    }
+
+    /// Returns the resident set size of this process in bytes
+    pub fn memory_usage(&self) -> Result<u64, Error> {
+        let mut contents = String::new();
+        File::open(format!("/proc/{}/statm", self.pid))?.read_to_string(&mut contents)?;
+        match contents.split_whitespace().nth(1).and_then(|pages| pages.parse::<u64>().ok()) {
+            Some(pages) => Ok(pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64),
+            None => Err(Error::Other(format!("Failed to parse /proc/{}/statm", self.pid)))
+        }
+    }
+
     pub fn lock(&self) -> Result<Lock, Error> {
         let mut locks = Vec::new();
         let mut locked = std::collections::HashSet::new();
@@ -75,7 +159,14 @@ impl Process {
             }
         }
 
-        Ok(Lock{locks})
+        Ok(Lock{locks, freezer: None})
+    }
+
+    /// Stops every thread of the process at the same instant by freezing its cgroup, rather than
+    /// stopping the threads one at a time with ptrace like lock does. This also freezes any other
+    /// processes in the same cgroup, and fails if we're in that cgroup ourselves
+    pub fn freeze(&self) -> Result<Lock, Error> {
+        Ok(Lock{locks: Vec::new(), freezer: Some(Freezer::new(self.pid)?)})
     }
 
     pub fn threads(&self) -> Result<Vec<Thread>, Error> {
@@ -106,7 +197,33 @@ impl Process {
 impl super::ProcessMemory for Process {
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
         let handle: ProcessHandle = self.pid.try_into()?;
-        Ok(handle.copy_address(addr, buf)?)
+        handle.copy_address(addr, buf).map_err(|e| Error::MemoryReadError(addr, e))
+    }
+
+    fn read_many(&self, reads: &mut [(usize, &mut [u8])]) -> Result<(), Error> {
+        // process_vm_readv takes at most IOV_MAX regions at a time
+        for chunk in reads.chunks_mut(1024) {
+            let mut local = Vec::with_capacity(chunk.len());
+            let mut remote = Vec::with_capacity(chunk.len());
+            for (addr, buf) in chunk.iter_mut() {
+                local.push(libc::iovec{iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len()});
+                remote.push(libc::iovec{iov_base: *addr as *mut libc::c_void, iov_len: buf.len()});
+            }
+            let expected: usize = local.iter().map(|iov| iov.iov_len).sum();
+            let read = unsafe {
+                libc::process_vm_readv(self.pid, local.as_ptr(), local.len() as libc::c_ulong,
+                                       remote.as_ptr(), remote.len() as libc::c_ulong, 0)
+            };
+
+            // reads stop at the first region that can't be copied, so fall back to copying
+            // each region separately to get an error for the region that failed
+            if read < 0 || read as usize != expected {
+                for (addr, buf) in chunk.iter_mut() {
+                    self.read(*addr, buf)?;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -147,13 +264,167 @@ impl Thread {
             None => Err(Error::Other(format!("Failed to parse /proc/{}/stat", self.tid)))
         }
     }
+
+    /// Returns the name of this thread, as set by pthread_setname_np or prctl
+    pub fn name(&self) -> Result<String, Error> {
+        let mut name = String::new();
+        File::open(format!("/proc/{}/comm", self.tid))?.read_to_string(&mut name)?;
+        Ok(name.trim_end().to_owned())
+    }
+
+    /// Returns the total amount of time this thread has spent running on a CPU
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        // schedstat has nanosecond resolution, unlike the clock ticks in /proc/<tid>/stat, but
+        // isn't available (or is always zero) on kernels built without CONFIG_SCHEDSTATS
+        let mut contents = String::new();
+        if File::open(format!("/proc/{}/schedstat", self.tid)).and_then(|mut f| f.read_to_string(&mut contents)).is_ok() {
+            if let Some(ns) = contents.split_whitespace().next().and_then(|ns| ns.parse::<u64>().ok()) {
+                if ns > 0 {
+                    return Ok(std::time::Duration::from_nanos(ns));
+                }
+            }
+        }
+
+        let mut contents = String::new();
+        File::open(format!("/proc/{}/stat", self.tid))?.read_to_string(&mut contents)?;
+        match get_cpu_ticks(&contents) {
+            Some(ticks) => Ok(ticks_to_duration(ticks)),
+            None => Err(Error::Other(format!("Failed to parse /proc/{}/stat", self.tid)))
+        }
+    }
 }
 
-/// This locks a target process using ptrace, and prevents it from running while this
-/// struct is alive
+/// This locks a target process using ptrace (or by freezing its cgroup), and prevents it from
+/// running while this struct is alive
 pub struct Lock {
     #[allow(dead_code)]
-    locks: Vec<ThreadLock>
+    locks: Vec<ThreadLock>,
+    #[allow(dead_code)]
+    freezer: Option<Freezer>,
+}
+
+// how long to wait for every task in a cgroup to stop after asking for it to be frozen
+const FREEZE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Freezes a cgroup, thawing it again when dropped
+struct Freezer {
+    control: FreezeControl,
+}
+
+impl Freezer {
+    fn new(pid: Pid) -> Result<Freezer, Error> {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+        let controls = get_freeze_controls(&std::fs::read_to_string(format!("/proc/{}/cgroup", pid))?, &mountinfo);
+        if !controls.is_empty() && controls.iter().all(|control| control.cgroup == "/") {
+            return Err(Error::Other(format!("Process {} is in the root cgroup, which can't be frozen", pid)));
+        }
+        let control = controls.into_iter().find(|control| control.cgroup != "/" && control.control.exists())
+            .ok_or_else(|| Error::Other(format!("Failed to find a cgroup freezer for process {}", pid)))?;
+        // freezing a cgroup we're in would stop us from ever thawing it
+        let ours = get_freeze_controls(&std::fs::read_to_string("/proc/self/cgroup")?, &mountinfo);
+        if ours.iter().any(|our| our.control.parent().is_some_and(|dir| dir.starts_with(control.control.parent().unwrap()))) {
+            return Err(Error::Other(format!("py-spy is in the same cgroup as process {} ({}), so it can't freeze it",
+                                            pid, control.cgroup)));
+        }
+        if control.frozen()? {
+            return Err(Error::Other(format!("The cgroup of process {} ({}) is already frozen", pid, control.cgroup)));
+        }
+
+        std::fs::write(&control.control, control.freeze)?;
+        let freezer = Freezer{control};
+        let start = std::time::Instant::now();
+        while !freezer.control.frozen()? {
+            if start.elapsed() > FREEZE_TIMEOUT {
+                return Err(Error::Other(format!("Timed out freezing cgroup {}", freezer.control.cgroup)));
+            }
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+        debug!("froze cgroup {}", freezer.control.cgroup);
+        Ok(freezer)
+    }
+}
+
+impl Drop for Freezer {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::write(&self.control.control, self.control.thaw) {
+            error!("Failed to thaw cgroup {} : {}", self.control.cgroup, e);
+        }
+        debug!("thawed cgroup {}", self.control.cgroup);
+    }
+}
+
+/// The files used to freeze a cgroup, with either the cgroup v2 freezer or the v1 freezer controller
+#[derive(Debug, PartialEq)]
+struct FreezeControl {
+    cgroup: String,
+    control: PathBuf,
+    freeze: &'static str,
+    thaw: &'static str,
+    state: PathBuf,
+    frozen: &'static str,
+}
+
+impl FreezeControl {
+    fn frozen(&self) -> Result<bool, Error> {
+        Ok(std::fs::read_to_string(&self.state)?.lines().any(|line| line.trim() == self.frozen))
+    }
+}
+
+/// Gets the freezers for the cgroups in /proc/<pid>/cgroup, from where /proc/self/mountinfo
+/// says the cgroup hierarchies are mounted
+fn get_freeze_controls(cgroups: &str, mountinfo: &str) -> Vec<FreezeControl> {
+    // the mount point and root of the cgroup v2 hierarchy and the v1 freezer hierarchy
+    let (mut unified, mut freezer) = (None, None);
+    for line in mountinfo.lines() {
+        let (mount, filesystem) = match line.split_once(" - ") {
+            Some(parts) => parts,
+            None => continue
+        };
+        let fields: Vec<&str> = mount.split_whitespace().collect();
+        let filesystem: Vec<&str> = filesystem.split_whitespace().collect();
+        let (root, mount_point) = match (fields.get(3), fields.get(4)) {
+            (Some(root), Some(mount_point)) => (*root, *mount_point),
+            _ => continue
+        };
+        match (filesystem.first(), filesystem.get(2)) {
+            (Some(&"cgroup2"), _) if unified.is_none() => unified = Some((root, mount_point)),
+            (Some(&"cgroup"), Some(options)) if options.split(',').any(|option| option == "freezer") => {
+                freezer = Some((root, mount_point));
+            },
+            _ => {}
+        }
+    }
+
+    let mut controls = Vec::new();
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (controllers, cgroup) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(_), Some(controllers), Some(cgroup)) => (controllers, cgroup),
+            _ => continue
+        };
+        let mount = if controllers.is_empty() {
+            unified
+        } else if controllers.split(',').any(|controller| controller == "freezer") {
+            freezer
+        } else {
+            continue
+        };
+        let (root, mount_point) = match mount {
+            Some(mount) => mount,
+            None => continue
+        };
+        // paths are relative to our cgroup namespace, which can be below the root of the mount
+        let relative = cgroup.strip_prefix(root).unwrap_or(cgroup);
+        let directory = PathBuf::from(mount_point).join(relative.trim_start_matches('/'));
+        controls.push(if controllers.is_empty() {
+            FreezeControl{cgroup: cgroup.to_owned(), control: directory.join("cgroup.freeze"), freeze: "1", thaw: "0",
+                          state: directory.join("cgroup.events"), frozen: "frozen 1"}
+        } else {
+            FreezeControl{cgroup: cgroup.to_owned(), control: directory.join("freezer.state"), freeze: "FROZEN",
+                          thaw: "THAWED", state: directory.join("freezer.state"), frozen: "FROZEN"}
+        });
+    }
+    controls
 }
 
 pub struct ThreadLock {
@@ -178,6 +449,20 @@ impl Drop for ThreadLock {
     }
 }
 
+/// Gets the path that a file the process sees can be opened at by us. Processes running in containers
+/// have their own mount namespace, whose root is visible from the host at /proc/<pid>/root
+pub fn namespace_path(pid: Pid, filename: &str) -> PathBuf {
+    let same_namespace = match (std::fs::read_link("/proc/self/ns/mnt"), std::fs::read_link(format!("/proc/{}/ns/mnt", pid))) {
+        (Ok(ours), Ok(theirs)) => ours == theirs,
+        _ => true
+    };
+    if same_namespace || !filename.starts_with('/') {
+        PathBuf::from(filename)
+    } else {
+        PathBuf::from(format!("/proc/{}/root{}", pid, filename))
+    }
+}
+
 pub struct Namespace {
     ns_file: Option<File>
 }
@@ -220,6 +505,107 @@ fn get_active_status(stat: &[u8]) -> Option<u8> {
     }
 }
 
+/// Returns the utime + stime fields from /proc/<tid>/stat, in clock ticks
+fn get_cpu_ticks(stat: &str) -> Option<u64> {
+    // fields after the command name start at the state field (field 3), utime and stime
+    // are fields 14 and 15
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Returns the exit_code field (52) from /proc/<pid>/stat for zombie processes, or Some(None)
+/// if the process hasn't exited. The field holds the status as returned by waitpid
+fn get_exit_code(stat: &str) -> Option<Option<i32>> {
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    if *fields.first()? != "Z" {
+        return Some(None);
+    }
+    let status: i32 = fields.get(49)?.parse().ok()?;
+    match status & 0x7f {
+        0 => Some(Some((status >> 8) & 0xff)),
+        signal => Some(Some(128 + signal))
+    }
+}
+
+/// Returns the pids of all the processes running on the system
+pub fn processes() -> Result<Vec<Pid>, Error> {
+    let mut ret = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        if let Ok(pid) = entry?.file_name().to_string_lossy().parse() {
+            ret.push(pid);
+        }
+    }
+    Ok(ret)
+}
+
+/// Gets the pid in the innermost pid namespace from the NSpid line of /proc/<pid>/status
+fn get_namespace_pid(status: &str) -> Option<Pid> {
+    let line = status.lines().find(|line| line.starts_with("NSpid:"))?;
+    line["NSpid:".len()..].split_whitespace().last()?.parse().ok()
+}
+
+fn get_parent_pid(stat: &str) -> Option<Pid> {
+    // the parent pid is field 4, right after the state
+    stat[stat.rfind(')')? + 1..].split_whitespace().nth(1)?.parse().ok()
+}
+
+fn ticks_to_duration(ticks: u64) -> std::time::Duration {
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    std::time::Duration::from_nanos(ticks * 1_000_000_000 / ticks_per_second)
+}
+
+#[test]
+fn test_parse_cpu_ticks() {
+    assert_eq!(get_cpu_ticks("1234 (with ) space) R 1 1234 1234 0 -1 4194304 100 0 0 0 25 17 0 0 20 0 1 0"), Some(42));
+    assert_eq!(get_cpu_ticks("1234 (bash) S 1233"), None);
+    assert_eq!(get_cpu_ticks("1234"), None);
+}
+
+#[test]
+fn test_parse_exit_code() {
+    let zombie = |status: i32| format!("1234 (python) Z 1 1234 1234 0 -1 4194380 {}{}", "0 ".repeat(42), status);
+    assert_eq!(get_exit_code(&zombie(3 << 8)), Some(Some(3)));
+    assert_eq!(get_exit_code(&zombie(9)), Some(Some(137)));
+    assert_eq!(get_exit_code("1234 (python) S 1 1234"), Some(None));
+    assert_eq!(get_exit_code("1234 (python) Z 1 1234"), None);
+}
+
+#[test]
+fn test_parse_namespace_pid() {
+    assert_eq!(get_namespace_pid("Name:\tpython\nPid:\t31337\nNSpid:\t31337\t1\nNSpgid:\t31337\t1\n"), Some(1));
+    assert_eq!(get_namespace_pid("Name:\tpython\nNSpid:\t1234\n"), Some(1234));
+    assert_eq!(get_namespace_pid("Name:\tpython\n"), None);
+}
+
+#[test]
+fn test_freeze_controls() {
+    let mountinfo = "25 30 0:23 / /sys/fs/cgroup ro,nosuid shared:9 - tmpfs tmpfs ro,mode=755\n\
+                     26 25 0:24 / /sys/fs/cgroup/unified rw,nosuid shared:10 - cgroup2 cgroup2 rw\n\
+                     31 25 0:28 / /sys/fs/cgroup/freezer rw,nosuid shared:15 - cgroup cgroup rw,freezer\n\
+                     32 25 0:29 / /sys/fs/cgroup/cpu,cpuacct rw,nosuid shared:16 - cgroup cgroup rw,cpu,cpuacct\n";
+    let controls = get_freeze_controls("7:freezer:/docker/abc\n4:cpu,cpuacct:/docker/abc\n0::/system.slice/docker.service\n",
+                                       mountinfo);
+    assert_eq!(controls.len(), 2);
+    assert_eq!(controls[0].control, PathBuf::from("/sys/fs/cgroup/freezer/docker/abc/freezer.state"));
+    assert_eq!((controls[0].freeze, controls[0].thaw), ("FROZEN", "THAWED"));
+    assert_eq!(controls[1].control, PathBuf::from("/sys/fs/cgroup/unified/system.slice/docker.service/cgroup.freeze"));
+    assert_eq!(controls[1].state, PathBuf::from("/sys/fs/cgroup/unified/system.slice/docker.service/cgroup.events"));
+
+    // inside a cgroup namespace, the mount's root is where our namespace starts
+    let mountinfo = "40 39 0:30 /kubepods/pod1 /sys/fs/cgroup rw,nosuid - cgroup2 cgroup2 rw\n";
+    let controls = get_freeze_controls("0::/kubepods/pod1/web\n", mountinfo);
+    assert_eq!(controls[0].control, PathBuf::from("/sys/fs/cgroup/web/cgroup.freeze"));
+    assert!(get_freeze_controls("0::/web\n", "").is_empty());
+}
+
+#[test]
+fn test_parse_parent_pid() {
+    assert_eq!(get_parent_pid("1234 (with ) space) R 1 1234 1234 0"), Some(1));
+    assert_eq!(get_parent_pid("1234 (bash)"), None);
+}
+
 #[test]
 fn test_parse_stat() {
     assert_eq!(get_active_status(b"1234 (bash) S 1233"), Some(b'S'));
@@ -0,0 +1,136 @@
+// Stores the symbol tables of binaries on disk, so that symbolicating large binaries (like libtorch
+// or a python interpreter with its full symbol table) doesn't require reading and sorting all of
+// their symbols every time we attach to a process. Entries are keyed by the build id of the binary,
+// which changes whenever the binary does, so they never need to be invalidated. Binaries without a
+// build id aren't cached. Line number information is still read from the binary when it's needed.
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+use crate::Error;
+
+// bump this whenever the format of the cache files changes
+const MAGIC: &[u8; 8] = b"RPSYMS01";
+
+// sanity limit on the length of symbol names, in case a cache file is corrupt
+const MAX_NAME_LENGTH: usize = 1 << 20;
+
+/// The symbols and dynamic symbols of a binary, as (address, size, name) sorted by address
+pub type SymbolTables = (Vec<(u64, u64, String)>, Vec<(u64, u64, String)>);
+
+pub struct SymbolCache {
+    directory: PathBuf
+}
+
+impl SymbolCache {
+    pub fn new(directory: PathBuf) -> SymbolCache {
+        SymbolCache{directory}
+    }
+
+    /// Gets the symbol tables of the binary with a build id, if they've been cached
+    pub fn load(&self, build_id: &[u8]) -> Option<SymbolTables> {
+        let filename = self.filename(build_id);
+        let file = File::open(&filename).ok()?;
+        match read_tables(&mut BufReader::new(file)) {
+            Ok(tables) => Some(tables),
+            Err(e) => {
+                warn!("Failed to read symbol cache {}: {}", filename.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Stores the symbol tables of the binary with a build id. The file is written under a
+    /// temporary name and renamed into place, so that concurrent runs never see a partial file
+    pub fn store(&self, build_id: &[u8], tables: &SymbolTables) -> Result<(), Error> {
+        fs::create_dir_all(&self.directory)?;
+        let filename = self.filename(build_id);
+        let temp = filename.with_extension(format!("tmp{}", std::process::id()));
+        {
+            let mut writer = BufWriter::new(File::create(&temp)?);
+            write_tables(&mut writer, tables)?;
+            writer.flush()?;
+        }
+        fs::rename(&temp, &filename)?;
+        Ok(())
+    }
+
+    fn filename(&self, build_id: &[u8]) -> PathBuf {
+        let hex: String = build_id.iter().map(|b| format!("{:02x}", b)).collect();
+        self.directory.join(format!("{}.syms", hex))
+    }
+}
+
+fn write_tables<W: Write>(writer: &mut W, tables: &SymbolTables) -> std::io::Result<()> {
+    writer.write_all(MAGIC)?;
+    for table in &[&tables.0, &tables.1] {
+        writer.write_all(&(table.len() as u64).to_le_bytes())?;
+        for (address, size, name) in table.iter() {
+            writer.write_all(&address.to_le_bytes())?;
+            writer.write_all(&size.to_le_bytes())?;
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_tables<R: Read>(reader: &mut R) -> Result<SymbolTables, Error> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::Other("unknown symbol cache format".to_owned()));
+    }
+    Ok((read_table(reader)?, read_table(reader)?))
+}
+
+fn read_table<R: Read>(reader: &mut R) -> Result<Vec<(u64, u64, String)>, Error> {
+    let count = read_u64(reader)?;
+    let mut table = Vec::new();
+    for _ in 0..count {
+        let address = read_u64(reader)?;
+        let size = read_u64(reader)?;
+        let mut length = [0u8; 4];
+        reader.read_exact(&mut length)?;
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_NAME_LENGTH {
+            return Err(Error::Other(format!("invalid symbol name length {}", length)));
+        }
+        let mut name = vec![0u8; length];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| Error::Other("invalid symbol name".to_owned()))?;
+        table.push((address, size, name));
+    }
+    Ok(table)
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut data = [0u8; 8];
+    reader.read_exact(&mut data)?;
+    Ok(u64::from_le_bytes(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_cache() {
+        let directory = std::env::temp_dir().join(format!("remoteprocess_symbol_cache_{}", std::process::id()));
+        let cache = SymbolCache::new(directory.clone());
+        let build_id = [0xde, 0xad, 0xbe, 0xef];
+        assert!(cache.load(&build_id).is_none());
+
+        let tables = (vec![(0x1000, 16, "main".to_owned()), (0x1010, 32, "_PyEval_EvalFrameDefault".to_owned())],
+                      vec![(0x2000, 8, "PyRun_SimpleString".to_owned())]);
+        cache.store(&build_id, &tables).unwrap();
+        assert_eq!(cache.load(&build_id), Some(tables));
+
+        // a truncated file is treated as missing
+        let filename = directory.join("deadbeef.syms");
+        let data = fs::read(&filename).unwrap();
+        fs::write(&filename, &data[..data.len() - 4]).unwrap();
+        assert!(cache.load(&build_id).is_none());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
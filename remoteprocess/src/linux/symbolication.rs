@@ -1,54 +1,81 @@
+use std::cell::RefCell;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use memmap;
 
 use object::{self, Object};
 use addr2line::Context;
 use gimli;
 use crate::{StackFrame, Error};
+use super::symbol_cache::{SymbolCache, SymbolTables};
 
 
 pub struct SymbolData {
     // Contains symbol info for a single binary
-    ctx: Context,
+    // the binary, or the separate file with its debug info if it has been stripped
+    map: memmap::Mmap,
+    // the debug info context is only created the first time we need line information
+    ctx: RefCell<Option<Result<Context, String>>>,
     offset: u64,
     symbols: Vec<(u64, u64, String)>,
     dynamic_symbols: Vec<(u64, u64, String)>,
+    build_id: Option<Vec<u8>>,
     filename: String
 }
 
 impl SymbolData {
-    pub fn new(filename: &str, offset: u64) -> Result<SymbolData, Error> {
+    pub fn new(filename: &str, offset: u64, cache: Option<&SymbolCache>, debuginfod: bool) -> Result<SymbolData, Error> {
         info!("opening {} for symbols", filename);
 
-        let file = File::open(filename)?;
-        let map = unsafe { memmap::Mmap::map(&file)? };
-        let file = match object::File::parse(&*map) {
-            Ok(f) => f,
-            Err(e) => {
-                error!("failed to parse file for symbolication {}: {:?}", filename, e);
-                return Err(gimli::Error::OffsetOutOfBounds.into());
-            }
-        };
+        let map = map_file(filename)?;
+        let (debug_map, build_id, (symbols, dynamic_symbols)) = {
+            let file = parse_object(filename, &map)?;
+            let build_id = file.build_id().filter(|id| !id.is_empty());
 
-        let ctx = Context::new(&file)
-            .map_err(|e| Error::Other(format!("Failed to get symbol context for {}: {:?}", filename, e)))?;
+            // stripped binaries can have their debug info (and full symbol table) in a separate file
+            let debug_map = match build_id {
+                Some(build_id) if file.section_data_by_name(".debug_info").is_none() => {
+                    find_debug_file(build_id, debuginfod).and_then(|path| {
+                        info!("using debug info for {} from {}", filename, path.display());
+                        map_file(&path).map_err(|e| warn!("Failed to open {}: {}", path.display(), e)).ok()
+                    })
+                },
+                _ => None
+            };
+            let debug_file = match debug_map.as_ref() {
+                Some(debug_map) => parse_object(filename, debug_map).ok(),
+                None => None
+            };
 
-        let mut symbols = Vec::new();
-        for (_, sym) in file.symbols() {
-            if let Some(name) = sym.name() {
-                symbols.push((sym.address(), sym.size(), name.to_string()));
-            }
-        }
-        symbols.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            let has_debug_file = debug_file.is_some();
+            let cache_key = build_id.map(|id| symbol_cache_key(id, has_debug_file));
+            let tables = match (cache, cache_key) {
+                (Some(cache), Some(key)) => match cache.load(&key) {
+                    Some(tables) => {
+                        info!("loaded symbols for {} from cache", filename);
+                        tables
+                    },
+                    None => {
+                        let tables = get_all_symbol_tables(&file, debug_file.as_ref());
+                        if let Err(e) = cache.store(&key, &tables) {
+                            warn!("Failed to cache symbols for {}: {}", filename, e);
+                        }
+                        tables
+                    }
+                },
+                _ => get_all_symbol_tables(&file, debug_file.as_ref())
+            };
+            // only read the debug info from the separate file if it could be parsed
+            (debug_map.filter(|_| has_debug_file), build_id.map(|id| id.to_vec()), tables)
+        };
+        let map = debug_map.unwrap_or(map);
+        Ok(SymbolData{map, ctx: RefCell::new(None), offset, dynamic_symbols, symbols, build_id, filename: filename.to_owned()})
+    }
 
-        let mut dynamic_symbols = Vec::new();
-        for (_, sym) in file.dynamic_symbols() {
-            if let Some(name) = sym.name() {
-                dynamic_symbols.push((sym.address(), sym.size(), name.to_string()));
-            }
-        }
-        dynamic_symbols.sort_unstable_by(|a, b| a.0.cmp(&b.0));
-        Ok(SymbolData{ctx, offset, dynamic_symbols, symbols, filename: filename.to_owned()})
+    /// The build id of the binary, which identifies the exact build it came from
+    pub fn build_id(&self) -> Option<&[u8]> {
+        self.build_id.as_ref().map(|id| id.as_slice())
     }
 
     pub fn symbolicate(&self, addr: u64, line_info: bool, callback: &mut FnMut(&StackFrame)) -> Result<(), Error> {
@@ -60,55 +87,220 @@ impl SymbolData {
         // if we are being asked for line information, sue gimli addr2line to look up the debug info
         // (this is slow, and not necessary all the time which is why we are skipping)
         if line_info {
-            let mut has_debug_info = false;
-
-            // addr2line0.8 uses an older version of gimli (0.0.19) than we are using here (0.0.21),
-            // this means we can't use the type of the error returned ourselves here since the
-            // type alias is private. hack by re-mapping the error
-            let error_handler = |e| Error::Other(format!("addr2line error: {:?}", e));
-
-            // if we have debugging info, get the appropiate stack frames for the adresss
-            let mut frames = self.ctx.find_frames(offset).map_err(error_handler)?;
-            while let Some(frame) = frames.next().map_err(error_handler)? {
-                has_debug_info = true;
-                if let Some(func) = frame.function {
-                    ret.function = Some(func.raw_name().map_err(error_handler)?.to_string());
+            let mut ctx = self.ctx.borrow_mut();
+            if ctx.is_none() {
+                *ctx = Some(parse_object(&self.filename, &self.map)
+                    .map_err(|e| e.to_string())
+                    .and_then(|file| Context::new(&file)
+                        .map_err(|e| format!("Failed to get symbol context for {}: {:?}", self.filename, e))));
+                if let Some(Err(e)) = ctx.as_ref() {
+                    warn!("{}", e);
                 }
-                if let Some(loc) = frame.location {
-                    ret.line = loc.line;
-                    if let Some(file) = loc.file.as_ref() {
-                        ret.filename = Some(file.to_string());
+            }
+            // fall back to the symbol tables below if the debug info can't be read
+            if let Some(Ok(ctx)) = ctx.as_ref() {
+                // addr2line0.8 uses an older version of gimli (0.0.19) than we are using here (0.0.21),
+                // this means we can't use the type of the error returned ourselves here since the
+                // type alias is private. hack by re-mapping the error
+                let error_handler = |e| Error::Other(format!("addr2line error: {:?}", e));
+
+                // if we have debugging info, get the appropiate stack frames for the adresss. There is
+                // a frame for each function that has been inlined at the address, innermost first, with
+                // the location in each function being where it calls the next one
+                let mut inlined = Vec::new();
+                let mut frames = ctx.find_frames(offset).map_err(error_handler)?;
+                while let Some(frame) = frames.next().map_err(error_handler)? {
+                    let mut inlined_frame = StackFrame{line: None, filename: None, function: None, addr, module: self.filename.clone()};
+                    if let Some(func) = frame.function {
+                        inlined_frame.function = Some(func.raw_name().map_err(error_handler)?.to_string());
                     }
+                    if let Some(loc) = frame.location {
+                        inlined_frame.line = loc.line;
+                        if let Some(file) = loc.file.as_ref() {
+                            inlined_frame.filename = Some(file.to_string());
+                        }
+                    }
+                    inlined.push(inlined_frame);
                 }
-                callback(&ret);
-            }
 
-            if has_debug_info {
-                return Ok(())
+                if !inlined.is_empty() {
+                    // the outermost function is the one in the symbol table, if the debug info doesn't name it
+                    let last = inlined.len() - 1;
+                    if inlined[last].function.is_none() {
+                        inlined[last].function = self.symbol_name(offset);
+                    }
+                    for frame in inlined.iter().filter(|frame| frame.function.is_some()) {
+                        callback(frame);
+                    }
+                    return Ok(())
+                }
             }
         }
 
         // otherwise try getting the function name from the symbols
-        if self.symbols.len() > 0 {
-            let symbol = match self.symbols.binary_search_by(|sym| sym.0.cmp(&offset)) {
-                Ok(i) => &self.symbols[i],
-                Err(i) => &self.symbols[if i > 0 { i - 1 } else { 0 }]
+        ret.function = self.symbol_name(offset);
+        callback(&ret);
+        Ok(())
+    }
+
+    // gets the name of the function containing an address from the symbol tables
+    fn symbol_name(&self, offset: u64) -> Option<String> {
+        for symbols in &[&self.symbols, &self.dynamic_symbols] {
+            if symbols.is_empty() {
+                continue;
+            }
+            let symbol = match symbols.binary_search_by(|sym| sym.0.cmp(&offset)) {
+                Ok(i) => &symbols[i],
+                Err(i) => &symbols[if i > 0 { i - 1 } else { 0 }]
             };
             if offset >= symbol.0 && offset < (symbol.0 + symbol.1) {
-                ret.function = Some(symbol.2.clone());
+                return Some(symbol.2.clone());
             }
         }
+        None
+    }
+}
 
-        if ret.function.is_none() && self.dynamic_symbols.len() > 0 {
-            let symbol = match self.dynamic_symbols.binary_search_by(|sym| sym.0.cmp(&offset)) {
-                Ok(i) => &self.dynamic_symbols[i],
-                Err(i) => &self.dynamic_symbols[if i > 0 { i - 1 } else { 0 }]
-            };
-            if offset >= symbol.0 && offset < (symbol.0 + symbol.1) {
-                ret.function = Some(symbol.2.clone());
-            }
+fn parse_object<'a>(filename: &str, data: &'a [u8]) -> Result<object::File<'a>, Error> {
+    match object::File::parse(data) {
+        Ok(f) => Ok(f),
+        Err(e) => {
+            error!("failed to parse file for symbolication {}: {:?}", filename, e);
+            Err(gimli::Error::OffsetOutOfBounds.into())
         }
-        callback(&ret);
-        Ok(())
+    }
+}
+
+// gets the symbols and dynamic symbols of a binary, sorted by address
+fn get_symbol_tables(file: &object::File) -> SymbolTables {
+    let mut symbols = Vec::new();
+    for (_, sym) in file.symbols() {
+        if let Some(name) = sym.name() {
+            symbols.push((sym.address(), sym.size(), name.to_string()));
+        }
+    }
+    symbols.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let mut dynamic_symbols = Vec::new();
+    for (_, sym) in file.dynamic_symbols() {
+        if let Some(name) = sym.name() {
+            dynamic_symbols.push((sym.address(), sym.size(), name.to_string()));
+        }
+    }
+    dynamic_symbols.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    (symbols, dynamic_symbols)
+}
+
+// gets the symbol tables of a binary, using the symbols from its debug file if it was stripped
+fn get_all_symbol_tables(file: &object::File, debug_file: Option<&object::File>) -> SymbolTables {
+    let (symbols, dynamic_symbols) = get_symbol_tables(file);
+    match debug_file {
+        Some(debug_file) if symbols.is_empty() => (get_symbol_tables(debug_file).0, dynamic_symbols),
+        _ => (symbols, dynamic_symbols)
+    }
+}
+
+// symbols from a separate debug file are cached separately from the ones in the binary
+fn symbol_cache_key(build_id: &[u8], debug_file: bool) -> Vec<u8> {
+    let mut key = build_id.to_vec();
+    if debug_file {
+        key.extend_from_slice(b"debug");
+    }
+    key
+}
+
+fn map_file<P: AsRef<Path>>(filename: P) -> Result<memmap::Mmap, Error> {
+    let file = File::open(filename)?;
+    Ok(unsafe { memmap::Mmap::map(&file)? })
+}
+
+// Finds the separate debug info file for a binary with a build id. This looks in /usr/lib/debug
+// (where distributions install debug info packages), and then optionally asks the debuginfod
+// servers in DEBUGINFOD_URLS for it with debuginfod-find, which caches what it downloads locally
+pub fn find_debug_file(build_id: &[u8], debuginfod: bool) -> Option<PathBuf> {
+    if let Some(local) = local_debug_file(Path::new("/usr/lib/debug"), build_id) {
+        return Some(local);
+    }
+    if !debuginfod {
+        return None;
+    }
+
+    let hex = hex_build_id(build_id);
+    let output = match Command::new("debuginfod-find").arg("debuginfo").arg(&hex).output() {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Failed to run debuginfod-find (which is part of elfutils): {}", e);
+            return None;
+        }
+    };
+    if !output.status.success() {
+        info!("debuginfod-find failed for build id {}: {}", hex, String::from_utf8_lossy(&output.stderr).trim());
+        return None;
+    }
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if path.is_empty() { None } else { Some(PathBuf::from(path)) }
+}
+
+// debug info packages install the debug file for a build id as .build-id/xx/rest.debug, where xx is
+// the first byte of the build id in hex and rest is the remainder
+fn local_debug_file(root: &Path, build_id: &[u8]) -> Option<PathBuf> {
+    let hex = hex_build_id(build_id);
+    let path = root.join(".build-id").join(&hex[..2]).join(format!("{}.debug", &hex[2..]));
+    if path.exists() { Some(path) } else { None }
+}
+
+fn hex_build_id(build_id: &[u8]) -> String {
+    build_id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_local_debug_file() {
+        let root = std::env::temp_dir().join(format!("remoteprocess_debug_file_{}", std::process::id()));
+        let build_id = [0xab, 0xcd, 0xef, 0x01];
+        assert_eq!(local_debug_file(&root, &build_id), None);
+
+        fs::create_dir_all(root.join(".build-id").join("ab")).unwrap();
+        let path = root.join(".build-id").join("ab").join("cdef01.debug");
+        fs::write(&path, b"").unwrap();
+        assert_eq!(local_debug_file(&root, &build_id), Some(path));
+        assert_eq!(local_debug_file(&root, &[0xab, 0xcd, 0xef, 0x02]), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_symbol_cache_key() {
+        assert_eq!(symbol_cache_key(&[0xde, 0xad], false), vec![0xde, 0xad]);
+        assert_eq!(symbol_cache_key(&[0xde, 0xad], true), b"\xde\xaddebug".to_vec());
+    }
+
+    #[test]
+    fn test_get_all_symbol_tables() {
+        // strip a copy of this binary, to get one without a symbol table
+        let exe = std::env::current_exe().unwrap();
+        let stripped = std::env::temp_dir().join(format!("remoteprocess_stripped_{}", std::process::id()));
+        let status = Command::new("strip").arg("-o").arg(&stripped).arg(&exe).status().unwrap();
+        assert!(status.success());
+
+        let map = map_file(&exe).unwrap();
+        let file = parse_object("exe", &map).unwrap();
+        let stripped_map = map_file(&stripped).unwrap();
+        let stripped_file = parse_object("stripped", &stripped_map).unwrap();
+        let (symbols, dynamic_symbols) = get_symbol_tables(&file);
+        let (stripped_symbols, stripped_dynamic_symbols) = get_symbol_tables(&stripped_file);
+        assert!(!symbols.is_empty());
+        assert!(stripped_symbols.is_empty());
+
+        // the symbols from the debug file are used when the binary has none, keeping its dynamic symbols
+        assert_eq!(get_all_symbol_tables(&stripped_file, None), (Vec::new(), stripped_dynamic_symbols.clone()));
+        assert_eq!(get_all_symbol_tables(&stripped_file, Some(&file)), (symbols.clone(), stripped_dynamic_symbols));
+
+        // and ignored when it has its own
+        assert_eq!(get_all_symbol_tables(&file, Some(&stripped_file)), (symbols, dynamic_symbols));
+        fs::remove_file(&stripped).unwrap();
     }
 }
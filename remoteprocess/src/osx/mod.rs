@@ -26,7 +26,7 @@ use mach::thread_act::{thread_get_state};
 pub use self::utils::{TaskLock, ThreadLock};
 pub use self::unwinder::Unwinder;
 
-use libproc::libproc::proc_pid::{pidpath, pidinfo, PIDInfo, PidInfoFlavor};
+use libproc::libproc::proc_pid::{listpids, pidpath, pidinfo, PIDInfo, PidInfoFlavor, ProcType, TaskInfo};
 
 pub type Pid = pid_t;
 pub type Tid = u32;
@@ -36,6 +36,12 @@ pub struct Process {
     pub task: mach_port_name_t
 }
 
+/// Returns the pids of all the processes running on the system
+pub fn processes() -> Result<Vec<Pid>, Error> {
+    listpids(ProcType::ProcAllPIDS).map(|pids| pids.into_iter().map(|pid| pid as Pid).collect())
+        .map_err(Error::Other)
+}
+
 #[derive(Eq, PartialEq, Hash, Copy, Clone)]
 pub struct Thread {
     pub tid: Tid
@@ -55,6 +61,35 @@ impl Process {
         pidpath(self.pid).map_err(|e| Error::Other(format!("proc_pidpath failed: {}", e)))
     }
 
+    /// Returns the command line arguments this process was started with
+    pub fn cmdline(&self) -> Result<Vec<String>, Error> {
+        Err(Error::Other("Getting the command line of a process isn't supported on OSX yet".to_owned()))
+    }
+
+    /// Returns the pids of all the descendants of this process (children, grandchildren etc)
+    pub fn child_processes(&self) -> Result<Vec<Pid>, Error> {
+        Err(Error::Other("Finding child processes isn't supported on OSX yet".to_owned()))
+    }
+
+    /// Returns the exit code of this process once it has exited, or None if it's still running
+    pub fn exit_code(&self) -> Result<Option<i32>, Error> {
+        Err(Error::Other("Getting the exit code of a process isn't supported on OSX yet".to_owned()))
+    }
+
+    /// Returns the total amount of CPU time used by all the threads in this process
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        let info = pidinfo::<TaskInfo>(self.pid, 0)
+            .map_err(|e| Error::Other(format!("proc_pidinfo failed: {}", e)))?;
+        Ok(std::time::Duration::from_nanos(info.pti_total_user + info.pti_total_system))
+    }
+
+    /// Returns the resident set size of this process in bytes
+    pub fn memory_usage(&self) -> Result<u64, Error> {
+        let info = pidinfo::<TaskInfo>(self.pid, 0)
+            .map_err(|e| Error::Other(format!("proc_pidinfo failed: {}", e)))?;
+        Ok(info.pti_resident_size)
+    }
+
     pub fn cwd(&self) -> Result<String, Error> {
         let cwd = pidinfo::<proc_vnodepathinfo>(self.pid, 0)
             .map_err(|e| Error::Other(format!("proc_pidinfo failed: {}", e)))?;
@@ -65,6 +100,11 @@ impl Process {
         Ok(TaskLock::new(self.task)?)
     }
 
+    /// Stops every thread at the same instant - which lock already does here
+    pub fn freeze(&self) -> Result<TaskLock, Error> {
+        self.lock()
+    }
+
     pub fn threads(&self) -> Result<Vec<Thread>, Error> {
         let mut threads: mach::mach_types::thread_act_array_t = unsafe { std::mem::zeroed() };
         let mut thread_count: u32 = 0;
@@ -89,11 +129,11 @@ impl Process {
 impl super::ProcessMemory for Process {
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
         let handle: ProcessHandle = self.task.try_into()?;
-        Ok(handle.copy_address(addr, buf)?)
+        handle.copy_address(addr, buf).map_err(|e| Error::MemoryReadError(addr, e))
     }
 }
 
-use self::mach_thread_bindings::{thread_info, thread_basic_info, thread_identifier_info,
+use self::mach_thread_bindings::{thread_info, thread_basic_info, thread_identifier_info, time_value_t,
                                  THREAD_IDENTIFIER_INFO, THREAD_BASIC_INFO,
                                  TH_FLAGS_IDLE, TH_STATE_RUNNING};
 
@@ -118,6 +158,18 @@ impl Thread {
            info.flags & TH_FLAGS_IDLE as i32 == 0)
     }
 
+    /// Returns the name of this thread
+    pub fn name(&self) -> Result<String, Error> {
+        Err(Error::Other("Getting thread names isn't supported on OSX yet".to_owned()))
+    }
+
+    /// Returns the total amount of time this thread has spent running on a CPU
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        let info = self.get_thread_basic_info()?;
+        let micros = |t: time_value_t| t.seconds as u64 * 1_000_000 + t.microseconds as u64;
+        Ok(std::time::Duration::from_micros(micros(info.user_time) + micros(info.system_time)))
+    }
+
     pub fn lock(&self) -> Result<ThreadLock, Error> {
         Ok(ThreadLock::new(self.tid)?)
     }
@@ -7,6 +7,7 @@ use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::Path;
 use std::cell::RefCell;
+use std::ops::Deref;
 
 use super::{Error, Thread, Pid, Process, ProcessMemory};
 use goblin::error::Error as GoblinError;
@@ -52,7 +53,7 @@ impl Unwinder {
                 continue;
             }
 
-            match SharedLibrary::new(&library) {
+            match SharedLibrary::new(&library, &self.process) {
                 Ok(library) => {
                     info!("loaded shared library {:?}", library);
                     loaded += 1;
@@ -121,16 +122,21 @@ pub struct SharedLibrary {
     pub address: usize,
     pub size: usize,
     pub mh_offset: usize,
-    pub buffer: memmap::Mmap,
+    pub buffer: LibraryBuffer,
     pub unwind_info: Option<OffsetRange>,
     pub eh_frame: Option<OffsetRange>,
     pub dwarf_info: RefCell<Option<UnwindInfo>>
 }
 
 impl SharedLibrary {
-    fn new(library: &proc_maps::mac_maps::DyldInfo) -> Result<SharedLibrary, GoblinError> {
+    fn new(library: &proc_maps::mac_maps::DyldInfo, process: &Process) -> Result<SharedLibrary, GoblinError> {
         debug!("loading file {} 0x{:X}", library.filename, library.address);
-        let file = File::open(Path::new(&library.filename))?;
+        let file = match File::open(Path::new(&library.filename)) {
+            Ok(file) => file,
+            // system libraries are only in the dyld shared cache on newer versions of OSX
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return SharedLibrary::from_memory(library, process),
+            Err(e) => return Err(e.into())
+        };
         let buffer = unsafe { memmap::Mmap::map(&file)? };
 
         // get the __eh_frame and __unwind_info sections from the mach binary
@@ -179,18 +185,112 @@ impl SharedLibrary {
         Ok(SharedLibrary{filename: library.filename.clone(),
                         address: library.address,
                         size: library.segment.vmsize as usize,
-                        buffer,
+                        buffer: LibraryBuffer::File(buffer),
                         mh_offset,
                         unwind_info,
                         eh_frame,
                         dwarf_info: RefCell::new(None)})
     }
 
+    // Loads a library that is only in the dyld shared cache by copying its __TEXT segment out of the
+    // process. goblin can't parse these, since the file offsets of the segments point into the
+    // cache and not the copied image, so the sections are found by their address instead
+    fn from_memory(library: &proc_maps::mac_maps::DyldInfo, process: &Process) -> Result<SharedLibrary, GoblinError> {
+        debug!("loading {} from the dyld shared cache", library.filename);
+        let buffer = process.copy(library.address, library.segment.vmsize as usize)
+            .map_err(|e| GoblinError::Malformed(format!("Failed to copy {} from process memory: {}", library.filename, e)))?;
+        let (unwind_info, eh_frame) = parse_text_sections(&buffer)
+            .ok_or_else(|| GoblinError::Malformed(format!("Failed to parse {} from process memory", library.filename)))?;
+        Ok(SharedLibrary{filename: library.filename.clone(),
+                        address: library.address,
+                        size: library.segment.vmsize as usize,
+                        buffer: LibraryBuffer::Memory(buffer),
+                        mh_offset: 0,
+                        unwind_info,
+                        eh_frame,
+                        dwarf_info: RefCell::new(None)})
+    }
+
     pub fn contains(&self, addr: usize) -> bool {
         addr >= self.address && addr < (self.address + self.size)
     }
 }
 
+/// The contents of a library, either mapped from its file or copied from the process
+pub enum LibraryBuffer {
+    File(memmap::Mmap),
+    Memory(Vec<u8>)
+}
+
+impl Deref for LibraryBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            LibraryBuffer::File(mmap) => mmap,
+            LibraryBuffer::Memory(data) => data
+        }
+    }
+}
+
+impl std::fmt::Debug for LibraryBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LibraryBuffer::File(mmap) => write!(f, "File({} bytes)", mmap.len()),
+            LibraryBuffer::Memory(data) => write!(f, "Memory({} bytes)", data.len())
+        }
+    }
+}
+
+// Finds the __unwind_info and __eh_frame sections of a 64 bit mach image that has been copied
+// out of memory, returning their offsets from the start of the __TEXT segment
+fn parse_text_sections(image: &[u8]) -> Option<(Option<OffsetRange>, Option<OffsetRange>)> {
+    const MH_MAGIC_64: u32 = 0xfeed_facf;
+    const LC_SEGMENT_64: u32 = 0x19;
+    let read_u32 = |offset: usize| image.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let read_u64 = |offset: usize| -> Option<u64> {
+        let b = image.get(offset..offset + 8)?;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(b);
+        Some(u64::from_le_bytes(bytes))
+    };
+    let name = |offset: usize| image.get(offset..offset + 16)
+        .map(|b| b.split(|&c| c == 0).next().unwrap_or(b));
+
+    if read_u32(0)? != MH_MAGIC_64 {
+        return None;
+    }
+    let ncmds = read_u32(16)?;
+    let mut command = 32;
+    for _ in 0..ncmds {
+        let cmd = read_u32(command)?;
+        let cmdsize = read_u32(command + 4)? as usize;
+        if cmd == LC_SEGMENT_64 && name(command + 8)? == b"__TEXT" {
+            let vmaddr = read_u64(command + 24)?;
+            let nsects = read_u32(command + 64)? as usize;
+            let mut unwind_info = None;
+            let mut eh_frame = None;
+            for i in 0..nsects {
+                let section = command + 72 + i * 80;
+                let range = OffsetRange{offset: read_u64(section + 32)?.checked_sub(vmaddr)? as usize,
+                                        size: read_u64(section + 40)? as usize};
+                if range.offset + range.size > image.len() {
+                    continue;
+                }
+                match name(section)? {
+                    b"__unwind_info" => unwind_info = Some(range),
+                    b"__eh_frame" => eh_frame = Some(range),
+                    _ => {}
+                }
+            }
+            return Some((unwind_info, eh_frame));
+        }
+        if cmdsize == 0 {
+            return None;
+        }
+        command += cmdsize;
+    }
+    None
+}
 
 pub struct Cursor<'a> {
     registers: x86_thread_state64_t,
@@ -311,3 +411,60 @@ impl OffsetRange {
         OffsetRange{offset: section.offset as usize + offset, size: section.size as usize}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // writes a 64 bit mach header with a __PAGEZERO segment, then a __TEXT segment at 0x1000 with
+    // the given (name, address, size) sections
+    fn mach_image(sections: &[(&str, u64, u64)]) -> Vec<u8> {
+        let mut image = Vec::new();
+        let name = |name: &str| { let mut b = [0u8; 16]; b[..name.len()].copy_from_slice(name.as_bytes()); b };
+        for value in &[0xfeed_facf_u32, 0x0100_0007, 3, 6, 2, 0, 0, 0] {
+            image.extend_from_slice(&value.to_le_bytes());
+        }
+        image.extend_from_slice(&0x19_u32.to_le_bytes());
+        image.extend_from_slice(&72_u32.to_le_bytes());
+        image.extend_from_slice(&name("__PAGEZERO"));
+        image.extend_from_slice(&[0u8; 48]);
+
+        image.extend_from_slice(&0x19_u32.to_le_bytes());
+        image.extend_from_slice(&(72 + 80 * sections.len() as u32).to_le_bytes());
+        image.extend_from_slice(&name("__TEXT"));
+        for value in &[0x1000_u64, 0x1000, 0, 0x1000] {
+            image.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in &[5_u32, 5, sections.len() as u32, 0] {
+            image.extend_from_slice(&value.to_le_bytes());
+        }
+        for &(section, address, size) in sections {
+            image.extend_from_slice(&name(section));
+            image.extend_from_slice(&name("__TEXT"));
+            image.extend_from_slice(&address.to_le_bytes());
+            image.extend_from_slice(&size.to_le_bytes());
+            image.extend_from_slice(&[0u8; 32]);
+        }
+        image.resize(0x1000, 0);
+        image
+    }
+
+    #[test]
+    fn test_parse_text_sections() {
+        let image = mach_image(&[("__text", 0x1100, 0x10), ("__unwind_info", 0x1200, 0x20), ("__eh_frame", 0x1300, 0x40)]);
+        let (unwind_info, eh_frame) = parse_text_sections(&image).unwrap();
+        let unwind_info = unwind_info.unwrap();
+        assert_eq!((unwind_info.offset, unwind_info.size), (0x200, 0x20));
+        let eh_frame = eh_frame.unwrap();
+        assert_eq!((eh_frame.offset, eh_frame.size), (0x300, 0x40));
+
+        // sections outside of the copied segment are left out
+        let (unwind_info, eh_frame) = parse_text_sections(&mach_image(&[("__unwind_info", 0x1f00, 0x200)])).unwrap();
+        assert!(unwind_info.is_none() && eh_frame.is_none());
+
+        let mut image = mach_image(&[]);
+        image[0] = 0;
+        assert!(parse_text_sections(&image).is_none());
+        assert!(parse_text_sections(&image[..8]).is_none());
+    }
+}
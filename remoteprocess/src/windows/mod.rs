@@ -1,9 +1,11 @@
-use winapi::um::processthreadsapi::{OpenProcess, OpenThread, GetThreadId, SuspendThread, ResumeThread};
+use winapi::um::processthreadsapi::{OpenProcess, OpenThread, GetThreadId, GetThreadTimes, GetProcessTimes, GetExitCodeProcess,
+                                    SuspendThread, ResumeThread};
 use winapi::um::winnt::{ACCESS_MASK, MAXIMUM_ALLOWED, PROCESS_QUERY_INFORMATION,
                         PROCESS_VM_READ, PROCESS_SUSPEND_RESUME, THREAD_QUERY_INFORMATION, THREAD_GET_CONTEXT, THREAD_ALL_ACCESS,
                         WCHAR, HANDLE};
-use winapi::shared::minwindef::{FALSE, DWORD, MAX_PATH, ULONG};
+use winapi::shared::minwindef::{FALSE, DWORD, FILETIME, MAX_PATH, ULONG};
 use winapi::um::handleapi::{CloseHandle};
+use winapi::um::psapi::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
 use winapi::um::winbase::QueryFullProcessImageNameW;
 use winapi::um::winnt::OSVERSIONINFOEXW;
 use std::ffi::OsString;
@@ -64,6 +66,55 @@ impl Process {
 
     pub fn handle(&self) -> ProcessHandle { self.handle }
 
+    /// Returns the command line arguments this process was started with
+    pub fn cmdline(&self) -> Result<Vec<String>, Error> {
+        Err(Error::Other("Getting the command line of a process isn't supported on Windows yet".to_owned()))
+    }
+
+    /// Returns the pids of all the descendants of this process (children, grandchildren etc)
+    pub fn child_processes(&self) -> Result<Vec<Pid>, Error> {
+        Err(Error::Other("Finding child processes isn't supported on Windows yet".to_owned()))
+    }
+
+    /// Returns the total amount of CPU time used by all the threads in this process
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        unsafe {
+            let mut creation = std::mem::zeroed::<FILETIME>();
+            let mut exit = std::mem::zeroed::<FILETIME>();
+            let mut kernel = std::mem::zeroed::<FILETIME>();
+            let mut user = std::mem::zeroed::<FILETIME>();
+            if GetProcessTimes(self.handle.0, &mut creation, &mut exit, &mut kernel, &mut user) == FALSE {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+            Ok(filetime_duration(kernel) + filetime_duration(user))
+        }
+    }
+
+    /// Returns the exit code of this process once it has exited, or None if it's still running.
+    /// Since we hold a handle to the process, this works even for processes that aren't our children
+    pub fn exit_code(&self) -> Result<Option<i32>, Error> {
+        unsafe {
+            let mut code: DWORD = 0;
+            if GetExitCodeProcess(self.handle.0, &mut code) == FALSE {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+            // STILL_ACTIVE, which a process could also exit with but there's no telling those apart
+            Ok(if code == 259 { None } else { Some(code as i32) })
+        }
+    }
+
+    /// Returns the resident set size (working set) of this process in bytes
+    pub fn memory_usage(&self) -> Result<u64, Error> {
+        unsafe {
+            let mut counters = std::mem::zeroed::<PROCESS_MEMORY_COUNTERS>();
+            let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as DWORD;
+            if GetProcessMemoryInfo(self.handle.0, &mut counters, size) == FALSE {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+            Ok(counters.WorkingSetSize as u64)
+        }
+    }
+
     pub fn exe(&self) -> Result<String, Error> {
         unsafe {
             let mut size = MAX_PATH as DWORD;
@@ -80,6 +131,11 @@ impl Process {
         Ok(Lock::new(self.handle)?)
     }
 
+    /// Stops every thread at the same instant - which lock already does here
+    pub fn freeze(&self) -> Result<Lock, Error> {
+        self.lock()
+    }
+
     pub fn cwd(&self) -> Result<String, Error> {
         // TODO: get the CWD.
         // seems a little involved: http://wj32.org/wp/2009/01/24/howto-get-the-command-line-of-processes/
@@ -121,10 +177,15 @@ impl Drop for Process {
 
 impl super::ProcessMemory for Process {
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
-        Ok(self.handle.copy_address(addr, buf)?)
+        self.handle.copy_address(addr, buf).map_err(|e| Error::MemoryReadError(addr, e))
     }
 }
 
+/// Returns the pids of all the processes running on the system
+pub fn processes() -> Result<Vec<Pid>, Error> {
+    Err(Error::Other("Listing processes isn't supported on Windows yet".to_owned()))
+}
+
 #[derive(Eq, PartialEq, Hash, Clone)]
 pub struct Thread {
     thread: ProcessHandle,
@@ -193,6 +254,31 @@ impl Thread {
             Ok(active)
         }
     }
+
+    /// Returns the name of this thread
+    pub fn name(&self) -> Result<String, Error> {
+        Err(Error::Other("Getting thread names isn't supported on Windows yet".to_owned()))
+    }
+
+    /// Returns the total amount of time this thread has spent running on a CPU
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        unsafe {
+            let mut creation = std::mem::zeroed::<FILETIME>();
+            let mut exit = std::mem::zeroed::<FILETIME>();
+            let mut kernel = std::mem::zeroed::<FILETIME>();
+            let mut user = std::mem::zeroed::<FILETIME>();
+            if GetThreadTimes(self.thread.0, &mut creation, &mut exit, &mut kernel, &mut user) == FALSE {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+            Ok(filetime_duration(kernel) + filetime_duration(user))
+        }
+    }
+}
+
+/// Converts a FILETIME (in 100 nanosecond intervals) into a Duration
+fn filetime_duration(t: FILETIME) -> std::time::Duration {
+    let ticks = (u64::from(t.dwHighDateTime) << 32) | u64::from(t.dwLowDateTime);
+    std::time::Duration::from_nanos(ticks * 100)
 }
 
 impl Drop for Thread {
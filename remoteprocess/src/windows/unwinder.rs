@@ -1,13 +1,17 @@
 use winapi::um::processthreadsapi::{GetThreadContext, };
 use winapi::um::winnt::{HANDLE, CONTEXT, IMAGE_FILE_MACHINE_AMD64, WCHAR};
 use winapi::um::errhandlingapi::GetLastError;
-use winapi::shared::minwindef::{TRUE, BOOL, DWORD, MAX_PATH};
+use winapi::shared::minwindef::{TRUE, BOOL, DWORD, MAX_PATH, HMODULE};
 use winapi::shared::guiddef::GUID;
 use winapi::shared::basetsd::DWORD64;
 use winapi::um::dbghelp::{SymInitializeW, SymCleanup,
                           StackWalk64, STACKFRAME64, AddrModeFlat, ADDRESS64,
+                          SymFunctionTableAccess64, SymGetModuleBase64,
                           SymFromAddrW, SymGetLineFromAddrW64, MAX_SYM_NAME, SYMBOL_INFOW, IMAGEHLP_LINEW64};
-use std::os::windows::ffi::{OsStringExt};
+use winapi::um::psapi::{EnumProcessModulesEx, GetModuleFileNameExW, LIST_MODULES_ALL};
+use std::ffi::OsString;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
 
 use super::Thread;
 use super::super::Error;
@@ -28,7 +32,10 @@ pub struct Cursor {
 impl Unwinder {
     pub fn new(handle: HANDLE) -> Result<Unwinder, Error> {
         unsafe {
-            if SymInitializeW(handle, std::ptr::null_mut(), TRUE) == 0 {
+            SymSetOptions(SYMOPT_UNDNAME | SYMOPT_DEFERRED_LOADS | SYMOPT_LOAD_LINES |
+                          SYMOPT_FAIL_CRITICAL_ERRORS | SYMOPT_NO_PROMPTS);
+            let search_path = wide_string(&symbol_search_path(handle));
+            if SymInitializeW(handle, search_path.as_ptr(), TRUE) == 0 {
                 return Err(Error::from(std::io::Error::last_os_error()));
             };
             Ok(Unwinder{handle})
@@ -37,7 +44,12 @@ impl Unwinder {
 
     pub fn reload(&mut self) -> Result<(), Error> {
         info!("reloading symbol module list");
-        unsafe { SymRefreshModuleList(self.handle); }
+        unsafe {
+            // modules loaded since the last reload (like extension modules) might have their pdb files next to them
+            let search_path = wide_string(&symbol_search_path(self.handle));
+            SymSetSearchPathW(self.handle, search_path.as_ptr());
+            SymRefreshModuleList(self.handle);
+        }
         Ok(())
     }
 
@@ -158,7 +170,7 @@ impl Cursor {
             if StackWalk64(IMAGE_FILE_MACHINE_AMD64.into(), self.process, self.thread,
                         &mut self.frame,
                         &mut self.ctx.0 as *mut CONTEXT as *mut _,
-                        None, None, None, None) != TRUE {
+                        None, Some(SymFunctionTableAccess64), Some(SymGetModuleBase64), None) != TRUE {
                 return Ok(None);
             }
             Ok(Some(self.ip()))
@@ -182,6 +194,67 @@ impl Iterator for Cursor {
     }
 }
 
+// The paths that dbghelp looks for pdb files in. Passing a search path replaces the default one,
+// so this includes the _NT_SYMBOL_PATH and _NT_ALTERNATE_SYMBOL_PATH environment variables along
+// with the directory of each module loaded in the process, since python and extension modules
+// are usually distributed with their pdb files next to the dll
+fn symbol_search_path(process: HANDLE) -> String {
+    let symbol_paths: Vec<String> = ["_NT_SYMBOL_PATH", "_NT_ALTERNATE_SYMBOL_PATH"].iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .collect();
+    join_search_path(&symbol_paths, &unsafe { module_filenames(process) })
+}
+
+// joins ';' separated symbol paths with the directories of the modules, leaving out duplicates
+fn join_search_path(symbol_paths: &[String], modules: &[String]) -> String {
+    let mut paths: Vec<String> = Vec::new();
+    for path in symbol_paths {
+        paths.extend(path.split(';').filter(|p| !p.is_empty()).map(|p| p.to_owned()));
+    }
+    for module in modules {
+        if let Some(directory) = Path::new(module).parent() {
+            let directory = directory.to_string_lossy().to_string();
+            if !paths.iter().any(|p| p.eq_ignore_ascii_case(&directory)) {
+                paths.push(directory);
+            }
+        }
+    }
+    paths.join(";")
+}
+
+// gets the filenames of all the modules loaded in a process
+unsafe fn module_filenames(process: HANDLE) -> Vec<String> {
+    let mut modules: Vec<HMODULE> = vec![std::ptr::null_mut(); 1024];
+    let mut needed = 0;
+    loop {
+        let size = (modules.len() * std::mem::size_of::<HMODULE>()) as DWORD;
+        if EnumProcessModulesEx(process, modules.as_mut_ptr(), size, &mut needed, LIST_MODULES_ALL) == 0 {
+            warn!("Failed to list modules: {}", std::io::Error::last_os_error());
+            return Vec::new();
+        }
+        let count = needed as usize / std::mem::size_of::<HMODULE>();
+        if count <= modules.len() {
+            modules.truncate(count);
+            break;
+        }
+        modules.resize(count, std::ptr::null_mut());
+    }
+
+    let mut filenames = Vec::with_capacity(modules.len());
+    let mut buffer = [0 as WCHAR; MAX_PATH * 4];
+    for module in modules {
+        let length = GetModuleFileNameExW(process, module, buffer.as_mut_ptr(), buffer.len() as DWORD);
+        if length > 0 {
+            filenames.push(OsString::from_wide(&buffer[..length as usize]).to_string_lossy().to_string());
+        }
+    }
+    filenames
+}
+
+fn wide_string(s: &str) -> Vec<u16> {
+    std::ffi::OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
 #[repr(C, align(8))]
 struct SymbolBuffer {
     buffer: [u8; std::mem::size_of::<SYMBOL_INFOW>() + MAX_SYM_NAME * 2]
@@ -240,4 +313,27 @@ pub struct IMAGEHLP_MODULEW64 {
 extern "system" {
     fn SymGetModuleInfoW64(process: HANDLE, addr: u64, info: *mut IMAGEHLP_MODULEW64) -> BOOL;
     fn SymRefreshModuleList(process: HANDLE) -> BOOL;
+    fn SymSetOptions(options: DWORD) -> DWORD;
+    fn SymSetSearchPathW(process: HANDLE, search_path: *const WCHAR) -> BOOL;
+}
+
+const SYMOPT_UNDNAME: DWORD = 0x00000002;
+const SYMOPT_DEFERRED_LOADS: DWORD = 0x00000004;
+const SYMOPT_LOAD_LINES: DWORD = 0x00000010;
+const SYMOPT_FAIL_CRITICAL_ERRORS: DWORD = 0x00000200;
+const SYMOPT_NO_PROMPTS: DWORD = 0x00080000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_search_path() {
+        let symbol_paths = vec!["srv*C:\\symbols*https://msdl.microsoft.com/download/symbols;".to_owned(), "C:\\pdbs".to_owned()];
+        let modules = vec!["C:\\Python37\\python37.dll".to_owned(), "C:\\Python37\\python.exe".to_owned(),
+                           "c:\\pdbs\\ext.pyd".to_owned(), "C:\\Windows\\System32\\ntdll.dll".to_owned()];
+        assert_eq!(join_search_path(&symbol_paths, &modules),
+                   "srv*C:\\symbols*https://msdl.microsoft.com/download/symbols;C:\\pdbs;C:\\Python37;C:\\Windows\\System32");
+        assert_eq!(join_search_path(&[], &[]), "");
+    }
 }
@@ -0,0 +1,72 @@
+// Adjusts the sampling rate so that the profiler stays under an overhead budget
+use std::time::Duration;
+
+/// Keeps track of how expensive each sample is, and picks the highest sampling rate that keeps
+/// the fraction of time spent sampling (which is when the target process is paused, unless
+/// running in nonblocking mode) under the budget.
+pub struct AdaptiveRate {
+    max_rate: f64,
+    budget: f64,
+    rate: f64,
+    // exponential moving average of the cost of a sample, in seconds
+    average_cost: Option<f64>,
+    samples_since_update: u64,
+}
+
+impl AdaptiveRate {
+    /// Creates a new AdaptiveRate, budget is the maximum fraction of time (0 - 1) to spend sampling
+    pub fn new(max_rate: f64, budget: f64) -> AdaptiveRate {
+        AdaptiveRate{max_rate, budget, rate: max_rate, average_cost: None, samples_since_update: 0}
+    }
+
+    /// Records how long a sample took, returning the new rate if it should be changed
+    pub fn update(&mut self, cost: Duration) -> Option<f64> {
+        let cost = cost.as_secs_f64();
+        let average = match self.average_cost {
+            Some(average) => 0.9 * average + 0.1 * cost,
+            None => cost
+        };
+        self.average_cost = Some(average);
+
+        // only reconsider the rate about once a second, so that it doesn't bounce around on every sample
+        self.samples_since_update += 1;
+        if (self.samples_since_update as f64) < self.rate {
+            return None;
+        }
+        self.samples_since_update = 0;
+
+        let desired = if average > 0.0 { self.budget / average } else { self.max_rate };
+        let desired = desired.max(1.0).min(self.max_rate);
+        if (desired - self.rate).abs() > 0.1 * self.rate {
+            self.rate = desired;
+            Some(desired)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_rate() {
+        // each sample taking 2ms with a 10% budget should settle on 50 samples per second
+        let mut rate = AdaptiveRate::new(100.0, 0.1);
+        let mut changes = Vec::new();
+        for _ in 0..200 {
+            if let Some(new_rate) = rate.update(Duration::from_millis(2)) {
+                changes.push(new_rate);
+            }
+        }
+        assert_eq!(changes.len(), 1);
+        assert!((changes[0] - 50.0).abs() < 0.01);
+
+        // cheap samples should go back up to the max rate, but not above
+        for _ in 0..200 {
+            rate.update(Duration::from_micros(10));
+        }
+        assert_eq!(rate.rate, 100.0);
+    }
+}
@@ -0,0 +1,449 @@
+// Lets py-spy be run on other machines: 'py-spy agent' listens for commands, runs them with its own
+// py-spy binary and streams the output back to 'py-spy remote', along with any files they recorded.
+//
+// The protocol is a series of messages, each a type byte and a big endian u32 length, then the payload.
+// The agent starts by sending a random challenge, and the client replies with the arguments to run
+// along with an HMAC-SHA256 of the challenge and the arguments keyed by a token both sides have. This
+// authenticates commands, but nothing is encrypted, so use an ssh tunnel or a private network when the
+// recordings shouldn't be visible on the wire.
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use failure::{Error, ResultExt};
+
+use crate::config::Config;
+use crate::sha256::{hmac_sha256, verify};
+
+// agent -> client: the protocol version, then the challenge
+const HELLO: u8 = b'H';
+// client -> agent: the MAC of the challenge and arguments, then the arguments as a JSON list
+const COMMAND: u8 = b'C';
+// client -> agent: stop the command, like pressing ctrl-c would
+const STOP: u8 = b'S';
+// agent -> client: output of the command
+const STDOUT: u8 = b'O';
+const STDERR: u8 = b'E';
+// agent -> client: a file the command wrote, as the u32 length of its name, the name, then the contents
+const FILE: u8 = b'F';
+// agent -> client: the i32 exit code of the command, which is the last message sent
+const EXIT: u8 = b'X';
+// agent -> client: why the command wasn't run
+const ERROR: u8 = b'!';
+
+const PROTOCOL: &[u8] = b"py-spy agent 1\n";
+const CHALLENGE_SIZE: usize = 32;
+const MIN_TOKEN_LENGTH: usize = 16;
+const MAX_COMMAND_SIZE: usize = 1 << 20;
+const MAX_MESSAGE_SIZE: usize = 1 << 30;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listens for commands from 'py-spy remote', running each connection's command on its own thread
+pub fn run_agent(address: &str, config: &Config) -> Result<(), Error> {
+    let token = read_token(config)?;
+    let listener = TcpListener::bind(address).context(format!("Failed to listen on {}", address))?;
+    eprintln!("py-spy agent listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let token = token.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+            if let Err(e) = handle_connection(stream, &token) {
+                warn!("Failed to run command from {}: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Runs py-spy with some arguments on the machine running an agent, copying what it prints and the
+/// files it records back here
+pub fn run_remote(address: &str, args: &[String], config: &Config) -> Result<(), Error> {
+    let token = read_token(config)?;
+    let mut stream = TcpStream::connect(address).context(format!("Failed to connect to py-spy agent at {}", address))?;
+    let (kind, hello) = read_message(&mut stream, MAX_COMMAND_SIZE)?;
+    if kind != HELLO || !hello.starts_with(PROTOCOL) || hello.len() != PROTOCOL.len() + CHALLENGE_SIZE {
+        return Err(format_err!("{} isn't running a compatible version of py-spy agent", address));
+    }
+    let command = serde_json::to_vec(args)?;
+    let mut payload = command_mac(&token, &hello[PROTOCOL.len()..], &command).to_vec();
+    payload.extend_from_slice(&command);
+    write_message(&mut stream, COMMAND, &payload)?;
+
+    // pressing ctrl-c stops the remote command, which then sends back whatever it recorded
+    crate::install_ctrlc_handler()?;
+    let mut stopper = stream.try_clone()?;
+    thread::spawn(move || {
+        while crate::RUNNING.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let _ = write_message(&mut stopper, STOP, &[]);
+    });
+
+    loop {
+        let (kind, payload) = read_message(&mut stream, MAX_MESSAGE_SIZE)
+            .context("Lost connection to the py-spy agent")?;
+        match kind {
+            STDOUT => std::io::stdout().write_all(&payload)?,
+            STDERR => std::io::stderr().write_all(&payload)?,
+            FILE => {
+                let (name, contents) = decode_file(&payload)?;
+                if let Some(parent) = Path::new(name).parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(name, contents).context(format!("Failed to write {}", name))?;
+            },
+            EXIT if payload.len() == 4 => {
+                let code = i32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                if code != 0 {
+                    std::process::exit(code);
+                }
+                return Ok(());
+            },
+            ERROR => return Err(format_err!("py-spy agent refused to run the command: {}", String::from_utf8_lossy(&payload))),
+            _ => return Err(format_err!("Unexpected message from the py-spy agent"))
+        }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, token: &[u8]) -> Result<(), Error> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let challenge: [u8; CHALLENGE_SIZE] = rand::random();
+    let mut hello = PROTOCOL.to_vec();
+    hello.extend_from_slice(&challenge);
+    write_message(&mut stream, HELLO, &hello)?;
+
+    let (kind, payload) = read_message(&mut stream, MAX_COMMAND_SIZE)?;
+    if kind != COMMAND || payload.len() < 32 {
+        return Err(format_err!("expected a command"));
+    }
+    let (mac, command) = payload.split_at(32);
+    if !verify(&command_mac(token, &challenge, command), mac) {
+        warn!("Refused command from {} with the wrong token", peer);
+        write_message(&mut stream, ERROR, b"wrong token")?;
+        return Ok(());
+    }
+    stream.set_read_timeout(None)?;
+
+    let args: Vec<String> = serde_json::from_slice(command)?;
+    info!("Running 'py-spy {}' for {}", args.join(" "), peer);
+    if let Err(e) = check_args(&args) {
+        write_message(&mut stream, ERROR, e.to_string().as_bytes())?;
+        return Ok(());
+    }
+    run_command(stream, &args)
+}
+
+// the options that commands run by the agent can use, as the long name, short name and whether it
+// takes a value. Anything else is refused, so that new options (which might read or write files on
+// this machine) can't be used remotely until they're added here
+const ALLOWED_OPTIONS: &[(&str, Option<char>, bool)] = &[
+    ("help", Some('h'), false), ("version", Some('V'), false),
+    ("pid", Some('p'), true), ("name", None, true), ("cmdline", None, true), ("port", None, true),
+    ("auto", None, true), ("include-new", None, false),
+    ("dump", None, false), ("json", None, false), ("interval", None, true), ("count", None, true),
+    ("watch", None, false), ("changes-only", None, false), ("native-threads", None, false),
+    ("locals", None, false), ("capture-args", None, true), ("exceptions", None, false),
+    ("nonblocking", None, false), ("dirty-reads", None, false), ("freeze", None, false),
+    ("max-pause", None, true), ("parallel", None, true), ("cpu-time", None, false), ("gil", Some('g'), false),
+    ("bare-names", None, false), ("gil-wait", None, false), ("locks", None, false), ("native", Some('n'), false),
+    ("unsymbolicated", None, false), ("resources", None, false), ("preview", None, false),
+    ("assert", None, true), ("assert-report", None, true), ("append", None, false), ("status-json", None, false),
+    ("kernel", None, false), ("idle", Some('i'), false), ("idle-rule", None, true), ("no-idle-rule", None, true),
+    ("asyncio", None, false), ("gevent", None, false), ("memory", None, false), ("interpreters", None, false),
+    ("tid", None, true), ("thread-name-regex", None, true), ("native-tid", None, true),
+    ("native-thread-name", None, true), ("include-frames", None, true), ("exclude-frames", None, true),
+    ("exclude-stdlib", None, false), ("flame", Some('f'), true), ("output", Some('o'), true),
+    ("format", None, true), ("function", Some('F'), false), ("granularity", None, true), ("group-by", None, true),
+    ("reverse", None, false), ("map-path", None, true), ("max-depth", None, true), ("trim-roots", None, true),
+    ("min-percent", None, true), ("trim-start", None, true), ("trim-end", None, true), ("label", None, true),
+    ("snapshot", None, true), ("snapshot-on-exit", None, false), ("rate", Some('r'), true), ("jitter", None, true),
+    ("duration", Some('d'), true), ("for", None, true), ("every", None, true), ("samples", None, true),
+    ("max-overhead", None, true), ("start-when", None, true), ("stop-when-clear", None, false),
+    ("respawn-wait", None, true), ("subprocesses", Some('s'), false), ("merge-processes", None, false),
+    ("per-process", None, false),
+];
+
+// subcommands that only look at a running process, and whose options are all in ALLOWED_OPTIONS
+const ALLOWED_SUBCOMMANDS: &[&str] = &["heap"];
+
+// refuses arguments that use an option (or subcommand) that isn't allowed. Everything after '--' is the
+// program to run, which check_args refuses
+fn check_options(args: &[String]) -> Result<(), Error> {
+    let mut args = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        let takes_value = if let Some(long) = arg.strip_prefix("--") {
+            let name = long.split('=').next().unwrap_or_default();
+            match ALLOWED_OPTIONS.iter().find(|option| option.0 == name) {
+                Some(option) => option.2 && !long.contains('='),
+                None => return Err(format_err!("--{} can't be used remotely", name))
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            // short flags can be combined like -gi, and the first one that takes a value takes the rest
+            let mut takes_value = false;
+            for (i, short) in shorts.char_indices() {
+                match ALLOWED_OPTIONS.iter().find(|option| option.1 == Some(short)) {
+                    Some(option) if option.2 => {
+                        takes_value = i + short.len_utf8() == shorts.len();
+                        break;
+                    },
+                    Some(_) => {},
+                    None => return Err(format_err!("-{} can't be used remotely", short))
+                }
+            }
+            takes_value
+        } else if ALLOWED_SUBCOMMANDS.contains(&arg.as_str()) {
+            false
+        } else {
+            return Err(format_err!("'{}' can't be used remotely", arg));
+        };
+        if takes_value {
+            args.next();
+        }
+    }
+    Ok(())
+}
+
+// the agent only profiles programs that are already running, and what they record is written
+// to a temporary directory and sent back, so commands can't touch files on this machine
+fn check_args(args: &[String]) -> Result<(), Error> {
+    check_options(args)?;
+    let config = Config::from_args(std::iter::once("py-spy".to_owned()).chain(args.iter().cloned()))?;
+    if config.python_program.is_some() {
+        return Err(format_err!("the agent only profiles programs that are already running"));
+    }
+    if config.convert_file.is_some() || config.agent_listen.is_some() || config.remote.is_some() {
+        return Err(format_err!("only commands that profile a program can be run remotely"));
+    }
+    if !config.dump && !config.heap && config.filename.is_none() {
+        return Err(format_err!("the top view can't be shown remotely, use --dump or record with --output"));
+    }
+    let outputs = [config.filename.as_ref(), config.snapshot_filename.as_ref(), config.assert_report.as_ref()];
+    if outputs.iter().flatten().any(|filename| !is_relative_path(filename)) {
+        return Err(format_err!("output files are copied back to the current directory, and have to be relative \
+                                paths inside it"));
+    }
+    Ok(())
+}
+
+fn run_command(stream: TcpStream, args: &[String]) -> Result<(), Error> {
+    let directory = tempfile::tempdir()?;
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(args)
+        .current_dir(directory.path())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let (sender, receiver) = mpsc::channel();
+    let outputs: Vec<(u8, Box<dyn Read + Send>)> = vec![(STDOUT, Box::new(child.stdout.take().unwrap())),
+                                                        (STDERR, Box::new(child.stderr.take().unwrap()))];
+    for (kind, mut output) in outputs {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let mut buffer = vec![0u8; 65536];
+            while let Ok(count) = output.read(&mut buffer) {
+                if count == 0 || sender.send((kind, buffer[..count].to_vec())).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    // stop the command when the client asks to (or goes away). The flag is set under the same lock as
+    // the command is reaped, so that we never signal a different process that has reused its pid
+    let exited = Arc::new(Mutex::new(false));
+    let mut reader = stream.try_clone()?;
+    let pid = child.id();
+    let stop_exited = exited.clone();
+    thread::spawn(move || {
+        let _ = read_message(&mut reader, MAX_COMMAND_SIZE);
+        if !*stop_exited.lock().unwrap() {
+            info!("Stopping py-spy process {}", pid);
+            stop_process(pid);
+        }
+    });
+
+    let mut writer = stream;
+    let mut result: Result<(), Error> = Ok(());
+    for (kind, data) in receiver {
+        if let Err(e) = write_message(&mut writer, kind, &data) {
+            result = Err(e.into());
+            stop_process(pid);
+            break;
+        }
+    }
+    let status = loop {
+        {
+            let mut exited = exited.lock().unwrap();
+            if let Some(status) = child.try_wait()? {
+                *exited = true;
+                break status;
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    result?;
+
+    send_files(&mut writer, directory.path(), directory.path())?;
+    write_message(&mut writer, EXIT, &status.code().unwrap_or(1).to_be_bytes())?;
+    Ok(())
+}
+
+fn send_files(writer: &mut TcpStream, root: &Path, directory: &Path) -> Result<(), Error> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            send_files(writer, root, &path)?;
+            continue;
+        }
+        let name = path.strip_prefix(root)?.to_string_lossy().replace('\\', "/");
+        let contents = fs::read(&path)?;
+        let mut payload = (name.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(name.as_bytes());
+        payload.extend_from_slice(&contents);
+        write_message(writer, FILE, &payload)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn stop_process(pid: u32) {
+    // py-spy writes out what it has recorded when it gets a SIGINT, the same as on ctrl-c
+    unsafe { libc::kill(pid as libc::pid_t, libc::SIGINT); }
+}
+
+#[cfg(windows)]
+fn stop_process(pid: u32) {
+    // processes without a console can't be sent ctrl-c, so this loses what hasn't been written yet
+    let _ = Command::new("taskkill").args(&["/F", "/PID", &pid.to_string()]).status();
+}
+
+fn read_token(config: &Config) -> Result<Vec<u8>, Error> {
+    let token = match config.agent_token_file.as_ref() {
+        Some(filename) => fs::read_to_string(filename).context(format!("Failed to read token from {}", filename))?,
+        None => std::env::var("PYSPY_AGENT_TOKEN")
+            .map_err(|_| format_err!("Set PYSPY_AGENT_TOKEN or pass --token-file with the secret the agent uses"))?
+    };
+    let token = token.trim();
+    if token.len() < MIN_TOKEN_LENGTH {
+        return Err(format_err!("The agent token should be at least {} characters long", MIN_TOKEN_LENGTH));
+    }
+    Ok(token.as_bytes().to_vec())
+}
+
+fn command_mac(token: &[u8], challenge: &[u8], command: &[u8]) -> [u8; 32] {
+    let mut message = challenge.to_vec();
+    message.extend_from_slice(command);
+    hmac_sha256(token, &message)
+}
+
+// whether a path stays inside the directory it's relative to
+fn is_relative_path(filename: &str) -> bool {
+    let path = Path::new(filename);
+    !filename.is_empty() && path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+// splits a FILE message into the name of the file and its contents, refusing names that would
+// write outside of the current directory
+fn decode_file(payload: &[u8]) -> Result<(&str, &[u8]), Error> {
+    let invalid = || format_err!("py-spy agent sent an invalid file");
+    let length = payload.get(..4).ok_or_else(invalid)?;
+    let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+    let name = payload.get(4..4 + length).ok_or_else(invalid)?;
+    let name = std::str::from_utf8(name).map_err(|_| invalid())?;
+    if !is_relative_path(name) {
+        return Err(format_err!("py-spy agent sent a file outside of the current directory: {}", name));
+    }
+    Ok((name, &payload[4 + length..]))
+}
+
+fn write_message<W: Write>(writer: &mut W, kind: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut header = vec![kind];
+    header.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+fn read_message<R: Read>(reader: &mut R, max_size: usize) -> Result<(u8, Vec<u8>), Error> {
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header)?;
+    let length = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if length > max_size {
+        return Err(format_err!("message of {} bytes is too large", length));
+    }
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, STDOUT, b"Thread 0x1234 (active)").unwrap();
+        write_message(&mut buffer, EXIT, &0i32.to_be_bytes()).unwrap();
+        let mut reader = &buffer[..];
+        assert_eq!(read_message(&mut reader, MAX_COMMAND_SIZE).unwrap(), (STDOUT, b"Thread 0x1234 (active)".to_vec()));
+        assert_eq!(read_message(&mut reader, MAX_COMMAND_SIZE).unwrap(), (EXIT, vec![0, 0, 0, 0]));
+        assert!(read_message(&mut reader, MAX_COMMAND_SIZE).is_err());
+        assert!(read_message(&mut &buffer[..], 4).is_err());
+    }
+
+    #[test]
+    fn test_check_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        assert!(check_args(&args(&["--pid", "12", "-o", "profile.svg", "--rate=50", "-gi"])).is_ok());
+        assert!(check_args(&args(&["-p12", "-o", "profile.raw", "--format", "raw", "--assert", "samples > 1",
+                                   "--assert-report", "report.json"])).is_ok());
+        assert!(check_args(&args(&["heap", "--pid", "12", "-o", "heap.json"])).is_ok());
+
+        // writing outside the temporary directory
+        assert!(check_args(&args(&["--pid", "12", "-o", "/tmp/profile.svg"])).is_err());
+        assert!(check_args(&args(&["--pid", "12", "-o", "a.raw", "--format", "raw", "--assert", "samples > 1",
+                                   "--assert-report", "/etc/cron.d/x"])).is_err());
+        assert!(check_args(&args(&["--pid", "12", "-o", "a.svg", "--snapshot", "../snapshot.svg"])).is_err());
+
+        // options and subcommands that aren't allowed, even combined with allowed short flags
+        assert!(check_args(&args(&["--pid", "12", "--dump", "--sudo"])).is_err());
+        assert!(check_args(&args(&["--pid", "12", "--dump", "--core=/tmp/core"])).is_err());
+        assert!(check_args(&args(&["--pid", "12", "-o", "a.svg", "--stdin", "/etc/shadow"])).is_err());
+        assert!(check_args(&args(&["heap", "--pid", "12", "-o", "heap.json", "--diff", "/etc/heap.json"])).is_err());
+        assert!(check_args(&args(&["alert", "--pid", "12", "--when", "gil>90%", "--exec", "rm -rf /"])).is_err());
+    }
+
+    #[test]
+    fn test_decode_file() {
+        let encode = |name: &str| {
+            let mut payload = (name.len() as u32).to_be_bytes().to_vec();
+            payload.extend_from_slice(name.as_bytes());
+            payload.extend_from_slice(b"<svg>");
+            payload
+        };
+        assert_eq!(decode_file(&encode("profile.svg")).unwrap(), ("profile.svg", &b"<svg>"[..]));
+        assert_eq!(decode_file(&encode("profiles/1234.svg")).unwrap().0, "profiles/1234.svg");
+        assert!(decode_file(&encode("../.bashrc")).is_err());
+        assert!(decode_file(&encode("/etc/passwd")).is_err());
+        assert!(decode_file(&encode("")).is_err());
+        assert!(decode_file(&[0, 0, 0, 10, b'a']).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+// Conditions on the samples being collected, like 'function:handle_request>30%' or 'gil>90%', that
+// 'py-spy alert' alerts on to catch known problems as they happen
+use crate::error::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Measure {
+    /// Percent of the thread stacks sampled that a function is in
+    Function(String),
+    /// Percent of samples in which a thread was holding the GIL
+    Gil,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub measure: Measure,
+    pub greater: bool,
+    pub threshold: f64,
+}
+
+impl Condition {
+    /// Parses a condition like 'function:handle_request>30%' or 'gil>90%'
+    pub fn parse(value: &str) -> Result<Condition, Error> {
+        let value = value.trim();
+        let position = value.rfind(&['>', '<'][..])
+            .ok_or_else(|| Error::InvalidConfig(format!("Invalid condition '{}': expected something like 'function:handle>30%' or 'gil>90%'", value)))?;
+        let greater = &value[position..=position] == ">";
+        let (name, threshold) = (value[..position].trim(), value[position + 1..].trim());
+        let threshold: f64 = threshold.trim_end_matches('%').trim().parse()
+            .map_err(|_| Error::InvalidConfig(format!("Invalid threshold '{}' in condition '{}'", threshold, value)))?;
+
+        let measure = match name.split_once(':') {
+            Some(("function", function)) if !function.trim().is_empty() => Measure::Function(function.trim().to_owned()),
+            None if name.eq_ignore_ascii_case("gil") => Measure::Gil,
+            _ => return Err(Error::InvalidConfig(format!("Unknown measure '{}' in condition '{}', expected 'function:<name>' or 'gil'", name, value)))
+        };
+        Ok(Condition{measure, greater, threshold})
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let op = if self.greater { ">" } else { "<" };
+        match &self.measure {
+            Measure::Function(function) => write!(f, "function:{}{}{}%", function, op, self.threshold),
+            Measure::Gil => write!(f, "gil{}{}%", op, self.threshold),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_condition() {
+        assert_eq!(Condition::parse("function:App.handle > 30%").unwrap(),
+                   Condition{measure: Measure::Function("App.handle".to_owned()), greater: true, threshold: 30.0});
+        assert_eq!(Condition::parse("gil<5").unwrap(), Condition{measure: Measure::Gil, greater: false, threshold: 5.0});
+        assert_eq!(Condition::parse("GIL>90%").unwrap().to_string(), "gil>90%");
+        assert!(Condition::parse("function:>30%").is_err());
+        assert!(Condition::parse("cpu>30%").is_err());
+        assert!(Condition::parse("gil=30").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+// Checks the conditions given to 'py-spy alert' over a sliding window of samples, and posts the
+// alerts they raise to webhooks
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use failure::{Error, ResultExt};
+
+use crate::alert::{Condition, Measure};
+use crate::stack_trace::StackTrace;
+
+/// Checks a condition against the samples taken over the last 'window'
+pub struct ConditionWindow {
+    pub condition: Condition,
+    window: Duration,
+    /// The timestamp of each sample, how many stacks (or samples, for the GIL) it counts for
+    /// in total and how many of those match
+    samples: VecDeque<(Duration, u64, u64)>,
+    total: u64,
+    matching: u64,
+    met: bool,
+}
+
+impl ConditionWindow {
+    pub fn new(condition: Condition, window: Duration) -> ConditionWindow {
+        ConditionWindow{condition, window, samples: VecDeque::new(), total: 0, matching: 0, met: false}
+    }
+
+    /// Adds a sample taken at 'timestamp', returning Some(true) when this makes the condition hold
+    /// and Some(false) when it stops holding. Conditions aren't checked until a full window of samples
+    /// has been taken
+    pub fn add(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Option<bool> {
+        let (total, matching) = match &self.condition.measure {
+            Measure::Function(function) => {
+                let matching = traces.iter().filter(|trace| trace.frames.iter().any(|frame| &frame.name == function)).count();
+                (traces.len() as u64, matching as u64)
+            },
+            Measure::Gil => (1, traces.iter().any(|trace| trace.owns_gil) as u64)
+        };
+        self.samples.push_back((timestamp, total, matching));
+        self.total += total;
+        self.matching += matching;
+        while let Some(&(oldest, total, matching)) = self.samples.front() {
+            if timestamp.saturating_sub(oldest) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+            self.total -= total;
+            self.matching -= matching;
+        }
+
+        let full = timestamp >= self.window;
+        let met = full && self.value().is_some_and(|value| {
+            if self.condition.greater { value > self.condition.threshold } else { value < self.condition.threshold }
+        });
+        if met == self.met {
+            return None;
+        }
+        self.met = met;
+        Some(met)
+    }
+
+    /// The percentage the condition measures over the window, if anything has been sampled
+    pub fn value(&self) -> Option<f64> {
+        if self.total == 0 {
+            return None;
+        }
+        Some(100.0 * self.matching as f64 / self.total as f64)
+    }
+}
+
+/// POSTs a JSON body to a webhook. Only plain http urls are supported
+pub fn post_webhook(url: &str, body: &str) -> Result<(), Error> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| format_err!("Only http:// webhooks are supported, not '{}'", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(split) => (&rest[..split], &rest[split..]),
+        None => (rest, "/")
+    };
+    let address = if host.contains(':') { host.to_owned() } else { format!("{}:80", host) };
+    let mut stream = TcpStream::connect(&address).context(format!("Failed to connect to {}", address))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+    write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           path, host, body.len(), body)?;
+
+    let mut response = Vec::new();
+    stream.take(256).read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split_whitespace().nth(1).unwrap_or("");
+    if !status.starts_with('2') {
+        return Err(format_err!("Webhook {} responded with '{}'", url, response.lines().next().unwrap_or("")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::Frame;
+
+    fn trace(functions: &[&str], owns_gil: bool) -> StackTrace {
+        let frames = functions.iter().map(|name| Frame{name: name.to_string(), filename: "app.py".to_owned(), module: None,
+                                                       short_filename: None, line: 1, frame_ptr: None, locals: None}).collect();
+        StackTrace{thread_id: 1, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true, owns_gil,
+                   frames, exception: None, awaiting: None, pid: None}
+    }
+
+    #[test]
+    fn test_condition_window() {
+        let condition = Condition::parse("function:handle>50%").unwrap();
+        let mut window = ConditionWindow::new(condition, Duration::from_secs(10));
+        let busy = [trace(&["handle", "main"], true)];
+        let idle = [trace(&["poll", "main"], false)];
+
+        // nothing fires until a full window has been sampled
+        for second in 0..10 {
+            assert_eq!(window.add(Duration::from_secs(second), &busy), None);
+        }
+        assert_eq!(window.add(Duration::from_secs(10), &busy), Some(true));
+        assert_eq!(window.value(), Some(100.0));
+        assert_eq!(window.add(Duration::from_secs(11), &idle), None);
+
+        // clears once most of the window's samples are from other functions
+        let mut cleared = None;
+        for second in 12..30 {
+            if window.add(Duration::from_secs(second), &idle) == Some(false) {
+                cleared = Some(second);
+                break;
+            }
+        }
+        assert_eq!(cleared, Some(16));
+    }
+}
@@ -0,0 +1,120 @@
+// Assertions like 'self_pct(my.module.slow_fn) < 5' that are checked against a finished recording
+// with --assert, so that py-spy can fail a CI job when a benchmark regresses
+use failure::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Metric {
+    /// Percent of the thread stacks sampled that a function is at the top of
+    SelfPct(String),
+    /// Percent of the thread stacks sampled that a function is anywhere in
+    TotalPct(String),
+    /// Percent of samples in which a thread was holding the GIL
+    GilPct,
+    /// Number of samples recorded
+    Samples,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assertion {
+    pub metric: Metric,
+    pub comparison: Comparison,
+    pub threshold: f64,
+}
+
+impl Assertion {
+    /// Parses an assertion like 'self_pct(my.module.slow_fn) < 5' or 'gil_pct <= 90'
+    pub fn parse(value: &str) -> Result<Assertion, Error> {
+        let value = value.trim();
+        let invalid = || format_err!("Invalid assertion '{}': expected something like 'self_pct(module.function) < 5'", value);
+        let position = value.rfind(&['<', '>'][..]).ok_or_else(invalid)?;
+        let (metric, rest) = (value[..position].trim(), &value[position..]);
+        let (comparison, threshold) = match rest.as_bytes() {
+            [b'<', b'=', ..] => (Comparison::LessEqual, &rest[2..]),
+            [b'>', b'=', ..] => (Comparison::GreaterEqual, &rest[2..]),
+            [b'<', ..] => (Comparison::Less, &rest[1..]),
+            _ => (Comparison::Greater, &rest[1..]),
+        };
+        let threshold: f64 = threshold.trim().trim_end_matches('%').trim().parse()
+            .map_err(|_| format_err!("Invalid threshold '{}' in assertion '{}'", threshold.trim(), value))?;
+
+        let function = |name: &str| {
+            let function = metric.strip_prefix(name)?.trim().strip_prefix('(')?.strip_suffix(')')?.trim();
+            if function.is_empty() { None } else { Some(function.to_owned()) }
+        };
+        let metric = if let Some(function) = function("self_pct") {
+            Metric::SelfPct(function)
+        } else if let Some(function) = function("total_pct") {
+            Metric::TotalPct(function)
+        } else {
+            match metric {
+                "gil_pct" => Metric::GilPct,
+                "samples" => Metric::Samples,
+                _ => return Err(format_err!("Unknown metric '{}' in assertion '{}', expected 'self_pct(<function>)', \
+                                             'total_pct(<function>)', 'gil_pct' or 'samples'", metric, value))
+            }
+        };
+        Ok(Assertion{metric, comparison, threshold})
+    }
+
+    /// Whether the assertion holds for the value of its metric
+    pub fn holds(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Less => value < self.threshold,
+            Comparison::LessEqual => value <= self.threshold,
+            Comparison::Greater => value > self.threshold,
+            Comparison::GreaterEqual => value >= self.threshold,
+        }
+    }
+}
+
+impl std::fmt::Display for Assertion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.metric {
+            Metric::SelfPct(function) => write!(f, "self_pct({})", function)?,
+            Metric::TotalPct(function) => write!(f, "total_pct({})", function)?,
+            Metric::GilPct => write!(f, "gil_pct")?,
+            Metric::Samples => write!(f, "samples")?,
+        }
+        let op = match self.comparison {
+            Comparison::Less => "<",
+            Comparison::LessEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterEqual => ">=",
+        };
+        write!(f, " {} {}", op, self.threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_assertion() {
+        assert_eq!(Assertion::parse("self_pct(my.module.slow_fn) < 5").unwrap(),
+                   Assertion{metric: Metric::SelfPct("my.module.slow_fn".to_owned()), comparison: Comparison::Less, threshold: 5.0});
+        assert_eq!(Assertion::parse("total_pct( App.handle )>=20%").unwrap(),
+                   Assertion{metric: Metric::TotalPct("App.handle".to_owned()), comparison: Comparison::GreaterEqual, threshold: 20.0});
+        assert_eq!(Assertion::parse("samples>100").unwrap().to_string(), "samples > 100");
+        assert_eq!(Assertion::parse("gil_pct <= 90.5").unwrap().to_string(), "gil_pct <= 90.5");
+        assert!(Assertion::parse("self_pct() < 5").is_err());
+        assert!(Assertion::parse("cpu_pct < 5").is_err());
+        assert!(Assertion::parse("samples = 5").is_err());
+        assert!(Assertion::parse("samples < many").is_err());
+    }
+
+    #[test]
+    fn test_holds() {
+        let assertion = Assertion::parse("gil_pct <= 90").unwrap();
+        assert!(assertion.holds(90.0));
+        assert!(!assertion.holds(90.1));
+    }
+}
@@ -0,0 +1,413 @@
+// Reconstructs the stacks of asyncio tasks. While a task is running its coroutines are on the
+// stack of the thread like any other function call, but once they are waiting on something (and
+// control goes back to the event loop) they only live in the task objects, so that every sample
+// of the thread ends at 'run_forever'. This finds the tasks of an event loop through
+// asyncio.tasks._all_tasks, and follows the chain of coroutines that each task is awaiting.
+// The chain ends at whatever the innermost coroutine is waiting on (usually a future, or another
+// task), which is described for the dump output.
+//
+// The generated bindings don't include the dict, set, weakref, generator or task objects, so
+// their layouts are copied here from the python 3.7 headers and Modules/_asynciomodule.c.
+// Only python 3.7 is supported.
+use std::mem::size_of;
+
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::python_bindings::v3_7_0::{PyBytesObject, PyCodeObject, PyFrameObject, PyObject, PyUnicodeObject, _typeobject};
+use crate::python_interpreters::BytesObject;
+use crate::stack_trace::{Frame, StackTrace, copy_string, get_frame};
+
+// opcode that a coroutine is suspended at when it's awaiting another object
+const YIELD_FROM: u8 = 72;
+
+// sanity limit on the size of the dicts and sets that we read, in case we read garbage
+const MAX_ENTRIES: usize = 1 << 20;
+
+#[repr(C)]
+struct DictObject {
+    ob_base: PyObject,
+    ma_used: isize,
+    ma_version_tag: u64,
+    ma_keys: usize,
+    ma_values: usize,
+}
+
+// the indices of the hash table follow this, and then the entries
+#[repr(C)]
+struct DictKeysObject {
+    dk_refcnt: isize,
+    dk_size: isize,
+    dk_lookup: usize,
+    dk_usable: isize,
+    dk_nentries: isize,
+}
+
+#[repr(C)]
+struct DictEntry {
+    me_hash: isize,
+    me_key: usize,
+    me_value: usize,
+}
+
+#[repr(C)]
+struct ModuleObject {
+    ob_base: PyObject,
+    md_dict: usize,
+}
+
+#[repr(C)]
+struct SetObject {
+    ob_base: PyObject,
+    fill: isize,
+    used: isize,
+    mask: isize,
+    table: usize,
+}
+
+#[repr(C)]
+struct SetEntry {
+    key: usize,
+    hash: isize,
+}
+
+#[repr(C)]
+struct WeakReference {
+    ob_base: PyObject,
+    wr_object: usize,
+}
+
+// the start of both PyGenObject and PyCoroObject
+#[repr(C)]
+struct GenObject {
+    ob_base: PyObject,
+    gi_frame: usize,
+    gi_running: i8,
+    gi_code: usize,
+    gi_weakreflist: usize,
+    gi_name: usize,
+    gi_qualname: usize,
+}
+
+// TaskObj from _asynciomodule.c, which starts with the same fields as FutureObj
+#[repr(C)]
+struct TaskObject {
+    ob_base: PyObject,
+    fut_loop: usize,
+    fut_callback0: usize,
+    fut_context0: usize,
+    fut_callbacks: usize,
+    fut_exception: usize,
+    fut_result: usize,
+    fut_source_tb: usize,
+    fut_state: i32,
+    fut_log_tb: i32,
+    fut_blocking: i32,
+    dict: usize,
+    fut_weakreflist: usize,
+    task_fut_waiter: usize,
+    task_coro: usize,
+}
+
+/// Gets a stack trace for each asyncio task that is waiting on the event loop run by the thread
+/// of the given trace, or nothing if the thread isn't running an event loop. Each task's coroutines
+/// are placed on top of the 'run_forever' frame of the thread, under a frame for the task itself.
+/// The task that is currently running is skipped, since it's already part of the thread's stack
+pub fn get_task_traces<P: ProcessMemory>(trace: &StackTrace, process: &P) -> Result<Vec<StackTrace>, Error> {
+    let loop_frame = match trace.frames.iter().position(|frame| frame.name == "run_forever" && frame.filename.contains("asyncio")) {
+        Some(index) => index,
+        None => return Ok(Vec::new())
+    };
+    let frame_ptr = trace.frames[loop_frame].address()
+        .ok_or_else(|| format_err!("Missing address of the run_forever frame"))?;
+    let frame: PyFrameObject = process.copy_struct(frame_ptr).context("Failed to copy run_forever frame")?;
+
+    // 'self' is the first local variable of run_forever
+    let event_loop: usize = process.copy_struct(frame_ptr + std::mem::offset_of!(PyFrameObject, f_localsplus))?;
+
+    // base_events.py imports the tasks module, which keeps a WeakSet of all the tasks
+    let tasks_module = dict_get(process, frame.f_globals as usize, "tasks")?
+        .ok_or_else(|| format_err!("Failed to find the asyncio.tasks module"))?;
+    let all_tasks = dict_get(process, module_dict(process, tasks_module)?, "_all_tasks")?
+        .ok_or_else(|| format_err!("Failed to find asyncio.tasks._all_tasks"))?;
+    let all_tasks = dict_get(process, instance_dict(process, all_tasks)?, "data")?
+        .ok_or_else(|| format_err!("Failed to find the contents of asyncio.tasks._all_tasks"))?;
+
+    let mut traces = Vec::new();
+    for weakref in set_items(process, all_tasks)? {
+        match get_task_frames(process, weakref, event_loop) {
+            Ok(Some((mut frames, awaiting))) => {
+                frames.push(Frame{name: "Task".to_owned(), filename: "<asyncio>".to_owned(), module: None,
+                                  short_filename: None, line: 0, frame_ptr: None, locals: None});
+                frames.extend_from_slice(&trace.frames[loop_frame..]);
+                traces.push(StackTrace{thread_id: trace.thread_id, os_thread_id: trace.os_thread_id,
+                                       thread_name: trace.thread_name.clone(), interpreter_id: trace.interpreter_id,
+                                       active: true, owns_gil: false, frames, exception: None, awaiting, pid: None});
+            },
+            Ok(None) => {},
+            // tasks can finish while we're reading them when not pausing the process
+            Err(err) => debug!("Failed to get stack for asyncio task: {}", err)
+        }
+    }
+    Ok(traces)
+}
+
+// the coroutine frames of a task, along with a description of what the innermost one is awaiting
+type TaskFrames = (Vec<Frame>, Option<String>);
+
+// gets the coroutine frames of a task, innermost first. Returns None if the weakref doesn't refer
+// to a task of the event loop that is waiting on something
+fn get_task_frames<P: ProcessMemory>(process: &P, weakref: usize, event_loop: usize) -> Result<Option<TaskFrames>, Error> {
+    let weakref: WeakReference = process.copy_struct(weakref)?;
+    if type_name(process, weakref.wr_object)? != "_asyncio.Task" {
+        return Ok(None);
+    }
+    let task: TaskObject = process.copy_struct(weakref.wr_object)?;
+    if task.fut_loop != event_loop {
+        return Ok(None);
+    }
+    let coroutine: GenObject = process.copy_struct(task.task_coro)?;
+    if coroutine.gi_running != 0 {
+        return Ok(None);
+    }
+
+    let mut frames = Vec::new();
+    let mut current = task.task_coro;
+    let mut awaited = None;
+    loop {
+        // stop once we get to something other than a coroutine, like the future being awaited
+        let name = type_name(process, current)?;
+        if name != "coroutine" && name != "generator" {
+            awaited = Some(current);
+            break;
+        }
+        let coroutine: GenObject = process.copy_struct(current)?;
+        if coroutine.gi_frame == 0 {
+            break;
+        }
+        let frame_ptr = coroutine.gi_frame as *mut PyFrameObject;
+        let frame: PyFrameObject = process.copy_pointer(frame_ptr).context("Failed to copy coroutine frame")?;
+        frames.push(get_frame(frame_ptr, &frame, process)?);
+        if frames.len() > 4096 {
+            return Err(format_err!("Max coroutine recursion depth reached"));
+        }
+
+        match awaiting(process, &frame)? {
+            Some(next) => current = next,
+            None => break
+        }
+    }
+
+    if frames.is_empty() {
+        return Ok(None);
+    }
+    frames.reverse();
+    let awaiting = awaited.map(|object| describe_awaitable(process, object).unwrap_or_else(|_| format!("<unknown at {:#x}>", object)));
+    Ok(Some((frames, awaiting)))
+}
+
+/// Describes an object that a coroutine is awaiting: the state of a future, or the coroutine of a task
+fn describe_awaitable<P: ProcessMemory>(process: &P, object: usize) -> Result<String, Error> {
+    let name = type_name(process, object)?;
+    let header: PyObject = process.copy_struct(object)?;
+    // futures and tasks are often subclassed in python, like asyncio's _GatheringFuture
+    let base = match base_type_name(process, header.ob_type as usize, &["_asyncio.Task", "_asyncio.Future"])? {
+        Some(base) => base,
+        None => return Ok(format!("{} at {:#x}", name, object))
+    };
+    let future: TaskObject = process.copy_struct(object)?;
+    let state = future_state(future.fut_state);
+    if base != "_asyncio.Task" {
+        return Ok(format!("{} at {:#x} ({})", name, object, state));
+    }
+    let coroutine: GenObject = process.copy_struct(future.task_coro)?;
+    let coroutine = copy_string(coroutine.gi_qualname as *const PyUnicodeObject, process)?;
+    Ok(format!("{} running {} at {:#x} ({})", name, coroutine, object, state))
+}
+
+// finds which of the given types a type is, or inherits from
+fn base_type_name<P: ProcessMemory>(process: &P, mut object_type: usize, names: &[&'static str]) -> Result<Option<&'static str>, Error> {
+    for _ in 0..32 {
+        if object_type == 0 {
+            break;
+        }
+        let type_object: _typeobject = process.copy_struct(object_type)?;
+        let name = copy_type_name(process, &type_object)?;
+        if let Some(&found) = names.iter().find(|&&n| n == name) {
+            return Ok(Some(found));
+        }
+        object_type = type_object.tp_base as usize;
+    }
+    Ok(None)
+}
+
+// the fut_state of a future, from the fut_state enum in _asynciomodule.c
+fn future_state(state: i32) -> &'static str {
+    match state {
+        0 => "pending",
+        1 => "cancelled",
+        2 => "finished",
+        _ => "unknown state"
+    }
+}
+
+// returns the object that a suspended coroutine frame is awaiting, the same way _PyGen_yf does
+fn awaiting<P: ProcessMemory>(process: &P, frame: &PyFrameObject) -> Result<Option<usize>, Error> {
+    if frame.f_stacktop.is_null() || frame.f_lasti < 0 {
+        return Ok(None);
+    }
+    let code: PyCodeObject = process.copy_pointer(frame.f_code)?;
+    let bytecode: PyBytesObject = process.copy_struct(code.co_code as usize)?;
+
+    // check the instruction after the last one executed, each of which take 2 bytes
+    let next = frame.f_lasti as usize + 2;
+    if next >= bytecode.size() {
+        return Ok(None);
+    }
+    let opcode: u8 = process.copy_struct(bytecode.address(code.co_code as usize) + next)?;
+    if opcode != YIELD_FROM {
+        return Ok(None);
+    }
+    // the object being awaited is on the top of the value stack
+    Ok(Some(process.copy_struct(frame.f_stacktop as usize - size_of::<usize>())?))
+}
+
+// gets the name of the type of an object, like 'coroutine' or '_asyncio.Task'
+fn type_name<P: ProcessMemory>(process: &P, object: usize) -> Result<String, Error> {
+    let object: PyObject = process.copy_struct(object)?;
+    let object_type: _typeobject = process.copy_pointer(object.ob_type)?;
+    copy_type_name(process, &object_type)
+}
+
+/// Copies the name of a type object (only the first 32 characters, which is enough to tell the
+/// types that we care about apart)
+pub fn copy_type_name<P: ProcessMemory>(process: &P, object_type: &_typeobject) -> Result<String, Error> {
+    let name = process.copy(object_type.tp_name as usize, 32)?;
+    let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Ok(String::from_utf8_lossy(&name[..end]).into_owned())
+}
+
+/// Gets the dict holding the globals of a module
+pub fn module_dict<P: ProcessMemory>(process: &P, module: usize) -> Result<usize, Error> {
+    let module: ModuleObject = process.copy_struct(module).context("Failed to copy module")?;
+    Ok(module.md_dict)
+}
+
+/// Gets the __dict__ of an instance of a python class
+pub fn instance_dict<P: ProcessMemory>(process: &P, object: usize) -> Result<usize, Error> {
+    let header: PyObject = process.copy_struct(object)?;
+    let object_type: _typeobject = process.copy_pointer(header.ob_type)?;
+    if object_type.tp_dictoffset <= 0 {
+        return Err(format_err!("Object doesn't have a __dict__"));
+    }
+    Ok(process.copy_struct(object + object_type.tp_dictoffset as usize)?)
+}
+
+/// Looks up a string key in a dict
+pub fn dict_get<P: ProcessMemory>(process: &P, dict: usize, key: &str) -> Result<Option<usize>, Error> {
+    for (entry_key, value) in dict_items(process, dict)? {
+        // keys that aren't strings won't match
+        match crate::stack_trace::copy_string(entry_key as *const crate::python_bindings::v3_7_0::PyUnicodeObject, process) {
+            Ok(name) if name == key => return Ok(Some(value)),
+            _ => continue
+        }
+    }
+    Ok(None)
+}
+
+/// Gets the keys and values in a dict
+pub fn dict_items<P: ProcessMemory>(process: &P, dict: usize) -> Result<Vec<(usize, usize)>, Error> {
+    let dict: DictObject = process.copy_struct(dict).context("Failed to copy dict")?;
+    let keys: DictKeysObject = process.copy_struct(dict.ma_keys).context("Failed to copy dict keys")?;
+    let entries = keys.dk_nentries as usize;
+    if keys.dk_nentries < 0 || entries > MAX_ENTRIES || keys.dk_size < 0 {
+        return Err(format_err!("Invalid dict size {}", keys.dk_nentries));
+    }
+
+    // the hash table indices get wider as the dict gets bigger
+    let index_size = match keys.dk_size {
+        size if size <= 0xff => 1,
+        size if size <= 0xffff => 2,
+        size if size <= 0xffff_ffff => 4,
+        _ => 8
+    };
+    let entries_address = dict.ma_keys + size_of::<DictKeysObject>() + keys.dk_size as usize * index_size;
+    let data = process.copy(entries_address, entries * size_of::<DictEntry>())?;
+
+    // split tables (used for instance dicts) store the values separately
+    let values = if dict.ma_values != 0 {
+        Some(process.copy(dict.ma_values, entries * size_of::<usize>())?)
+    } else {
+        None
+    };
+
+    let mut items = Vec::with_capacity(dict.ma_used.max(0) as usize);
+    for i in 0..entries {
+        let entry: DictEntry = unsafe { std::ptr::read_unaligned(data.as_ptr().add(i * size_of::<DictEntry>()) as *const _) };
+        let value = match values.as_ref() {
+            Some(values) => unsafe { std::ptr::read_unaligned(values.as_ptr().add(i * size_of::<usize>()) as *const usize) },
+            None => entry.me_value
+        };
+        if entry.me_key != 0 && value != 0 {
+            items.push((entry.me_key, value));
+        }
+    }
+    Ok(items)
+}
+
+// gets the items in a set
+fn set_items<P: ProcessMemory>(process: &P, set: usize) -> Result<Vec<usize>, Error> {
+    let set: SetObject = process.copy_struct(set).context("Failed to copy set")?;
+    let slots = set.mask as usize + 1;
+    if set.mask < 0 || slots > MAX_ENTRIES {
+        return Err(format_err!("Invalid set size {}", slots));
+    }
+    let data = process.copy(set.table, slots * size_of::<SetEntry>())?;
+    let mut items = Vec::with_capacity(set.used.max(0) as usize);
+    for i in 0..slots {
+        let entry: SetEntry = unsafe { std::ptr::read_unaligned(data.as_ptr().add(i * size_of::<SetEntry>()) as *const _) };
+        // deleted entries are left behind as a dummy key with a hash of -1
+        if entry.key != 0 && entry.hash != -1 {
+            items.push(entry.key);
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+
+    #[test]
+    fn test_set_items() {
+        let table = [SetEntry{key: 0x10, hash: 5}, SetEntry{key: 0, hash: 0},
+                     SetEntry{key: 0x20, hash: -1}, SetEntry{key: 0x30, hash: 7}];
+        let set = SetObject{ob_base: PyObject::default(), fill: 3, used: 2, mask: 3, table: table.as_ptr() as usize};
+        let items = set_items(&LocalProcess, &set as *const SetObject as usize).unwrap();
+        // empty slots and deleted entries get skipped
+        assert_eq!(items, vec![0x10, 0x30]);
+    }
+
+    #[test]
+    fn test_describe_awaitable() {
+        let future_name = b"_asyncio.Future\0";
+        let mut future_type = _typeobject{tp_name: future_name.as_ptr() as *const _, ..Default::default()};
+        let subclass_name = b"_GatheringFuture\0";
+        let mut subclass_type = _typeobject{tp_name: subclass_name.as_ptr() as *const _, tp_base: &mut future_type,
+                                            ..Default::default()};
+        let future = TaskObject{ob_base: PyObject{ob_refcnt: 1, ob_type: &mut subclass_type}, fut_loop: 0, fut_callback0: 0,
+                                fut_context0: 0, fut_callbacks: 0, fut_exception: 0, fut_result: 0, fut_source_tb: 0,
+                                fut_state: 1, fut_log_tb: 0, fut_blocking: 0, dict: 0, fut_weakreflist: 0,
+                                task_fut_waiter: 0, task_coro: 0};
+        let address = &future as *const TaskObject as usize;
+        assert_eq!(describe_awaitable(&LocalProcess, address).unwrap(),
+                   format!("_GatheringFuture at {:#x} (cancelled)", address));
+
+        let other_name = b"Event\0";
+        let mut other_type = _typeobject{tp_name: other_name.as_ptr() as *const _, ..Default::default()};
+        let other = PyObject{ob_refcnt: 1, ob_type: &mut other_type};
+        let address = &other as *const PyObject as usize;
+        assert_eq!(describe_awaitable(&LocalProcess, address).unwrap(), format!("Event at {:#x}", address));
+    }
+}
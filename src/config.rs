@@ -1,9 +1,24 @@
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use failure::Error;
 use remoteprocess::Pid;
+use std::ffi::OsString;
+use std::time::Duration;
 
-/// Options on how to collect samples from a python process
+use crate::alert::Condition;
+use crate::assertion::Assertion;
+use crate::error;
+use crate::idle_rules::{self, IdleRule};
+use crate::time_range::TimeBound;
+use crate::config_file::Defaults;
+use crate::trigger::Trigger;
+
+/// Number of allocation snapshots to take per second with --memory, unless told otherwise
+const MEMORY_SAMPLING_RATE: u64 = 1;
+
+/// Options on how to collect samples from a python process. Outside of py-spy, this is created with
+/// `Config::default()` or a `ConfigBuilder`, which checks that the options can be used together
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct Config {
     /// Whether or not we should stop the python process when taking samples.
     /// Setting this to false will reduce the performance impact on the target
@@ -11,51 +26,610 @@ pub struct Config {
     /// traces being returned or a higher sampling error rate
     pub non_blocking: bool,
 
+    /// Never suspend the python process or attach to it with ptrace, only reading its memory. Each
+    /// stack is checked for having changed while it was being read, and stacks that did are dropped.
+    /// This implies non_blocking
+    #[doc(hidden)]
+    pub dirty_reads: bool,
+
+    /// Stop all the threads of the python process at the same instant for each sample, by freezing
+    /// its cgroup on Linux, rather than pausing them one after another
+    #[doc(hidden)]
+    pub freeze: bool,
+
+    /// Run py-spy again with sudo when attaching to the process is denied
+    #[doc(hidden)]
+    pub sudo: bool,
+
     /// Whether or not to profile native extensions. Note: this option can not be
     /// used with the nonblocking option, as we have to pause the process to collect
     /// the native stack traces
     pub native: bool,
 
+    /// The longest that the process can be paused for while taking a single sample, when not
+    /// using the non_blocking option. Samples that take longer are abandoned (resuming the process)
+    /// and return a PauseTimeExceeded error
+    pub max_pause: Option<Duration>,
+
+    /// How many threads to unwind python stacks with. Setting this above 1 unwinds the stacks of
+    /// different python threads in parallel, which shortens samples for programs with a lot of
+    /// threads. This doesn't apply when collecting native stack traces
+    pub unwind_workers: usize,
+
+    /// Whether or not to only count threads as active when the OS reports that they have used
+    /// CPU time since the previous sample. This produces an on-CPU profile rather than
+    /// a wall clock profile
+    pub cpu_time: bool,
+
+    /// Only sample the threads with these OS or python thread ids. All threads are sampled if empty
+    pub thread_ids: Vec<u64>,
+
+    /// Only sample threads whose OS thread name matches this regex. Thread names are currently
+    /// only available on Linux
+    pub thread_name_regex: Option<String>,
+
+    /// With native, only get native stack traces for the threads with these OS or python thread ids,
+    /// or whose name matches native_thread_name_regex. Other threads just get their python stack.
+    /// Native stacks are collected for every thread if both are empty
+    pub native_thread_ids: Vec<u64>,
+    pub native_thread_name_regex: Option<String>,
+
+    /// Whether or not to also get stack traces for asyncio tasks that are waiting on an event loop,
+    /// made up of the chain of coroutines that each task is awaiting. Each waiting task is returned
+    /// as an active stack trace for the thread running its event loop. Only supported for python 3.7
+    pub asyncio: bool,
+
+    /// Whether or not to also get stack traces for greenlets that are switched out, for gevent and
+    /// other greenlet based programs. Each greenlet is returned as an active stack trace for the
+    /// thread it belongs to. Only supported for 64 bit builds of python 3.7
+    pub gevent: bool,
+
+    /// Whether or not to copy the local variables of each python frame into the stack traces,
+    /// describing simple values like ints and strings and giving the type of other objects
+    pub dump_locals: bool,
+
+    /// Copies up to this many positional arguments of the innermost few python frames of each
+    /// stack trace, so that samples can tell apart calls like which query was being run
+    pub capture_args: Option<usize>,
+
+    /// Whether or not to get the exception that each thread is handling, along with where it was raised
+    pub capture_exceptions: bool,
+
+    /// Whether or not to add a frame for the interpreter at the root of each stack trace, so that
+    /// the stacks of each subinterpreter are grouped together
+    pub group_by_interpreter: bool,
+
     // The following config options only apply when using py-spy as an application
     #[doc(hidden)]
     pub sampling_rate: u64,
     #[doc(hidden)]
     pub pid: Option<Pid>,
+    /// The rest of the processes given with --pid, which are sampled along with the first one
+    #[doc(hidden)]
+    pub other_pids: Vec<Pid>,
+    #[doc(hidden)]
+    pub name: Option<String>,
+    #[doc(hidden)]
+    pub include_new: bool,
+    /// Find the master process of this server to profile along with its workers
+    pub auto: Option<Server>,
     #[doc(hidden)]
     pub python_program: Option<Vec<String>>,
+    /// Environment variables to set for the python program, on top of py-spy's own environment
+    #[doc(hidden)]
+    pub program_env: Vec<(String, String)>,
+    /// Directory to run the python program in
+    #[doc(hidden)]
+    pub program_cwd: Option<String>,
+    /// Files for the python program to read its input from and write its output to, where '-'
+    /// means py-spy's own. By default there's no input, and output is only shown if it fails
+    #[doc(hidden)]
+    pub program_stdin: Option<String>,
+    #[doc(hidden)]
+    pub program_stdout: Option<String>,
+    #[doc(hidden)]
+    pub program_stderr: Option<String>,
     #[doc(hidden)]
     pub dump: bool,
+    /// Print dumps as JSON rather than text
+    #[doc(hidden)]
+    pub dump_json: bool,
+    /// How often to dump the stacks again, for repeated dumps
+    #[doc(hidden)]
+    pub dump_interval: Option<Duration>,
+    #[doc(hidden)]
+    pub dump_count: Option<u64>,
+    /// Only print repeated dumps when the stacks have changed since the last one printed
+    #[doc(hidden)]
+    pub dump_changes_only: bool,
+    /// Also dump the native stacks of threads that aren't running python
+    #[doc(hidden)]
+    pub native_threads: bool,
+    #[doc(hidden)]
+    pub filename: Option<String>,
+    #[doc(hidden)]
+    pub format: FileFormat,
+    /// Where the top view writes snapshots of the samples it has collected, in the format given by --format
+    #[doc(hidden)]
+    pub snapshot_filename: Option<String>,
+    #[doc(hidden)]
+    pub snapshot_on_exit: bool,
     #[doc(hidden)]
-    pub flame_file_name: Option<String>,
+    pub jitter: Jitter,
+    #[doc(hidden)]
+    pub convert_file: Option<String>,
     #[doc(hidden)]
     pub show_line_numbers: bool,
     #[doc(hidden)]
-    pub duration: u64,
+    pub reverse: bool,
+    #[doc(hidden)]
+    pub group_by: GroupBy,
+    #[doc(hidden)]
+    pub gil_only: bool,
+    #[doc(hidden)]
+    pub include_idle: bool,
+    #[doc(hidden)]
+    pub duration: Option<Duration>,
+    #[doc(hidden)]
+    pub samples: Option<u64>,
+    #[doc(hidden)]
+    pub every: Option<Duration>,
+    /// Profile in windows until stopped with 'py-spy monitor', following the process when it restarts
+    #[doc(hidden)]
+    pub monitor: bool,
+    /// Address to serve the latest profile and metrics on while monitoring
+    #[doc(hidden)]
+    pub monitor_listen: Option<String>,
+    /// How many of the files written while monitoring to keep
+    #[doc(hidden)]
+    pub monitor_keep: Option<usize>,
+    /// The conditions to alert on with 'py-spy alert'
+    #[doc(hidden)]
+    pub alert_conditions: Vec<Condition>,
+    /// How long a window of samples the conditions are checked over
+    #[doc(hidden)]
+    pub alert_window: Duration,
+    /// A command to run when an alert fires or clears
+    #[doc(hidden)]
+    pub alert_exec: Option<String>,
+    /// An http url to POST alerts to
+    #[doc(hidden)]
+    pub alert_webhook: Option<String>,
+    #[doc(hidden)]
+    pub max_overhead: Option<f64>,
+    #[doc(hidden)]
+    pub start_paused: bool,
+    #[doc(hidden)]
+    pub control_file: Option<String>,
+    #[doc(hidden)]
+    pub start_when: Option<Trigger>,
+    #[doc(hidden)]
+    pub stop_when_clear: bool,
+    #[doc(hidden)]
+    pub respawn_wait: Option<Duration>,
+    #[doc(hidden)]
+    pub subprocesses: bool,
+    #[doc(hidden)]
+    pub merge_processes: bool,
+    /// Write a separate output file for each process being sampled
+    #[doc(hidden)]
+    pub per_process: bool,
+    #[doc(hidden)]
+    pub memory: bool,
+    #[doc(hidden)]
+    pub qualified_names: bool,
+    #[doc(hidden)]
+    pub gil_wait: bool,
+    #[doc(hidden)]
+    pub lock_contention: bool,
+    #[doc(hidden)]
+    pub kernel: bool,
+    /// Record the memory, CPU time, open files and IO of the process alongside the samples
+    #[doc(hidden)]
+    pub resources: bool,
+    /// Show the top functions recorded so far while recording
+    #[doc(hidden)]
+    pub preview: bool,
+    /// Add the recording to the end of an existing raw capture, rather than overwriting it
+    #[doc(hidden)]
+    pub append: bool,
+    /// Write events about the progress of recordings to stderr as JSON
+    #[doc(hidden)]
+    pub status_json: bool,
+    /// Assertions to check against the recording once it's finished, failing when one doesn't hold
+    #[doc(hidden)]
+    pub assertions: Vec<Assertion>,
+    /// A file to write a JSON report on the assertions to
+    #[doc(hidden)]
+    pub assert_report: Option<String>,
+    /// Only keep the frames whose function name and filename match this regex
+    #[doc(hidden)]
+    pub include_frames: Option<String>,
+    /// Leave out the frames whose function name and filename match this regex
+    #[doc(hidden)]
+    pub exclude_frames: Option<String>,
+    /// Leave out the frames in the python standard library
+    #[doc(hidden)]
+    pub exclude_stdlib: bool,
+    /// Only keep this many frames from the root of each stack
+    #[doc(hidden)]
+    pub max_depth: Option<usize>,
+    /// Leave out the frames at the root of each stack that match this regex
+    #[doc(hidden)]
+    pub trim_roots: Option<String>,
+    /// Leave frames with less than this percent of the samples out of flamegraphs
+    #[doc(hidden)]
+    pub min_percent: Option<f64>,
+    /// Functions that threads are idle in, on top of the built-in ones
+    #[doc(hidden)]
+    pub idle_rules: Vec<IdleRule>,
+    /// Built-in idle rules to leave out
+    #[doc(hidden)]
+    pub removed_idle_rules: Vec<IdleRule>,
+    /// Leave out the samples before this point in the recording
+    #[doc(hidden)]
+    pub trim_start: Option<TimeBound>,
+    /// Leave out the samples after this point in the recording, or in this much of its end
+    #[doc(hidden)]
+    pub trim_end: Option<TimeBound>,
+    /// Labels like ('service', 'web') to store in the output, so that recordings can be told apart later
+    #[doc(hidden)]
+    pub labels: Vec<(String, String)>,
+    #[doc(hidden)]
+    pub debuginfod: bool,
+    #[doc(hidden)]
+    pub unsymbolicated: bool,
+    #[doc(hidden)]
+    pub symbolicate: bool,
+    #[doc(hidden)]
+    pub symbol_paths: Vec<String>,
+    #[doc(hidden)]
+    pub path_maps: Vec<(String, String)>,
+    #[doc(hidden)]
+    pub cmdline: Option<String>,
+    #[doc(hidden)]
+    pub port: Option<u16>,
+    #[doc(hidden)]
+    pub pod: Option<String>,
+    #[doc(hidden)]
+    pub pod_container: Option<String>,
+    #[doc(hidden)]
+    pub pod_namespace: Option<String>,
+    #[doc(hidden)]
+    pub core_file: Option<String>,
+    #[doc(hidden)]
+    pub core_exe: Option<String>,
+    #[doc(hidden)]
+    pub agent_listen: Option<String>,
+    #[doc(hidden)]
+    pub agent_token_file: Option<String>,
+    #[doc(hidden)]
+    pub remote: Option<String>,
+    #[doc(hidden)]
+    pub remote_args: Vec<String>,
+    #[doc(hidden)]
+    pub heap: bool,
+    #[doc(hidden)]
+    pub heap_baseline: Option<String>,
+    /// List the python processes running on this machine, for 'py-spy ps'
+    #[doc(hidden)]
+    pub list_processes: bool,
+}
+
+arg_enum!{
+    /// How the time between samples is randomized, to avoid aliasing with periodic work in the
+    /// program being profiled. All of these keep the mean sampling rate the same
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub enum Jitter {
+        exponential,
+        uniform,
+        none
+    }
+}
+
+arg_enum!{
+    /// What samples are aggregated by: the function of each frame, or the line within it
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub enum Granularity {
+        function,
+        line
+    }
+}
+
+arg_enum!{
+    /// What the frames of each stack are collapsed to: the top level package or dotted module path
+    /// of their file, the file itself, or the function (which leaves stacks as they are)
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub enum GroupBy {
+        package,
+        module,
+        file,
+        function
+    }
+}
+
+arg_enum!{
+    /// Servers that fork off python workers, for finding their master process with --auto
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub enum Server {
+        gunicorn,
+        celery,
+        uwsgi
+    }
+}
+
+arg_enum!{
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub enum FileFormat {
+        flamegraph,
+        folded,
+        speedscope,
+        pprof,
+        html,
+        summary,
+        csv,
+        raw
+    }
 }
 
 impl Default for Config {
     /// Initializes a new Config object with default parameters
     #[allow(dead_code)]
     fn default() -> Config {
-        Config{pid: None, python_program: None, dump: false, flame_file_name: None,
-               non_blocking: false, show_line_numbers: false, sampling_rate: 100,
-               duration: 2, native: false}
+        Config{
+            pid: None,
+            other_pids: Vec::new(),
+            name: None,
+            include_new: false,
+            auto: None,
+            python_program: None,
+            program_env: Vec::new(),
+            program_cwd: None,
+            program_stdin: None,
+            program_stdout: None,
+            program_stderr: None,
+            dump: false,
+            dump_json: false,
+            dump_interval: None,
+            dump_count: None,
+            dump_changes_only: false,
+            native_threads: false,
+            filename: None,
+            format: FileFormat::flamegraph,
+            snapshot_filename: None,
+            snapshot_on_exit: false,
+            jitter: Jitter::exponential,
+            convert_file: None,
+            non_blocking: false,
+            dirty_reads: false,
+            freeze: false,
+            sudo: false,
+            cpu_time: false,
+            thread_ids: Vec::new(),
+            thread_name_regex: None,
+            native_thread_ids: Vec::new(),
+            native_thread_name_regex: None,
+            asyncio: false,
+            gevent: false,
+            dump_locals: false,
+            capture_args: None,
+            capture_exceptions: false,
+            group_by_interpreter: false,
+            memory: false,
+            qualified_names: true,
+            gil_wait: false,
+            lock_contention: false,
+            kernel: false,
+            resources: false,
+            preview: false,
+            append: false,
+            status_json: false,
+            assertions: Vec::new(),
+            assert_report: None,
+            include_frames: None,
+            exclude_frames: None,
+            exclude_stdlib: false,
+            max_depth: None,
+            trim_roots: None,
+            min_percent: None,
+            idle_rules: Vec::new(),
+            removed_idle_rules: Vec::new(),
+            trim_start: None,
+            trim_end: None,
+            labels: Vec::new(),
+            debuginfod: false,
+            unsymbolicated: false,
+            symbolicate: false,
+            symbol_paths: Vec::new(),
+            path_maps: Vec::new(),
+            cmdline: None,
+            port: None,
+            pod: None,
+            pod_container: None,
+            pod_namespace: None,
+            core_file: None,
+            core_exe: None,
+            agent_listen: None,
+            agent_token_file: None,
+            remote: None,
+            remote_args: Vec::new(),
+            heap: false,
+            heap_baseline: None,
+            list_processes: false,
+            show_line_numbers: false,
+            reverse: false,
+            group_by: GroupBy::function,
+            gil_only: false,
+            include_idle: false,
+            sampling_rate: 100,
+            duration: None,
+            samples: None,
+            every: None,
+            monitor: false,
+            monitor_listen: None,
+            monitor_keep: None,
+            alert_conditions: Vec::new(),
+            alert_window: Duration::from_secs(60),
+            alert_exec: None,
+            alert_webhook: None,
+            max_overhead: None,
+            start_paused: false,
+            control_file: None,
+            start_when: None,
+            stop_when_clear: false,
+            max_pause: None,
+            unwind_workers: 1,
+            respawn_wait: None,
+            subprocesses: false,
+            merge_processes: false,
+            per_process: false,
+            native: false,
+        }
     }
 }
 
 impl Config {
     /// Uses clap to set config options from commandline arguments
-    pub fn from_commandline() -> Result<Config, Error> {
+    pub fn from_commandline() -> Result<Config, error::Error> {
+        let defaults = Defaults::load().map_err(error::Error::invalid_config)?;
+        match Config::parse_args(std::env::args_os(), &defaults) {
+            Ok(config) => Ok(config),
+            // exits with clap's usage message (or prints --help and --version)
+            Err(err) => match err.downcast::<clap::Error>() {
+                Ok(err) => err.exit(),
+                Err(err) => Err(error::Error::invalid_config(err))
+            }
+        }
+    }
+
+    /// Sets config options from a list of arguments, starting with the name of the program. Unlike
+    /// from_commandline, this returns invalid arguments as an error instead of exiting
+    pub fn from_args<I, T>(args: I) -> Result<Config, error::Error> where I: IntoIterator<Item=T>, T: Into<OsString> + Clone {
+        Config::from_args_with_defaults(args, &Defaults::default())
+    }
+
+    /// Sets config options from a list of arguments, using the defaults from py-spy.toml files for the
+    /// options that aren't given
+    pub fn from_args_with_defaults<I, T>(args: I, defaults: &Defaults) -> Result<Config, error::Error>
+            where I: IntoIterator<Item=T>, T: Into<OsString> + Clone {
+        Config::parse_args(args, defaults).map_err(error::Error::invalid_config)
+    }
+
+    fn parse_args<I, T>(args: I, defaults: &Defaults) -> Result<Config, Error>
+            where I: IntoIterator<Item=T>, T: Into<OsString> + Clone {
+        let args: Vec<OsString> = args.into_iter().map(|arg| arg.into()).collect();
         // we don't yet support native tracing on 32 bit linux
         let allow_native = cfg!(unwind);
 
-        let matches = App::new(crate_name!())
+        let reverse_arg = Arg::with_name("reverse")
+            .long("reverse")
+            .help("Merge stacks from the leaf function upwards, showing which callers lead into the hottest functions");
+
+        let granularity_arg = Arg::with_name("granularity")
+            .long("granularity")
+            .value_name("granularity")
+            .help("Whether to aggregate samples by function or by line. Line granularity also reports \
+                   the hottest lines of each function in the summary format")
+            .possible_values(&Granularity::variants())
+            .case_insensitive(true)
+            .conflicts_with("function")
+            .takes_value(true);
+
+        let group_by_arg = Arg::with_name("group_by")
+            .long("group-by")
+            .value_name("group_by")
+            .help("Collapse the frames of each stack to the package, module or file they come from, showing \
+                   which dependencies the most time is spent in")
+            .possible_values(&GroupBy::variants())
+            .case_insensitive(true)
+            .default_value("function")
+            .takes_value(true);
+
+        let map_path_arg = Arg::with_name("map_path")
+            .long("map-path")
+            .value_name("from=to")
+            .help("Rewrite the paths of source files starting with 'from' to start with 'to' instead, so that \
+                   recordings made in containers point at the source on this machine. Can be repeated")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true);
+
+        let max_depth_arg = Arg::with_name("max_depth")
+            .long("max-depth")
+            .value_name("depth")
+            .help("Only keep this many frames from the root of each stack, so that the time in deeper frames goes \
+                   to the frame at that depth")
+            .takes_value(true);
+
+        let trim_roots_arg = Arg::with_name("trim_roots")
+            .long("trim-roots")
+            .value_name("regex")
+            .help("Leave out the frames at the root of each stack for as long as they match this regex (against \
+                   the function name and filename), to drop boilerplate like 'runpy|wsgi'")
+            .takes_value(true);
+
+        let min_percent_arg = Arg::with_name("min_percent")
+            .long("min-percent")
+            .value_name("percent")
+            .help("Hide frames with less than this percent of the samples from flamegraph and folded outputs, \
+                   counting their time towards their caller")
+            .takes_value(true);
+
+        let trim_start_arg = Arg::with_name("trim_start")
+            .long("trim-start")
+            .value_name("time")
+            .help("Leave out the samples in this much of the start of the recording (like '10s'), or before a UTC \
+                   time like '12:00:10' or '2019-06-01 12:00:10'")
+            .takes_value(true);
+
+        let trim_end_arg = Arg::with_name("trim_end")
+            .long("trim-end")
+            .value_name("time")
+            .help("Leave out the samples in this much of the end of the recording (like '5s'), or after a UTC \
+                   time like '12:05:00' or '2019-06-01 12:05:00'")
+            .takes_value(true);
+
+        let label_arg = Arg::with_name("label")
+            .long("label")
+            .value_name("key=value")
+            .help("Store a label like 'service=web' in the output, so that recordings can be told apart once they're \
+                   collected together. Can be repeated")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true);
+
+        let token_file_arg = Arg::with_name("token_file")
+            .long("token-file")
+            .value_name("filename")
+            .help("File containing the secret shared by the agent and the machines allowed to use it. Defaults \
+                   to the PYSPY_AGENT_TOKEN environment variable")
+            .takes_value(true);
+
+        let format_arg = Arg::with_name("format")
+            .long("format")
+            .value_name("format")
+            .help("Output file format")
+            .possible_values(&FileFormat::variants())
+            .case_insensitive(true)
+            .default_value("flamegraph")
+            .takes_value(true);
+
+        let mut app = App::new(crate_name!())
             .version(crate_version!())
             .about(crate_description!())
+            .setting(AppSettings::SubcommandsNegateReqs)
             .arg(Arg::with_name("function")
                 .short("F")
                 .long("function")
                 .help("Aggregate samples by function name instead of by line number"))
+            .arg(granularity_arg.clone())
+            .arg(group_by_arg.clone())
+            .arg(map_path_arg.clone())
             .arg(Arg::with_name("native")
                 .short("n")
                 .long("native")
@@ -65,22 +639,343 @@ impl Config {
                 .short("p")
                 .long("pid")
                 .value_name("pid")
-                .help("PID of a running python program to spy on")
+                .help("PID of a running python program to spy on. Pass this more than once (or a comma separated \
+                       list of pids) to sample several programs together")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .required_unless_one(&["python_program", "name", "cmdline", "port", "auto", "core"]))
+            .arg(Arg::with_name("name")
+                .long("name")
+                .value_name("pattern")
+                .help("Spy on all the python programs whose command line matches a glob pattern like 'celery*'")
+                .takes_value(true)
+                .conflicts_with_all(&["pid", "python_program"]))
+            .arg(Arg::with_name("cmdline")
+                .long("cmdline")
+                .value_name("text")
+                .help("Spy on the python program whose command line contains some text, asking which one to use \
+                       if there's more than one")
+                .takes_value(true)
+                .conflicts_with_all(&["pid", "name", "python_program"]))
+            .arg(Arg::with_name("port")
+                .long("port")
+                .value_name("port")
+                .help("Spy on the python program listening on a TCP port, asking which one to use if there's more \
+                       than one (like the workers of a web server)")
                 .takes_value(true)
-                .required_unless("python_program"))
+                .conflicts_with_all(&["pid", "name", "cmdline", "python_program"]))
+            .arg(Arg::with_name("auto")
+                .long("auto")
+                .value_name("server")
+                .help("Find the master process of a gunicorn, celery or uwsgi server, and profile it along with \
+                       all of its workers (including ones that start later), labelling each stack with its worker")
+                .possible_values(&Server::variants())
+                .case_insensitive(true)
+                .takes_value(true)
+                .conflicts_with_all(&["pid", "name", "cmdline", "port", "python_program", "memory"]))
+            .arg(Arg::with_name("include_new")
+                .long("include-new")
+                .requires("name")
+                .help("Also sample processes matching --name that start after py-spy"))
             .arg(Arg::with_name("dump")
                 .long("dump")
                 .help("Dump the current stack traces to stdout"))
+            .arg(Arg::with_name("core")
+                .long("core")
+                .value_name("core_file")
+                .requires("dump")
+                .conflicts_with_all(&["pid", "name", "auto", "python_program"])
+                .help("Dump the stack traces of the python threads in a core file, instead of a running program")
+                .takes_value(true))
+            .arg(Arg::with_name("core_exe")
+                .long("exe")
+                .value_name("python_binary")
+                .requires("core")
+                .help("The python binary that dumped core, used for its symbols. Defaults to the executable recorded \
+                       in the core file")
+                .takes_value(true))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .requires("dump")
+                .help("Print the dump as JSON, for reading with other programs"))
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .value_name("interval")
+                .requires("dump")
+                .conflicts_with("core")
+                .help("Dump the stacks again every interval (like '5s'), with the time of each dump, until \
+                       Control-C is pressed or --count dumps have been taken")
+                .takes_value(true))
+            .arg(Arg::with_name("watch")
+                .long("watch")
+                .requires("dump")
+                .conflicts_with("core")
+                .help("Dump the stacks every second (or every --interval) until Control-C is pressed"))
+            .arg(Arg::with_name("count")
+                .long("count")
+                .value_name("count")
+                .help("How many dumps to take with --interval or --watch")
+                .takes_value(true))
+            .arg(Arg::with_name("native_threads")
+                .long("native-threads")
+                .hidden(!allow_native)
+                .requires("dump")
+                .conflicts_with_all(&["core", "nonblocking"])
+                .help("Also dump the native stacks of threads that aren't running python code, like threads \
+                       started by C extensions"))
+            .arg(Arg::with_name("changes_only")
+                .long("changes-only")
+                .help("Only print repeated dumps when the stacks are different from the last dump printed"))
+            .arg(Arg::with_name("locals")
+                .long("locals")
+                .requires("dump")
+                .help("Show the local variables of each function in the dump"))
+            .arg(Arg::with_name("capture_args")
+                .long("capture-args")
+                .value_name("count")
+                .help("Record the values of up to this many arguments of the innermost few functions in each \
+                       sample, shown in dumps and speedscope profiles")
+                .takes_value(true))
+            .arg(Arg::with_name("exceptions")
+                .long("exceptions")
+                .requires("dump")
+                .help("Show the exception that each thread is handling in the dump, along with where it was raised"))
             .arg(Arg::with_name("nonblocking")
                 .long("nonblocking")
                 .help("Don't pause the python process when collecting samples. Setting this option will reduce \
                       the perfomance impact of sampling, but may lead to inaccurate results"))
+            .arg(Arg::with_name("sudo")
+                .long("sudo")
+                .hidden(cfg!(windows))
+                .help("When attaching to the process is denied, run py-spy again with sudo. On Linux it then switches \
+                       back to your user, keeping only the capabilities needed to read other processes"))
+            .arg(Arg::with_name("dirty_reads")
+                .long("dirty-reads")
+                .conflicts_with_all(&["native", "max_pause", "gil_wait", "locks"])
+                .help("Only read the memory of the python process, without ever pausing or attaching to it (this \
+                       implies --nonblocking). Stacks that changed while they were being read are dropped, rather \
+                       than recorded with frames from different points in time"))
+            .arg(Arg::with_name("freeze")
+                .long("freeze")
+                .conflicts_with_all(&["nonblocking", "dirty_reads"])
+                .help("Stop every thread of the python process at the same instant for each sample, so that stacks \
+                       from different threads can be compared. On Linux this freezes the process's cgroup (which \
+                       also freezes anything else in it) and can't be used with --native"))
+            .arg(Arg::with_name("max_pause")
+                .long("max-pause")
+                .value_name("duration")
+                .help("Longest time to pause the python process for in a single sample, like '5ms'. Samples that \
+                       take longer are dropped and the process is resumed")
+                .takes_value(true)
+                .conflicts_with("nonblocking"))
+            .arg(Arg::with_name("parallel")
+                .long("parallel")
+                .value_name("workers")
+                .help("Unwind the stacks of different python threads in parallel on this many worker threads, \
+                       which shortens each sample for programs with a lot of threads")
+                .takes_value(true))
+            .arg(Arg::with_name("cpu_time")
+                .long("cpu-time")
+                .help("Only sample threads that have used CPU time since the previous sample, as reported by the OS"))
+            .arg(Arg::with_name("gil")
+                .short("g")
+                .long("gil")
+                .help("Only include traces that are holding on to the GIL. Can be toggled while running with \
+                       the 'G' key in top, or with --control when recording"))
+            .arg(Arg::with_name("bare_names")
+                .long("bare-names")
+                .help("Show just the name of each function, instead of qualifying methods with the name of their \
+                       class (like 'HttpServer.handle_request')"))
+            .arg(Arg::with_name("gil_wait")
+                .long("gil-wait")
+                .hidden(!allow_native)
+                .help("Detect threads that are waiting to take the GIL, and count them as active with a frame \
+                       showing the wait. This needs the symbols of libpython"))
+            .arg(Arg::with_name("locks")
+                .long("locks")
+                .hidden(!allow_native)
+                .requires("output")
+                .conflicts_with_all(&["dump", "memory"])
+                .help("Record only the threads that are blocked acquiring a threading.Lock, RLock or Condition, \
+                       producing a profile of lock contention. This needs the symbols of libpython"))
+            .arg(Arg::with_name("debuginfod")
+                .long("debuginfod")
+                .hidden(!(allow_native && cfg!(target_os="linux")))
+                .help("Download the debug info of stripped native libraries from the debuginfod servers in \
+                       DEBUGINFOD_URLS (with debuginfod-find), to get the names and line numbers of native frames"))
+            .arg(Arg::with_name("unsymbolicated")
+                .long("unsymbolicated")
+                .hidden(!(allow_native && cfg!(target_os="linux")))
+                .requires("native")
+                .conflicts_with("dump")
+                .help("Record the addresses of native frames along with the libraries they are in, instead of \
+                       symbolicating them. Use with '--format raw', and then 'py-spy symbolicate' on a machine with \
+                       the debug symbols"))
+            .arg(Arg::with_name("resources")
+                .long("resources")
+                .hidden(!cfg!(target_os="linux"))
+                .conflicts_with("dump")
+                .help("Record the memory, CPU usage, open files and IO of the process every second alongside the \
+                       samples. These are kept in raw captures and reported by the summary format"))
+            .arg(Arg::with_name("preview")
+                .long("preview")
+                .requires("output")
+                .conflicts_with_all(&["dump", "memory"])
+                .help("Show the functions using the most time so far, along with the number of samples and errors \
+                       and the time left, while recording to a file"))
+            .arg(Arg::with_name("assert")
+                .long("assert")
+                .value_name("assertion")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("output")
+                .conflicts_with_all(&["dump", "every", "memory"])
+                .help("Check the finished recording against an assertion like 'self_pct(my.module.slow_fn) < 5' \
+                       (also total_pct(<function>), gil_pct or samples), exiting with code 3 if it doesn't hold. \
+                       Can be given more than once"))
+            .arg(Arg::with_name("assert_report")
+                .long("assert-report")
+                .value_name("filename")
+                .takes_value(true)
+                .requires("assert")
+                .help("Write a JSON report on how each --assert did to a file"))
+            .arg(Arg::with_name("append")
+                .long("append")
+                .requires("output")
+                .conflicts_with_all(&["dump", "every", "per_process"])
+                .help("Add the samples to the end of the raw capture given by --output, continuing an earlier \
+                       recording of the same program, rather than overwriting it. Needs '--format raw'"))
+            .arg(Arg::with_name("status_json")
+                .long("status-json")
+                .conflicts_with("dump")
+                .help("Write events about the recording (attached, sampling-started, rate-adjusted, error and finished, \
+                       with the files written) to stderr as a line of JSON each, for tools that run py-spy"))
+            .arg(Arg::with_name("kernel")
+                .long("kernel")
+                .hidden(!cfg!(target_os="linux"))
+                .conflicts_with("dump")
+                .help("Include the kernel stack of each thread (from perf events), so that time spent in syscalls shows \
+                       up under the python code that made them. This needs permission to sample the kernel"))
+            .arg(Arg::with_name("idle")
+                .short("i")
+                .long("idle")
+                .help("Include stack traces for idle threads. Can be toggled while running with the 'I' key \
+                       in top, or with --control when recording"))
+            .arg(Arg::with_name("idle_rule")
+                .long("idle-rule")
+                .value_name("function[@file]")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Count threads whose stack ends in this function (in a file whose path contains 'file') as idle, \
+                       for frameworks that wait for work in loops of their own. Can be repeated"))
+            .arg(Arg::with_name("no_idle_rule")
+                .long("no-idle-rule")
+                .value_name("function@file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Stop counting threads in one of the built-in idle functions as idle, like 'poll@tornado'. \
+                       Can be repeated"))
+            .arg(Arg::with_name("asyncio")
+                .long("asyncio")
+                .help("Also sample asyncio tasks that are waiting on the event loop, showing the chain of \
+                       coroutines each one is awaiting (python 3.7 only)"))
+            .arg(Arg::with_name("gevent")
+                .long("gevent")
+                .help("Also sample greenlets that are switched out, labelling each stack with the greenlet \
+                       it came from (python 3.7 only)"))
+            .arg(Arg::with_name("memory")
+                .long("memory")
+                .requires("output")
+                .conflicts_with_all(&["dump", "name", "subprocesses"])
+                .help("Record where memory is allocated instead of where time is spent, weighting stacks by the \
+                       bytes allocated from them. Needs the program to be running with tracemalloc enabled"))
+            .arg(Arg::with_name("interpreters")
+                .long("interpreters")
+                .help("Group the stacks of each subinterpreter together, under a frame for the interpreter"))
+            .arg(Arg::with_name("tid")
+                .long("tid")
+                .value_name("tid")
+                .help("Only sample the thread with this OS or python thread id (decimal or 0x prefixed hex). Can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("thread_name_regex")
+                .long("thread-name-regex")
+                .value_name("regex")
+                .help("Only sample threads whose OS thread name matches this regex (Linux only)")
+                .takes_value(true))
+            .arg(Arg::with_name("include_frames")
+                .long("include-frames")
+                .value_name("regex")
+                .takes_value(true)
+                .help("Only keep the frames matching this regex, which is matched against the function name and \
+                       filename like 'handle (/srv/app/server.py)'. Time in the frames left out goes to their caller"))
+            .arg(Arg::with_name("exclude_frames")
+                .long("exclude-frames")
+                .value_name("regex")
+                .takes_value(true)
+                .help("Leave out the frames matching this regex, which is matched against the function name and \
+                       filename like 'handle (/srv/app/server.py)'. Time in the frames left out goes to their caller"))
+            .arg(Arg::with_name("exclude_stdlib")
+                .long("exclude-stdlib")
+                .help("Leave out the frames in the python standard library"))
+            .arg(Arg::with_name("native_tid")
+                .long("native-tid")
+                .value_name("tid")
+                .hidden(!allow_native)
+                .requires("native")
+                .help("Only collect native stack traces for the thread with this OS or python thread id, getting just the \
+                       python stack of the other threads (which is much quicker). Can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("native_thread_name")
+                .long("native-thread-name")
+                .value_name("regex")
+                .hidden(!allow_native)
+                .requires("native")
+                .help("Only collect native stack traces for threads whose python or OS thread name matches this regex")
+                .takes_value(true))
             .arg(Arg::with_name("flame")
                 .short("f")
                 .long("flame")
                 .value_name("flamefile")
                 .help("Generate a flame graph and write to a file")
+                .conflicts_with("output")
+                .takes_value(true))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("filename")
+                .help("Write samples to a file, in the format given by --format. The filename can contain \
+                       {pid}, {exe}, {timestamp} and {format} placeholders. With --dump, each dump is written \
+                       to its own file, with the time it was taken added to the filename if it has no {timestamp}")
                 .takes_value(true))
+            .arg(format_arg.clone())
+            .arg(reverse_arg.clone())
+            .arg(max_depth_arg.clone())
+            .arg(trim_roots_arg.clone())
+            .arg(min_percent_arg.clone())
+            .arg(trim_start_arg.clone().conflicts_with("dump"))
+            .arg(trim_end_arg.clone().conflicts_with("dump"))
+            .arg(label_arg.clone())
+            .arg(Arg::with_name("snapshot")
+                .long("snapshot")
+                .value_name("filename")
+                .help("Where to write the snapshots taken by pressing S in the top view, in the format given by \
+                       --format (flamegraph, folded or speedscope). Can contain the same placeholders as --output, \
+                       and defaults to 'snapshot-{pid}-{timestamp}' with the extension of the format")
+                .conflicts_with_all(&["flame", "output", "dump"])
+                .takes_value(true))
+            .arg(Arg::with_name("snapshot_on_exit")
+                .long("snapshot-on-exit")
+                .help("Write a snapshot of the samples collected by the top view when it exits")
+                .conflicts_with_all(&["flame", "output", "dump"]))
             .arg(Arg::with_name("rate")
                 .short("r")
                 .long("rate")
@@ -88,35 +983,621 @@ impl Config {
                 .help("The number of samples to collect per second")
                 .default_value("100")
                 .takes_value(true))
+            .arg(Arg::with_name("jitter")
+                .long("jitter")
+                .value_name("jitter")
+                .help("How to randomize the time between samples, which avoids aliasing with periodic work \
+                      in the program. The mean sampling rate stays the same")
+                .possible_values(&Jitter::variants())
+                .case_insensitive(true)
+                .default_value("exponential")
+                .takes_value(true))
             .arg(Arg::with_name("duration")
                 .short("d")
                 .long("duration")
+                .visible_alias("for")
+                .value_name("duration")
+                .help("How long to sample for, like '30s', '5m' or '500ms' (plain numbers are seconds). \
+                       Defaults to 2 seconds when writing to a file, and no limit otherwise")
+                .takes_value(true))
+            .arg(Arg::with_name("every")
+                .long("every")
+                .value_name("interval")
+                .help("When writing to a file, record a window of --for/--duration every interval (like '5m'), \
+                       writing a separate file for each window")
+                .requires("duration")
+                .takes_value(true))
+            .arg(Arg::with_name("samples")
+                .long("samples")
+                .value_name("samples")
+                .help("Stop after collecting this many samples")
+                .takes_value(true))
+            .arg(Arg::with_name("max_overhead")
+                .long("max-overhead")
+                .value_name("percent")
+                .help("Lower the sampling rate as needed to keep the time spent sampling (while the python \
+                       process is paused) under this percentage")
+                .takes_value(true))
+            .arg(Arg::with_name("paused")
+                .long("paused")
+                .hidden(!cfg!(unix))
+                .help("Start out with sampling paused. When recording, sending SIGUSR1 to py-spy pauses sampling \
+                       and SIGUSR2 resumes it"))
+            .arg(Arg::with_name("control")
+                .long("control")
+                .value_name("file")
+                .help("Change the --gil and --idle filters while recording by writing them to this file, like \
+                       'echo gil idle > file'. Filters that aren't in the file are turned off")
+                .takes_value(true))
+            .arg(Arg::with_name("start_when")
+                .long("start-when")
+                .value_name("condition")
+                .help("Wait until a condition like 'cpu>80%' or 'rss>2G' holds for the process before recording")
+                .takes_value(true))
+            .arg(Arg::with_name("stop_when_clear")
+                .long("stop-when-clear")
+                .requires("start_when")
+                .help("Stop recording once the --start-when condition no longer holds"))
+            .arg(Arg::with_name("respawn_wait")
+                .long("respawn-wait")
                 .value_name("duration")
-                .help("The number of seconds to sample for when generating a flame graph")
-                .default_value("2")
+                .help("When the process exits, wait this long for a new process with the same command line to \
+                       start (like a supervisor restarting a worker) and carry on recording it")
                 .takes_value(true))
+            .arg(Arg::with_name("subprocesses")
+                .short("s")
+                .long("subprocesses")
+                .help("Also sample python subprocesses of the process, labelling each stack with the pid and \
+                       command line of the process it came from"))
+            .arg(Arg::with_name("merge_processes")
+                .long("merge-processes")
+                .help("Merge stacks from different processes together instead of separating them by process"))
+            .arg(Arg::with_name("per_process")
+                .long("per-process")
+                .requires("output")
+                .conflicts_with_all(&["merge_processes", "memory"])
+                .help("Write a separate file for each process being sampled, rather than one file with the stacks \
+                       of each process under its own root. Use {pid} in the output filename to say where the pid \
+                       goes, otherwise it's added before the extension"))
+            .arg(Arg::with_name("env")
+                .long("env")
+                .value_name("key=value")
+                .help("Set an environment variable for the python program being run. Can be repeated")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .requires("python_program"))
+            .arg(Arg::with_name("cwd")
+                .long("cwd")
+                .value_name("directory")
+                .help("Directory to run the python program in")
+                .takes_value(true)
+                .requires("python_program"))
+            .arg(Arg::with_name("stdin")
+                .long("stdin")
+                .value_name("file")
+                .help("File for the python program being run to read its input from, or '-' to read from py-spy's \
+                       input. By default it gets no input")
+                .takes_value(true)
+                .requires("python_program"))
+            .arg(Arg::with_name("stdout")
+                .long("stdout")
+                .value_name("file")
+                .help("File to write the output of the python program being run to, or '-' to show it. By default \
+                       it's only shown when the program fails")
+                .takes_value(true)
+                .requires("python_program"))
+            .arg(Arg::with_name("stderr")
+                .long("stderr")
+                .value_name("file")
+                .help("File to write the errors of the python program being run to, or '-' to show them. By default \
+                       they're only shown when the program fails")
+                .takes_value(true)
+                .requires("python_program"))
             .arg(Arg::with_name("python_program")
                 .help("commandline of a python program to run")
                 .multiple(true)
                 )
-            .get_matches();
+            .subcommand(SubCommand::with_name("convert")
+                .about("Converts a file recorded with '--format raw' into a different output format")
+                .arg(Arg::with_name("input")
+                    .value_name("input")
+                    .help("Raw capture file to read samples from")
+                    .required(true))
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("filename")
+                    .help("Output filename")
+                    .required(true)
+                    .takes_value(true))
+                .arg(format_arg.clone())
+                .arg(reverse_arg)
+                .arg(max_depth_arg)
+                .arg(trim_roots_arg)
+                .arg(min_percent_arg)
+                .arg(trim_start_arg)
+                .arg(trim_end_arg)
+                .arg(label_arg)
+                .arg(granularity_arg)
+                .arg(group_by_arg)
+                .arg(map_path_arg.clone())
+                .arg(Arg::with_name("function")
+                    .short("F")
+                    .long("function")
+                    .help("Aggregate samples by function name instead of by line number")))
+            .subcommand(SubCommand::with_name("symbolicate")
+                .about("Symbolicates the native frames of a file recorded with '--unsymbolicated --format raw'")
+                .arg(Arg::with_name("input")
+                    .value_name("input")
+                    .help("Raw capture file to read samples from")
+                    .required(true))
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("filename")
+                    .help("Output filename")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("symbols")
+                    .long("symbols")
+                    .value_name("directory")
+                    .help("Directory to look for the profiled binaries or their debug info in, either by path \
+                           (like a copy of the container image), by filename, or by build id in .build-id/")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true))
+                .arg(Arg::with_name("debuginfod")
+                    .long("debuginfod")
+                    .help("Download the debug info of binaries that can't be found locally from the debuginfod \
+                           servers in DEBUGINFOD_URLS (with debuginfod-find)"))
+                .arg(map_path_arg)
+                .arg(format_arg.default_value("raw")))
+            .subcommand(SubCommand::with_name("k8s")
+                .about("Profiles a container in a kubernetes pod running on this node, found with crictl. Options \
+                        for how to profile go before 'k8s', like 'py-spy --dump k8s --pod mypod'")
+                .arg(Arg::with_name("pod")
+                    .long("pod")
+                    .value_name("name")
+                    .help("Name of the pod to profile")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("container")
+                    .long("container")
+                    .short("c")
+                    .value_name("name")
+                    .help("Name of the container in the pod to profile, if it has more than one")
+                    .takes_value(true))
+                .arg(Arg::with_name("namespace")
+                    .long("namespace")
+                    .short("n")
+                    .value_name("namespace")
+                    .help("Namespace of the pod, if there are pods with the same name in other namespaces")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("monitor")
+                .about("Profiles a process continuously, for leaving running for days. Records a window every 10 minutes \
+                        (or --every) for a minute (or --for), follows the process when it restarts and keeps the \
+                        overhead under 1% of a core (or --max-overhead). Options for how to profile go before \
+                        'monitor', like 'py-spy --pid 1234 -o profiles/{pid}.svg monitor --keep 144'")
+                .arg(Arg::with_name("listen")
+                    .long("listen")
+                    .value_name("address")
+                    .help("Address to serve the latest profile (at /) and prometheus metrics (at /metrics) on, \
+                           like 127.0.0.1:9090")
+                    .takes_value(true))
+                .arg(Arg::with_name("keep")
+                    .long("keep")
+                    .value_name("files")
+                    .help("How many of the most recent files to keep, deleting older ones")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("alert")
+                .about("Samples a process continuously, and alerts when a condition holds over the last minute (or \
+                        --over), like 'py-spy --pid 1234 alert --when \"function:handle_request>30%\" --exec ./page.sh'. \
+                        Options for how to sample go before 'alert'")
+                .arg(Arg::with_name("when")
+                    .long("when")
+                    .value_name("condition")
+                    .help("Condition to alert on: 'function:<name>>N%' for a function being in more than N% of the \
+                           stacks sampled, or 'gil>N%' for the GIL being held in more than N% of samples. Can be \
+                           given more than once")
+                    .required(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true))
+                .arg(Arg::with_name("over")
+                    .long("over")
+                    .value_name("duration")
+                    .help("How long a window of samples to check the conditions over")
+                    .default_value("1m")
+                    .takes_value(true))
+                .arg(Arg::with_name("exec")
+                    .long("exec")
+                    .value_name("command")
+                    .help("Shell command to run when an alert fires or clears, with PYSPY_ALERT, PYSPY_ALERT_STATE, \
+                           PYSPY_ALERT_VALUE and PYSPY_PID set in its environment")
+                    .takes_value(true))
+                .arg(Arg::with_name("webhook")
+                    .long("webhook")
+                    .value_name("url")
+                    .help("http:// url to POST a JSON description of each alert to")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("heap")
+                .about("Summarizes the objects tracked by the garbage collector by type (python 3.7 only)")
+                .arg(Arg::with_name("pid")
+                    .short("p")
+                    .long("pid")
+                    .value_name("pid")
+                    .help("PID of a running python program to inspect")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("filename")
+                    .help("Save the summary to a file, which can be compared against with --diff later")
+                    .takes_value(true))
+                .arg(Arg::with_name("diff")
+                    .long("diff")
+                    .value_name("snapshot")
+                    .help("Show how the objects have changed since a summary saved with --output")
+                    .takes_value(true))
+                .arg(Arg::with_name("nonblocking")
+                    .long("nonblocking")
+                    .help("Don't pause the python process while walking its objects")))
+            .subcommand(SubCommand::with_name("ps")
+                .about("Lists the python processes running on this machine, with their python version, virtualenv and \
+                        whether py-spy can profile them"))
+            .subcommand(SubCommand::with_name("agent")
+                .about("Listens for profiling commands from 'py-spy remote' on other machines, and runs them on this one")
+                .arg(Arg::with_name("listen")
+                    .long("listen")
+                    .value_name("address")
+                    .help("Address to listen on, like 0.0.0.0:7777")
+                    .required(true)
+                    .takes_value(true))
+                .arg(token_file_arg.clone()))
+            .subcommand(SubCommand::with_name("remote")
+                .about("Runs py-spy on a machine running 'py-spy agent', like 'py-spy remote host:7777 -- --dump --pid 1234'. \
+                        Files that it records are copied back to the current directory")
+                .arg(Arg::with_name("address")
+                    .value_name("address")
+                    .help("Address of the agent, like host:7777")
+                    .required(true))
+                .arg(token_file_arg)
+                .arg(Arg::with_name("args")
+                    .value_name("args")
+                    .help("Arguments to run py-spy with on the remote machine")
+                    .multiple(true)
+                    .last(true)
+                    .required(true)))
+            ;
+        let mut matches = app.get_matches_from_safe_borrow(args.iter())?;
+
+        // the defaults go before the arguments given, so that they apply to the top level command
+        let default_args = defaults.args(&|name| default_overridden(&matches, name));
+        if !default_args.is_empty() {
+            info!("Defaults from config files: {:?}", default_args);
+            let args = args.iter().take(1).cloned().chain(default_args.into_iter().map(OsString::from)).chain(args.iter().skip(1).cloned());
+            matches = app.get_matches_from_safe_borrow(args)?;
+        }
         info!("Command line args: {:?}", matches);
 
+        if let Some(matches) = matches.subcommand_matches("convert") {
+            let convert_file = matches.value_of("input").map(|f| f.to_owned());
+            let filename = matches.value_of("output").map(|f| f.to_owned());
+            let format = value_t!(matches, "format", FileFormat)?;
+            let show_line_numbers = matches.occurrences_of("function") == 0 &&
+                value_t!(matches, "granularity", Granularity).unwrap_or(Granularity::line) == Granularity::line;
+            let reverse = matches.occurrences_of("reverse") > 0;
+            let group_by = value_t!(matches, "group_by", GroupBy)?;
+            let path_maps = parse_path_maps(matches)?;
+            let (max_depth, trim_roots, min_percent) = parse_trimming(matches, format)?;
+            let (trim_start, trim_end) = parse_time_range(matches, format)?;
+            let labels = parse_labels(matches)?;
+            return Ok(Config{convert_file, filename, format, show_line_numbers, reverse, group_by, path_maps,
+                             max_depth, trim_roots, min_percent, trim_start, trim_end, labels, ..Default::default()});
+        }
+
+        if let Some(matches) = matches.subcommand_matches("symbolicate") {
+            if !(allow_native && cfg!(target_os="linux")) {
+                return Err(format_err!("Symbolicating captures is only supported on Linux"));
+            }
+            let convert_file = matches.value_of("input").map(|f| f.to_owned());
+            let filename = matches.value_of("output").map(|f| f.to_owned());
+            let format = value_t!(matches, "format", FileFormat)?;
+            let symbol_paths = matches.values_of("symbols").map(|paths| paths.map(|p| p.to_owned()).collect()).unwrap_or_default();
+            let debuginfod = matches.occurrences_of("debuginfod") > 0;
+            let path_maps = parse_path_maps(matches)?;
+            return Ok(Config{convert_file, filename, format, symbolicate: true, symbol_paths, debuginfod, path_maps, show_line_numbers: true,
+                             ..Default::default()});
+        }
+
+        if let Some(matches) = matches.subcommand_matches("agent") {
+            let agent_listen = matches.value_of("listen").map(|a| a.to_owned());
+            let agent_token_file = matches.value_of("token_file").map(|f| f.to_owned());
+            return Ok(Config{agent_listen, agent_token_file, ..Default::default()});
+        }
+
+        if let Some(matches) = matches.subcommand_matches("remote") {
+            let remote = matches.value_of("address").map(|a| a.to_owned());
+            let agent_token_file = matches.value_of("token_file").map(|f| f.to_owned());
+            let remote_args = matches.values_of("args").map(|args| args.map(|a| a.to_owned()).collect()).unwrap_or_default();
+            return Ok(Config{remote, remote_args, agent_token_file, ..Default::default()});
+        }
+
+        if matches.subcommand_matches("ps").is_some() {
+            return Ok(Config{list_processes: true, ..Default::default()});
+        }
+
+        if let Some(matches) = matches.subcommand_matches("heap") {
+            let pid = Some(value_t!(matches, "pid", Pid)?);
+            let filename = matches.value_of("output").map(|f| f.to_owned());
+            let heap_baseline = matches.value_of("diff").map(|f| f.to_owned());
+            let non_blocking = matches.occurrences_of("nonblocking") > 0;
+            return Ok(Config{pid, heap: true, filename, heap_baseline, non_blocking, ..Default::default()});
+        }
+
         // what to sample
-        let pid = matches.value_of("pid").map(|p| p.parse().expect("invalid pid"));
+        let mut pids = Vec::new();
+        for pid in matches.values_of("pid").into_iter().flatten() {
+            let pid: Pid = pid.trim().parse().map_err(|_| format_err!("Invalid pid '{}'", pid))?;
+            if !pids.contains(&pid) {
+                pids.push(pid);
+            }
+        }
+        let pid = pids.first().cloned();
+        let other_pids: Vec<Pid> = pids.iter().skip(1).cloned().collect();
+        let pod_matches = matches.subcommand_matches("k8s");
+        let pod = pod_matches.and_then(|m| m.value_of("pod")).map(|p| p.to_owned());
+        let pod_container = pod_matches.and_then(|m| m.value_of("container")).map(|c| c.to_owned());
+        let pod_namespace = pod_matches.and_then(|m| m.value_of("namespace")).map(|n| n.to_owned());
+        let name = matches.value_of("name").map(|name| name.to_owned());
+        let cmdline = matches.value_of("cmdline").map(|text| text.to_owned());
+        let port = match matches.value_of("port") {
+            Some(_) => Some(value_t!(matches, "port", u16)?),
+            None => None
+        };
+        let include_new = matches.occurrences_of("include_new") > 0;
+        let auto = match matches.value_of("auto") {
+            Some(_) => Some(value_t!(matches, "auto", Server)?),
+            None => None
+        };
         let python_program = matches.values_of("python_program").map(|vals| {
             vals.map(|v| v.to_owned()).collect()
         });
+        let program_env = parse_env(&matches)?;
+        let program_cwd = matches.value_of("cwd").map(|cwd| cwd.to_owned());
+        let program_stdin = matches.value_of("stdin").map(|stdin| stdin.to_owned());
+        let program_stdout = matches.value_of("stdout").map(|stdout| stdout.to_owned());
+        let program_stderr = matches.value_of("stderr").map(|stderr| stderr.to_owned());
 
         // what to generate
-        let flame_file_name = matches.value_of("flame").map(|f| f.to_owned());
+        let (filename, format) = match matches.value_of("flame") {
+            Some(flame) => (Some(flame.to_owned()), FileFormat::flamegraph),
+            None => (matches.value_of("output").map(|f| f.to_owned()), value_t!(matches, "format", FileFormat)?)
+        };
         let dump = matches.occurrences_of("dump") > 0;
+        let dump_json = matches.occurrences_of("json") > 0;
+        let dump_interval = match (matches.value_of("interval"), matches.occurrences_of("watch") > 0) {
+            (Some(interval), _) => Some(parse_duration(interval)?),
+            (None, true) => Some(Duration::from_secs(1)),
+            (None, false) => None
+        };
+        let dump_count = match matches.value_of("count") {
+            Some(_) => Some(value_t!(matches, "count", u64)?),
+            None => None
+        };
+        let dump_changes_only = matches.occurrences_of("changes_only") > 0;
+        let native_threads = matches.occurrences_of("native_threads") > 0;
+        if native_threads && !allow_native {
+            return Err(format_err!("Native stack traces are not yet supported on this OS"));
+        }
+        if dump_interval.is_none() && (dump_count.is_some() || dump_changes_only) {
+            return Err(format_err!("--count and --changes-only are for repeated dumps, with --interval or --watch"));
+        }
+        let snapshot_filename = matches.value_of("snapshot").map(|f| f.to_owned());
+        let snapshot_on_exit = matches.occurrences_of("snapshot_on_exit") > 0;
+        if (snapshot_filename.is_some() || snapshot_on_exit) &&
+            !(format == FileFormat::flamegraph || format == FileFormat::folded || format == FileFormat::speedscope) {
+            return Err(format_err!("Snapshots from the top view can only be written as flamegraph, folded or speedscope"));
+        }
+        let core_file = matches.value_of("core").map(|f| f.to_owned());
+        let core_exe = matches.value_of("core_exe").map(|f| f.to_owned());
 
         // how to sample
-        let sampling_rate = value_t!(matches, "rate", u64)?;
-        let duration = value_t!(matches, "duration", u64)?;
-        let show_line_numbers = matches.occurrences_of("function") == 0;
-        let non_blocking = matches.occurrences_of("nonblocking") > 0;
+        let memory = matches.occurrences_of("memory") > 0;
+        if memory && format != FileFormat::flamegraph && format != FileFormat::folded {
+            return Err(format_err!("--memory only supports the flamegraph and folded formats"));
+        }
+        if memory && !other_pids.is_empty() {
+            return Err(format_err!("--memory can only record the allocations of one process"));
+        }
+        // reading every allocation is a lot slower than getting stack traces, so take fewer snapshots by default
+        let sampling_rate = if memory && matches.occurrences_of("rate") == 0 {
+            MEMORY_SAMPLING_RATE
+        } else {
+            value_t!(matches, "rate", u64)?
+        };
+        let jitter = value_t!(matches, "jitter", Jitter)?;
+        let duration = match matches.value_of("duration") {
+            Some(duration) => Some(parse_duration(duration)?),
+            None => None
+        };
+        let samples = match matches.value_of("samples") {
+            Some(_) => Some(value_t!(matches, "samples", u64)?),
+            None => None
+        };
+        let every = match matches.value_of("every") {
+            Some(every) => Some(parse_duration(every)?),
+            None => None
+        };
+        let monitor_matches = matches.subcommand_matches("monitor");
+        let monitor = monitor_matches.is_some();
+        let monitor_listen = monitor_matches.and_then(|m| m.value_of("listen")).map(|a| a.to_owned())
+            .or_else(|| if monitor { defaults.get("monitor.listen") } else { None });
+        let monitor_keep = match monitor_matches {
+            Some(monitor_matches) if monitor_matches.value_of("keep").is_some() => Some(value_t!(monitor_matches, "keep", usize)?),
+            Some(_) => match defaults.get("monitor.keep") {
+                Some(keep) => Some(keep.parse().map_err(|_| format_err!("Invalid value '{}' for 'keep' in [monitor] of the config file", keep))?),
+                None => None
+            },
+            _ => None
+        };
+        let alert_matches = matches.subcommand_matches("alert");
+        let alert_conditions = match alert_matches.and_then(|m| m.values_of("when")) {
+            Some(conditions) => conditions.map(Condition::parse).collect::<Result<Vec<Condition>, _>>()?,
+            None => Vec::new()
+        };
+        let alert_window = match alert_matches.and_then(|m| m.value_of("over")) {
+            Some(window) => parse_duration(window)?,
+            None => Duration::from_secs(60)
+        };
+        let alert_exec = alert_matches.and_then(|m| m.value_of("exec")).map(|c| c.to_owned());
+        let alert_webhook = alert_matches.and_then(|m| m.value_of("webhook")).map(|u| u.to_owned());
+        if alert_webhook.as_ref().is_some_and(|url| !url.starts_with("http://")) {
+            return Err(format_err!("Only http:// webhooks are supported"));
+        }
+        if alert_matches.is_some() && (dump || python_program.is_some() || filename.is_some()) {
+            return Err(format_err!("'py-spy alert' samples a running process, and can't be used with --dump, --output \
+                                    or to run a program"));
+        }
+        if monitor && (dump || python_program.is_some()) {
+            return Err(format_err!("'py-spy monitor' profiles a running process, and can't be used with --dump or to run a program"));
+        }
+        // monitoring records a minute every ten minutes by default, to a file named after the process
+        let (every, duration) = match (monitor, every, duration) {
+            (true, None, None) => (Some(Duration::from_secs(600)), Some(Duration::from_secs(60))),
+            (true, every, None) => (every, Some(Duration::from_secs(60))),
+            (true, None, Some(duration)) => (Some(duration * 10), Some(duration)),
+            (_, every, duration) => (every, duration)
+        };
+        let filename = match (monitor, filename) {
+            (true, None) => Some("profile-{pid}.{format}".to_owned()),
+            (_, filename) => filename
+        };
+        if let (Some(every), Some(duration)) = (every, duration) {
+            if every <= duration {
+                return Err(format_err!("--every must be longer than the --for/--duration of each window"));
+            }
+        }
+        let max_overhead = match matches.value_of("max_overhead") {
+            Some(_) => Some(value_t!(matches, "max_overhead", f64)?),
+            None if monitor => Some(1.0),
+            None => None
+        };
+        if let Some(overhead) = max_overhead {
+            if overhead <= 0.0 || overhead > 100.0 {
+                return Err(format_err!("--max-overhead must be between 0 and 100"));
+            }
+        }
+        let show_line_numbers = matches.occurrences_of("function") == 0 &&
+            value_t!(matches, "granularity", Granularity).unwrap_or(Granularity::line) == Granularity::line;
+        let reverse = matches.occurrences_of("reverse") > 0;
+        let group_by = value_t!(matches, "group_by", GroupBy)?;
+        let path_maps = parse_path_maps(&matches)?;
+        let dirty_reads = matches.occurrences_of("dirty_reads") > 0;
+        let freeze = matches.occurrences_of("freeze") > 0;
+        let sudo = matches.occurrences_of("sudo") > 0;
+        let non_blocking = matches.occurrences_of("nonblocking") > 0 || dirty_reads;
+        let cpu_time = matches.occurrences_of("cpu_time") > 0;
+        let gil_only = matches.occurrences_of("gil") > 0;
+        let qualified_names = matches.occurrences_of("bare_names") == 0;
+        let mut gil_wait = matches.occurrences_of("gil_wait") > 0;
+        let lock_contention = matches.occurrences_of("locks") > 0;
+        let kernel = matches.occurrences_of("kernel") > 0;
+        let resources = matches.occurrences_of("resources") > 0;
+        let preview = matches.occurrences_of("preview") > 0;
+        let append = matches.occurrences_of("append") > 0;
+        let status_json = matches.occurrences_of("status_json") > 0;
+        let assertions = match matches.values_of("assert") {
+            Some(assertions) => assertions.map(Assertion::parse).collect::<Result<Vec<Assertion>, Error>>()?,
+            None => Vec::new()
+        };
+        let assert_report = matches.value_of("assert_report").map(|f| f.to_owned());
+        if append && format != FileFormat::raw {
+            return Err(format_err!("--append only works with raw captures, from '--format raw'"));
+        }
+        let mut debuginfod = matches.occurrences_of("debuginfod") > 0;
+        let unsymbolicated = matches.occurrences_of("unsymbolicated") > 0;
+        let asyncio = matches.occurrences_of("asyncio") > 0;
+        let gevent = matches.occurrences_of("gevent") > 0;
+        let dump_locals = matches.occurrences_of("locals") > 0;
+        let group_by_interpreter = matches.occurrences_of("interpreters") > 0;
+        let capture_exceptions = matches.occurrences_of("exceptions") > 0;
+        let capture_args = match matches.value_of("capture_args") {
+            Some(_) => Some(value_t!(matches, "capture_args", usize)?),
+            None => None
+        };
+        let include_idle = matches.occurrences_of("idle") > 0;
+        let idle_rules = match matches.values_of("idle_rule") {
+            Some(rules) => rules.map(IdleRule::parse).collect::<Result<Vec<IdleRule>, Error>>()?,
+            None => Vec::new()
+        };
+        let removed_idle_rules = match matches.values_of("no_idle_rule") {
+            Some(rules) => rules.map(IdleRule::parse).collect::<Result<Vec<IdleRule>, Error>>()?,
+            None => Vec::new()
+        };
+        let default_idle_rules = idle_rules::default_rules();
+        if let Some(rule) = removed_idle_rules.iter().find(|rule| !default_idle_rules.contains(rule)) {
+            let defaults: Vec<String> = default_idle_rules.iter().map(|rule| rule.to_string()).collect();
+            return Err(format_err!("--no-idle-rule '{}' isn't one of the built-in rules: {}", rule, defaults.join(", ")));
+        }
+        let start_paused = matches.occurrences_of("paused") > 0;
+        let control_file = matches.value_of("control").map(|path| path.to_owned());
+        let thread_ids = match matches.values_of("tid") {
+            Some(tids) => tids.map(parse_thread_id).collect::<Result<Vec<u64>, Error>>()?,
+            None => Vec::new()
+        };
+        let thread_name_regex = matches.value_of("thread_name_regex").map(|regex| regex.to_owned());
+        if let Some(regex) = thread_name_regex.as_ref() {
+            if let Err(e) = regex::Regex::new(regex) {
+                return Err(format_err!("Invalid --thread-name-regex '{}': {}", regex, e));
+            }
+        }
+        let include_frames = matches.value_of("include_frames").map(|regex| regex.to_owned());
+        let exclude_frames = matches.value_of("exclude_frames").map(|regex| regex.to_owned());
+        for (option, regex) in [("--include-frames", &include_frames), ("--exclude-frames", &exclude_frames)] {
+            if let Some(regex) = regex.as_ref() {
+                if let Err(e) = regex::Regex::new(regex) {
+                    return Err(format_err!("Invalid {} '{}': {}", option, regex, e));
+                }
+            }
+        }
+        let exclude_stdlib = matches.occurrences_of("exclude_stdlib") > 0;
+        let (max_depth, trim_roots, min_percent) = parse_trimming(&matches, format)?;
+        let (trim_start, trim_end) = parse_time_range(&matches, format)?;
+        let labels = parse_labels(&matches)?;
+        let native_thread_ids = match matches.values_of("native_tid") {
+            Some(tids) => tids.map(parse_thread_id).collect::<Result<Vec<u64>, Error>>()?,
+            None => Vec::new()
+        };
+        let native_thread_name_regex = matches.value_of("native_thread_name").map(|regex| regex.to_owned());
+        if let Some(regex) = native_thread_name_regex.as_ref() {
+            if let Err(e) = regex::Regex::new(regex) {
+                return Err(format_err!("Invalid --native-thread-name '{}': {}", regex, e));
+            }
+        }
+        let start_when = match matches.value_of("start_when") {
+            Some(condition) => Some(Trigger::parse(condition)?),
+            None => None
+        };
+        let stop_when_clear = matches.occurrences_of("stop_when_clear") > 0;
+        let max_pause = match matches.value_of("max_pause") {
+            Some(pause) => Some(parse_duration(pause)?),
+            None => None
+        };
+        let unwind_workers = match matches.value_of("parallel") {
+            Some(_) => value_t!(matches, "parallel", usize)?.max(1),
+            None => 1
+        };
+        let respawn_wait = match matches.value_of("respawn_wait") {
+            Some(wait) => Some(parse_duration(wait)?),
+            None => None
+        };
+        // the workers of servers are subprocesses of the master
+        let subprocesses = matches.occurrences_of("subprocesses") > 0 || auto.is_some();
+        let merge_processes = matches.occurrences_of("merge_processes") > 0;
+        let per_process = matches.occurrences_of("per_process") > 0;
         let mut native = matches.occurrences_of("native") > 0;
 
         if !allow_native && native {
@@ -124,13 +1605,454 @@ impl Config {
             native = false;
         }
 
+        if !allow_native && gil_wait {
+            error!("Detecting threads waiting on the GIL is not yet supported on this OS. Disabling");
+            gil_wait = false;
+        }
+
+        if !allow_native && lock_contention {
+            return Err(format_err!("Profiling lock contention is not yet supported on this OS"));
+        }
+
+        if debuginfod && !(allow_native && cfg!(target_os="linux")) {
+            error!("Downloading debug info with debuginfod is only supported on Linux. Disabling");
+            debuginfod = false;
+        }
+
+        if unsymbolicated && !(allow_native && cfg!(target_os="linux")) {
+            return Err(format_err!("Recording unsymbolicated native frames is only supported on Linux"));
+        }
+
+        if unsymbolicated && format != FileFormat::raw {
+            return Err(format_err!("--unsymbolicated needs '--format raw', so that the capture can be symbolicated later"));
+        }
+
+        if resources && !cfg!(target_os="linux") {
+            return Err(format_err!("Recording resource usage is only supported on Linux"));
+        }
+        if kernel && !cfg!(target_os="linux") {
+            return Err(format_err!("Kernel stacks are only supported on Linux"));
+        }
+        // sampling is resumed with SIGUSR2, which other platforms don't have
+        if start_paused && !cfg!(unix) {
+            return Err(format_err!("--paused is only supported on Linux, OSX and FreeBSD"));
+        }
+
+        if freeze && native && cfg!(target_os="linux") {
+            return Err(format_err!("Native stack traces need ptrace to stop each thread, and can't be used with --freeze"));
+        }
+
         if native && non_blocking {
             error!("Can't get native stack traces with the --nonblocking option. Disabling native.");
             native = false;
         }
 
-        Ok(Config{pid, python_program, dump, flame_file_name,
-                  sampling_rate, duration,
-                  show_line_numbers, non_blocking, native})
+        Ok(Config{
+            pid,
+            other_pids,
+            name,
+            include_new,
+            auto,
+            python_program,
+            program_env,
+            program_cwd,
+            program_stdin,
+            program_stdout,
+            program_stderr,
+            dump,
+            dump_json,
+            dump_interval,
+            dump_count,
+            dump_changes_only,
+            native_threads,
+            filename,
+            format,
+            snapshot_filename,
+            snapshot_on_exit,
+            jitter,
+            convert_file: None,
+            sampling_rate,
+            duration,
+            samples,
+            every,
+            monitor,
+            monitor_listen,
+            monitor_keep,
+            alert_conditions,
+            alert_window,
+            alert_exec,
+            alert_webhook,
+            max_overhead,
+            start_paused,
+            control_file,
+            start_when,
+            stop_when_clear,
+            max_pause,
+            unwind_workers,
+            respawn_wait,
+            subprocesses,
+            merge_processes,
+            per_process,
+            memory,
+            qualified_names,
+            gil_wait,
+            lock_contention,
+            kernel,
+            resources,
+            preview,
+            append,
+            status_json,
+            assertions,
+            assert_report,
+            include_frames,
+            exclude_frames,
+            exclude_stdlib,
+            max_depth,
+            trim_roots,
+            min_percent,
+            idle_rules,
+            removed_idle_rules,
+            trim_start,
+            trim_end,
+            labels,
+            debuginfod,
+            unsymbolicated,
+            symbolicate: false,
+            symbol_paths: Vec::new(),
+            path_maps,
+            cmdline,
+            port,
+            pod,
+            pod_container,
+            pod_namespace,
+            core_file,
+            core_exe,
+            agent_listen: None,
+            agent_token_file: None,
+            remote: None,
+            remote_args: Vec::new(),
+            heap: false,
+            heap_baseline: None,
+            list_processes: false,
+            show_line_numbers,
+            reverse,
+            group_by,
+            gil_only,
+            include_idle,
+            non_blocking,
+            dirty_reads,
+            freeze,
+            sudo,
+            cpu_time,
+            thread_ids,
+            thread_name_regex,
+            native_thread_ids,
+            native_thread_name_regex,
+            asyncio,
+            gevent,
+            dump_locals,
+            capture_args,
+            capture_exceptions,
+            group_by_interpreter,
+            native,
+        })
+    }
+}
+
+// whether the command line overrides the default for an option from a config file. Recording to the
+// default output only makes sense when py-spy would otherwise record or show the top view
+fn default_overridden(matches: &ArgMatches, name: &str) -> bool {
+    let given = |name: &str| matches.occurrences_of(name) > 0;
+    match name {
+        "output" => given("output") || given("flame") || given("dump") || given("core") ||
+            matches.subcommand_name().is_some_and(|command| command != "monitor" && command != "k8s"),
+        "function" | "granularity" => given("function") || given("granularity"),
+        name => given(name)
+    }
+}
+
+/// Parses a thread id, either in decimal or as 0x prefixed hex (which is how py-spy displays them)
+fn parse_thread_id(value: &str) -> Result<u64, Error> {
+    let parsed = if value.starts_with("0x") || value.starts_with("0X") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    };
+    parsed.map_err(|_| format_err!("Invalid thread id '{}'", value))
+}
+
+/// Parses the --map-path rules, which are split at the last '=' like rustc's --remap-path-prefix
+fn parse_path_maps(matches: &ArgMatches) -> Result<Vec<(String, String)>, Error> {
+    let rules = match matches.values_of("map_path") {
+        Some(rules) => rules,
+        None => return Ok(Vec::new())
+    };
+    rules.map(|rule| match rule.rfind('=') {
+        Some(split) if split > 0 => Ok((rule[..split].to_owned(), rule[split + 1..].to_owned())),
+        _ => Err(format_err!("Invalid --map-path '{}', expected 'from=to'", rule))
+    }).collect()
+}
+
+/// --max-depth, --trim-roots and --min-percent
+type Trimming = (Option<usize>, Option<String>, Option<f64>);
+
+// the trimming options, which recording and 'py-spy convert' share
+fn parse_trimming(matches: &ArgMatches, format: FileFormat) -> Result<Trimming, Error> {
+    let max_depth = match matches.value_of("max_depth") {
+        Some(_) => match value_t!(matches, "max_depth", usize)? {
+            0 => return Err(format_err!("--max-depth must be at least 1")),
+            depth => Some(depth)
+        },
+        None => None
+    };
+    let trim_roots = matches.value_of("trim_roots").map(|regex| regex.to_owned());
+    if let Some(regex) = trim_roots.as_ref() {
+        if let Err(e) = regex::Regex::new(regex) {
+            return Err(format_err!("Invalid --trim-roots '{}': {}", regex, e));
+        }
+    }
+    let min_percent = match matches.value_of("min_percent") {
+        Some(_) => Some(value_t!(matches, "min_percent", f64)?),
+        None => None
+    };
+    if let Some(min_percent) = min_percent {
+        if !(0.0..100.0).contains(&min_percent) {
+            return Err(format_err!("--min-percent must be between 0 and 100"));
+        }
+        if format != FileFormat::flamegraph && format != FileFormat::folded {
+            return Err(format_err!("--min-percent only works with the flamegraph and folded formats"));
+        }
+    }
+    Ok((max_depth, trim_roots, min_percent))
+}
+
+// --trim-start and --trim-end, which are either durations or times
+fn parse_time_range(matches: &ArgMatches, format: FileFormat) -> Result<(Option<TimeBound>, Option<TimeBound>), Error> {
+    let parse = |name: &str| -> Result<Option<TimeBound>, Error> {
+        let value = match matches.value_of(name) {
+            Some(value) => value,
+            None => return Ok(None)
+        };
+        if let Ok(offset) = parse_duration(value) {
+            return Ok(Some(TimeBound::Offset(offset)));
+        }
+        TimeBound::parse_time(value).map(Some).ok_or_else(|| {
+            format_err!("Invalid --{} '{}': expected a duration like '10s' or a UTC time like '2019-06-01 12:00:10'",
+                        name.replace('_', "-"), value)
+        })
+    };
+    let (trim_start, trim_end) = (parse("trim_start")?, parse("trim_end")?);
+    if (trim_start.is_some() || trim_end.is_some()) && format == FileFormat::raw {
+        return Err(format_err!("Raw captures keep every sample, so trim them with --trim-start and --trim-end when \
+                                converting them instead"));
+    }
+    Ok((trim_start, trim_end))
+}
+
+fn parse_labels(matches: &ArgMatches) -> Result<Vec<(String, String)>, Error> {
+    let mut labels: Vec<(String, String)> = Vec::new();
+    for label in matches.values_of("label").into_iter().flatten() {
+        let (key, value) = match label.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim().to_owned(), value.trim().to_owned()),
+            _ => return Err(format_err!("Invalid --label '{}', expected 'key=value'", label))
+        };
+        // a label that's given more than once keeps its last value
+        labels.retain(|(existing, _)| *existing != key);
+        labels.push((key, value));
+    }
+    Ok(labels)
+}
+
+fn parse_env(matches: &ArgMatches) -> Result<Vec<(String, String)>, Error> {
+    matches.values_of("env").into_iter().flatten().map(|variable| match variable.find('=') {
+        Some(split) if split > 0 => Ok((variable[..split].to_owned(), variable[split + 1..].to_owned())),
+        _ => Err(format_err!("Invalid --env '{}', expected 'key=value'", variable))
+    }).collect()
+}
+
+/// Parses a duration like '30s', '5m', '1h' or '500ms'. Numbers without a unit are in seconds
+fn parse_duration(value: &str) -> Result<Duration, Error> {
+    let value = value.trim();
+    let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format_err!("Invalid duration '{}'", value))?;
+    let seconds = match unit.trim() {
+        "" | "s" => number,
+        "ms" => number / 1000.0,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format_err!("Invalid duration '{}': unknown unit '{}'", value, unit))
+    };
+    Ok(Duration::from_nanos((seconds * 1e9) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("1.5m").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse_duration("10 days").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
+    #[test]
+    fn test_parse_thread_id() {
+        assert_eq!(parse_thread_id("1234").unwrap(), 1234);
+        assert_eq!(parse_thread_id("0x7F1A").unwrap(), 0x7f1a);
+        assert!(parse_thread_id("main").is_err());
+    }
+
+    #[test]
+    fn test_pid_list() {
+        let config = Config::from_args(["py-spy", "--dump", "--pid", "12,34", "-p", "56", "--pid", "12"]).unwrap();
+        assert_eq!(config.pid, Some(12));
+        assert_eq!(config.other_pids, vec![34, 56]);
+        assert!(Config::from_args(["py-spy", "--dump", "--pid", "12,abc"]).is_err());
+    }
+
+    #[test]
+    fn test_program_env() {
+        let config = Config::from_args(["py-spy", "--env", "A=1", "--env", "URL=http://x/?a=b", "--", "python", "app.py"]).unwrap();
+        assert_eq!(config.program_env, vec![("A".to_owned(), "1".to_owned()), ("URL".to_owned(), "http://x/?a=b".to_owned())]);
+        assert!(Config::from_args(["py-spy", "--env", "=1", "--", "python", "app.py"]).is_err());
+    }
+
+    #[test]
+    fn test_repeated_dumps() {
+        let config = Config::from_args(["py-spy", "--dump", "--pid", "1", "--interval", "5s", "--count", "12"]).unwrap();
+        assert_eq!(config.dump_interval, Some(Duration::from_secs(5)));
+        assert_eq!(config.dump_count, Some(12));
+        let config = Config::from_args(["py-spy", "--dump", "--pid", "1", "--watch", "--changes-only"]).unwrap();
+        assert_eq!(config.dump_interval, Some(Duration::from_secs(1)));
+        assert!(config.dump_changes_only);
+        assert!(Config::from_args(["py-spy", "--dump", "--pid", "1", "--count", "12"]).is_err());
+    }
+
+    #[test]
+    fn test_append() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "-o", "app.rawcapture", "--format", "raw", "--append"]).unwrap();
+        assert!(config.append);
+        assert!(Config::from_args(["py-spy", "--pid", "1", "-o", "profile.svg", "--append"]).is_err());
+    }
+
+    #[test]
+    fn test_assert() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "-o", "profile.svg", "--assert", "self_pct(app.slow) < 5",
+                                        "--assert", "samples > 100", "--assert-report", "report.json"]).unwrap();
+        assert_eq!(config.assertions.len(), 2);
+        assert_eq!(config.assert_report.as_deref(), Some("report.json"));
+        assert!(Config::from_args(["py-spy", "--pid", "1", "-o", "profile.svg", "--assert", "slow < 5"]).is_err());
+        assert!(Config::from_args(["py-spy", "--pid", "1", "--dump", "--assert", "samples > 1"]).is_err());
+    }
+
+    #[test]
+    fn test_frame_filters() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "--exclude-stdlib", "--include-frames", "/srv/app/"]).unwrap();
+        assert!(config.exclude_stdlib);
+        assert_eq!(config.include_frames.as_deref(), Some("/srv/app/"));
+        assert!(Config::from_args(["py-spy", "--pid", "1", "--dump", "--exclude-frames", "(unclosed"]).is_err());
+    }
+
+    #[test]
+    fn test_trimming() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "-o", "profile.svg", "--max-depth", "20", "--trim-roots", "runpy",
+                                        "--min-percent", "0.5"]).unwrap();
+        assert_eq!(config.max_depth, Some(20));
+        assert_eq!(config.trim_roots.as_deref(), Some("runpy"));
+        assert_eq!(config.min_percent, Some(0.5));
+        let config = Config::from_args(["py-spy", "convert", "profile.raw", "-o", "profile.txt", "--format", "folded", "--max-depth", "5"]).unwrap();
+        assert_eq!(config.max_depth, Some(5));
+        assert!(Config::from_args(["py-spy", "--pid", "1", "-o", "profile.json", "--format", "speedscope", "--min-percent", "1"]).is_err());
+        assert!(Config::from_args(["py-spy", "--pid", "1", "-o", "profile.svg", "--max-depth", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_idle_rules() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "--idle-rule", "wait_for_job@jobs/worker.py",
+                                        "--no-idle-rule", "poll@tornado"]).unwrap();
+        assert_eq!(config.idle_rules, vec![IdleRule::parse("wait_for_job@jobs/worker.py").unwrap()]);
+        assert_eq!(config.removed_idle_rules.len(), 1);
+        assert!(Config::from_args(["py-spy", "--pid", "1", "--no-idle-rule", "wait_for_job"]).is_err());
+    }
+
+    #[test]
+    fn test_trim_time() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "-o", "profile.svg", "--trim-start", "10s", "--trim-end", "12:05:00"]).unwrap();
+        assert_eq!(config.trim_start, Some(TimeBound::Offset(Duration::from_secs(10))));
+        assert_eq!(config.trim_end, Some(TimeBound::TimeOfDay(12 * 3600 + 5 * 60)));
+        let config = Config::from_args(["py-spy", "convert", "profile.raw", "-o", "profile.svg", "--trim-end", "5s"]).unwrap();
+        assert_eq!(config.trim_end, Some(TimeBound::Offset(Duration::from_secs(5))));
+        assert!(Config::from_args(["py-spy", "--pid", "1", "-o", "profile.raw", "--format", "raw", "--trim-start", "10s"]).is_err());
+        assert!(Config::from_args(["py-spy", "--pid", "1", "-o", "profile.svg", "--trim-start", "soon"]).is_err());
+    }
+
+    #[test]
+    fn test_labels() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "-o", "profile.svg", "--label", "service=web",
+                                        "--label", "version = 1.2", "--label", "service=api"]).unwrap();
+        assert_eq!(config.labels, vec![("version".to_owned(), "1.2".to_owned()), ("service".to_owned(), "api".to_owned())]);
+        let config = Config::from_args(["py-spy", "convert", "profile.raw", "-o", "profile.svg", "--label", "experiment=42"]).unwrap();
+        assert_eq!(config.labels, vec![("experiment".to_owned(), "42".to_owned())]);
+        assert!(Config::from_args(["py-spy", "--pid", "1", "--label", "service"]).is_err());
+        assert!(Config::from_args(["py-spy", "--pid", "1", "--label", "=web"]).is_err());
+    }
+
+    #[test]
+    fn test_monitor() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "monitor", "--keep", "144"]).unwrap();
+        assert!(config.monitor);
+        assert_eq!(config.monitor_keep, Some(144));
+        assert_eq!(config.every, Some(Duration::from_secs(600)));
+        assert_eq!(config.duration, Some(Duration::from_secs(60)));
+        assert_eq!(config.max_overhead, Some(1.0));
+        assert_eq!(config.filename.as_deref(), Some("profile-{pid}.{format}"));
+        let config = Config::from_args(["py-spy", "--pid", "1", "-o", "app.svg", "--for", "30s", "monitor"]).unwrap();
+        assert_eq!(config.every, Some(Duration::from_secs(300)));
+        assert_eq!(config.filename.as_deref(), Some("app.svg"));
+        assert!(Config::from_args(["py-spy", "--pid", "1", "--dump", "monitor"]).is_err());
+    }
+
+    #[test]
+    fn test_alert() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "alert", "--when", "function:handle>30%", "--when", "gil>90%",
+                                        "--over", "5m"]).unwrap();
+        assert_eq!(config.alert_conditions.len(), 2);
+        assert_eq!(config.alert_window, Duration::from_secs(300));
+        assert!(Config::from_args(["py-spy", "--pid", "1", "alert", "--when", "cpu>30%"]).is_err());
+        assert!(Config::from_args(["py-spy", "--pid", "1", "alert", "--when", "gil>90%", "--webhook", "https://example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_defaults() {
+        use crate::config_file::Value;
+        let mut defaults = Defaults::default();
+        for (key, value) in [("rate", Value::Number("250".to_owned())), ("output", Value::String("profile.svg".to_owned())),
+                                ("idle", Value::Bool(true)), ("monitor.keep", Value::Number("24".to_owned()))] {
+            defaults.values.insert(key.to_owned(), (value, std::path::PathBuf::from("py-spy.toml")));
+        }
+        let config = Config::from_args_with_defaults(["py-spy", "--pid", "1", "--rate", "10"], &defaults).unwrap();
+        assert_eq!(config.sampling_rate, 10);
+        assert!(config.include_idle);
+        assert_eq!(config.filename.as_deref(), Some("profile.svg"));
+        let config = Config::from_args_with_defaults(["py-spy", "--pid", "1", "--dump"], &defaults).unwrap();
+        assert!(config.dump);
+        assert_eq!(config.filename, None);
+        let config = Config::from_args_with_defaults(["py-spy", "--pid", "1", "monitor"], &defaults).unwrap();
+        assert_eq!(config.sampling_rate, 250);
+        assert_eq!(config.monitor_keep, Some(24));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let config = Config::from_args(["py-spy", "--pid", "1", "--snapshot-on-exit", "--format", "speedscope"]).unwrap();
+        assert!(config.snapshot_on_exit);
+        assert!(Config::from_args(["py-spy", "--pid", "1", "--snapshot", "top.txt", "--format", "raw"]).is_err());
+        assert!(Config::from_args(["py-spy", "--pid", "1", "--snapshot-on-exit", "-o", "profile.svg"]).is_err());
     }
 }
@@ -0,0 +1,192 @@
+// Builds the Config for using py-spy as a library, checking that the options make sense together
+// in the way that the command line parser does for the application
+use std::time::Duration;
+
+use regex::Regex;
+
+use crate::config::{Config, FileFormat};
+use crate::error::Error;
+
+/// Builds a [`Config`](struct.Config.html) for sampling a python process from rust:
+///
+/// ```rust,no_run
+/// # fn build() -> Result<py_spy::Config, py_spy::Error> {
+/// let config = py_spy::ConfigBuilder::new()
+///     .sampling_rate(200)
+///     .native(true)
+///     .gil_only(true)
+///     .build()?;
+/// # Ok(config)
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+}
+
+impl ConfigBuilder {
+    /// Starts from the default options, which sample every thread 100 times a second
+    pub fn new() -> ConfigBuilder {
+        ConfigBuilder{config: Config::default()}
+    }
+
+    /// How many times a second to sample, with Profiler
+    pub fn sampling_rate(mut self, rate: u64) -> ConfigBuilder {
+        self.config.sampling_rate = rate;
+        self
+    }
+
+    /// The format that Profiler writes the profile out in
+    pub fn format(mut self, format: FileFormat) -> ConfigBuilder {
+        self.config.format = format;
+        self
+    }
+
+    /// Don't stop the python process when taking samples, see `Config::non_blocking`
+    pub fn non_blocking(mut self, non_blocking: bool) -> ConfigBuilder {
+        self.config.non_blocking = non_blocking;
+        self
+    }
+
+    /// Also get the native stack traces of extensions, which can't be done with non_blocking
+    pub fn native(mut self, native: bool) -> ConfigBuilder {
+        self.config.native = native;
+        self
+    }
+
+    /// The longest that the process can be paused for while taking a single sample
+    pub fn max_pause(mut self, max_pause: Duration) -> ConfigBuilder {
+        self.config.max_pause = Some(max_pause);
+        self
+    }
+
+    /// How many threads to unwind python stacks with, which has to be at least 1
+    pub fn unwind_workers(mut self, workers: usize) -> ConfigBuilder {
+        self.config.unwind_workers = workers;
+        self
+    }
+
+    /// Only count threads as active when they've used CPU time since the previous sample
+    pub fn cpu_time(mut self, cpu_time: bool) -> ConfigBuilder {
+        self.config.cpu_time = cpu_time;
+        self
+    }
+
+    /// Only record the threads that are holding the GIL, with Profiler
+    pub fn gil_only(mut self, gil_only: bool) -> ConfigBuilder {
+        self.config.gil_only = gil_only;
+        self
+    }
+
+    /// Also record the threads that are idle, with Profiler
+    pub fn include_idle(mut self, include_idle: bool) -> ConfigBuilder {
+        self.config.include_idle = include_idle;
+        self
+    }
+
+    /// Also sample the python subprocesses of the process
+    pub fn subprocesses(mut self, subprocesses: bool) -> ConfigBuilder {
+        self.config.subprocesses = subprocesses;
+        self
+    }
+
+    /// Record the memory allocated by each stack with tracemalloc, rather than sampling CPU usage
+    pub fn memory(mut self, memory: bool) -> ConfigBuilder {
+        self.config.memory = memory;
+        self
+    }
+
+    /// Only sample the threads with these OS or python thread ids
+    pub fn thread_ids(mut self, thread_ids: &[u64]) -> ConfigBuilder {
+        self.config.thread_ids = thread_ids.to_vec();
+        self
+    }
+
+    /// Only sample the threads whose OS thread name matches this regex
+    pub fn thread_name_regex(mut self, regex: &str) -> ConfigBuilder {
+        self.config.thread_name_regex = Some(regex.to_owned());
+        self
+    }
+
+    /// Also get the stack traces of asyncio tasks waiting on an event loop
+    pub fn asyncio(mut self, asyncio: bool) -> ConfigBuilder {
+        self.config.asyncio = asyncio;
+        self
+    }
+
+    /// Also get the stack traces of greenlets that are switched out
+    pub fn gevent(mut self, gevent: bool) -> ConfigBuilder {
+        self.config.gevent = gevent;
+        self
+    }
+
+    /// Copy the local variables of each python frame into the stack traces
+    pub fn dump_locals(mut self, dump_locals: bool) -> ConfigBuilder {
+        self.config.dump_locals = dump_locals;
+        self
+    }
+
+    /// Get the exception that each thread is handling
+    pub fn capture_exceptions(mut self, capture_exceptions: bool) -> ConfigBuilder {
+        self.config.capture_exceptions = capture_exceptions;
+        self
+    }
+
+    /// Checks that the options can be used together, returning the config if they can
+    pub fn build(self) -> Result<Config, Error> {
+        let config = self.config;
+        if config.sampling_rate == 0 {
+            return Err(Error::InvalidConfig("The sampling rate has to be at least 1 sample a second".to_owned()));
+        }
+        if config.unwind_workers == 0 {
+            return Err(Error::InvalidConfig("There has to be at least 1 thread unwinding stacks".to_owned()));
+        }
+        if config.native && !cfg!(unwind) {
+            return Err(Error::InvalidConfig("Native stack traces are not yet supported on this OS".to_owned()));
+        }
+        if config.native && config.non_blocking {
+            return Err(Error::InvalidConfig("Native stack traces need the process to be paused, and can't be collected with non_blocking".to_owned()));
+        }
+        if config.max_pause.is_some() && config.non_blocking {
+            return Err(Error::InvalidConfig("max_pause limits how long the process is paused for, so it can't be used with non_blocking".to_owned()));
+        }
+        if config.memory && config.format != FileFormat::flamegraph && config.format != FileFormat::folded {
+            return Err(Error::InvalidConfig("Memory allocations can only be recorded in the flamegraph and folded formats".to_owned()));
+        }
+        if config.memory && config.subprocesses {
+            return Err(Error::InvalidConfig("Memory allocations can only be recorded for one process, and not its subprocesses".to_owned()));
+        }
+        if let Some(regex) = config.thread_name_regex.as_ref() {
+            if let Err(e) = Regex::new(regex) {
+                return Err(Error::InvalidConfig(format!("Invalid thread_name_regex '{}': {}", regex, e)));
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let config = ConfigBuilder::new().sampling_rate(200).gil_only(true).thread_ids(&[1, 2]).build().unwrap();
+        assert_eq!(config.sampling_rate, 200);
+        assert!(config.gil_only);
+        assert_eq!(config.thread_ids, vec![1, 2]);
+
+        assert!(ConfigBuilder::new().sampling_rate(0).build().is_err());
+        assert!(ConfigBuilder::new().native(true).non_blocking(true).build().is_err());
+        assert!(ConfigBuilder::new().max_pause(Duration::from_millis(10)).non_blocking(true).build().is_err());
+        assert!(ConfigBuilder::new().memory(true).format(FileFormat::speedscope).build().is_err());
+        assert!(ConfigBuilder::new().memory(true).subprocesses(true).build().is_err());
+        assert!(ConfigBuilder::new().thread_name_regex("(worker").build().is_err());
+    }
+}
@@ -0,0 +1,235 @@
+// Reads defaults for the command line options from py-spy.toml files, so that a team can check in how
+// a project should be profiled. The project's file (found in the current directory or one of its
+// parents) takes precedence over the user's (~/.config/py-spy/py-spy.toml), and options given on the
+// command line take precedence over both. PYSPY_CONFIG names a file to use instead, or disables
+// them when it's empty.
+//
+// Only the parts of TOML that these files need are supported: comments, [sections], and keys set to
+// strings, numbers, booleans or arrays of strings.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, ResultExt};
+
+pub const FILENAME: &str = "py-spy.toml";
+
+/// The command line options that can be given defaults, by their long name
+const OPTIONS: &[&str] = &["rate", "format", "output", "granularity", "function", "group-by", "reverse", "bare-names",
+                           "map-path", "idle", "idle-rule", "no-idle-rule", "gil", "thread-name-regex", "include-frames",
+                           "exclude-frames", "exclude-stdlib", "max-depth", "trim-roots", "min-percent", "label", "nonblocking",
+                           "native", "subprocesses", "jitter", "duration", "max-overhead", "asyncio", "gevent",
+                           "interpreters"];
+/// Options of 'py-spy monitor', which go in a [monitor] section
+const MONITOR_OPTIONS: &[&str] = &["listen", "keep"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(String),
+    Bool(bool),
+    Array(Vec<String>),
+}
+
+/// The defaults from the config files, keyed by option name ('rate', or 'monitor.listen')
+#[derive(Debug, Default)]
+pub struct Defaults {
+    pub values: BTreeMap<String, (Value, PathBuf)>,
+}
+
+impl Defaults {
+    /// Loads the user's and the project's config files, if there are any
+    pub fn load() -> Result<Defaults, Error> {
+        let files = match std::env::var_os("PYSPY_CONFIG") {
+            Some(file) if file.is_empty() => Vec::new(),
+            Some(file) => vec![PathBuf::from(file)],
+            None => {
+                let user = user_config_file().filter(|file| file.is_file());
+                let project = std::env::current_dir().ok().and_then(|dir| project_config_file(&dir));
+                user.into_iter().chain(project).collect()
+            }
+        };
+
+        let mut defaults = Defaults::default();
+        for file in files {
+            let text = std::fs::read_to_string(&file).context(format!("Failed to read '{}'", file.display()))?;
+            let values = parse(&text).map_err(|err| format_err!("{} in '{}'", err, file.display()))?;
+            for (key, value) in values {
+                defaults.values.insert(key, (value, file.clone()));
+            }
+        }
+        Ok(defaults)
+    }
+
+    /// The command line arguments for the defaults of top level options, other than the ones that
+    /// 'given' says are already on the command line
+    pub fn args(&self, given: &dyn Fn(&str) -> bool) -> Vec<String> {
+        let mut args = Vec::new();
+        for (key, (value, _)) in &self.values {
+            if key.contains('.') || given(&key.replace('-', "_")) {
+                continue;
+            }
+            let flag = format!("--{}", key);
+            match value {
+                Value::Bool(true) => args.push(flag),
+                Value::Bool(false) => {},
+                Value::String(value) | Value::Number(value) => args.push(format!("{}={}", flag, value)),
+                Value::Array(values) => args.extend(values.iter().map(|value| format!("{}={}", flag, value)))
+            }
+        }
+        args
+    }
+
+    /// The value of an option in a section, like 'monitor.listen'
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.values.get(key) {
+            Some((Value::String(value), _)) | Some((Value::Number(value), _)) => Some(value.clone()),
+            _ => None
+        }
+    }
+}
+
+// ~/.config/py-spy/py-spy.toml (or under $XDG_CONFIG_HOME), or %APPDATA%\py-spy\py-spy.toml on Windows
+fn user_config_file() -> Option<PathBuf> {
+    let config = if cfg!(windows) {
+        PathBuf::from(std::env::var_os("APPDATA")?)
+    } else {
+        match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(config) if !config.is_empty() => PathBuf::from(config),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config")
+        }
+    };
+    Some(config.join("py-spy").join(FILENAME))
+}
+
+// the closest py-spy.toml in 'dir' or one of its parents
+fn project_config_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().map(|dir| dir.join(FILENAME)).find(|file| file.is_file())
+}
+
+/// Parses a config file into its options, checking that each one is an option that can be set
+fn parse(text: &str) -> Result<Vec<(String, Value)>, Error> {
+    let mut values = Vec::new();
+    let mut section = String::new();
+    for (number, line) in text.lines().enumerate() {
+        let invalid = |message: &str| format_err!("{} on line {}", message, number + 1);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            section = line.strip_prefix('[').and_then(|line| line.strip_suffix(']'))
+                .ok_or_else(|| invalid("Invalid section header"))?.trim().to_owned();
+            if section != "monitor" {
+                return Err(invalid(&format!("Unknown section '{}'", section)));
+            }
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid("Expected 'option = value'"))?;
+        let key = key.trim().trim_matches('"').replace('_', "-");
+        let known = if section.is_empty() { OPTIONS } else { MONITOR_OPTIONS };
+        if !known.contains(&key.as_str()) {
+            return Err(invalid(&format!("Unknown option '{}'", key)));
+        }
+        let value = parse_value(value.trim()).ok_or_else(|| invalid(&format!("Invalid value for '{}'", key)))?;
+        let key = if section.is_empty() { key } else { format!("{}.{}", section, key) };
+        values.push((key, value));
+    }
+    Ok(values)
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    match value {
+        "true" => return Some(Value::Bool(true)),
+        "false" => return Some(Value::Bool(false)),
+        _ => {}
+    }
+    if let Some(items) = value.strip_prefix('[').and_then(|value| value.strip_suffix(']')) {
+        let items = items.split(',').map(|item| item.trim()).filter(|item| !item.is_empty());
+        return items.map(parse_string).collect::<Option<Vec<String>>>().map(Value::Array);
+    }
+    if value.starts_with('"') || value.starts_with('\'') {
+        return parse_string(value).map(Value::String);
+    }
+    value.parse::<f64>().ok().map(|_| Value::Number(value.to_owned()))
+}
+
+// a "basic" string with escapes, or a 'literal' one without
+fn parse_string(value: &str) -> Option<String> {
+    if let Some(literal) = value.strip_prefix('\'').and_then(|value| value.strip_suffix('\'')) {
+        return Some(literal.to_owned());
+    }
+    let basic = value.strip_prefix('"')?.strip_suffix('"')?;
+    let mut ret = String::new();
+    let mut chars = basic.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => ret.push('\n'),
+            't' => ret.push('\t'),
+            c @ ('\\' | '"') => ret.push(c),
+            _ => return None
+        }
+    }
+    Some(ret)
+}
+
+// removes a '#' comment, unless it's inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            ('#', None) => return &line[..i],
+            ('"', None) | ('\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+            # shared profiling defaults
+            rate = 250
+            format = "speedscope"   # for speedscope.app
+            output = 'profiles/{pid}-{timestamp}.{format}'
+            idle = true
+            map_path = ["/app=src", "/venv=.venv"]
+
+            [monitor]
+            listen = "127.0.0.1:9090"
+        "#;
+        let values = parse(text).unwrap();
+        assert_eq!(values, vec![("rate".to_owned(), Value::Number("250".to_owned())),
+                                ("format".to_owned(), Value::String("speedscope".to_owned())),
+                                ("output".to_owned(), Value::String("profiles/{pid}-{timestamp}.{format}".to_owned())),
+                                ("idle".to_owned(), Value::Bool(true)),
+                                ("map-path".to_owned(), Value::Array(vec!["/app=src".to_owned(), "/venv=.venv".to_owned()])),
+                                ("monitor.listen".to_owned(), Value::String("127.0.0.1:9090".to_owned()))]);
+
+        assert!(parse("pid = 1234").is_err());
+        assert!(parse("[record]\nrate = 10").is_err());
+        assert!(parse("rate = fast").is_err());
+        assert!(parse("format = \"speedscope").is_err());
+    }
+
+    #[test]
+    fn test_args() {
+        let mut defaults = Defaults::default();
+        for (key, value) in parse("rate = 250\nidle = true\ngil = false\nmap-path = ['/app=src']\n[monitor]\nkeep = 24").unwrap() {
+            defaults.values.insert(key, (value, PathBuf::from(FILENAME)));
+        }
+        assert_eq!(defaults.args(&|_| false), vec!["--idle", "--map-path=/app=src", "--rate=250"]);
+        assert_eq!(defaults.args(&|name| name == "rate" || name == "map_path"), vec!["--idle"]);
+        assert_eq!(defaults.get("monitor.keep").as_deref(), Some("24"));
+    }
+}
@@ -5,6 +5,7 @@ use std::io;
 use std::io::{Read, Write};
 use std::sync::{Mutex, Arc, atomic};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use console::{Term, style};
 use failure::Error;
@@ -14,22 +15,29 @@ use crate::stack_trace::{StackTrace, Frame};
 pub struct ConsoleViewer {
     #[allow(dead_code)]
     console_config: os_impl::ConsoleConfig,
-    show_idle: bool,
     version: String,
     command: String,
     sampling_rate: f64,
     running: Arc<atomic::AtomicBool>,
     options: Arc<Mutex<Options>>,
-    stats: Stats
+    stats: Stats,
+    // how many times the statistics have been reset, so that snapshots can be reset along with them
+    resets: u64,
+    message: Option<(String, Instant)>
 }
 
+/// How long messages (like where a snapshot was written) are shown for
+const MESSAGE_DURATION: Duration = Duration::from_secs(5);
+
 impl ConsoleViewer {
     pub fn new(show_linenumbers: bool,
+               show_idle: bool,
+               gil_only: bool,
                python_command: &str,
                version: &str,
                sampling_rate: f64) -> io::Result<ConsoleViewer> {
         let running = Arc::new(atomic::AtomicBool::new(true));
-        let options = Arc::new(Mutex::new(Options::new(show_linenumbers)));
+        let options = Arc::new(Mutex::new(Options::new(show_linenumbers, show_idle, gil_only)));
 
         // listen for keyboard events in a separate thread to avoid blocking here
         let input_running = running.clone();
@@ -41,9 +49,29 @@ impl ConsoleViewer {
                 if let Some(Ok(key)) = std::io::stdin().bytes().next() {
                     let mut options = input_options.lock().unwrap();
                     options.dirty = true;
+                    // while typing a search, keys go to the search rather than being shortcuts
+                    if options.searching {
+                        match key {
+                            b'\n' | b'\r' => options.searching = false,
+                            // escape
+                            27 => { options.searching = false; options.filter.clear(); },
+                            // backspace and delete
+                            8 | 127 => { options.filter.pop(); },
+                            key if key == b' ' || key.is_ascii_graphic() => options.filter.push(key as char),
+                            _ => {}
+                        }
+                        continue;
+                    }
                     match key as char {
                         'R' | 'r' => options.reset = true,
                         'L' | 'l' => options.show_linenumbers = !options.show_linenumbers,
+                        'I' | 'i' => options.show_idle = !options.show_idle,
+                        'G' | 'g' => options.gil_only = !options.gil_only,
+                        'P' | 'p' => options.paused = !options.paused,
+                        'S' | 's' => options.snapshot = true,
+                        'T' | 't' => options.per_thread = !options.per_thread,
+                        'B' | 'b' => options.thread_breakdown = !options.thread_breakdown,
+                        '/' => { options.searching = true; options.filter.clear(); },
                         'X' | 'x' => options.usage = false,
                         '?' => options.usage = true,
                         '1' => options.sort_column = 1,
@@ -59,17 +87,33 @@ impl ConsoleViewer {
         Ok(ConsoleViewer{console_config: os_impl::ConsoleConfig::new()?,
                          version:version.to_owned(),
                          command: python_command.to_owned(),
-                         show_idle: false, running, options, sampling_rate,
-                         stats: Stats::new()})
+                         running, options, sampling_rate,
+                         stats: Stats::new(), resets: 0, message: None})
     }
 
     pub fn increment(&mut self, traces: &[StackTrace]) -> Result<(), Error> {
         self.maybe_reset();
+        let (show_idle, gil_only) = self.filters();
         self.stats.threads = 0;
+        self.stats.thread_names.clear();
         for trace in traces {
             self.stats.threads += 1;
+            if let Some(name) = trace.thread_name.as_ref() {
+                if !self.stats.thread_names.contains(name) {
+                    self.stats.thread_names.push(name.clone());
+                }
+            }
 
-            if !(self.show_idle || trace.active) {
+            // the status of each thread is tracked even when the filters leave its samples out
+            let label = thread_label(trace);
+            let thread = self.stats.thread_counts.entry(label.clone()).or_default();
+            thread.current_samples += 1;
+            thread.active += trace.active as u64;
+            thread.gil += trace.owns_gil as u64;
+            thread.last_active = trace.active;
+            thread.last_owns_gil = trace.owns_gil;
+
+            if !(show_idle || trace.active) || (gil_only && !trace.owns_gil) {
                 continue;
             }
 
@@ -81,19 +125,12 @@ impl ConsoleViewer {
                 self.stats.active += 1
             }
 
-            update_function_statistics(&mut self.stats.line_counts, trace, |frame| {
-                let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
-                if frame.line != 0 {
-                    format!("{} ({}:{})", frame.name, filename, frame.line)
-                } else {
-                    format!("{} ({})", frame.name, filename)
-                }
-            });
+            update_function_statistics(&mut self.stats.line_counts, trace, line_label);
+            update_function_statistics(&mut self.stats.function_counts, trace, function_label);
 
-            update_function_statistics(&mut self.stats.function_counts, trace, |frame| {
-                let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
-                format!("{} ({})", frame.name, filename)
-            });
+            let thread = self.stats.thread_counts.entry(label).or_default();
+            update_function_statistics(&mut thread.line_counts, trace, line_label);
+            update_function_statistics(&mut thread.function_counts, trace, function_label);
         }
         self.increment_common()?;
         Ok(())
@@ -103,17 +140,16 @@ impl ConsoleViewer {
         // Get the top aggregate function calls (either by line or by function as )
         let mut options = self.options.lock().unwrap();
         options.dirty = false;
-        let counts = if options.show_linenumbers { &self.stats.line_counts } else { &self.stats.function_counts };
-        let mut counts:Vec<(&FunctionStatistics, &str)> = counts.iter().map(|(x,y)| (y, x.as_ref())).collect();
-
-        // TODO: subsort ?
-        match options.sort_column {
-            1 => counts.sort_unstable_by(|a, b| b.0.current_own.cmp(&a.0.current_own)),
-            2 => counts.sort_unstable_by(|a, b| b.0.current_total.cmp(&a.0.current_total)),
-            3 => counts.sort_unstable_by(|a, b| b.0.overall_own.cmp(&a.0.overall_own)),
-            4 => counts.sort_unstable_by(|a, b| b.0.overall_total.cmp(&a.0.overall_total)),
-            _ => panic!("unknown sort column. this really shouldn't happen")
-        }
+        let mut counts: Vec<(&FunctionStatistics, String)> = if options.per_thread {
+            self.stats.thread_counts.iter().flat_map(|(thread, stats)| {
+                let counts = if options.show_linenumbers { &stats.line_counts } else { &stats.function_counts };
+                counts.iter().map(move |(label, samples)| (samples, format!("[{}] {}", thread, label)))
+            }).collect()
+        } else {
+            let counts = if options.show_linenumbers { &self.stats.line_counts } else { &self.stats.function_counts };
+            counts.iter().map(|(label, samples)| (samples, label.clone())).collect()
+        };
+        sort_counts(&mut counts, &options);
         let term = Term::stdout();
         let (height, width) = term.size();
         let width = width as usize;
@@ -126,7 +162,14 @@ impl ConsoleViewer {
             ($($arg:tt)*) => { term.clear_line()?; term.write_line(&format!($($arg)*))?; }
         }
         self.console_config.reset_cursor()?;
-        let mut header_lines = if options.usage { 18 } else { 8 };
+        let mut header_lines = if options.usage { 23 } else { 8 };
+
+        if let Some((message, shown)) = self.message.as_ref() {
+            if shown.elapsed() < MESSAGE_DURATION {
+                out!("{}", style(message).green());
+                header_lines += 1;
+            }
+        }
 
         if let Some(delay) = self.stats.last_delay {
             let late_rate = self.stats.late_samples as f64 / self.stats.overall_samples as f64;
@@ -156,6 +199,18 @@ impl ConsoleViewer {
             style(100.0 * self.stats.active as f64 / self.stats.current_samples as f64).bold(),
             style(self.stats.threads).bold());
 
+        if !self.stats.thread_names.is_empty() {
+            let names = format!("Thread names: {}", self.stats.thread_names.join(", "));
+            out!("{}", names.chars().take(width).collect::<String>());
+            header_lines += 1;
+        }
+
+        if options.searching || !options.filter.is_empty() {
+            let cursor = if options.searching { "_" } else { "" };
+            out!("Showing functions matching: {}{}", style(&options.filter).bold(), cursor);
+            header_lines += 1;
+        }
+
         out!();
 
         // Build up the header for the table
@@ -185,17 +240,56 @@ impl ConsoleViewer {
         out!("{:>7}{:>8}{:>9}{:>11}{:width$}", percent_own_header, percent_total_header,
              time_own_header, time_total_header, function_header, width=max_function_width);
 
-        let mut written = 0;
-        for (samples, label) in counts.iter().take(height as usize - header_lines) {
-            out!("{:>6.2}% {:>6.2}% {:>7}s {:>8}s   {:.width$}",
+        let row = |samples: &FunctionStatistics, label: &str| format!("{:>6.2}% {:>6.2}% {:>7}s {:>8}s   {:.width$}",
                 100.0 * samples.current_own as f64 / (self.stats.current_samples as f64),
                 100.0 * samples.current_total as f64 / (self.stats.current_samples as f64),
                 display_time(samples.overall_own as f64 * self.sampling_rate),
                 display_time(samples.overall_total as f64 * self.sampling_rate),
                 label, width=max_function_width - 2);
+
+        let rows = height as usize - header_lines;
+        let mut written = 0;
+        if options.thread_breakdown {
+            // a section for each thread, with the busiest threads first and the rows split evenly between them
+            let mut threads: Vec<(&String, &ThreadStatistics)> = self.stats.thread_counts.iter()
+                .filter(|(_, thread)| thread.current_samples > 0)
+                .collect();
+            threads.sort_by(|a, b| b.1.active.cmp(&a.1.active).then_with(|| a.0.cmp(b.0)));
+            let functions_per_thread = (rows / threads.len().max(1)).max(2) - 1;
+            for (label, thread) in threads {
+                if written >= rows {
+                    break;
+                }
+                let status = match (thread.last_active, thread.last_owns_gil) {
+                    (true, true) => "active, holding the GIL",
+                    (true, false) => "active",
+                    (false, true) => "idle, holding the GIL",
+                    (false, false) => "idle"
+                };
+                let heading = format!("{} ({}) GIL: {:.2}%, Active: {:.2}%", label, status,
+                                      100.0 * thread.gil as f64 / thread.current_samples as f64,
+                                      100.0 * thread.active as f64 / thread.current_samples as f64);
+                out!("{}", style(heading.chars().take(width).collect::<String>()).bold());
+                written += 1;
+
+                let counts = if options.show_linenumbers { &thread.line_counts } else { &thread.function_counts };
+                let mut counts: Vec<(&FunctionStatistics, String)> = counts.iter()
+                    .filter(|(_, samples)| samples.current_total > 0)
+                    .map(|(label, samples)| (samples, label.clone()))
+                    .collect();
+                sort_counts(&mut counts, &options);
+                for (samples, label) in counts.iter().take(functions_per_thread.min(rows - written)) {
+                    out!("{}", row(samples, label));
+                    written += 1;
+                }
+            }
+        } else {
+            for (samples, label) in counts.iter().take(rows) {
+                out!("{}", row(samples, label));
                 written += 1;
+            }
         }
-        for _ in written.. height as usize - header_lines {
+        for _ in written..rows {
             out!();
         }
 
@@ -209,10 +303,22 @@ impl ConsoleViewer {
             out!("{:^12}{:<}", "3", "Sort by OwnTime (Overall time spent in the function)");
             out!("{:^12}{:<}", "4", "Sort by TotalTime (Overall time spent in the function and its children)");
             out!("{:^12}{:<}", "L,l", "Toggle between aggregating by line number or by function");
+            out!("{:^12}{:<}", "I,i", "Toggle including idle threads");
+            out!("{:^12}{:<}", "G,g", "Toggle only including threads holding the GIL");
+            out!("{:^12}{:<}", "T,t", "Toggle between showing functions for the whole process or for each thread");
+            out!("{:^12}{:<}", "B,b", "Toggle showing a section for each thread, with its status and hottest functions");
+            out!("{:^12}{:<}", "/", "Only show functions matching some text (Enter to finish, Escape to clear)");
+            out!("{:^12}{:<}", "P,p", "Pause updating the display");
+            out!("{:^12}{:<}", "S,s", "Write a snapshot of the samples collected so far to a file");
             out!("{:^12}{:<}", "R,r", "Reset statistics");
             out!("{:^12}{:<}", "X,x", "Exit this help screen");
             out!();
             //println!("{:^12}{:<}", "Control-C", "Quit py-spy");
+        } else if options.paused {
+            out!("{} Press {} to resume, or {} for help.",
+                 style("Paused.").bold().red(),
+                 style("P").bold().reverse(),
+                 style("?").bold().reverse());
         } else {
             out!("Press {} to quit, or {} for help.",
                  style("Control-C").bold().reverse(),
@@ -230,17 +336,43 @@ impl ConsoleViewer {
         self.increment_common()
     }
 
+    /// Whether a snapshot has been asked for since the last call
+    pub fn take_snapshot_request(&self) -> bool {
+        std::mem::replace(&mut self.options.lock().unwrap().snapshot, false)
+    }
+
+    /// The idle and GIL filters currently selected, as (show_idle, gil_only)
+    pub fn filters(&self) -> (bool, bool) {
+        let options = self.options.lock().unwrap();
+        (options.show_idle, options.gil_only)
+    }
+
+    /// How many times the statistics have been reset with the R key
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    /// Shows a message at the top of the display for a few seconds
+    pub fn show_message(&mut self, message: String) {
+        self.message = Some((message, Instant::now()));
+        self.options.lock().unwrap().dirty = true;
+    }
+
     pub fn increment_late_sample(&mut self, delay: std::time::Duration) {
         self.stats.late_samples += 1;
         self.stats.last_delay = Some(delay);
     }
 
     pub fn should_refresh(&self) -> bool {
-        // update faster if we only have a few samples, or if we changed options
+        // update faster if we only have a few samples, or if we changed options. While paused,
+        // the display is only redrawn to show changed options
+        let options = self.options.lock().unwrap();
+        if options.paused {
+            return options.dirty;
+        }
         match self.stats.overall_samples {
             10 | 100 | 500 => true,
-            _ => self.options.lock().unwrap().dirty ||
-                 self.stats.elapsed >= 1.0
+            _ => options.dirty || self.stats.elapsed >= 1.0
         }
     }
 
@@ -261,6 +393,7 @@ impl ConsoleViewer {
         let mut options = self.options.lock().unwrap();
         if options.reset {
             self.stats = Stats::new();
+            self.resets += 1;
             options.reset = false;
         }
     }
@@ -272,7 +405,7 @@ impl Drop for ConsoleViewer {
     }
 }
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Default)]
 struct FunctionStatistics {
     current_own: u64,
     current_total: u64,
@@ -290,8 +423,7 @@ fn update_function_statistics<K>(counts: &mut HashMap<String, FunctionStatistics
     }
 
     for (key, order) in current {
-        let entry = counts.entry(key).or_insert_with(|| FunctionStatistics{current_own: 0, current_total: 0,
-                                                                           overall_own: 0, overall_total: 0});
+        let entry = counts.entry(key).or_default();
         entry.current_total += 1;
         entry.overall_total += 1;
 
@@ -302,12 +434,79 @@ fn update_function_statistics<K>(counts: &mut HashMap<String, FunctionStatistics
     }
 }
 
+fn line_label(frame: &Frame) -> String {
+    let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
+    if frame.line != 0 {
+        format!("{} ({}:{})", frame.name, filename, frame.line)
+    } else {
+        format!("{} ({})", frame.name, filename)
+    }
+}
+
+fn function_label(frame: &Frame) -> String {
+    let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
+    format!("{} ({})", frame.name, filename)
+}
+
+// names a thread by its id (since threads can share a name), its name if it has one, and its pid
+// when sampling several processes
+fn thread_label(trace: &StackTrace) -> String {
+    let name = match trace.thread_name.as_ref() {
+        Some(name) => format!("{} {:#X}", name, trace.thread_id),
+        None => format!("{:#X}", trace.thread_id)
+    };
+    match trace.pid {
+        Some(pid) => format!("{}:{}", pid, name),
+        None => name
+    }
+}
+
+// filters the rows of a table by the text being searched for, and sorts them by the selected column
+fn sort_counts(counts: &mut Vec<(&FunctionStatistics, String)>, options: &Options) {
+    if !options.filter.is_empty() {
+        let filter = options.filter.to_lowercase();
+        counts.retain(|(_, label)| label.to_lowercase().contains(&filter));
+    }
+
+    // TODO: subsort ?
+    match options.sort_column {
+        1 => counts.sort_unstable_by(|a, b| b.0.current_own.cmp(&a.0.current_own)),
+        2 => counts.sort_unstable_by(|a, b| b.0.current_total.cmp(&a.0.current_total)),
+        3 => counts.sort_unstable_by(|a, b| b.0.overall_own.cmp(&a.0.overall_own)),
+        4 => counts.sort_unstable_by(|a, b| b.0.overall_total.cmp(&a.0.overall_total)),
+        _ => panic!("unknown sort column. this really shouldn't happen")
+    }
+}
+
+/// The statistics of a single thread, for showing functions per thread and the thread breakdown
+#[derive(Default)]
+struct ThreadStatistics {
+    function_counts: HashMap<String, FunctionStatistics>,
+    line_counts: HashMap<String, FunctionStatistics>,
+    // samples of the thread since the last refresh, and how many of those were active or holding the GIL
+    current_samples: u64,
+    active: u64,
+    gil: u64,
+    // the status of the thread in its latest sample
+    last_active: bool,
+    last_owns_gil: bool,
+}
+
 struct Options {
     dirty: bool,
     usage: bool,
     sort_column: i32,
     show_linenumbers: bool,
+    show_idle: bool,
+    gil_only: bool,
     reset: bool,
+    paused: bool,
+    per_thread: bool,
+    thread_breakdown: bool,
+    snapshot: bool,
+    // whether keys are being typed into the filter, and the text that functions have to contain to be shown
+    searching: bool,
+    filter: String,
 }
 
 struct Stats {
@@ -317,25 +516,28 @@ struct Stats {
     errors: u64,
     late_samples: u64,
     threads: u64,
+    thread_names: Vec<String>,
     active: u64,
     gil: u64,
     function_counts: HashMap<String, FunctionStatistics>,
     line_counts: HashMap<String, FunctionStatistics>,
+    thread_counts: HashMap<String, ThreadStatistics>,
     last_error: Option<String>,
     last_delay: Option<std::time::Duration>,
 }
 
 impl Options {
-    fn new(show_linenumbers: bool) -> Options {
-        Options{dirty: false, usage: false, reset: false, sort_column: 1, show_linenumbers}
+    fn new(show_linenumbers: bool, show_idle: bool, gil_only: bool) -> Options {
+        Options{dirty: false, usage: false, reset: false, sort_column: 1, show_linenumbers, show_idle, gil_only,
+                paused: false, per_thread: false, thread_breakdown: false, snapshot: false, searching: false, filter: String::new()}
     }
 }
 
 impl Stats {
     fn new() -> Stats {
         Stats{current_samples: 0, overall_samples: 0, elapsed: 0.,
-              errors: 0, late_samples: 0, threads: 0, gil: 0, active: 0,
-              line_counts: HashMap::new(), function_counts: HashMap::new(),
+              errors: 0, late_samples: 0, threads: 0, thread_names: Vec::new(), gil: 0, active: 0,
+              line_counts: HashMap::new(), function_counts: HashMap::new(), thread_counts: HashMap::new(),
               last_error: None, last_delay: None}
     }
 
@@ -350,6 +552,16 @@ impl Stats {
             val.current_total = 0;
             val.current_own = 0;
         }
+
+        for thread in self.thread_counts.values_mut() {
+            thread.current_samples = 0;
+            thread.active = 0;
+            thread.gil = 0;
+            for val in thread.line_counts.values_mut().chain(thread.function_counts.values_mut()) {
+                val.current_total = 0;
+                val.current_own = 0;
+            }
+        }
         self.gil = 0;
         self.active = 0;
         self.current_samples = 0;
@@ -0,0 +1,119 @@
+// Lets the --gil and --idle filters be changed while recording, by writing to the file given with
+// --control. Whenever the file's contents change, py-spy takes the filters from it: 'gil' turns
+// on --gil, 'idle' turns on --idle, and a filter that isn't listed is turned off, so writing an
+// empty file goes back to recording every active thread. Whatever is in the file when recording
+// starts is left alone, so the filters start out as given on the command line.
+use std::time::{Duration, Instant};
+
+use failure::{Error, ResultExt};
+
+/// How often to check the control file for changes
+const CONTROL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The filters read from a control file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Filters {
+    pub gil_only: bool,
+    pub include_idle: bool,
+}
+
+/// Watches a control file for changes to the filters
+pub struct ControlFile {
+    path: String,
+    // None if the file doesn't exist
+    contents: Option<String>,
+    last_check: Instant,
+}
+
+impl ControlFile {
+    pub fn new(path: &str) -> Result<ControlFile, Error> {
+        let contents = read(path).context(format!("Failed to read control file '{}'", path))?;
+        Ok(ControlFile{path: path.to_owned(), contents, last_check: Instant::now()})
+    }
+
+    /// Returns the filters in the control file if it has changed since it was last checked. Files
+    /// that can't be parsed are ignored with a warning, rather than stopping the recording
+    pub fn check(&mut self) -> Option<Filters> {
+        if self.last_check.elapsed() < CONTROL_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+
+        let contents = match read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Failed to read control file '{}': {}", self.path, e);
+                return None;
+            }
+        };
+        if contents == self.contents {
+            return None;
+        }
+        self.contents = contents;
+        match parse_filters(self.contents.as_deref().unwrap_or("")) {
+            Ok(filters) => Some(filters),
+            Err(e) => {
+                warn!("Ignoring control file '{}': {}", self.path, e);
+                None
+            }
+        }
+    }
+}
+
+fn read(path: &str) -> Result<Option<String>, std::io::Error> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+/// Parses the filters to use from a list of 'gil' and 'idle', separated by whitespace or commas
+pub fn parse_filters(contents: &str) -> Result<Filters, Error> {
+    let mut filters = Filters{gil_only: false, include_idle: false};
+    for word in contents.split(|c: char| c.is_whitespace() || c == ',').filter(|word| !word.is_empty()) {
+        match word.trim_start_matches("--") {
+            "gil" => filters.gil_only = true,
+            "idle" => filters.include_idle = true,
+            other => return Err(format_err!("Unknown filter '{}', expected 'gil' or 'idle'", other))
+        }
+    }
+    Ok(filters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!(parse_filters("").unwrap(), Filters{gil_only: false, include_idle: false});
+        assert_eq!(parse_filters("gil\n").unwrap(), Filters{gil_only: true, include_idle: false});
+        assert_eq!(parse_filters("idle, --gil").unwrap(), Filters{gil_only: true, include_idle: true});
+        assert!(parse_filters("gil native").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("control");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "idle").unwrap();
+
+        // the contents when recording starts don't change the filters
+        let mut control = ControlFile::new(path).unwrap();
+        control.last_check -= CONTROL_INTERVAL;
+        assert_eq!(control.check(), None);
+
+        std::fs::write(path, "gil").unwrap();
+        control.last_check -= CONTROL_INTERVAL;
+        assert_eq!(control.check(), Some(Filters{gil_only: true, include_idle: false}));
+        control.last_check -= CONTROL_INTERVAL;
+        assert_eq!(control.check(), None);
+
+        // removing the file turns the filters off
+        std::fs::remove_file(path).unwrap();
+        control.last_check -= CONTROL_INTERVAL;
+        assert_eq!(control.check(), Some(Filters{gil_only: false, include_idle: false}));
+    }
+}
@@ -0,0 +1,324 @@
+// Gets the python stack traces out of a core dump, so that a crashed program (or one saved with gcore)
+// can be looked at after the fact. The memory of the process is read from the PT_LOAD segments of the
+// core. The kernel doesn't write out memory that is backed by an unmodified file (like the code and
+// read only data of binaries) by default, so those reads go to the files listed in the NT_FILE note
+// instead. Cores don't have symbol tables, so the python binary has to be read from disk too.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use failure::{Error, ResultExt};
+use goblin::elf::{Elf, program_header};
+use memmap::Mmap;
+use remoteprocess::ProcessMemory;
+
+use crate::binary_parser::{parse_binary, BinaryInfo};
+use crate::python_bindings::{pyruntime, v2_7_15, v3_3_7, v3_5_5, v3_6_6, v3_7_0};
+use crate::python_interpreters::{InterpreterState, ThreadState};
+use crate::python_spy::is_python_lib;
+use crate::stack_trace::{get_stack_traces, StackTrace};
+use crate::version::Version;
+
+// the note that lists the files mapped into the process, from linux/elf.h
+const NT_FILE: u32 = 0x4649_4c45;
+
+struct Segment {
+    address: u64,
+    size: u64,
+    offset: u64,
+    file_size: u64,
+    executable: bool,
+}
+
+/// A file that was mapped into the process when it dumped core
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedFile {
+    pub start: u64,
+    pub end: u64,
+    /// offset in the file that the mapping starts at, in bytes
+    pub offset: u64,
+    pub filename: String,
+}
+
+pub struct CoreDump {
+    data: Mmap,
+    segments: Vec<Segment>,
+    pub files: Vec<MappedFile>,
+    opened: RefCell<HashMap<String, Option<File>>>,
+}
+
+impl CoreDump {
+    pub fn new(filename: &str) -> Result<CoreDump, Error> {
+        let file = File::open(filename).context(format!("Failed to open core file {}", filename))?;
+        let data = unsafe { Mmap::map(&file)? };
+
+        let (mut segments, files) = {
+            let elf = Elf::parse(&data)?;
+            if elf.header.e_type != goblin::elf::header::ET_CORE {
+                return Err(format_err!("{} isn't a core file", filename));
+            }
+            let segments: Vec<Segment> = elf.program_headers.iter()
+                .filter(|header| header.p_type == program_header::PT_LOAD)
+                .map(|header| Segment{address: header.p_vaddr, size: header.p_memsz, offset: header.p_offset,
+                                      file_size: header.p_filesz, executable: header.p_flags & program_header::PF_X != 0})
+                .collect();
+
+            let mut files = Vec::new();
+            if let Some(notes) = elf.iter_note_headers(&data) {
+                for note in notes {
+                    let note = note?;
+                    if note.n_type == NT_FILE {
+                        files = parse_file_note(note.desc)?;
+                    }
+                }
+            }
+            (segments, files)
+        };
+        segments.sort_by_key(|segment| segment.address);
+        Ok(CoreDump{data, segments, files, opened: RefCell::new(HashMap::new())})
+    }
+
+    /// Finds the executable mapping of a binary, as (address, size)
+    fn executable_mapping(&self, filename: &str) -> Option<(u64, u64)> {
+        self.files.iter()
+            .filter(|file| file.filename == filename)
+            .find(|file| self.segments.iter().any(|segment| segment.address == file.start && segment.executable))
+            .map(|file| (file.start, file.end - file.start))
+    }
+
+    fn segment(&self, addr: u64) -> Option<&Segment> {
+        let index = match self.segments.binary_search_by_key(&addr, |segment| segment.address) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1
+        };
+        let segment = &self.segments[index];
+        if addr < segment.address + segment.size { Some(segment) } else { None }
+    }
+
+    fn read_mapped_file(&self, addr: u64, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        let mapped = self.files.iter().find(|file| addr >= file.start && addr < file.end)
+            .ok_or_else(|| remoteprocess::Error::Other(format!("0x{:016x} wasn't saved in the core file", addr)))?;
+        let mut opened = self.opened.borrow_mut();
+        let file = opened.entry(mapped.filename.clone()).or_insert_with(|| File::open(&mapped.filename).ok());
+        match file {
+            Some(file) => Ok(file.read_exact_at(buf, mapped.offset + addr - mapped.start)?),
+            None => Err(remoteprocess::Error::Other(format!("0x{:016x} is in {}, which wasn't saved in the core file and can't be opened",
+                                                            addr, mapped.filename)))
+        }
+    }
+}
+
+impl ProcessMemory for CoreDump {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        let mut addr = addr as u64;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let segment = self.segment(addr)
+                .ok_or_else(|| remoteprocess::Error::Other(format!("0x{:016x} isn't mapped in the core file", addr)))?;
+            let position = addr - segment.address;
+            let length = std::cmp::min(buf.len() as u64, segment.size - position) as usize;
+            let (chunk, rest) = buf.split_at_mut(length);
+            if position < segment.file_size {
+                let length = std::cmp::min(length as u64, segment.file_size - position) as usize;
+                let start = (segment.offset + position) as usize;
+                let saved = self.data.get(start..start + length)
+                    .ok_or_else(|| remoteprocess::Error::Other("core file is truncated".to_owned()))?;
+                chunk[..length].copy_from_slice(saved);
+                if length < chunk.len() {
+                    self.read_mapped_file(addr + length as u64, &mut chunk[length..])?;
+                }
+            } else {
+                self.read_mapped_file(addr, chunk)?;
+            }
+            addr += chunk.len() as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the NT_FILE note: a count and page size, then the (start, end, page offset) of each
+/// mapping, then the NUL terminated filename of each mapping
+fn parse_file_note(desc: &[u8]) -> Result<Vec<MappedFile>, Error> {
+    let word = |index: usize| -> Result<u64, Error> {
+        let bytes = desc.get(index * 8..index * 8 + 8).ok_or_else(|| format_err!("NT_FILE note is truncated"))?;
+        let mut value = [0u8; 8];
+        value.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(value))
+    };
+    let count = word(0)? as usize;
+    let page_size = word(1)?;
+    let names_start = 16 + count.checked_mul(24).ok_or_else(|| format_err!("NT_FILE note is invalid"))?;
+    let mut names = desc.get(names_start..).ok_or_else(|| format_err!("NT_FILE note is truncated"))?
+        .split(|&b| b == 0);
+
+    let mut files = Vec::with_capacity(count);
+    for i in 0..count {
+        let name = names.next().ok_or_else(|| format_err!("NT_FILE note is missing filenames"))?;
+        files.push(MappedFile{start: word(2 + i * 3)?, end: word(3 + i * 3)?, offset: word(4 + i * 3)? * page_size,
+                              filename: String::from_utf8_lossy(name).into_owned()});
+    }
+    Ok(files)
+}
+
+/// Finds the python binaries in a core file, and reads their symbols from disk. The executable can
+/// be given either as the path to a local copy of it, or as a command on the PATH like 'python3'
+fn find_binaries(core: &CoreDump, exe: Option<&str>) -> Result<(BinaryInfo, Option<BinaryInfo>), Error> {
+    let local = exe.and_then(resolve_executable);
+    let exe_filename = match exe {
+        Some(exe) => {
+            // symlinks like python3 -> python3.7 are resolved, and a copy of the binary from another
+            // machine is matched by its filename
+            let names: Vec<&str> = local.iter().map(|path| path.as_str()).chain(std::iter::once(exe)).collect();
+            core.files.iter().map(|file| &file.filename)
+                .find(|filename| names.iter().any(|name| filename.as_str() == *name ||
+                                                  Path::new(filename).file_name() == Path::new(name).file_name()))
+                .ok_or_else(|| format_err!("{} wasn't mapped into the process that dumped core", exe))?
+        },
+        // the kernel lists the executable first
+        None => &core.files.first().ok_or_else(|| format_err!("Core file doesn't list any mapped files"))?.filename
+    };
+    let (addr, size) = core.executable_mapping(exe_filename)
+        .ok_or_else(|| format_err!("Failed to find the executable mapping of {} in the core file", exe_filename))?;
+    let local = local.as_deref().unwrap_or(exe_filename);
+    let python_binary = parse_binary(local, addr, size)
+        .context(format!("Failed to read symbols from {}", local))?;
+
+    let libpython_binary = match core.files.iter().find(|file| is_python_lib(&file.filename)) {
+        Some(file) => match core.executable_mapping(&file.filename) {
+            Some((addr, size)) => Some(parse_binary(&file.filename, addr, size)
+                .context(format!("Failed to read symbols from {}", file.filename))?),
+            None => None
+        },
+        None => None
+    };
+    Ok((python_binary, libpython_binary))
+}
+
+/// Gets the canonical path of a binary, looking it up on the PATH if it's just a command name
+fn resolve_executable(exe: &str) -> Option<String> {
+    let path = if exe.contains('/') {
+        std::fs::canonicalize(exe).ok()?
+    } else {
+        let paths = std::env::var_os("PATH")?;
+        std::env::split_paths(&paths).filter_map(|dir| std::fs::canonicalize(dir.join(exe)).ok())
+            .find(|path| path.is_file())?
+    };
+    path.to_str().map(|path| path.to_owned())
+}
+
+/// Gets the version of python from the sys.version string in memory, or from the name of the binary
+fn get_python_version(core: &CoreDump, binaries: &[&BinaryInfo]) -> Result<Version, Error> {
+    for binary in binaries {
+        if let Ok(bss) = core.copy(binary.bss_addr as usize, binary.bss_size as usize) {
+            if let Ok(version) = Version::scan_bytes(&bss) {
+                return Ok(version);
+            }
+        }
+    }
+    for binary in binaries {
+        let name = Path::new(&binary.filename).file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let name = name.trim_start_matches("lib");
+        if let Some(name) = name.strip_prefix("python") {
+            let tokens: Vec<&str> = name.split(|c: char| !c.is_ascii_digit()).collect();
+            if tokens.len() >= 2 {
+                if let (Ok(major), Ok(minor)) = (tokens[0].parse::<u64>(), tokens[1].parse::<u64>()) {
+                    return Ok(Version{major, minor, patch: 0, release_flags: "".to_owned()});
+                }
+            }
+        }
+    }
+    Err(format_err!("Failed to find the python version in the core file"))
+}
+
+fn get_symbol<'a>(binaries: &[&'a BinaryInfo], symbol: &str) -> Option<&'a u64> {
+    binaries.iter().filter_map(|binary| binary.symbols.get(symbol)).next()
+}
+
+/// Gets the python stack traces of every thread in a core file, along with the python version
+pub fn get_stack_traces_from_core(filename: &str, exe: Option<&str>) -> Result<(String, Version, Vec<StackTrace>), Error> {
+    let core = CoreDump::new(filename)?;
+    let (python_binary, libpython_binary) = find_binaries(&core, exe)?;
+    let binaries: Vec<&BinaryInfo> = std::iter::once(&python_binary).chain(libpython_binary.iter()).collect();
+    let version = get_python_version(&core, &binaries)?;
+    info!("python version {} detected in core file {}", version, filename);
+
+    let (interp_head, threadstate) = match version {
+        Version{major: 3, minor: 7..=8, ..} => {
+            let runtime = get_symbol(&binaries, "_PyRuntime")
+                .ok_or_else(|| format_err!("Failed to find the _PyRuntime symbol in {}", python_binary.filename))?;
+            (*runtime as usize + pyruntime::get_interp_head_offset(&version),
+             pyruntime::get_tstate_current_offset(&version).map(|offset| *runtime as usize + offset))
+        },
+        Version{major: 2, ..} | Version{major: 3, minor: 3..=6, ..} => {
+            let head = get_symbol(&binaries, "interp_head")
+                .ok_or_else(|| format_err!("Failed to find the interp_head symbol in {}", python_binary.filename))?;
+            (*head as usize, get_symbol(&binaries, "_PyThreadState_Current").map(|&addr| addr as usize))
+        },
+        _ => return Err(format_err!("Unsupported version of Python: {}", version))
+    };
+
+    let traces = match version {
+        Version{major: 3, minor: 7..=8, ..} => get_traces::<v3_7_0::_is>(&core, interp_head, threadstate),
+        Version{major: 3, minor: 6, ..} => get_traces::<v3_6_6::_is>(&core, interp_head, threadstate),
+        Version{major: 3, minor: 4..=5, ..} => get_traces::<v3_5_5::_is>(&core, interp_head, threadstate),
+        Version{major: 3, minor: 3, ..} => get_traces::<v3_3_7::_is>(&core, interp_head, threadstate),
+        Version{major: 2, minor: 3..=7, ..} => get_traces::<v2_7_15::_is>(&core, interp_head, threadstate),
+        _ => Err(format_err!("Unsupported version of Python: {}", version))
+    }?;
+    Ok((python_binary.filename, version, traces))
+}
+
+fn get_traces<I: InterpreterState>(core: &CoreDump, interp_head: usize, threadstate: Option<usize>) -> Result<Vec<StackTrace>, Error> {
+    let gil_thread_id = match threadstate.map(|addr| core.copy_struct::<usize>(addr)) {
+        Some(Ok(addr)) if addr != 0 => core.copy_struct::<I::ThreadState>(addr).map(|thread| thread.thread_id()).ok(),
+        _ => None
+    };
+
+    let mut interpreters = Vec::new();
+    let mut address: usize = core.copy_struct(interp_head).context("Failed to read the list of interpreters")?;
+    while address != 0 {
+        let interp: I = core.copy_struct(address).context("Failed to read PyInterpreterState from core file")?;
+        address = interp.next() as usize;
+        interpreters.push(interp);
+        if interpreters.len() > 4096 {
+            return Err(format_err!("Max interpreter recursion depth reached"));
+        }
+    }
+
+    // interpreters without a stored id are numbered from the tail of the list, like PythonSpy does
+    let count = interpreters.len();
+    let mut traces = Vec::new();
+    for (i, interp) in interpreters.iter().enumerate() {
+        let interpreter_id = interp.id().unwrap_or((count - 1 - i) as i64);
+        for mut trace in get_stack_traces(interp, core)? {
+            trace.owns_gil = Some(trace.thread_id) == gil_thread_id;
+            trace.interpreter_id = interpreter_id;
+            traces.push(trace);
+        }
+    }
+    Ok(traces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file_note() {
+        let mut desc = Vec::new();
+        for value in &[2u64, 4096, 0x400000, 0x401000, 0, 0x7f0000, 0x7f2000, 3] {
+            desc.extend_from_slice(&value.to_le_bytes());
+        }
+        desc.extend_from_slice(b"/usr/bin/python3.7\0/usr/lib/libpython3.7m.so.1.0\0");
+        let files = parse_file_note(&desc).unwrap();
+        assert_eq!(files, vec![
+            MappedFile{start: 0x400000, end: 0x401000, offset: 0, filename: "/usr/bin/python3.7".to_owned()},
+            MappedFile{start: 0x7f0000, end: 0x7f2000, offset: 3 * 4096, filename: "/usr/lib/libpython3.7m.so.1.0".to_owned()}]);
+
+        assert!(parse_file_note(&desc[..40]).is_err());
+    }
+}
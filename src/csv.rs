@@ -0,0 +1,88 @@
+// Writes out aggregated samples as CSV, with one row per function/line and thread
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use failure::Error;
+
+use crate::function_stats::FunctionCounts;
+use crate::stack_trace::StackTrace;
+
+#[derive(Hash, Eq, PartialEq, Clone)]
+struct Key {
+    thread_id: u64,
+    name: String,
+    filename: String,
+    line: Option<i32>,
+}
+
+pub struct CsvStats {
+    rows: HashMap<Key, FunctionCounts>,
+    show_linenumbers: bool,
+}
+
+impl CsvStats {
+    pub fn new(show_linenumbers: bool) -> CsvStats {
+        CsvStats{rows: HashMap::new(), show_linenumbers}
+    }
+
+    pub fn increment(&mut self, traces: &[StackTrace]) {
+        for trace in traces {
+            if !(trace.active) {
+                continue;
+            }
+
+            let mut seen = HashSet::new();
+            for (i, frame) in trace.frames.iter().enumerate() {
+                let line = if self.show_linenumbers { Some(frame.line) } else { None };
+                let key = Key{thread_id: trace.thread_id, name: frame.name.clone(), filename: frame.filename.clone(), line};
+                if !seen.insert(key.clone()) {
+                    continue;
+                }
+                let counts = self.rows.entry(key).or_default();
+                counts.total += 1;
+                if i == 0 {
+                    counts.own += 1;
+                }
+            }
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let mut rows: Vec<(&Key, &FunctionCounts)> = self.rows.iter().collect();
+        rows.sort_unstable_by(|a, b| a.0.thread_id.cmp(&b.0.thread_id)
+                                     .then(b.1.own.cmp(&a.1.own))
+                                     .then(b.1.total.cmp(&a.1.total))
+                                     .then(a.0.name.cmp(&b.0.name))
+                                     .then(a.0.filename.cmp(&b.0.filename))
+                                     .then(a.0.line.cmp(&b.0.line)));
+
+        writeln!(w, "function,filename,line,thread_id,self,cumulative")?;
+        for (key, counts) in rows {
+            let line = key.line.map(|l| l.to_string()).unwrap_or_default();
+            writeln!(w, "{},{},{},{:#X},{},{}", escape(&key.name), escape(&key.filename), line,
+                     key.thread_id, counts.own, counts.total)?;
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a field if it contains any characters that have special meaning in CSV
+fn escape(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("<module>"), "<module>");
+        assert_eq!(escape("C:\\a,b.py"), "\"C:\\a,b.py\"");
+        assert_eq!(escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
@@ -1,6 +1,7 @@
 
 use std;
 use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use regex::Regex;
 
 use failure::Error;
@@ -124,11 +125,27 @@ pub fn ignore_frame(name: &str) -> bool {
     ignorable.iter().any(|&f| f == name)
 }
 
+// prefixes that cython adds to the names of the C functions it generates
+const PREFIXES: [&str; 8] = ["__pyx_fuse_1_0__pyx_pw",  "__pyx_pf", "__pyx_pw", "__pyx_f", "___pyx_f", "___pyx_pw",
+                             "use_0__pyx_f", "use_1__pyx_f"];
+
+/// Checks if a native function was generated by cython, given its mangled name
+pub fn is_cython_function(name: &str) -> bool {
+    PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+/// Gets the filename of the .pyx file that an extension module was built from, given
+/// the filename of the module (like _als.cpython-37m-x86_64-linux-gnu.so for _als.pyx)
+pub fn module_source_file(module: &str) -> Option<String> {
+    let path = Path::new(module);
+    let name = path.file_name()?.to_str()?;
+    let stem = name.split('.').next().filter(|stem| !stem.is_empty())?;
+    Some(path.with_file_name(format!("{}.pyx", stem)).to_string_lossy().into_owned())
+}
+
 pub fn demangle(name: &str) -> &str {
     // slice off any leading cython prefix
-    let prefixes = ["__pyx_fuse_1_0__pyx_pw",  "__pyx_pf", "__pyx_pw", "__pyx_f", "___pyx_f", "___pyx_pw",
-                    "use_0__pyx_f", "use_1__pyx_f"];
-    let mut current = match prefixes.iter().find(|&prefix| name.starts_with(prefix)) {
+    let mut current = match PREFIXES.iter().find(|&prefix| name.starts_with(prefix)) {
         Some(prefix) => &name[prefix.len()..],
         None => return name
     };
@@ -184,6 +201,15 @@ mod tests {
         assert_eq!(demangle("use_1__pyx_f_8implicit_3bpr_has_non_zero"), "bpr_has_non_zero");
     }
 
+    #[test]
+    fn test_module_source_file() {
+        assert!(is_cython_function("__pyx_pw_8implicit_4_als_5least_squares_cg"));
+        assert!(!is_cython_function("PyEval_EvalFrameDefault"));
+        assert_eq!(module_source_file("/lib/implicit/_als.cpython-37m-x86_64-linux-gnu.so").as_deref(),
+                   Some("/lib/implicit/_als.pyx"));
+        assert_eq!(module_source_file("/lib/.so"), None);
+    }
+
     #[test]
     fn test_source_map() {
         let map = SourceMap::from_contents(include_str!("../ci/testdata/cython_test.c"), &None).unwrap();
@@ -0,0 +1,100 @@
+// Writes out dumps as JSON (with 'py-spy --dump --json'), so that scripts and incident tooling can
+// read the stacks of a process without having to parse the text that's printed for people
+use std::io::Write;
+
+use failure::Error;
+use remoteprocess::Pid;
+
+use crate::stack_trace::{ExceptionInfo, Frame, StackTrace};
+
+/// The stack traces of a single process (or core file)
+#[derive(Serialize)]
+pub struct ProcessDump<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<Pid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub core_file: Option<&'a str>,
+    pub exe: &'a str,
+    pub python_version: String,
+    /// When the dump was taken, for repeated dumps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    pub threads: Vec<ThreadDump<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct ThreadDump<'a> {
+    pub thread_id: u64,
+    pub os_thread_id: Option<u64>,
+    pub name: Option<&'a str>,
+    pub interpreter_id: i64,
+    /// The status shown in text dumps, like 'active+gil', 'idle' or 'waiting for gil'
+    pub status: &'a str,
+    pub active: bool,
+    pub owns_gil: bool,
+    /// Innermost frame first
+    pub frames: &'a [Frame],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exception: Option<&'a ExceptionInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub awaiting: Option<&'a str>,
+}
+
+impl<'a> ProcessDump<'a> {
+    pub fn new(pid: Option<Pid>, exe: &'a str, python_version: String, traces: &'a [StackTrace]) -> ProcessDump<'a> {
+        let threads = traces.iter().map(|trace| ThreadDump{
+            thread_id: trace.thread_id,
+            os_thread_id: trace.os_thread_id,
+            name: trace.thread_name.as_deref(),
+            interpreter_id: trace.interpreter_id,
+            status: trace.status_str(),
+            active: trace.active,
+            owns_gil: trace.owns_gil,
+            frames: &trace.frames,
+            exception: trace.exception.as_ref(),
+            awaiting: trace.awaiting.as_deref(),
+        }).collect();
+        ProcessDump{pid, core_file: None, exe, python_version, timestamp: None, threads}
+    }
+}
+
+/// Writes the dump of a single process as an object, or of several processes as an array of them.
+/// Repeated dumps are written on a single line each, so that they can be read as JSON lines
+pub fn write_dumps(w: &mut dyn Write, dumps: &[ProcessDump], pretty: bool) -> Result<(), Error> {
+    match (dumps, pretty) {
+        ([dump], true) => serde_json::to_writer_pretty(&mut *w, dump)?,
+        ([dump], false) => serde_json::to_writer(&mut *w, dump)?,
+        (dumps, true) => serde_json::to_writer_pretty(&mut *w, dumps)?,
+        (dumps, false) => serde_json::to_writer(&mut *w, dumps)?
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::LocalVariable;
+
+    #[test]
+    fn test_write_dumps() {
+        let frame = Frame{name: "handle".to_owned(), filename: "/srv/app/server.py".to_owned(), module: None,
+                          short_filename: Some("server.py".to_owned()), line: 12, frame_ptr: Some("0x1000".to_owned()),
+                          locals: Some(vec![LocalVariable{name: "request".to_owned(), arg: true, repr: "Request".to_owned()}])};
+        let trace = StackTrace{thread_id: 1, os_thread_id: Some(4242), thread_name: Some("MainThread".to_owned()),
+                               interpreter_id: 0, active: true, owns_gil: true, frames: vec![frame], exception: None,
+                               awaiting: None, pid: None};
+        let traces = vec![trace];
+        let dump = ProcessDump::new(Some(4242), "/usr/bin/python3.7", "3.7.3".to_owned(), &traces);
+
+        let mut out = Vec::new();
+        write_dumps(&mut out, &[dump], true).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["pid"], 4242);
+        assert_eq!(value["threads"][0]["status"], "active+gil");
+        assert_eq!(value["threads"][0]["frames"][0]["short_filename"], "server.py");
+        assert_eq!(value["threads"][0]["frames"][0]["locals"][0]["repr"], "Request");
+        assert!(value["threads"][0]["frames"][0].get("frame_ptr").is_none());
+        assert!(value["threads"][0].get("exception").is_none());
+    }
+}
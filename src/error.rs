@@ -0,0 +1,125 @@
+// The errors returned by the rust API, so that library users can match on why something failed
+// rather than digging through a chain of causes. Internally py-spy still uses failure::Error, which
+// is converted to one of these at the edges of the API
+use std::time::Duration;
+
+use remoteprocess::{Pid, Process};
+
+/// Why using py-spy as a library failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// Not allowed to read the memory of the process, which usually needs root (or the SYS_PTRACE
+    /// capability in a container)
+    PermissionDenied,
+    /// The process exited, or there isn't a process with that pid
+    ProcessExited,
+    /// The process is running a version of python that py-spy doesn't support
+    UnsupportedPythonVersion{found: String},
+    /// Copying memory from the process at this address failed, usually because the interpreter
+    /// changed it while it was being read
+    #[allow(clippy::enum_variant_names)]
+    MemoryReadError{addr: usize},
+    /// A sample was abandoned because it would have paused the process for longer than max_pause
+    PauseTimeExceeded{max_pause: Duration},
+    /// Options that can't be used, or can't be used together
+    InvalidConfig(String),
+    /// Anything else, with a description of what went wrong and why
+    Other(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::PermissionDenied => write!(f, "Permission denied reading the memory of the process"),
+            Error::ProcessExited => write!(f, "The process isn't running (it may have exited)"),
+            Error::UnsupportedPythonVersion{found} => write!(f, "Unsupported version of Python: {}", found),
+            Error::MemoryReadError{addr} => write!(f, "Failed to read memory at 0x{:016x}", addr),
+            Error::PauseTimeExceeded{max_pause} => {
+                write!(f, "Sample abandoned after pausing the process for more than {:?}", max_pause)
+            },
+            Error::InvalidConfig(message) | Error::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Converts an error from reading a process, which is usually because the process exited
+    pub(crate) fn from_process(err: failure::Error, pid: Pid) -> Error {
+        match Error::from(err) {
+            Error::Other(_) | Error::MemoryReadError{..} if exited(pid) => Error::ProcessExited,
+            err => err
+        }
+    }
+
+    /// Converts an error from parsing the options, which are all down to invalid values
+    pub(crate) fn invalid_config(err: failure::Error) -> Error {
+        match Error::from(err) {
+            Error::Other(message) => Error::InvalidConfig(message),
+            err => err
+        }
+    }
+}
+
+impl From<failure::Error> for Error {
+    fn from(err: failure::Error) -> Error {
+        for cause in err.iter_chain() {
+            if let Some(err) = cause.downcast_ref::<Error>() {
+                return err.clone();
+            }
+            if let Some(err) = cause.downcast_ref::<remoteprocess::Error>().and_then(classify) {
+                return err;
+            }
+            if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+                if err.kind() == std::io::ErrorKind::PermissionDenied {
+                    return Error::PermissionDenied;
+                }
+            }
+        }
+        let causes: Vec<String> = err.iter_chain().map(|cause| cause.to_string()).collect();
+        Error::Other(causes.join(": "))
+    }
+}
+
+impl From<remoteprocess::Error> for Error {
+    fn from(err: remoteprocess::Error) -> Error {
+        classify(&err).unwrap_or_else(|| Error::Other(err.to_string()))
+    }
+}
+
+// the errors from remoteprocess that have a variant of their own
+fn classify(err: &remoteprocess::Error) -> Option<Error> {
+    match err {
+        remoteprocess::Error::IOError(e) | remoteprocess::Error::MemoryReadError(_, e)
+            if e.kind() == std::io::ErrorKind::PermissionDenied => Some(Error::PermissionDenied),
+        remoteprocess::Error::MemoryReadError(addr, _) => Some(Error::MemoryReadError{addr: *addr}),
+        _ => None
+    }
+}
+
+fn exited(pid: Pid) -> bool {
+    Process::new(pid).and_then(|process| process.exe()).is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_failure() {
+        let err = Error::from(failure::Error::from(Error::UnsupportedPythonVersion{found: "4.0".to_owned()}));
+        assert_eq!(err, Error::UnsupportedPythonVersion{found: "4.0".to_owned()});
+
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let err = failure::Error::from(remoteprocess::Error::MemoryReadError(0x10, denied)).context("Failed to copy");
+        assert_eq!(Error::from(failure::Error::from(err)), Error::PermissionDenied);
+
+        let missing = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        let err = failure::Error::from(remoteprocess::Error::MemoryReadError(0x10, missing)).context("Failed to copy");
+        assert_eq!(Error::from(failure::Error::from(err)), Error::MemoryReadError{addr: 0x10});
+
+        let err = failure::Error::from(format_err!("no interpreter").context("Failed to attach"));
+        assert_eq!(Error::from(err), Error::Other("Failed to attach: no interpreter".to_owned()));
+    }
+}
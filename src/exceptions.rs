@@ -0,0 +1,111 @@
+// Gets the exception that a thread is handling, so that a thread stuck in a retry loop (or
+// blocked in a finally block) can be diagnosed from a dump. This is what sys.exc_info() would
+// return in the thread: the exception caught by the innermost except block that is running.
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::locals::{format_value, object_type_name, tuple_items};
+use crate::python_interpreters::{CodeObject, FrameObject, ThreadState};
+use crate::stack_trace::{ExceptionInfo, get_frame};
+use crate::version::Version;
+
+// number of arguments of the exception to describe
+const MAX_ARGS: usize = 3;
+
+// BaseException starts with these fields in every python version
+#[repr(C)]
+struct BaseExceptionObject {
+    ob_refcnt: isize,
+    ob_type: usize,
+    dict: usize,
+    args: usize,
+}
+
+#[repr(C)]
+struct TracebackObject {
+    ob_refcnt: isize,
+    ob_type: usize,
+    tb_next: usize,
+    tb_frame: usize,
+    tb_lasti: i32,
+    tb_lineno: i32,
+}
+
+/// Gets the exception being handled by a thread, given the thread state and its address
+pub fn get_exception<T, P>(thread: &T, thread_address: usize, process: &P, version: &Version) -> Result<Option<ExceptionInfo>, Error>
+        where T: ThreadState, P: ProcessMemory {
+    let (_exc_type, exc_value, exc_traceback): (usize, usize, usize) = process.copy_struct(thread.exc_info(thread_address))
+        .context("Failed to copy exception info")?;
+    if exc_value == 0 {
+        return Ok(None);
+    }
+    let type_name = object_type_name(process, exc_value)?;
+    if type_name == "NoneType" {
+        return Ok(None);
+    }
+
+    type StringObject<T> = <<<T as ThreadState>::FrameObject as FrameObject>::CodeObject as CodeObject>::StringObject;
+    let exception: BaseExceptionObject = process.copy_struct(exc_value).context("Failed to copy exception")?;
+    let args = tuple_items(process, exception.args)?.into_iter().take(MAX_ARGS)
+        .map(|arg| format_value::<StringObject<T>, P>(process, arg, version).unwrap_or_else(|_| "?".to_owned()))
+        .collect();
+
+    // the traceback starts at the frame that caught the exception, and ends where it was raised
+    let mut traceback = Vec::new();
+    let mut tb = exc_traceback;
+    while tb != 0 {
+        let entry: TracebackObject = process.copy_struct(tb).context("Failed to copy traceback")?;
+        let frame_ptr = entry.tb_frame as *mut T::FrameObject;
+        let frame: T::FrameObject = process.copy_pointer(frame_ptr).context("Failed to copy traceback frame")?;
+        let mut frame = get_frame(frame_ptr, &frame, process)?;
+        frame.line = entry.tb_lineno;
+        traceback.push(frame);
+        if traceback.len() > 4096 {
+            return Err(format_err!("Max traceback depth reached"));
+        }
+        tb = entry.tb_next;
+    }
+    traceback.reverse();
+    Ok(Some(ExceptionInfo{type_name, args, traceback}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+    use crate::python_bindings::v3_7_0::{PyObject, PyThreadState, _PyErr_StackItem, _typeobject};
+
+    #[repr(C)]
+    struct Tuple {
+        ob_refcnt: isize,
+        ob_type: usize,
+        ob_size: isize,
+        items: [usize; 1],
+    }
+
+    #[test]
+    fn test_get_exception() {
+        let none_name = b"NoneType\0";
+        let mut none_type = _typeobject{tp_name: none_name.as_ptr() as *const _, ..Default::default()};
+        let none = PyObject{ob_refcnt: 1, ob_type: &mut none_type};
+        let error_name = b"ValueError\0";
+        let mut error_type = _typeobject{tp_name: error_name.as_ptr() as *const _, ..Default::default()};
+
+        let args = Tuple{ob_refcnt: 1, ob_type: 0, ob_size: 1, items: [&none as *const PyObject as usize]};
+        let error = BaseExceptionObject{ob_refcnt: 1, ob_type: &mut error_type as *mut _typeobject as usize, dict: 0,
+                                        args: &args as *const Tuple as usize};
+        let mut exc_info = _PyErr_StackItem{exc_value: &error as *const BaseExceptionObject as *mut _, ..Default::default()};
+        let thread = PyThreadState{exc_info: &mut exc_info, ..Default::default()};
+        let version = Version{major: 3, minor: 7, patch: 0, release_flags: "".to_owned()};
+
+        let exception = get_exception(&thread, 0, &LocalProcess, &version).unwrap().unwrap();
+        assert_eq!(exception.type_name, "ValueError");
+        assert_eq!(exception.args, vec!["None"]);
+        assert!(exception.traceback.is_empty());
+
+        // no exception is stored as None in python 3.7
+        exc_info.exc_value = &none as *const PyObject as *mut _;
+        let thread = PyThreadState{exc_info: &mut exc_info, ..Default::default()};
+        assert!(get_exception(&thread, 0, &LocalProcess, &version).unwrap().is_none());
+    }
+}
@@ -0,0 +1,129 @@
+// Expands placeholders in output filenames, so that automated profiling jobs can generate unique
+// names like 'profile-{pid}-{timestamp}.svg' without needing a wrapper script
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct FilenameTemplate<'a> {
+    pub pid: Option<remoteprocess::Pid>,
+    pub exe: Option<&'a str>,
+    pub format: &'a str,
+    pub time: SystemTime,
+}
+
+impl<'a> FilenameTemplate<'a> {
+    /// Replaces {pid}, {exe}, {timestamp} and {format} in the filename. Any placeholders that
+    /// aren't recognized (or aren't known, like the pid when converting a file) are left as is.
+    pub fn expand(&self, filename: &str) -> String {
+        let mut ret = String::with_capacity(filename.len());
+        let mut rest = filename;
+        while let Some(start) = rest.find('{') {
+            ret.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break
+            };
+            match self.value(&rest[1..end]) {
+                Some(value) => ret.push_str(&value),
+                None => ret.push_str(&rest[..=end])
+            }
+            rest = &rest[end + 1..];
+        }
+        ret.push_str(rest);
+        ret
+    }
+
+    fn value(&self, placeholder: &str) -> Option<String> {
+        match placeholder {
+            "pid" => self.pid.map(|pid| pid.to_string()),
+            "exe" => self.exe.map(|exe| {
+                Path::new(exe).file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_else(|| exe.to_owned())
+            }),
+            "format" => Some(self.format.to_owned()),
+            "timestamp" => Some(format_timestamp(self.time)),
+            _ => None
+        }
+    }
+}
+
+/// Adds a placeholder like {timestamp} to the end of the file name, before its extension, for
+/// filenames that get written to more than once. Filenames that already have it are left as is.
+pub fn with_placeholder(filename: &str, placeholder: &str) -> String {
+    let placeholder = format!("{{{}}}", placeholder);
+    if filename.contains(&placeholder) {
+        return filename.to_owned();
+    }
+    // only the name of the file is changed, not any dots in the directories it's in
+    let path = Path::new(filename);
+    let name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!("{}-{}.{}", stem.to_string_lossy(), placeholder, extension.to_string_lossy()),
+        (Some(stem), None) => format!("{}-{}", stem.to_string_lossy(), placeholder),
+        (None, _) => return format!("{}-{}", filename, placeholder)
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Formats a time as YYYYMMDD-HHMMSS in UTC
+fn format_timestamp(time: SystemTime) -> String {
+    let (year, month, day, secs) = civil_time(time);
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+/// Formats a time as 'YYYY-MM-DD HH:MM:SS UTC', for showing to people
+pub fn format_time(time: SystemTime) -> String {
+    let (year, month, day, secs) = civil_time(time);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+// gets the year, month, day and seconds into the day of a time in UTC
+fn civil_time(time: SystemTime) -> (i64, i64, i64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // convert days since the epoch to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_expand() {
+        let template = FilenameTemplate{pid: Some(1234), exe: Some("/usr/bin/python3.7"), format: "svg",
+                                        time: UNIX_EPOCH + Duration::from_secs(1_570_000_000)};
+        assert_eq!(template.expand("{exe}-{pid}-{timestamp}.{format}"), "python3.7-1234-20191002-070640.svg");
+        assert_eq!(template.expand("profile.svg"), "profile.svg");
+        assert_eq!(template.expand("{unknown}-{pid"), "{unknown}-{pid");
+
+        let template = FilenameTemplate{pid: None, exe: None, format: "svg", time: UNIX_EPOCH};
+        assert_eq!(template.expand("{pid}-{timestamp}"), "{pid}-19700101-000000");
+    }
+
+    #[test]
+    fn test_with_placeholder() {
+        assert_eq!(with_placeholder("profile.svg", "timestamp"), "profile-{timestamp}.svg");
+        assert_eq!(with_placeholder("profile", "timestamp"), "profile-{timestamp}");
+        assert_eq!(with_placeholder("./profiles/app", "timestamp"), "./profiles/app-{timestamp}");
+        assert_eq!(with_placeholder("out.d/profile", "timestamp"), "out.d/profile-{timestamp}");
+        assert_eq!(with_placeholder("out.d/profile.raw", "timestamp"), "out.d/profile-{timestamp}.raw");
+        assert_eq!(with_placeholder("{timestamp}-profile.svg", "timestamp"), "{timestamp}-profile.svg");
+        assert_eq!(with_placeholder("./profiles/app.svg", "pid"), "./profiles/app-{pid}.svg");
+        assert_eq!(with_placeholder("profile-{pid}.svg", "pid"), "profile-{pid}.svg");
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_secs(1_570_000_000)), "2019-10-02 07:06:40 UTC");
+    }
+}
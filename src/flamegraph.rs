@@ -28,56 +28,153 @@ SOFTWARE.
 
 use std;
 use std::collections::HashMap;
-use std::fs::File;
+use std::io::Write;
 
 
 use failure::Error;
 use inferno::flamegraph::{Direction, Options};
 
+use crate::labels::Labels;
 use crate::stack_trace::StackTrace;
 
 pub struct Flamegraph {
     pub counts: HashMap<String, usize>,
     pub show_linenumbers: bool,
+    /// Merge stacks from the leaf upwards, showing the callers of the hottest functions
+    pub reverse: bool,
+    /// What the counts are of, shown when hovering over the flamegraph
+    pub count_name: String,
+    /// Frames with less than this percent of the total are left out, with --min-percent
+    pub min_percent: Option<f64>,
+    /// Labels given with --label, shown under the title
+    pub labels: Labels,
 }
 
 impl Flamegraph {
-    pub fn new(show_linenumbers: bool) -> Flamegraph {
-        Flamegraph { counts: HashMap::new(), show_linenumbers }
+    pub fn new(show_linenumbers: bool, reverse: bool) -> Flamegraph {
+        Flamegraph { counts: HashMap::new(), show_linenumbers, reverse, count_name: "samples".to_owned(), min_percent: None,
+                     labels: Labels::default() }
     }
 
     pub fn increment(&mut self, traces: &[StackTrace]) -> std::io::Result<()> {
         for trace in traces {
-            if !(trace.active) {
-                continue;
-            }
+            self.add(trace, 1);
+        }
+        Ok(())
+    }
 
-            // convert the frame into a single ';' delimited String
-            let frame = trace.frames.iter().rev().map(|frame| {
-                let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
-                if self.show_linenumbers && frame.line != 0 {
-                    format!("{} ({}:{})", frame.name, filename, frame.line)
-                } else {
-                    format!("{} ({})", frame.name, filename)
-                }
-            }).collect::<Vec<String>>().join(";");
-
-            // update counts for that frame
-            *self.counts.entry(frame).or_insert(0) += 1;
+    /// Adds traces that are weighted by something other than the number of samples, like bytes allocated
+    pub fn increment_weighted(&mut self, traces: &[(StackTrace, u64)]) -> std::io::Result<()> {
+        for (trace, weight) in traces {
+            self.add(trace, *weight as usize);
         }
         Ok(())
     }
 
-    pub fn write(&self, w: File) -> Result<(), Error> {
-        let lines: Vec<String> = self.counts.iter().map(|(k, v)| format!("{} {}", k, v)).collect();
+    fn add(&mut self, trace: &StackTrace, weight: usize) {
+        if !(trace.active) {
+            return;
+        }
+
+        // convert the frame into a single ';' delimited String, ordered from the root
+        // of the stack (or from the leaf when reversed)
+        let frames: Box<dyn Iterator<Item=_>> = if self.reverse {
+            Box::new(trace.frames.iter())
+        } else {
+            Box::new(trace.frames.iter().rev())
+        };
+        let frame = frames.map(|frame| {
+            let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
+            if self.show_linenumbers && frame.line != 0 {
+                format!("{} ({}:{})", frame.name, filename, frame.line)
+            } else {
+                format!("{} ({})", frame.name, filename)
+            }
+        }).collect::<Vec<String>>().join(";");
+
+        // update counts for that frame
+        *self.counts.entry(frame).or_insert(0) += weight;
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let lines = self.get_lines();
+        if lines.is_empty() && !self.counts.is_empty() {
+            return Err(format_err!("Every stack had less than --min-percent of the samples, so there's nothing to show"));
+        }
         let mut opts =  Options {
             direction: Direction::Inverted,
             min_width: 1.0,
-            title: "py-spy".to_owned(),
+            title: if self.reverse { "py-spy (reversed)".to_owned() } else { "py-spy".to_owned() },
+            count_name: self.count_name.clone(),
+            subtitle: if self.labels.is_empty() { None } else { Some(self.labels.to_string()) },
             ..Default::default()
         };
 
         inferno::flamegraph::from_lines(&mut opts, lines.iter().map(|x| x.as_str()), w).unwrap();
         Ok(())
     }
+
+    /// Writes out the collapsed stacks, in the same 'folded' format used by the perl flamegraph scripts
+    pub fn write_folded(&self, w: &mut dyn Write) -> Result<(), Error> {
+        for line in self.get_lines() {
+            writeln!(w, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn get_lines(&self) -> Vec<String> {
+        match self.min_percent {
+            Some(min_percent) => prune(&self.counts, min_percent).iter().map(|(k, v)| format!("{} {}", k, v)).collect(),
+            None => self.counts.iter().map(|(k, v)| format!("{} {}", k, v)).collect()
+        }
+    }
+}
+
+// cuts each stack off at the first frame that has less than 'min_percent' of the total, so that the
+// time below it goes to its parent. Stacks whose root is below the threshold are left out entirely
+fn prune(counts: &HashMap<String, usize>, min_percent: f64) -> HashMap<String, usize> {
+    let total: usize = counts.values().sum();
+    let threshold = total as f64 * min_percent / 100.0;
+
+    let mut prefix_counts: HashMap<&str, usize> = HashMap::new();
+    for (stack, &count) in counts {
+        for end in prefixes(stack) {
+            *prefix_counts.entry(&stack[..end]).or_insert(0) += count;
+        }
+    }
+
+    let mut pruned = HashMap::new();
+    for (stack, &count) in counts {
+        let mut kept = None;
+        for end in prefixes(stack) {
+            if (prefix_counts[&stack[..end]] as f64) < threshold {
+                break;
+            }
+            kept = Some(&stack[..end]);
+        }
+        if let Some(kept) = kept {
+            *pruned.entry(kept.to_owned()).or_insert(0) += count;
+        }
+    }
+    pruned
+}
+
+// where each frame of a ';' delimited stack ends, from the root
+fn prefixes(stack: &str) -> impl Iterator<Item=usize> + '_ {
+    stack.match_indices(';').map(|(i, _)| i).chain(std::iter::once(stack.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        let counts: HashMap<String, usize> = [("main;handle;query", 60), ("main;handle;render", 5), ("main;log", 30), ("gc", 5)]
+            .iter().map(|&(stack, count)| (stack.to_owned(), count)).collect();
+        let pruned = prune(&counts, 10.0);
+        let mut pruned: Vec<(&str, usize)> = pruned.iter().map(|(stack, &count)| (stack.as_str(), count)).collect();
+        pruned.sort();
+        assert_eq!(pruned, vec![("main;handle", 5), ("main;handle;query", 60), ("main;log", 30)]);
+    }
 }
@@ -0,0 +1,141 @@
+// Leaves frames out of stack traces with --include-frames, --exclude-frames and --exclude-stdlib, so
+// that recordings, the top view and dumps can be scoped to the application's own code. The time spent
+// in frames that are left out goes to the closest caller that's kept. --trim-roots and --max-depth
+// then cut down the stacks of deep frameworks from either end.
+use failure::Error;
+use regex::Regex;
+
+use crate::config::Config;
+use crate::stack_trace::{Frame, StackTrace};
+
+pub struct FrameFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+    exclude_stdlib: bool,
+    stdlib: Regex,
+    trim_roots: Option<Regex>,
+    max_depth: Option<usize>,
+}
+
+impl FrameFilter {
+    /// Creates the filter for the frame filtering options, or None when there aren't any
+    pub fn new(config: &Config) -> Result<Option<FrameFilter>, Error> {
+        if config.include_frames.is_none() && config.exclude_frames.is_none() && !config.exclude_stdlib &&
+                config.trim_roots.is_none() && config.max_depth.is_none() {
+            return Ok(None);
+        }
+        let include = config.include_frames.as_deref().map(Regex::new).transpose()?;
+        let exclude = config.exclude_frames.as_deref().map(Regex::new).transpose()?;
+        let trim_roots = config.trim_roots.as_deref().map(Regex::new).transpose()?;
+        // lib/python3.7/ on unix and \Lib\ on windows, but not the site-packages inside them
+        let stdlib = Regex::new(r"(/lib(64)?/(python|pypy)[\d.]*/|(?i)\\lib\\)")?;
+        Ok(Some(FrameFilter{include, exclude, exclude_stdlib: config.exclude_stdlib, stdlib, trim_roots,
+                            max_depth: config.max_depth}))
+    }
+
+    /// Whether a frame is kept. The regexes are matched against the function name and filename,
+    /// like 'handle_request (/srv/app/server.py)'
+    pub fn keep(&self, frame: &Frame) -> bool {
+        if self.exclude_stdlib && self.is_stdlib(&frame.filename) {
+            return false;
+        }
+        let name = matched_name(frame);
+        self.include.as_ref().is_none_or(|include| include.is_match(&name)) &&
+            self.exclude.as_ref().is_none_or(|exclude| !exclude.is_match(&name))
+    }
+
+    fn is_stdlib(&self, filename: &str) -> bool {
+        if filename.starts_with("<frozen ") {
+            return true;
+        }
+        self.stdlib.is_match(filename) && !filename.contains("site-packages") && !filename.contains("dist-packages")
+    }
+
+    pub fn filter_frames(&self, trace: &mut StackTrace) {
+        trace.frames.retain(|frame| self.keep(frame));
+
+        // frames are ordered from the leaf, so the roots are at the end
+        if let Some(trim_roots) = self.trim_roots.as_ref() {
+            while trace.frames.last().is_some_and(|frame| trim_roots.is_match(&matched_name(frame))) {
+                trace.frames.pop();
+            }
+        }
+        if let Some(max_depth) = self.max_depth {
+            let deeper = trace.frames.len().saturating_sub(max_depth);
+            trace.frames.drain(..deeper);
+        }
+    }
+
+    /// Filters the frames of each trace, leaving out the traces that have no frames left
+    pub fn filter_traces(&self, traces: &mut Vec<StackTrace>) {
+        traces.retain_mut(|trace| {
+            let had_frames = !trace.frames.is_empty();
+            self.filter_frames(trace);
+            !had_frames || !trace.frames.is_empty()
+        });
+    }
+}
+
+// what the regexes are matched against
+fn matched_name(frame: &Frame) -> String {
+    format!("{} ({})", frame.name, frame.filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, filename: &str) -> Frame {
+        Frame{name: name.to_owned(), filename: filename.to_owned(), module: None, short_filename: None, line: 1,
+              frame_ptr: None, locals: None}
+    }
+
+    fn trace(frames: Vec<Frame>) -> StackTrace {
+        StackTrace{thread_id: 1, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true, owns_gil: false,
+                   frames, exception: None, awaiting: None, pid: None}
+    }
+
+    #[test]
+    fn test_keep() {
+        let config = Config{exclude_stdlib: true, exclude_frames: Some("^_".to_owned()), ..Default::default()};
+        let filter = FrameFilter::new(&config).unwrap().unwrap();
+        assert!(filter.keep(&frame("handle", "/srv/app/server.py")));
+        assert!(!filter.keep(&frame("_private", "/srv/app/server.py")));
+        assert!(!filter.keep(&frame("wait", "/usr/lib/python3.7/threading.py")));
+        assert!(!filter.keep(&frame("_find_and_load", "<frozen importlib._bootstrap>")));
+        assert!(!filter.keep(&frame("recv", "C:\\Python37\\Lib\\socket.py")));
+        assert!(filter.keep(&frame("get", "/usr/lib/python3.7/site-packages/requests/api.py")));
+
+        let config = Config{include_frames: Some("/srv/app/".to_owned()), ..Default::default()};
+        let filter = FrameFilter::new(&config).unwrap().unwrap();
+        assert!(filter.keep(&frame("handle", "/srv/app/server.py")));
+        assert!(!filter.keep(&frame("get", "/usr/lib/python3.7/site-packages/requests/api.py")));
+        assert!(FrameFilter::new(&Config::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_filter_traces() {
+        let config = Config{include_frames: Some("/srv/app/".to_owned()), ..Default::default()};
+        let filter = FrameFilter::new(&config).unwrap().unwrap();
+        let mut traces = vec![trace(vec![frame("recv", "/usr/lib/python3.7/socket.py"), frame("handle", "/srv/app/server.py")]),
+                              trace(vec![frame("wait", "/usr/lib/python3.7/threading.py")]),
+                              trace(Vec::new())];
+        filter.filter_traces(&mut traces);
+        assert_eq!(traces.len(), 2);
+        assert_eq!(traces[0].frames.len(), 1);
+        assert_eq!(traces[0].frames[0].name, "handle");
+        assert!(traces[1].frames.is_empty());
+    }
+
+    #[test]
+    fn test_trim() {
+        let config = Config{trim_roots: Some("runpy|wsgi".to_owned()), max_depth: Some(2), ..Default::default()};
+        let filter = FrameFilter::new(&config).unwrap().unwrap();
+        let mut trace = trace(vec![frame("query", "/srv/app/db.py"), frame("handle", "/srv/app/views.py"),
+                                   frame("dispatch", "/srv/app/urls.py"), frame("__call__", "/venv/django/wsgi.py"),
+                                   frame("_run_code", "/usr/lib/python3.7/runpy.py")]);
+        filter.filter_frames(&mut trace);
+        let names: Vec<&str> = trace.frames.iter().map(|frame| frame.name.as_str()).collect();
+        assert_eq!(names, vec!["handle", "dispatch"]);
+    }
+}
@@ -0,0 +1,133 @@
+// Aggregates how much time is spent in each function, shared by the different text based reports.
+use std::collections::{HashMap, HashSet};
+
+use crate::stack_trace::{StackTrace, Frame};
+
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FunctionCounts {
+    /// Number of samples where this function was at the top of the stack
+    pub own: u64,
+    /// Number of samples where this function was anywhere in the stack
+    pub total: u64,
+}
+
+/// A function with its own sample count, and the own sample counts of its hottest lines
+pub type FunctionLines<'a> = (&'a str, u64, Vec<(i32, u64)>);
+
+pub struct FunctionStats {
+    pub functions: HashMap<String, FunctionCounts>,
+    // own samples of each line, keyed by function. Only kept when showing line numbers
+    lines: HashMap<String, HashMap<i32, u64>>,
+    show_linenumbers: bool,
+}
+
+impl FunctionStats {
+    pub fn new(show_linenumbers: bool) -> FunctionStats {
+        FunctionStats{functions: HashMap::new(), lines: HashMap::new(), show_linenumbers}
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) {
+        // deduplicate function names, so that recursive calls don't inflate the total
+        let mut seen = HashSet::new();
+        for (i, frame) in trace.frames.iter().enumerate() {
+            let key = self.function_key(frame);
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let counts = self.functions.entry(key).or_default();
+            counts.total += 1;
+            if i == 0 {
+                counts.own += 1;
+            }
+        }
+
+        if let Some(frame) = trace.frames.first() {
+            if self.show_linenumbers && frame.line != 0 {
+                *self.lines.entry(function_name(frame)).or_default().entry(frame.line).or_default() += 1;
+            }
+        }
+    }
+
+    /// Returns the functions with the most own time, along with the lines in each of them that
+    /// the most samples landed on. Empty unless line numbers are being shown
+    pub fn hottest_lines(&self, functions: usize, lines: usize) -> Vec<FunctionLines<'_>> {
+        let mut hottest: Vec<FunctionLines> = self.lines.iter().map(|(name, counts)| {
+            let mut counts: Vec<(i32, u64)> = counts.iter().map(|(&line, &count)| (line, count)).collect();
+            counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            let own = counts.iter().map(|(_, count)| count).sum();
+            counts.truncate(lines);
+            (name.as_str(), own, counts)
+        }).collect();
+        hottest.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        hottest.truncate(functions);
+        hottest
+    }
+
+    /// Returns the functions with the most own time (breaking ties with total time)
+    pub fn top_own(&self, count: usize) -> Vec<(&str, FunctionCounts)> {
+        let mut functions: Vec<(&str, FunctionCounts)> = self.functions.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+        functions.sort_unstable_by(|a, b| b.1.own.cmp(&a.1.own).then(b.1.total.cmp(&a.1.total)).then(a.0.cmp(b.0)));
+        functions.truncate(count);
+        functions
+    }
+
+    /// Returns the functions with the most total time (breaking ties with own time)
+    pub fn top_total(&self, count: usize) -> Vec<(&str, FunctionCounts)> {
+        let mut functions: Vec<(&str, FunctionCounts)> = self.functions.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+        functions.sort_unstable_by(|a, b| b.1.total.cmp(&a.1.total).then(b.1.own.cmp(&a.1.own)).then(a.0.cmp(b.0)));
+        functions.truncate(count);
+        functions
+    }
+
+    fn function_key(&self, frame: &Frame) -> String {
+        if self.show_linenumbers && frame.line != 0 {
+            let filename = match &frame.short_filename { Some(f) => f, None => &frame.filename };
+            format!("{} ({}:{})", frame.name, filename, frame.line)
+        } else {
+            function_name(frame)
+        }
+    }
+}
+
+fn function_name(frame: &Frame) -> String {
+    let filename = match &frame.short_filename { Some(f) => f, None => &frame.filename };
+    format!("{} ({})", frame.name, filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str) -> Frame {
+        Frame{name: name.to_owned(), filename: "test.py".to_owned(), module: None,
+              short_filename: None, line: 1, frame_ptr: None, locals: None}
+    }
+
+    #[test]
+    fn test_recursive_totals() {
+        let trace = StackTrace{thread_id: 1, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true, owns_gil: false,
+                               frames: vec![frame("fib"), frame("fib"), frame("fib"), frame("main")], exception: None, awaiting: None, pid: None};
+        let mut stats = FunctionStats::new(false);
+        stats.increment(&trace);
+        stats.increment(&trace);
+
+        assert_eq!(stats.top_own(1), vec![("fib (test.py)", FunctionCounts{own: 2, total: 2})]);
+        let totals = stats.top_total(2);
+        assert_eq!(totals[1], ("main (test.py)", FunctionCounts{own: 0, total: 2}));
+        assert!(stats.hottest_lines(10, 10).is_empty());
+    }
+
+    #[test]
+    fn test_hottest_lines() {
+        let mut stats = FunctionStats::new(true);
+        for &(name, line) in &[("fib", 3), ("fib", 5), ("fib", 3), ("main", 9)] {
+            let mut top = frame(name);
+            top.line = line;
+            stats.increment(&StackTrace{thread_id: 1, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true,
+                                        owns_gil: false, frames: vec![top, frame("main")], exception: None, awaiting: None, pid: None});
+        }
+
+        assert_eq!(stats.top_own(1), vec![("fib (test.py:3)", FunctionCounts{own: 2, total: 2})]);
+        assert_eq!(stats.hottest_lines(2, 1), vec![("fib (test.py)", 3, vec![(3, 2)]), ("main (test.py)", 1, vec![(9, 1)])]);
+    }
+}
@@ -0,0 +1,134 @@
+// Evaluates the --assert assertions over a finished recording, and reports on them in JSON so that CI
+// jobs using py-spy as a performance gate can show what regressed
+use std::collections::HashMap;
+
+use crate::assertion::{Assertion, Metric};
+use crate::stack_trace::{Frame, StackTrace};
+
+/// The value of an assertion's metric over the recording, and whether it held. The value is None
+/// when there was nothing sampled to measure a percentage of, which counts as a failure
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AssertionResult {
+    pub assertion: String,
+    pub value: Option<f64>,
+    pub passed: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub passed: bool,
+    pub samples: u64,
+    pub assertions: Vec<AssertionResult>,
+}
+
+/// Counts what the assertions measure as samples are recorded
+pub struct AssertionStats {
+    assertions: Vec<Assertion>,
+    /// Own and total counts of the thread stacks matching each assertion's function
+    counts: HashMap<String, (u64, u64)>,
+    samples: u64,
+    stacks: u64,
+    gil_samples: u64,
+}
+
+impl AssertionStats {
+    pub fn new(assertions: &[Assertion]) -> AssertionStats {
+        let mut counts = HashMap::new();
+        for assertion in assertions {
+            if let Metric::SelfPct(function) | Metric::TotalPct(function) = &assertion.metric {
+                counts.insert(function.clone(), (0, 0));
+            }
+        }
+        AssertionStats{assertions: assertions.to_vec(), counts, samples: 0, stacks: 0, gil_samples: 0}
+    }
+
+    pub fn increment(&mut self, traces: &[StackTrace]) {
+        self.samples += 1;
+        self.stacks += traces.len() as u64;
+        self.gil_samples += traces.iter().any(|trace| trace.owns_gil) as u64;
+        for (function, (own, total)) in self.counts.iter_mut() {
+            for trace in traces {
+                if trace.frames.first().is_some_and(|frame| matches(function, frame)) {
+                    *own += 1;
+                }
+                if trace.frames.iter().any(|frame| matches(function, frame)) {
+                    *total += 1;
+                }
+            }
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let percent = |count: u64, of: u64| if of == 0 { None } else { Some(100.0 * count as f64 / of as f64) };
+        let assertions: Vec<AssertionResult> = self.assertions.iter().map(|assertion| {
+            let value = match &assertion.metric {
+                Metric::SelfPct(function) => percent(self.counts[function].0, self.stacks),
+                Metric::TotalPct(function) => percent(self.counts[function].1, self.stacks),
+                Metric::GilPct => percent(self.gil_samples, self.samples),
+                Metric::Samples => Some(self.samples as f64),
+            };
+            AssertionResult{assertion: assertion.to_string(), value, passed: value.is_some_and(|value| assertion.holds(value))}
+        }).collect();
+        Report{passed: assertions.iter().all(|result| result.passed), samples: self.samples, assertions}
+    }
+}
+
+// whether a frame is the function an assertion names, either by its name alone ('slow_fn' or
+// 'Class.method') or qualified by the python module it's in ('my.module.slow_fn')
+fn matches(function: &str, frame: &Frame) -> bool {
+    if function == frame.name {
+        return true;
+    }
+    let module = match function.strip_suffix(frame.name.as_str()).and_then(|module| module.strip_suffix('.')) {
+        Some(module) => module,
+        None => return false
+    };
+    let filename = frame.filename.trim_end_matches(".py").trim_end_matches("/__init__");
+    let path = module.replace('.', "/");
+    filename == path || filename.ends_with(&format!("/{}", path)) || filename.ends_with(&format!("\\{}", module.replace('.', "\\")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(frames: &[(&str, &str)], owns_gil: bool) -> StackTrace {
+        let frames = frames.iter().map(|&(name, filename)| {
+            Frame{name: name.to_owned(), filename: filename.to_owned(), module: None, short_filename: None, line: 1,
+                  frame_ptr: None, locals: None}
+        }).collect();
+        StackTrace{thread_id: 1, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true, owns_gil,
+                   frames, exception: None, awaiting: None, pid: None}
+    }
+
+    #[test]
+    fn test_matches() {
+        let frame = trace(&[("slow_fn", "/srv/app/my/module.py")], false).frames.remove(0);
+        assert!(matches("slow_fn", &frame));
+        assert!(matches("my.module.slow_fn", &frame));
+        assert!(matches("module.slow_fn", &frame));
+        assert!(!matches("other.module.slow_fn", &frame));
+        assert!(!matches("y.module.slow_fn", &frame));
+        let frame = trace(&[("run", "/srv/app/my/__init__.py")], false).frames.remove(0);
+        assert!(matches("my.run", &frame));
+    }
+
+    #[test]
+    fn test_report() {
+        let assertions: Vec<Assertion> = ["self_pct(my.module.slow_fn) < 5", "total_pct(handle) < 80", "gil_pct > 0", "samples >= 3"]
+            .iter().map(|assertion| Assertion::parse(assertion).unwrap()).collect();
+        let mut stats = AssertionStats::new(&assertions);
+        stats.increment(&[trace(&[("slow_fn", "/srv/my/module.py"), ("handle", "/srv/server.py")], true),
+                          trace(&[("poll", "/srv/server.py")], false)]);
+        stats.increment(&[trace(&[("handle", "/srv/server.py")], false)]);
+        let report = stats.report();
+        assert!(!report.passed);
+        assert_eq!(report.samples, 2);
+        let values: Vec<(Option<f64>, bool)> = report.assertions.iter().map(|result| (result.value, result.passed)).collect();
+        assert_eq!(values, vec![(Some(100.0 / 3.0), false), (Some(200.0 / 3.0), true), (Some(50.0), true), (Some(2.0), false)]);
+
+        let report = AssertionStats::new(&assertions[..1]).report();
+        assert_eq!(report.assertions[0].value, None);
+        assert!(!report.passed);
+    }
+}
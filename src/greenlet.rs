@@ -0,0 +1,164 @@
+// Gets the stacks of greenlets, for gevent and other greenlet based programs. Only the greenlet
+// that is currently running has its frames on the stack of the thread, the others keep the top
+// of their stack in the greenlet object while they're switched out. So for a gevent service
+// almost every sample lands in the hub, while the greenlets doing the work are waiting.
+//
+// Greenlets aren't registered anywhere that we can find them from, so this walks the lists of
+// objects tracked by the garbage collector to find them (using the heap module) (which is slow, so the caller should hold
+// on to the greenlets and only rescan occasionally). The PyGreenlet layout is copied from
+// greenlet.h, and only python 3.7 is supported.
+use std::collections::HashMap;
+
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::asyncio::copy_type_name;
+use crate::heap::for_each_tracked_object;
+use crate::python_bindings::pyruntime::GcLayout;
+use crate::python_bindings::v3_7_0::{PyFrameObject, PyObject, _typeobject};
+use crate::stack_trace::{Frame, StackTrace, get_frame};
+
+#[repr(C)]
+struct GreenletObject {
+    ob_base: PyObject,
+    stack_start: usize,
+    stack_stop: usize,
+    stack_copy: usize,
+    stack_saved: isize,
+    stack_prev: usize,
+    parent: usize,
+    run_info: usize,
+    top_frame: usize,
+}
+
+/// A greenlet object found in the target process, along with its type so that we can tell
+/// if the memory has since been reused for something else
+#[derive(Debug, Clone, Copy)]
+pub struct Greenlet {
+    pub address: usize,
+    pub object_type: usize,
+}
+
+/// Finds all the greenlets in the process by walking the garbage collector's lists of objects,
+/// given the address of _PyRuntime
+pub fn find_greenlets<P: ProcessMemory>(process: &P, runtime: usize, layout: &GcLayout) -> Result<Vec<Greenlet>, Error> {
+    // whether each type that we've seen is greenlet.greenlet or a subclass of it
+    let mut greenlet_types: HashMap<usize, bool> = HashMap::new();
+    let mut greenlets = Vec::new();
+    for_each_tracked_object(process, runtime, layout, |address, object_type| {
+        let is_greenlet = *greenlet_types.entry(object_type)
+            .or_insert_with(|| is_greenlet_type(process, object_type).unwrap_or(false));
+        if is_greenlet {
+            greenlets.push(Greenlet{address, object_type});
+        }
+    })?;
+    Ok(greenlets)
+}
+
+/// Gets a stack trace for each greenlet that is switched out. Each trace is assigned to the thread
+/// that the greenlet belongs to, given as the address of its thread state dict along with its python
+/// and OS thread ids. A frame labelled with the address of the greenlet is added as the root of the stack
+pub fn get_greenlet_traces<P: ProcessMemory>(process: &P, greenlets: &[Greenlet],
+                                             threads: &[(usize, u64, Option<u64>)]) -> Vec<StackTrace> {
+    let mut traces = Vec::new();
+    for greenlet in greenlets {
+        match get_greenlet_trace(process, greenlet, threads) {
+            Ok(Some(trace)) => traces.push(trace),
+            Ok(None) => {},
+            Err(err) => debug!("Failed to get stack for greenlet {:#x}: {}", greenlet.address, err)
+        }
+    }
+    traces
+}
+
+fn get_greenlet_trace<P: ProcessMemory>(process: &P, greenlet: &Greenlet,
+                                        threads: &[(usize, u64, Option<u64>)]) -> Result<Option<StackTrace>, Error> {
+    let object: GreenletObject = process.copy_struct(greenlet.address).context("Failed to copy greenlet")?;
+    // skip greenlets that have been freed, haven't started or have finished. The running greenlet
+    // doesn't have a top_frame, since its frames are on the stack of the thread
+    if object.ob_base.ob_type as usize != greenlet.object_type ||
+        object.stack_stop == 0 || object.stack_start == 0 || object.top_frame == 0 {
+        return Ok(None);
+    }
+    let (thread_id, os_thread_id) = match threads.iter().find(|thread| thread.0 == object.run_info) {
+        Some(&(_, thread_id, os_thread_id)) => (thread_id, os_thread_id),
+        None => return Ok(None)
+    };
+
+    let mut frames = Vec::new();
+    let mut frame_ptr = object.top_frame as *mut PyFrameObject;
+    while !frame_ptr.is_null() {
+        let frame: PyFrameObject = process.copy_pointer(frame_ptr).context("Failed to copy greenlet frame")?;
+        frames.push(get_frame(frame_ptr, &frame, process)?);
+        if frames.len() > 4096 {
+            return Err(format_err!("Max frame recursion depth reached"));
+        }
+        frame_ptr = frame.f_back;
+    }
+    frames.push(Frame{name: format!("greenlet {:#x}", greenlet.address), filename: "<greenlet>".to_owned(),
+                      module: None, short_filename: None, line: 0, frame_ptr: None, locals: None});
+    Ok(Some(StackTrace{thread_id, os_thread_id, thread_name: None, interpreter_id: 0, active: true, owns_gil: false, frames,
+                       exception: None, awaiting: None, pid: None}))
+}
+
+// checks if a type is greenlet.greenlet, or a subclass of it like gevent's Greenlet
+fn is_greenlet_type<P: ProcessMemory>(process: &P, mut object_type: usize) -> Result<bool, Error> {
+    for _ in 0..32 {
+        if object_type == 0 {
+            return Ok(false);
+        }
+        let type_object: _typeobject = process.copy_struct(object_type)?;
+        if copy_type_name(process, &type_object)? == "greenlet.greenlet" {
+            return Ok(true);
+        }
+        object_type = type_object.tp_base as usize;
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+
+    #[repr(C)]
+    struct TrackedObject {
+        gc_next: usize,
+        gc_prev: usize,
+        gc_refs: isize,
+        object: PyObject,
+    }
+
+    #[test]
+    fn test_find_greenlets() {
+        let base_name = b"greenlet.greenlet\0";
+        let mut base_type = _typeobject{tp_name: base_name.as_ptr() as *const _, ..Default::default()};
+        let subclass_name = b"gevent._greenlet.Greenlet\0";
+        let mut subclass_type = _typeobject{tp_name: subclass_name.as_ptr() as *const _, tp_base: &mut base_type,
+                                            ..Default::default()};
+        let other_name = b"dict\0";
+        let mut other_type = _typeobject{tp_name: other_name.as_ptr() as *const _, ..Default::default()};
+
+        // a fake _PyRuntime, with a list of three objects in the first generation. The lists of
+        // the other generations point back at themselves, since they are empty
+        let layout = GcLayout{generations: 0, stride: 32, head_size: 24, collecting: 96};
+        let mut runtime = [0usize; 12];
+        let runtime_address = runtime.as_ptr() as usize;
+        runtime[4] = runtime_address + 32;
+        runtime[8] = runtime_address + 64;
+
+        let types: [*mut _typeobject; 3] = [&mut subclass_type, &mut other_type, &mut base_type];
+        let mut objects: Vec<TrackedObject> = types.iter()
+            .map(|&ob_type| TrackedObject{gc_next: 0, gc_prev: 0, gc_refs: 0, object: PyObject{ob_refcnt: 1, ob_type}})
+            .collect();
+        let addresses: Vec<usize> = objects.iter().map(|o| o as *const TrackedObject as usize).collect();
+        objects[0].gc_next = addresses[1];
+        objects[1].gc_next = addresses[2];
+        objects[2].gc_next = runtime_address;
+        runtime[0] = addresses[0];
+
+        let greenlets = find_greenlets(&LocalProcess, runtime.as_ptr() as usize, &layout).unwrap();
+        let found: Vec<usize> = greenlets.iter().map(|g| g.address).collect();
+        assert_eq!(found, vec![addresses[0] + 24, addresses[2] + 24]);
+    }
+}
@@ -0,0 +1,89 @@
+// Collapses the frames of stack traces to the package, module or file that they come from, for
+// profiles that show which dependencies the time goes to rather than which functions. Python
+// frames are grouped by their short filename, which is the path from the directory holding the
+// top level package (so 'requests/sessions.py' is the module 'requests.sessions' of the package
+// 'requests'). Native frames are grouped by the library they are from, and the frames we add to
+// mark things like garbage collection are left as they are.
+use crate::config::GroupBy;
+use crate::stack_trace::{Frame, StackTrace};
+
+/// Collapses the frames of a trace, merging neighbouring frames that end up in the same group
+pub fn group_trace(trace: &StackTrace, group_by: GroupBy) -> StackTrace {
+    let mut frames: Vec<Frame> = Vec::with_capacity(trace.frames.len());
+    for frame in &trace.frames {
+        let grouped = group_frame(frame, group_by);
+        if frames.last().is_some_and(|last| last.name == grouped.name && last.filename == grouped.filename) {
+            continue;
+        }
+        frames.push(grouped);
+    }
+    StackTrace{frames, ..trace.clone()}
+}
+
+fn group_frame(frame: &Frame, group_by: GroupBy) -> Frame {
+    if group_by == GroupBy::function || frame.filename.starts_with('<') {
+        return frame.clone();
+    }
+
+    let (name, filename) = match (&frame.module, group_by) {
+        (Some(library), GroupBy::file) => (frame.filename.clone(), library_name(library).to_owned()),
+        (Some(library), _) => (library_name(library).to_owned(), library_name(library).to_owned()),
+        (None, _) => {
+            let path = match &frame.short_filename {
+                Some(short) => short.replace('\\', "/"),
+                None => library_name(&frame.filename).to_owned()
+            };
+            match group_by {
+                GroupBy::package => {
+                    let package = module_path(&path).split('.').next().unwrap_or_default().to_owned();
+                    (package.clone(), package)
+                },
+                GroupBy::module => (module_path(&path), path),
+                _ => (path.clone(), path)
+            }
+        }
+    };
+    Frame{name, filename, module: frame.module.clone(), short_filename: None, line: 0, frame_ptr: None, locals: None}
+}
+
+// converts a path like 'requests/adapters/__init__.py' to the module 'requests.adapters'
+fn module_path(path: &str) -> String {
+    let path = match path.rfind('.') {
+        Some(extension) if !path[extension..].contains('/') => &path[..extension],
+        _ => path
+    };
+    let path = path.trim_end_matches("/__init__");
+    path.replace('/', ".")
+}
+
+// the filename of a shared library (or a source file), without the directory it's in
+fn library_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, filename: &str, short_filename: Option<&str>, module: Option<&str>) -> Frame {
+        Frame{name: name.to_owned(), filename: filename.to_owned(), module: module.map(|m| m.to_owned()),
+              short_filename: short_filename.map(|f| f.to_owned()), line: 10, frame_ptr: None, locals: None}
+    }
+
+    #[test]
+    fn test_group_trace() {
+        // frames are innermost first
+        let frames = vec![frame("send", "/site-packages/requests/adapters/__init__.py", Some("requests/adapters/__init__.py"), None),
+                          frame("request", "/site-packages/requests/sessions.py", Some("requests/sessions.py"), None),
+                          frame("PyEval_EvalFrameDefault", "Python/ceval.c", None, Some("/usr/lib/libpython3.7m.so")),
+                          frame("main", "/app/main.py", Some("main.py"), None)];
+        let trace = StackTrace{thread_id: 1, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true,
+                               owns_gil: false, frames, exception: None, awaiting: None, pid: None};
+        let names = |group_by| -> Vec<String> { group_trace(&trace, group_by).frames.into_iter().map(|f| f.name).collect() };
+
+        assert_eq!(names(GroupBy::package), vec!["requests", "libpython3.7m.so", "main"]);
+        assert_eq!(names(GroupBy::module), vec!["requests.adapters", "requests.sessions", "libpython3.7m.so", "main"]);
+        assert_eq!(names(GroupBy::file), vec!["requests/adapters/__init__.py", "requests/sessions.py", "Python/ceval.c", "main.py"]);
+        assert_eq!(names(GroupBy::function), vec!["send", "request", "PyEval_EvalFrameDefault", "main"]);
+    }
+}
@@ -0,0 +1,139 @@
+// Summarizes the objects tracked by the garbage collector by type, for triaging memory growth
+// without instrumenting the program. Only container objects (like dicts, lists and instances of
+// classes) are tracked by the gc, so things like strings and ints don't show up here. Sizes are
+// approximate: they include the object itself but not any memory it owns outside of the object,
+// like the buffer holding the items of a list. Only python 3.7 is supported, the same as greenlets.
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::mem::size_of;
+
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::asyncio::copy_type_name;
+use crate::python_bindings::pyruntime::GcLayout;
+use crate::python_bindings::v3_7_0::{PyObject, PyVarObject, _typeobject};
+
+// sanity limit on the number of objects to look at, in case we end up following garbage
+const MAX_OBJECTS: usize = 50_000_000;
+
+/// The number of objects of a type, and their total size in bytes
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct TypeCounts {
+    pub count: u64,
+    pub size: u64,
+}
+
+/// Calls f with the address and type of every object tracked by the garbage collector, given the address of _PyRuntime
+pub fn for_each_tracked_object<P, F>(process: &P, runtime: usize, layout: &GcLayout, mut f: F) -> Result<(), Error>
+        where P: ProcessMemory, F: FnMut(usize, usize) {
+    let mut visited = 0;
+    for generation in 0..3 {
+        let head = runtime + layout.generations + generation * layout.stride;
+        let mut next: usize = process.copy_struct(head).context("Failed to copy gc generation")?;
+        while next != head {
+            visited += 1;
+            if visited > MAX_OBJECTS || next == 0 {
+                return Err(format_err!("Failed to walk the objects tracked by the garbage collector"));
+            }
+
+            // the gc_next pointer comes first in the PyGC_Head, and the object follows it
+            let data = process.copy(next, layout.head_size + size_of::<PyObject>())?;
+            let object: PyObject = unsafe { std::ptr::read_unaligned(data.as_ptr().add(layout.head_size) as *const _) };
+            f(next + layout.head_size, object.ob_type as usize);
+            next = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const usize) };
+        }
+    }
+    Ok(())
+}
+
+/// Counts the objects tracked by the garbage collector by the name of their type, along with
+/// their approximate size (including the PyGC_Head before each object)
+pub fn get_heap_summary<P: ProcessMemory>(process: &P, runtime: usize, layout: &GcLayout) -> Result<HashMap<String, TypeCounts>, Error> {
+    // the name, basic size and item size of each type we've seen
+    let mut types: HashMap<usize, (String, usize, usize)> = HashMap::new();
+    let mut summary: HashMap<String, TypeCounts> = HashMap::new();
+    let mut error = None;
+
+    for_each_tracked_object(process, runtime, layout, |address, object_type| {
+        if error.is_some() {
+            return;
+        }
+        let &mut (ref name, basic_size, item_size) = match types.entry(object_type) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match get_type_info(process, object_type) {
+                Ok(info) => entry.insert(info),
+                Err(err) => { error = Some(err); return; }
+            }
+        };
+
+        // variable sized objects like tuples store their items inline, after the basic object
+        let mut size = layout.head_size + basic_size;
+        if item_size != 0 {
+            match process.copy_struct::<PyVarObject>(address) {
+                Ok(object) => size += object.ob_size.unsigned_abs() * item_size,
+                Err(err) => { error = Some(err.into()); return; }
+            }
+        }
+        let counts = summary.entry(name.clone()).or_default();
+        counts.count += 1;
+        counts.size += size as u64;
+    })?;
+
+    match error {
+        Some(err) => Err(err),
+        None => Ok(summary)
+    }
+}
+
+fn get_type_info<P: ProcessMemory>(process: &P, object_type: usize) -> Result<(String, usize, usize), Error> {
+    let type_object: _typeobject = process.copy_struct(object_type).context("Failed to copy type object")?;
+    let name = copy_type_name(process, &type_object)?;
+    Ok((name, type_object.tp_basicsize.max(0) as usize, type_object.tp_itemsize.max(0) as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+
+    #[repr(C)]
+    struct TrackedObject {
+        gc_next: usize,
+        gc_prev: usize,
+        gc_refs: isize,
+        object: PyVarObject,
+    }
+
+    #[test]
+    fn test_get_heap_summary() {
+        let dict_name = b"dict\0";
+        let mut dict_type = _typeobject{tp_name: dict_name.as_ptr() as *const _, tp_basicsize: 48, ..Default::default()};
+        let tuple_name = b"tuple\0";
+        let mut tuple_type = _typeobject{tp_name: tuple_name.as_ptr() as *const _, tp_basicsize: 24, tp_itemsize: 8,
+                                         ..Default::default()};
+
+        // a fake _PyRuntime with two dicts and a tuple of three items in the first generation
+        let layout = GcLayout{generations: 0, stride: 32, head_size: 24, collecting: 96};
+        let mut runtime = Box::new([0usize; 12]);
+        let runtime_address = runtime.as_ptr() as usize;
+        runtime[4] = runtime_address + 32;
+        runtime[8] = runtime_address + 64;
+
+        let objects: [(*mut _typeobject, isize); 3] = [(&mut dict_type, 0), (&mut tuple_type, 3), (&mut dict_type, 0)];
+        let mut objects: Vec<TrackedObject> = objects.iter()
+            .map(|&(ob_type, ob_size)| TrackedObject{gc_next: 0, gc_prev: 0, gc_refs: 0,
+                                                     object: PyVarObject{ob_base: PyObject{ob_refcnt: 1, ob_type}, ob_size}})
+            .collect();
+        let addresses: Vec<usize> = objects.iter().map(|o| o as *const TrackedObject as usize).collect();
+        objects[0].gc_next = addresses[1];
+        objects[1].gc_next = addresses[2];
+        objects[2].gc_next = runtime_address;
+        runtime[0] = addresses[0];
+
+        let summary = get_heap_summary(&LocalProcess, runtime_address, &layout).unwrap();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary["dict"], TypeCounts{count: 2, size: 2 * (24 + 48)});
+        assert_eq!(summary["tuple"], TypeCounts{count: 1, size: 24 + 24 + 3 * 8});
+    }
+}
@@ -0,0 +1,126 @@
+// Writes out the summaries of the objects tracked by the garbage collector from 'py-spy heap', and
+// compares them against snapshots saved by an earlier run to show what has grown in between.
+use std::collections::HashMap;
+use std::io::Write;
+
+use failure::Error;
+
+use crate::heap::TypeCounts;
+
+// number of types to list in the reports
+const MAX_TYPES: usize = 30;
+
+// the first line of saved snapshots, so that we don't try comparing against some other file
+const SNAPSHOT_HEADER: &str = "# py-spy heap snapshot: count\tsize\ttype";
+
+/// Writes the types with the most memory, along with the totals over every type
+pub fn write_summary(w: &mut dyn Write, summary: &HashMap<String, TypeCounts>) -> Result<(), Error> {
+    let count: u64 = summary.values().map(|counts| counts.count).sum();
+    let size: u64 = summary.values().map(|counts| counts.size).sum();
+    writeln!(w, "{} objects tracked by the garbage collector, using {}", count, format_size(size as i64))?;
+    writeln!(w, "  {:>10} {:>10}  Type", "Count", "Size")?;
+
+    let mut types: Vec<(&String, &TypeCounts)> = summary.iter().collect();
+    types.sort_unstable_by(|a, b| b.1.size.cmp(&a.1.size).then(a.0.cmp(b.0)));
+    for (name, counts) in types.into_iter().take(MAX_TYPES) {
+        writeln!(w, "  {:>10} {:>10}  {}", counts.count, format_size(counts.size as i64), name)?;
+    }
+    Ok(())
+}
+
+/// Writes the types whose memory has changed the most since an earlier snapshot
+pub fn write_diff(w: &mut dyn Write, before: &HashMap<String, TypeCounts>, after: &HashMap<String, TypeCounts>) -> Result<(), Error> {
+    let changes = diff(before, after);
+    let count: i64 = changes.iter().map(|(_, count, _)| count).sum();
+    let size: i64 = changes.iter().map(|(_, _, size)| size).sum();
+    writeln!(w, "{:+} objects tracked by the garbage collector since the snapshot, using {}", count, format_size(size))?;
+    writeln!(w, "  {:>10} {:>10}  Type", "Count", "Size")?;
+    for (name, count, size) in changes.into_iter().take(MAX_TYPES) {
+        writeln!(w, "  {:>+10} {:>10}  {}", count, format_size(size), name)?;
+    }
+    Ok(())
+}
+
+/// Saves a summary in a format that can be compared against later with parse_snapshot
+pub fn write_snapshot(w: &mut dyn Write, summary: &HashMap<String, TypeCounts>) -> Result<(), Error> {
+    writeln!(w, "{}", SNAPSHOT_HEADER)?;
+    let mut types: Vec<(&String, &TypeCounts)> = summary.iter().collect();
+    types.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    for (name, counts) in types {
+        writeln!(w, "{}\t{}\t{}", counts.count, counts.size, name)?;
+    }
+    Ok(())
+}
+
+/// Reads a snapshot saved with write_snapshot
+pub fn parse_snapshot(contents: &str) -> Result<HashMap<String, TypeCounts>, Error> {
+    let mut lines = contents.lines();
+    if lines.next() != Some(SNAPSHOT_HEADER) {
+        return Err(format_err!("Not a heap snapshot saved by py-spy"));
+    }
+    let mut summary = HashMap::new();
+    for line in lines {
+        let mut parts = line.splitn(3, '\t');
+        match (parts.next().map(str::parse), parts.next().map(str::parse), parts.next()) {
+            (Some(Ok(count)), Some(Ok(size)), Some(name)) => { summary.insert(name.to_owned(), TypeCounts{count, size}); },
+            _ => return Err(format_err!("Invalid line in heap snapshot '{}'", line))
+        }
+    }
+    Ok(summary)
+}
+
+/// Gets the change in the count and size of each type that changed, with the most growth first
+fn diff(before: &HashMap<String, TypeCounts>, after: &HashMap<String, TypeCounts>) -> Vec<(String, i64, i64)> {
+    let mut changes: Vec<(String, i64, i64)> = before.keys().chain(after.keys().filter(|name| !before.contains_key(*name)))
+        .map(|name| {
+            let before = before.get(name).copied().unwrap_or_default();
+            let after = after.get(name).copied().unwrap_or_default();
+            (name.clone(), after.count as i64 - before.count as i64, after.size as i64 - before.size as i64)
+        })
+        .filter(|&(_, count, size)| count != 0 || size != 0)
+        .collect();
+    changes.sort_unstable_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)).then(a.0.cmp(&b.0)));
+    changes
+}
+
+/// Formats a number of bytes like '1.5M'
+pub fn format_size(bytes: i64) -> String {
+    let magnitude = bytes.unsigned_abs() as f64;
+    let sign = if bytes < 0 { "-" } else { "" };
+    if magnitude >= 1024.0 * 1024.0 * 1024.0 {
+        format!("{}{:.1}G", sign, magnitude / (1024.0 * 1024.0 * 1024.0))
+    } else if magnitude >= 1024.0 * 1024.0 {
+        format!("{}{:.1}M", sign, magnitude / (1024.0 * 1024.0))
+    } else if magnitude >= 1024.0 {
+        format!("{}{:.1}K", sign, magnitude / 1024.0)
+    } else {
+        format!("{}{}B", sign, magnitude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(types: &[(&str, u64, u64)]) -> HashMap<String, TypeCounts> {
+        types.iter().map(|&(name, count, size)| (name.to_owned(), TypeCounts{count, size})).collect()
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let before = summary(&[("dict", 10, 1000), ("foo.Bar", 2, 112)]);
+        let mut saved = Vec::new();
+        write_snapshot(&mut saved, &before).unwrap();
+        assert_eq!(parse_snapshot(&String::from_utf8(saved).unwrap()).unwrap(), before);
+        assert!(parse_snapshot("count size type\n").is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = summary(&[("dict", 10, 1000), ("list", 5, 200), ("set", 1, 200)]);
+        let after = summary(&[("dict", 12, 1200), ("list", 5, 200), ("tuple", 3, 150)]);
+        assert_eq!(diff(&before, &after), vec![("dict".to_owned(), 2, 200), ("tuple".to_owned(), 3, 150),
+                                               ("set".to_owned(), -1, -200)]);
+        assert_eq!(format_size(-2048), "-2.0K");
+    }
+}
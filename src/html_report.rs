@@ -0,0 +1,235 @@
+// Generates a single self contained html file with a flame graph, a chart of thread activity
+// over time and a table of the functions where the most time was spent. Everything is inlined
+// into the file so that it can be opened offline, or attached to a bug report.
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+use failure::Error;
+
+use crate::flamegraph::Flamegraph;
+use crate::function_stats::FunctionStats;
+use crate::labels::Labels;
+use crate::stack_trace::StackTrace;
+use crate::summary::parse_failed_samples;
+use crate::thread_events::{ThreadChange, ThreadEvent};
+
+// number of functions to include in the hot function table
+const MAX_FUNCTIONS: usize = 100;
+
+#[derive(Default, Clone, Copy)]
+struct ActivityBucket {
+    samples: u64,
+    active: u64,
+    gil: u64,
+}
+
+pub struct HtmlReport {
+    flamegraph: Flamegraph,
+    functions: FunctionStats,
+    // per thread activity, bucketed per second of the recording
+    threads: BTreeMap<u64, Vec<ActivityBucket>>,
+    thread_names: BTreeMap<u64, String>,
+    // when each thread was first seen, and last seen if it exited during the recording
+    thread_lifetimes: BTreeMap<u64, (Duration, Option<Duration>)>,
+    samples: u64,
+    duration: Duration,
+    sampling_rate: u64,
+    effective_rate: Option<f64>,
+    failed_samples: Vec<(String, u64)>,
+    labels: Labels,
+    version: String,
+}
+
+impl HtmlReport {
+    pub fn new(sampling_rate: u64, show_linenumbers: bool, reverse: bool, version: &str) -> HtmlReport {
+        HtmlReport{flamegraph: Flamegraph::new(show_linenumbers, reverse), functions: FunctionStats::new(show_linenumbers),
+                   threads: BTreeMap::new(), thread_names: BTreeMap::new(), thread_lifetimes: BTreeMap::new(), samples: 0, duration: Duration::from_secs(0),
+                   sampling_rate, effective_rate: None, failed_samples: Vec::new(), labels: Labels::default(),
+                   version: version.to_owned()}
+    }
+
+    /// Sets the sampling rate that was actually achieved while recording
+    pub fn set_effective_rate(&mut self, rate: f64) {
+        self.effective_rate = Some(rate);
+    }
+
+    /// Sets the number of samples that failed for each reason, as formatted in the 'failed_samples' metadata
+    pub fn set_failed_samples(&mut self, failed_samples: &str) {
+        self.failed_samples = parse_failed_samples(failed_samples);
+    }
+
+    /// Sets a label given with --label, like 'service=web'
+    pub fn set_label(&mut self, key: &str, value: &str) {
+        self.labels.set(key, value);
+    }
+
+    /// Records a thread starting or exiting, to show how long each thread was around for
+    pub fn thread_event(&mut self, change: &ThreadChange) {
+        match change.event {
+            ThreadEvent::Started => { self.thread_lifetimes.insert(change.thread_id, (change.timestamp, None)); },
+            ThreadEvent::Exited => {
+                if let Some(lifetime) = self.thread_lifetimes.get_mut(&change.thread_id) {
+                    lifetime.1 = Some(change.timestamp);
+                }
+            }
+        }
+    }
+
+    pub fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        self.flamegraph.increment(traces)?;
+        self.samples += 1;
+        self.duration = std::cmp::max(self.duration, timestamp);
+
+        let bucket = timestamp.as_secs() as usize;
+        for trace in traces {
+            if let Some(name) = trace.thread_name.as_ref() {
+                self.thread_names.insert(trace.thread_id, name.clone());
+            }
+            let activity = self.threads.entry(trace.thread_id).or_default();
+            if activity.len() <= bucket {
+                activity.resize(bucket + 1, ActivityBucket::default());
+            }
+            let activity = &mut activity[bucket];
+            activity.samples += 1;
+            if trace.active { activity.active += 1; }
+            if trace.owns_gil { activity.gil += 1; }
+
+            if trace.active {
+                self.functions.increment(trace);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let mut svg = Vec::new();
+        self.flamegraph.write(&mut svg)?;
+
+        writeln!(w, "<!DOCTYPE html>")?;
+        writeln!(w, "<html><head><meta charset=\"utf-8\"><title>py-spy report</title>")?;
+        writeln!(w, "<style>{}</style></head><body>", STYLE)?;
+        writeln!(w, "<h1>py-spy report</h1>")?;
+        writeln!(w, "<p>Python {} &middot; {} samples at {:.1} Hz &middot; {:.1}s recorded</p>",
+                 escape(&self.version), self.samples, self.rate(), self.duration.as_secs_f64())?;
+        if !self.labels.is_empty() {
+            writeln!(w, "<p>Labels: {}</p>", escape(&self.labels.to_string()))?;
+        }
+        if !self.failed_samples.is_empty() {
+            let failed: Vec<String> = self.failed_samples.iter().map(|(reason, count)| format!("{} {}", reason, count)).collect();
+            writeln!(w, "<p>Failed samples: {}</p>", escape(&failed.join(", ")))?;
+        }
+
+        writeln!(w, "<h2>Flame Graph</h2>")?;
+        writeln!(w, "<object type=\"image/svg+xml\" data=\"data:image/svg+xml;base64,{}\"></object>", base64(&svg))?;
+
+        self.write_thread_activity(w)?;
+        self.write_function_table(w)?;
+
+        writeln!(w, "</body></html>")?;
+        Ok(())
+    }
+
+    fn rate(&self) -> f64 {
+        self.effective_rate.unwrap_or(self.sampling_rate as f64)
+    }
+
+    fn write_thread_activity(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let buckets = self.threads.values().map(|b| b.len()).max().unwrap_or(0).max(1);
+        let width = 800.0 / buckets as f64;
+
+        writeln!(w, "<h2>Thread Activity</h2>")?;
+        writeln!(w, "<p>Each column is one second. Darker means a larger fraction of samples active, \
+                     outlined columns held the GIL in most samples.</p>")?;
+        writeln!(w, "<table class=\"threads\">")?;
+        for (thread_id, activity) in &self.threads {
+            let total: u64 = activity.iter().map(|b| b.samples).sum();
+            let active: u64 = activity.iter().map(|b| b.active).sum();
+            let gil: u64 = activity.iter().map(|b| b.gil).sum();
+            let name = self.thread_names.get(thread_id).map(|name| format!(" ({})", escape(name))).unwrap_or_default();
+            let lifetime = match self.thread_lifetimes.get(thread_id) {
+                Some((started, Some(exited))) => format!(", seen {:.1}s to {:.1}s", started.as_secs_f64(), exited.as_secs_f64()),
+                Some((started, None)) => format!(", seen from {:.1}s", started.as_secs_f64()),
+                None => String::new()
+            };
+            write!(w, "<tr><td>Thread {:#X}{}</td><td>{:.1}% active, {:.1}% GIL{}</td><td><svg width=\"800\" height=\"20\">",
+                   thread_id, name, percent(active, total), percent(gil, total), lifetime)?;
+            for (i, bucket) in activity.iter().enumerate() {
+                if bucket.samples == 0 {
+                    continue;
+                }
+                let opacity = bucket.active as f64 / bucket.samples as f64;
+                let stroke = if bucket.gil * 2 > bucket.samples { " stroke=\"#c00\"" } else { "" };
+                write!(w, "<rect x=\"{:.2}\" y=\"1\" width=\"{:.2}\" height=\"18\" fill=\"#e66000\" fill-opacity=\"{:.2}\"{}>\
+                           <title>{}s: {:.0}% active, {:.0}% GIL</title></rect>",
+                       i as f64 * width, width, opacity, stroke,
+                       i, percent(bucket.active, bucket.samples), percent(bucket.gil, bucket.samples))?;
+            }
+            writeln!(w, "</svg></td></tr>")?;
+        }
+        writeln!(w, "</table>")?;
+        Ok(())
+    }
+
+    fn write_function_table(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let period = 1.0 / self.rate();
+        writeln!(w, "<h2>Hot Functions</h2>")?;
+        writeln!(w, "<table class=\"functions\"><tr><th>%Own</th><th>%Total</th><th>OwnTime</th><th>TotalTime</th><th>Function</th></tr>")?;
+        for (name, counts) in self.functions.top_own(MAX_FUNCTIONS) {
+            writeln!(w, "<tr><td>{:.2}%</td><td>{:.2}%</td><td>{:.2}s</td><td>{:.2}s</td><td class=\"name\">{}</td></tr>",
+                     percent(counts.own, self.samples), percent(counts.total, self.samples),
+                     counts.own as f64 * period, counts.total as f64 * period, escape(name))?;
+        }
+        writeln!(w, "</table>")?;
+        Ok(())
+    }
+}
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; } \
+    object { width: 100%; } \
+    table { border-collapse: collapse; } \
+    td, th { padding: 2px 8px; text-align: right; } \
+    td.name { text-align: left; font-family: monospace; } \
+    table.functions tr:nth-child(even) { background: #f0f0f0; } \
+    table.threads td { text-align: left; }";
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { 100.0 * count as f64 / total as f64 }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut ret = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        ret.push(CHARS[(n >> 18) as usize & 63] as char);
+        ret.push(CHARS[(n >> 12) as usize & 63] as char);
+        ret.push(if chunk.len() > 1 { CHARS[(n >> 6) as usize & 63] as char } else { '=' });
+        ret.push(if chunk.len() > 2 { CHARS[n as usize & 63] as char } else { '=' });
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("<module> (a.py)"), "&lt;module&gt; (a.py)");
+    }
+}
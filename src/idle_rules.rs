@@ -0,0 +1,111 @@
+// Rules for what a thread is doing when it's idle, like waiting in threading.Condition.wait or in
+// selectors.select. When the OS can't tell us whether a thread is running, a thread is counted as idle
+// when the top frame of its stack matches one of the rules. Rules added with --idle-rule also apply
+// when the OS says a thread is running, to catch frameworks that spin in their own wait loops.
+use failure::Error;
+
+use crate::stack_trace::Frame;
+
+/// The rules that are used unless they're removed with --no-idle-rule
+const DEFAULT_RULES: &[(&str, &str)] = &[("wait", "threading.py"), ("select", "selectors.py"), ("poll", "asyncore.py"),
+                                         ("poll", "zmq"), ("poll", "gevent"), ("poll", "tornado")];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdleRule {
+    pub function: String,
+    /// Matches files whose path contains this. Any file matches when it's None
+    pub file: Option<String>,
+}
+
+impl IdleRule {
+    /// Parses a rule like 'wait_for_job@jobs/worker.py', or just 'wait_for_job' for any file
+    pub fn parse(value: &str) -> Result<IdleRule, Error> {
+        let (function, file) = match value.split_once('@') {
+            Some((function, file)) => (function.trim(), Some(file.trim())),
+            None => (value.trim(), None)
+        };
+        if function.is_empty() || file.is_some_and(|file| file.is_empty()) {
+            return Err(format_err!("Invalid idle rule '{}': expected something like 'wait_for_job@jobs/worker.py'", value));
+        }
+        Ok(IdleRule{function: function.to_owned(), file: file.map(|file| file.to_owned())})
+    }
+
+    pub fn matches(&self, frame: &Frame) -> bool {
+        // methods can be qualified with the name of their class, like 'Worker.wait_for_job'
+        let name = frame.name.rsplit('.').next().unwrap_or(&frame.name);
+        (frame.name == self.function || name == self.function) &&
+            self.file.as_ref().is_none_or(|file| frame.filename.contains(file.as_str()))
+    }
+}
+
+impl std::fmt::Display for IdleRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}@{}", self.function, file),
+            None => write!(f, "{}", self.function),
+        }
+    }
+}
+
+pub fn default_rules() -> Vec<IdleRule> {
+    DEFAULT_RULES.iter().map(|&(function, file)| IdleRule{function: function.to_owned(), file: Some(file.to_owned())}).collect()
+}
+
+pub struct IdleRules {
+    /// The default rules that haven't been removed, followed by the added ones
+    rules: Vec<IdleRule>,
+    added: Vec<IdleRule>,
+}
+
+impl IdleRules {
+    pub fn new(added: &[IdleRule], removed: &[IdleRule]) -> IdleRules {
+        let mut rules: Vec<IdleRule> = default_rules().into_iter().filter(|rule| !removed.contains(rule)).collect();
+        rules.extend(added.iter().cloned());
+        IdleRules{rules, added: added.to_vec()}
+    }
+
+    /// Whether a thread with these frames is idle, going by the rules alone
+    pub fn is_idle(&self, frames: &[Frame]) -> bool {
+        // we could have 0 python frames, but still be active running native code
+        frames.first().is_some_and(|frame| self.rules.iter().any(|rule| rule.matches(frame)))
+    }
+
+    /// Whether a thread the OS says is running is actually waiting in a loop of its own
+    pub fn is_waiting(&self, frames: &[Frame]) -> bool {
+        frames.first().is_some_and(|frame| self.added.iter().any(|rule| rule.matches(frame)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, filename: &str) -> Frame {
+        Frame{name: name.to_owned(), filename: filename.to_owned(), module: None, short_filename: None, line: 1,
+              frame_ptr: None, locals: None}
+    }
+
+    #[test]
+    fn test_parse_idle_rule() {
+        assert_eq!(IdleRule::parse("wait_for_job@jobs/worker.py").unwrap(),
+                   IdleRule{function: "wait_for_job".to_owned(), file: Some("jobs/worker.py".to_owned())});
+        assert_eq!(IdleRule::parse("spin_wait").unwrap().to_string(), "spin_wait");
+        assert!(IdleRule::parse("@jobs/worker.py").is_err());
+        assert!(IdleRule::parse("wait@").is_err());
+    }
+
+    #[test]
+    fn test_idle_rules() {
+        let rules = IdleRules::new(&[IdleRule::parse("wait_for_job@worker.py").unwrap()],
+                                   &[IdleRule::parse("poll@tornado").unwrap()]);
+        assert!(rules.is_idle(&[frame("wait", "/usr/lib/python3.7/threading.py")]));
+        assert!(rules.is_idle(&[frame("Condition.wait", "/usr/lib/python3.7/threading.py")]));
+        assert!(rules.is_idle(&[frame("Queue.wait_for_job", "/srv/jobs/worker.py")]));
+        assert!(!rules.is_idle(&[frame("poll", "/venv/tornado/ioloop.py")]));
+        assert!(!rules.is_idle(&[frame("handle", "/srv/jobs/worker.py"), frame("wait_for_job", "/srv/jobs/worker.py")]));
+        assert!(!rules.is_idle(&[]));
+
+        assert!(rules.is_waiting(&[frame("wait_for_job", "/srv/jobs/worker.py")]));
+        assert!(!rules.is_waiting(&[frame("wait", "/usr/lib/python3.7/threading.py")]));
+    }
+}
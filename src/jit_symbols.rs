@@ -0,0 +1,284 @@
+// Names functions in code generated at runtime by JIT compilers, which isn't part of any binary and
+// so can't be symbolicated from a file. These can be found three ways:
+//  * perf map files (/tmp/perf-<pid>.map), which list the address, size and name of each function.
+//    These are written by things like PyTorch and python 3.12's perf trampolines
+//  * jitdump files, which the JIT maps into its own memory so that profilers can find them (which is
+//    why we look in the memory maps for them), and which record each function as it's compiled or moved
+//  * the interface that GDB uses for JIT code, where the JIT registers an in memory ELF object with the
+//    symbols of the code it generated. LLVM's MCJIT (and so numba) does this by default
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use goblin::elf::{Elf, header::ET_REL, program_header::{PT_LOAD, PF_X}, sym::STT_FUNC};
+use proc_maps::get_process_maps;
+use remoteprocess::{namespace_path, Pid, Process, ProcessMemory};
+
+use crate::stack_trace::Frame;
+
+/// The frames of functions in JIT generated code are labelled with this filename
+pub const JIT_FILENAME: &str = "<jit>";
+
+// how often to look for new JIT code, when there's an address we can't name
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+// sanity limits for reading GDB JIT entries, in case we end up following garbage
+const MAX_GDB_JIT_ENTRIES: usize = 100_000;
+const MAX_GDB_JIT_OBJECT_SIZE: u64 = 64 * 1024 * 1024;
+
+pub struct JitSymbols {
+    pid: Pid,
+    // the pid the process knows itself by, which is what the JIT puts in filenames. This is
+    // different from our pid for it when it's running in a container
+    namespace_pid: Pid,
+    // (start, size, name) sorted by start
+    symbols: Vec<(u64, u64, String)>,
+    // the address of __jit_debug_descriptor, if an LLVM library has been loaded
+    gdb_descriptor: Option<u64>,
+    searched_libraries: Vec<String>,
+    last_load: Option<Instant>,
+}
+
+impl JitSymbols {
+    pub fn new(pid: Pid) -> JitSymbols {
+        let namespace_pid = Process::new(pid).and_then(|process| process.namespace_pid()).unwrap_or(pid);
+        JitSymbols{pid, namespace_pid, symbols: Vec::new(), gdb_descriptor: None, searched_libraries: Vec::new(), last_load: None}
+    }
+
+    /// Gets a frame for an address in JIT generated code. If the address isn't known, this looks for
+    /// new JIT code (at most once a second, since this is only needed for addresses we couldn't
+    /// otherwise symbolicate)
+    pub fn frame<P: ProcessMemory>(&mut self, process: &P, address: u64) -> Option<Frame> {
+        if self.lookup(address).is_none() && self.last_load.is_none_or(|last| last.elapsed() >= RELOAD_INTERVAL) {
+            self.last_load = Some(Instant::now());
+            if let Err(e) = self.reload(process) {
+                debug!("Failed to load JIT symbols: {}", e);
+            }
+        }
+        let name = self.lookup(address)?;
+        Some(Frame{name: name.to_owned(), filename: JIT_FILENAME.to_owned(), module: None, short_filename: None,
+                   line: 0, frame_ptr: None, locals: None})
+    }
+
+    fn lookup(&self, address: u64) -> Option<&str> {
+        let i = match self.symbols.binary_search_by(|symbol| symbol.0.cmp(&address)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1
+        };
+        let (start, size, name) = &self.symbols[i];
+        if address < start + size { Some(name) } else { None }
+    }
+
+    fn reload<P: ProcessMemory>(&mut self, process: &P) -> Result<(), Error> {
+        let mut symbols = Vec::new();
+        let perf_map = namespace_path(self.pid, &format!("/tmp/perf-{}.map", self.namespace_pid));
+        if let Ok(perf_map) = std::fs::read_to_string(perf_map) {
+            symbols.extend(parse_perf_map(&perf_map));
+        }
+
+        let maps = get_process_maps(self.pid)?;
+        let jitdump_name = format!("jit-{}.dump", self.namespace_pid);
+        for map in &maps {
+            let filename = match map.filename() {
+                Some(filename) => filename,
+                None => continue
+            };
+            if filename.ends_with(&jitdump_name) {
+                match std::fs::read(namespace_path(self.pid, filename)) {
+                    Ok(data) => symbols.extend(parse_jitdump(&data)),
+                    Err(e) => debug!("Failed to read {}: {}", filename, e)
+                }
+            } else if self.gdb_descriptor.is_none() && map.is_exec() && filename.to_lowercase().contains("llvm") &&
+                    !self.searched_libraries.iter().any(|searched| searched == filename) {
+                self.searched_libraries.push(filename.to_owned());
+                self.gdb_descriptor = find_gdb_descriptor(&namespace_path(self.pid, filename), map.start() as u64);
+            }
+        }
+        if let Some(descriptor) = self.gdb_descriptor {
+            symbols.extend(get_gdb_jit_symbols(process, descriptor)?);
+        }
+
+        symbols.sort_unstable();
+        symbols.dedup_by(|a, b| a.0 == b.0);
+        self.symbols = symbols;
+        Ok(())
+    }
+}
+
+// parses a perf map file, which has a line of 'START SIZE name' (with hex numbers) for each function
+fn parse_perf_map(data: &str) -> Vec<(u64, u64, String)> {
+    data.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let start = u64::from_str_radix(fields.next()?.trim_start_matches("0x"), 16).ok()?;
+            let size = u64::from_str_radix(fields.next()?.trim_start_matches("0x"), 16).ok()?;
+            let name = fields.next()?.trim();
+            Some((start, size, name.to_owned()))
+        })
+        .collect()
+}
+
+// parses the functions out of a jitdump file (as described in tools/perf/Documentation/jitdump-specification.txt
+// in the linux source). Functions that have been moved are reported at their latest address
+fn parse_jitdump(data: &[u8]) -> Vec<(u64, u64, String)> {
+    const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+    const JIT_CODE_LOAD: u32 = 0;
+    const JIT_CODE_MOVE: u32 = 1;
+    const JIT_CODE_CLOSE: u32 = 3;
+
+    let read_u32 = |offset: usize| data.get(offset..offset + 4).map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]));
+    let read_u64 = |offset: usize| -> Option<u64> {
+        let mut value = [0u8; 8];
+        value.copy_from_slice(data.get(offset..offset + 8)?);
+        Some(u64::from_ne_bytes(value))
+    };
+
+    if read_u32(0) != Some(JITDUMP_MAGIC) {
+        return Vec::new();
+    }
+    // functions by their code index, which is how moves refer to them
+    let mut functions: HashMap<u64, (u64, u64, String)> = HashMap::new();
+    let mut offset = read_u32(8).unwrap_or(0) as usize;
+    while let (Some(id), Some(size)) = (read_u32(offset), read_u32(offset + 4)) {
+        let size = size as usize;
+        if size < 16 || id == JIT_CODE_CLOSE {
+            break;
+        }
+        // the body of each record starts after the id, size and timestamp, and then the pid and tid
+        let body = offset + 24;
+        match id {
+            JIT_CODE_LOAD => {
+                if let (Some(address), Some(code_size), Some(index)) = (read_u64(body + 8), read_u64(body + 16), read_u64(body + 24)) {
+                    let name = data.get(body + 32..offset + size).unwrap_or_default();
+                    let name = name.split(|&c| c == 0).next().unwrap_or_default();
+                    functions.insert(index, (address, code_size, String::from_utf8_lossy(name).into_owned()));
+                }
+            },
+            JIT_CODE_MOVE => {
+                if let (Some(address), Some(index)) = (read_u64(body + 16), read_u64(body + 32)) {
+                    if let Some(function) = functions.get_mut(&index) {
+                        function.0 = address;
+                    }
+                }
+            },
+            _ => {}
+        }
+        offset += size;
+    }
+    functions.into_values().collect()
+}
+
+// finds the address of __jit_debug_descriptor in a library loaded at an address
+fn find_gdb_descriptor(filename: &Path, address: u64) -> Option<u64> {
+    let data = std::fs::read(filename).ok()?;
+    let elf = Elf::parse(&data).ok()?;
+    let load = elf.program_headers.iter().find(|header| header.p_type == PT_LOAD && header.p_flags & PF_X != 0)?;
+    let symbol = elf.dynsyms.iter().map(|sym| (sym, &elf.dynstrtab))
+        .chain(elf.syms.iter().map(|sym| (sym, &elf.strtab)))
+        .find(|(sym, strtab)| sym.st_value != 0 && strtab.get(sym.st_name).and_then(|name| name.ok()) == Some("__jit_debug_descriptor"))?;
+    info!("found __jit_debug_descriptor in {}", filename.display());
+    Some(symbol.0.st_value + address - load.p_vaddr)
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: u64,
+    first_entry: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct JitCodeEntry {
+    next: u64,
+    prev: u64,
+    symfile_addr: u64,
+    symfile_size: u64,
+}
+
+// gets the functions in the objects registered with the GDB JIT interface
+fn get_gdb_jit_symbols<P: ProcessMemory>(process: &P, descriptor: u64) -> Result<Vec<(u64, u64, String)>, Error> {
+    let descriptor: JitDescriptor = process.copy_struct(descriptor as usize)?;
+    let mut symbols = Vec::new();
+    let mut next = descriptor.first_entry;
+    let mut entries = 0;
+    while next != 0 && entries < MAX_GDB_JIT_ENTRIES {
+        entries += 1;
+        let entry: JitCodeEntry = process.copy_struct(next as usize)?;
+        if entry.symfile_size > 0 && entry.symfile_size <= MAX_GDB_JIT_OBJECT_SIZE {
+            let object = process.copy(entry.symfile_addr as usize, entry.symfile_size as usize)?;
+            symbols.extend(get_object_functions(&object));
+        }
+        next = entry.next;
+    }
+    Ok(symbols)
+}
+
+// gets the functions in an in memory ELF object. The JIT updates the addresses of the sections to where
+// they were loaded, but the symbols of relocatable objects might still be relative to their section
+fn get_object_functions(object: &[u8]) -> Vec<(u64, u64, String)> {
+    let elf = match Elf::parse(object) {
+        Ok(elf) => elf,
+        Err(_) => return Vec::new()
+    };
+    elf.syms.iter()
+        .filter(|sym| sym.st_type() == STT_FUNC && sym.st_size > 0)
+        .filter_map(|sym| {
+            let name = elf.strtab.get(sym.st_name)?.ok()?;
+            let mut address = sym.st_value;
+            if elf.header.e_type == ET_REL {
+                let section = elf.section_headers.get(sym.st_shndx)?;
+                if address < section.sh_addr {
+                    address += section.sh_addr;
+                }
+            }
+            Some((address, sym.st_size, name.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_perf_map() {
+        let symbols = parse_perf_map("7f0a1c000000 40 py::fib:/app/fib.py\n7f0a1c000100 0x20 [jit] some function\nbad line\n");
+        assert_eq!(symbols, vec![(0x7f0a1c000000, 0x40, "py::fib:/app/fib.py".to_owned()),
+                                 (0x7f0a1c000100, 0x20, "[jit] some function".to_owned())]);
+    }
+
+    #[test]
+    fn test_parse_jitdump() {
+        let mut data = Vec::new();
+        // header: magic, version, header size, elf machine, padding, pid, timestamp, flags
+        for value in &[0x4A69_5444u32, 1, 40, 62, 0, 1234] {
+            data.extend_from_slice(&value.to_ne_bytes());
+        }
+        data.extend_from_slice(&[0u8; 16]);
+
+        let mut record = |id: u32, fields: &[u64], name: &[u8]| {
+            let size = 16 + 8 + fields.len() * 8 + name.len();
+            data.extend_from_slice(&id.to_ne_bytes());
+            data.extend_from_slice(&(size as u32).to_ne_bytes());
+            data.extend_from_slice(&0u64.to_ne_bytes());
+            data.extend_from_slice(&[0u8; 8]);
+            for field in fields {
+                data.extend_from_slice(&field.to_ne_bytes());
+            }
+            data.extend_from_slice(name);
+        };
+        // loads are vma, code address, code size, code index. moves are vma, old address, new address, size, index
+        record(0, &[0x1000, 0x1000, 0x80, 1], b"compiled_function\0");
+        record(0, &[0x2000, 0x2000, 0x40, 2], b"moved_function\0");
+        record(1, &[0x3000, 0x2000, 0x3000, 0x40, 2], b"");
+
+        let mut symbols = parse_jitdump(&data);
+        symbols.sort_unstable();
+        assert_eq!(symbols, vec![(0x1000, 0x80, "compiled_function".to_owned()), (0x3000, 0x40, "moved_function".to_owned())]);
+    }
+}
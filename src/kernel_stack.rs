@@ -0,0 +1,344 @@
+// Gets the kernel stacks of the threads of the process with perf_event_open, so that time spent in
+// syscalls (like reading from a socket or waiting on a futex) shows up under the python code that
+// made them. The kernel stack of a thread can't be read from outside of the kernel, so instead each
+// thread gets two perf events: a cpu clock event that samples the stack while the thread is running
+// in the kernel, and a context switch event that records the stack whenever the thread blocks. Each
+// python sample is matched up with the latest of these for its thread by OS thread id and time.
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::io::RawFd;
+
+use failure::Error;
+use remoteprocess::{Pid, Tid};
+
+use crate::stack_trace::Frame;
+
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+const PERF_RECORD_SAMPLE: u32 = 9;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+
+// bits of the flags in perf_event_attr
+const ATTR_EXCLUDE_USER: u64 = 1 << 4;
+const ATTR_EXCLUDE_HV: u64 = 1 << 6;
+const ATTR_EXCLUDE_CALLCHAIN_USER: u64 = 1 << 22;
+const ATTR_USE_CLOCKID: u64 = 1 << 25;
+
+// callchains have markers in them for whether the following addresses are in the kernel or in userspace
+const PERF_CONTEXT_MAX: u64 = -4095i64 as u64;
+
+// the number of pages in the ring buffer of each event, which has to be a power of 2
+const RING_PAGES: usize = 8;
+
+/// The frames of a kernel stack are labelled with this filename
+pub const KERNEL_FILENAME: &str = "<kernel>";
+
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    event_type: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+/// A kernel stack of a thread, with the addresses innermost first
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct KernelSample {
+    pub tid: u32,
+    // nanoseconds, from CLOCK_MONOTONIC
+    pub time: u64,
+    pub addresses: Vec<u64>,
+}
+
+// a perf event for one thread, along with its memory mapped ring buffer
+struct PerfEvent {
+    fd: RawFd,
+    buffer: *mut u8,
+    page_size: usize,
+}
+
+impl PerfEvent {
+    fn open(tid: Tid, config: u64, sample_period: u64) -> std::io::Result<PerfEvent> {
+        let attr = PerfEventAttr{event_type: PERF_TYPE_SOFTWARE, size: std::mem::size_of::<PerfEventAttr>() as u32,
+                                 config, sample_period,
+                                 sample_type: PERF_SAMPLE_TID | PERF_SAMPLE_TIME | PERF_SAMPLE_CALLCHAIN,
+                                 flags: ATTR_EXCLUDE_USER | ATTR_EXCLUDE_HV | ATTR_EXCLUDE_CALLCHAIN_USER | ATTR_USE_CLOCKID,
+                                 clockid: libc::CLOCK_MONOTONIC,
+                                 ..Default::default()};
+        let fd = unsafe { libc::syscall(libc::SYS_perf_event_open, &attr as *const PerfEventAttr, tid, -1, -1, PERF_FLAG_FD_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let buffer = unsafe { libc::mmap(std::ptr::null_mut(), page_size * (1 + RING_PAGES), libc::PROT_READ | libc::PROT_WRITE,
+                                         libc::MAP_SHARED, fd, 0) };
+        if buffer == libc::MAP_FAILED {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::close(fd); }
+            return Err(err);
+        }
+        Ok(PerfEvent{fd, buffer: buffer as *mut u8, page_size})
+    }
+
+    // reads the samples that have been written to the ring buffer since the last call
+    fn read_samples(&mut self) -> Vec<KernelSample> {
+        // data_head and data_tail are at offsets 1024 and 1032 of the perf_event_mmap_page header
+        let head_ptr = unsafe { self.buffer.add(1024) as *const u64 };
+        let tail_ptr = unsafe { self.buffer.add(1032) as *mut u64 };
+        let head = unsafe { std::ptr::read_volatile(head_ptr) };
+        std::sync::atomic::fence(std::sync::atomic::Ordering::Acquire);
+        let tail = unsafe { std::ptr::read_volatile(tail_ptr) };
+
+        let data = unsafe { std::slice::from_raw_parts(self.buffer.add(self.page_size), self.page_size * RING_PAGES) };
+        let samples = read_ring(data, tail, head);
+
+        std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
+        unsafe { std::ptr::write_volatile(tail_ptr, head); }
+        samples
+    }
+}
+
+impl Drop for PerfEvent {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.buffer as *mut libc::c_void, self.page_size * (1 + RING_PAGES));
+            libc::close(self.fd);
+        }
+    }
+}
+
+// the latest samples of a thread: from when it was last running in the kernel, and when it last blocked
+#[derive(Default)]
+struct ThreadSamples {
+    running: Option<KernelSample>,
+    blocked: Option<KernelSample>,
+}
+
+pub struct KernelStacks {
+    pid: Pid,
+    // the cpu clock and context switch events of each thread
+    events: HashMap<Tid, (PerfEvent, PerfEvent)>,
+    samples: HashMap<Tid, ThreadSamples>,
+    symbols: KernelSymbols,
+    sample_period: u64,
+}
+
+impl KernelStacks {
+    /// Starts sampling the kernel stacks of the threads of a process, at about the sampling rate of py-spy
+    pub fn new(pid: Pid, sampling_rate: u64) -> Result<KernelStacks, Error> {
+        let kallsyms = fs::read_to_string("/proc/kallsyms")
+            .map_err(|e| format_err!("Failed to read kernel symbols from /proc/kallsyms: {}", e))?;
+        let symbols = KernelSymbols::parse(&kallsyms);
+        if symbols.is_empty() {
+            return Err(format_err!("The addresses of kernel symbols are hidden. Try running as root, or setting \
+                                    /proc/sys/kernel/kptr_restrict to 0"));
+        }
+
+        let sample_period = 1_000_000_000 / sampling_rate.max(1);
+        let mut stacks = KernelStacks{pid, events: HashMap::new(), samples: HashMap::new(), symbols, sample_period};
+        stacks.update_threads().map_err(|e| match e.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EPERM) =>
+                format_err!("Permission denied opening perf events for kernel stacks. Try running as root, or setting \
+                             /proc/sys/kernel/perf_event_paranoid to 1 or less"),
+            _ => format_err!("Failed to open perf events for kernel stacks: {}", e)
+        })?;
+        Ok(stacks)
+    }
+
+    /// Starts sampling threads that have been created since the last update, and reads the new samples
+    pub fn update(&mut self) {
+        if let Err(e) = self.update_threads() {
+            debug!("Failed to update the threads sampled for kernel stacks: {}", e);
+        }
+        for (tid, (running, blocked)) in self.events.iter_mut() {
+            let samples = self.samples.entry(*tid).or_default();
+            if let Some(sample) = running.read_samples().pop() {
+                samples.running = Some(sample);
+            }
+            if let Some(sample) = blocked.read_samples().pop() {
+                samples.blocked = Some(sample);
+            }
+        }
+    }
+
+    /// Gets the kernel frames of a thread, innermost first. Threads that are idle are given the stack
+    /// they blocked in, as long as they haven't been seen running since. Active threads are given their
+    /// latest stack in the kernel if it was sampled recently, as otherwise they're running in userspace
+    pub fn frames(&self, tid: Tid, active: bool, now: u64) -> Option<Vec<Frame>> {
+        let samples = self.samples.get(&tid)?;
+        let sample = if active {
+            samples.running.as_ref().filter(|running| now.saturating_sub(running.time) <= 2 * self.sample_period)?
+        } else {
+            let blocked = samples.blocked.as_ref()?;
+            if samples.running.as_ref().is_some_and(|running| running.time > blocked.time) {
+                return None;
+            }
+            blocked
+        };
+        Some(sample.addresses.iter().map(|&address| self.symbols.frame(address)).collect())
+    }
+
+    fn update_threads(&mut self) -> std::io::Result<()> {
+        let mut tids = Vec::new();
+        for entry in fs::read_dir(format!("/proc/{}/task", self.pid))? {
+            if let Ok(tid) = entry?.file_name().to_string_lossy().parse::<Tid>() {
+                tids.push(tid);
+            }
+        }
+        self.events.retain(|tid, _| tids.contains(tid));
+        self.samples.retain(|tid, _| tids.contains(tid));
+        for tid in tids {
+            if !self.events.contains_key(&tid) {
+                let running = PerfEvent::open(tid, PERF_COUNT_SW_CPU_CLOCK, self.sample_period)?;
+                let blocked = PerfEvent::open(tid, PERF_COUNT_SW_CONTEXT_SWITCHES, 1)?;
+                self.events.insert(tid, (running, blocked));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The current time, in the same clock as the timestamps of the samples
+pub fn monotonic_time() -> u64 {
+    let mut time = libc::timespec{tv_sec: 0, tv_nsec: 0};
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time); }
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+// reads the sample records between tail and head of the data area of a ring buffer, which wrap around
+fn read_ring(data: &[u8], mut tail: u64, head: u64) -> Vec<KernelSample> {
+    let copy = |offset: u64, length: usize| -> Vec<u8> {
+        (0..length).map(|i| data[(offset as usize + i) % data.len()]).collect()
+    };
+    let mut samples = Vec::new();
+    while tail + 8 <= head {
+        let header = copy(tail, 8);
+        let record_type = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+        let size = u16::from_ne_bytes([header[6], header[7]]) as u64;
+        if size < 8 || tail + size > head {
+            break;
+        }
+        if record_type == PERF_RECORD_SAMPLE {
+            if let Some(sample) = parse_sample(&copy(tail + 8, size as usize - 8)) {
+                samples.push(sample);
+            }
+        }
+        tail += size;
+    }
+    samples
+}
+
+// parses the body of a PERF_RECORD_SAMPLE with the tid, time and callchain in it
+fn parse_sample(data: &[u8]) -> Option<KernelSample> {
+    let read_u64 = |offset: usize| -> Option<u64> {
+        let bytes = data.get(offset..offset + 8)?;
+        let mut value = [0u8; 8];
+        value.copy_from_slice(bytes);
+        Some(u64::from_ne_bytes(value))
+    };
+    let tid = (read_u64(0)? >> 32) as u32;
+    let time = read_u64(8)?;
+    let count = read_u64(16)? as usize;
+    let mut addresses = Vec::with_capacity(count.min(256));
+    for i in 0..count {
+        let address = read_u64(24 + i * 8)?;
+        if address < PERF_CONTEXT_MAX {
+            addresses.push(address);
+        }
+    }
+    Some(KernelSample{tid, time, addresses})
+}
+
+// the function symbols of the kernel, sorted by address
+struct KernelSymbols {
+    symbols: Vec<(u64, String)>,
+}
+
+impl KernelSymbols {
+    fn parse(kallsyms: &str) -> KernelSymbols {
+        let mut symbols: Vec<(u64, String)> = kallsyms.lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let symbol_type = fields.next()?;
+                let name = fields.next()?;
+                match symbol_type {
+                    "t" | "T" | "w" | "W" if address != 0 => Some((address, name.to_owned())),
+                    _ => None
+                }
+            })
+            .collect();
+        symbols.sort_unstable();
+        KernelSymbols{symbols}
+    }
+
+    fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    fn frame(&self, address: u64) -> Frame {
+        let name = match self.symbols.binary_search_by(|symbol| symbol.0.cmp(&address)) {
+            Ok(i) => self.symbols[i].1.clone(),
+            Err(0) => format!("0x{:016x}", address),
+            Err(i) => self.symbols[i - 1].1.clone()
+        };
+        Frame{name, filename: KERNEL_FILENAME.to_owned(), module: None, short_filename: None, line: 0, frame_ptr: None, locals: None}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kernel_samples() {
+        let symbols = KernelSymbols::parse("0000000000000000 A fixed_percpu_data\n\
+                                            ffffffff81000000 T _stext\n\
+                                            ffffffff81200000 t do_syscall_64\n\
+                                            ffffffff81100000 T schedule\n\
+                                            ffffffff81300000 d some_data\n");
+        assert_eq!(symbols.frame(0xffffffff81100040).name, "schedule");
+        assert_eq!(symbols.frame(0xffffffff81200000).name, "do_syscall_64");
+        assert_eq!(symbols.frame(0x1000).name, "0x0000000000001000");
+
+        // a sample record split across the end of the ring buffer, with a kernel context marker in its callchain
+        let mut record = Vec::new();
+        record.extend_from_slice(&PERF_RECORD_SAMPLE.to_ne_bytes());
+        record.extend_from_slice(&0u16.to_ne_bytes());
+        record.extend_from_slice(&56u16.to_ne_bytes());
+        record.extend_from_slice(&((42u64 << 32) | 42).to_ne_bytes());
+        record.extend_from_slice(&1234u64.to_ne_bytes());
+        record.extend_from_slice(&3u64.to_ne_bytes());
+        for address in &[-128i64 as u64, 0xffffffff81100040, 0xffffffff81200010] {
+            record.extend_from_slice(&address.to_ne_bytes());
+        }
+        let mut data = vec![0u8; 64];
+        for (i, byte) in record.iter().enumerate() {
+            data[(40 + i) % 64] = *byte;
+        }
+        let samples = read_ring(&data, 40, 40 + record.len() as u64);
+        assert_eq!(samples, vec![KernelSample{tid: 42, time: 1234, addresses: vec![0xffffffff81100040, 0xffffffff81200010]}]);
+    }
+}
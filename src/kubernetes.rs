@@ -0,0 +1,136 @@
+// Finds the processes of a container in a kubernetes pod, so that pods can be profiled by name from
+// the node they're running on (like from a privileged DaemonSet with hostPID). The container's host
+// pid is looked up with crictl when it's installed, and otherwise by looking for the processes whose
+// HOSTNAME is the pod name, which kubelet sets for every container it starts.
+use std::collections::HashSet;
+use std::process::Command;
+
+use failure::Error;
+use remoteprocess::{Pid, Process};
+use serde_json::Value;
+
+/// Returns the pid of the main process of a container in a pod, followed by its descendants
+pub fn container_processes(pod: &str, container: Option<&str>, namespace: Option<&str>) -> Result<Vec<Pid>, Error> {
+    let pid = match crictl(&["version"]) {
+        Ok(_) => crictl_container_pid(pod, container, namespace)?,
+        Err(e) => {
+            info!("Failed to run crictl ({}), looking for the processes of pod {} by hostname", e, pod);
+            if container.is_some() || namespace.is_some() {
+                return Err(format_err!("--container and --namespace need crictl to find which container is which"));
+            }
+            hostname_pid(pod)?
+        }
+    };
+    info!("Found pid {} for pod {}", pid, pod);
+    let mut pids = vec![pid];
+    pids.extend(Process::new(pid)?.child_processes()?);
+    Ok(pids)
+}
+
+fn crictl(args: &[&str]) -> Result<Value, Error> {
+    let output = Command::new("crictl").args(args).output()?;
+    if !output.status.success() {
+        return Err(format_err!("crictl {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(serde_json::from_slice(&output.stdout).unwrap_or(Value::Null))
+}
+
+fn crictl_container_pid(pod: &str, container: Option<&str>, namespace: Option<&str>) -> Result<Pid, Error> {
+    let mut args = vec!["pods", "-o", "json", "--state", "ready", "--name", pod];
+    if let Some(namespace) = namespace {
+        args.extend(&["--namespace", namespace]);
+    }
+    let pod_id = match parse_pods(&crictl(&args)?, pod, namespace).as_slice() {
+        [] => return Err(format_err!("Failed to find a running pod named {} on this node", pod)),
+        [pod_id] => pod_id.clone(),
+        _ => return Err(format_err!("Found more than one pod named {}, use --namespace to pick one", pod))
+    };
+
+    let containers = crictl(&["ps", "-o", "json", "--state", "running", "--pod", &pod_id])?;
+    let container_id = parse_containers(&containers, pod, container)?;
+    let status = crictl(&["inspect", "-o", "json", &container_id])?;
+    parse_container_pid(&status).ok_or_else(|| format_err!("Failed to get the pid of container {} from crictl", container_id))
+}
+
+// gets the ids of the pods with a name, since crictl matches names by regex
+fn parse_pods(pods: &Value, pod: &str, namespace: Option<&str>) -> Vec<String> {
+    let items = match pods["items"].as_array() {
+        Some(items) => items,
+        None => return Vec::new()
+    };
+    items.iter()
+        .filter(|item| item["metadata"]["name"].as_str() == Some(pod))
+        .filter(|item| namespace.is_none() || item["metadata"]["namespace"].as_str() == namespace)
+        .filter_map(|item| item["id"].as_str().map(|id| id.to_owned()))
+        .collect()
+}
+
+fn parse_containers(containers: &Value, pod: &str, container: Option<&str>) -> Result<String, Error> {
+    let containers: Vec<(&str, &str)> = containers["containers"].as_array().map(|containers| containers.iter()
+        .filter_map(|c| Some((c["id"].as_str()?, c["metadata"]["name"].as_str()?)))
+        .collect()).unwrap_or_default();
+    let names = || containers.iter().map(|(_, name)| *name).collect::<Vec<&str>>().join(", ");
+    match container {
+        Some(container) => containers.iter().find(|(_, name)| *name == container).map(|(id, _)| id.to_string())
+            .ok_or_else(|| format_err!("Pod {} doesn't have a running container named {} (it has: {})", pod, container, names())),
+        None => match containers.as_slice() {
+            [] => Err(format_err!("Pod {} doesn't have any running containers", pod)),
+            [(id, _)] => Ok(id.to_string()),
+            _ => Err(format_err!("Pod {} has more than one container ({}), use --container to pick one", pod, names()))
+        }
+    }
+}
+
+fn parse_container_pid(status: &Value) -> Option<Pid> {
+    status["info"]["pid"].as_i64().filter(|&pid| pid > 0).map(|pid| pid as Pid)
+}
+
+// finds the first process started in the pod, from the HOSTNAME in the environment of each process
+fn hostname_pid(pod: &str) -> Result<Pid, Error> {
+    let hostname = format!("HOSTNAME={}", pod);
+    let matching: Vec<Pid> = remoteprocess::processes()?.into_iter()
+        .filter(|pid| std::fs::read(format!("/proc/{}/environ", pid))
+            .is_ok_and(|environ| environ.split(|&b| b == 0).any(|var| var == hostname.as_bytes())))
+        .collect();
+
+    let mut children = HashSet::new();
+    for &pid in &matching {
+        if let Ok(pids) = Process::new(pid).and_then(|process| process.child_processes()) {
+            children.extend(pids);
+        }
+    }
+    let roots: Vec<Pid> = matching.into_iter().filter(|pid| !children.contains(pid)).collect();
+    match roots.as_slice() {
+        [] => Err(format_err!("Failed to find any processes for pod {} on this node", pod)),
+        [pid] => Ok(*pid),
+        _ => Err(format_err!("Found more than one container for pod {} ({:?}), install crictl and use --container \
+                              to pick one", pod, roots))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_crictl() {
+        let pods: Value = serde_json::from_str(r#"{"items": [
+            {"id": "abc", "metadata": {"name": "web-1", "namespace": "default"}},
+            {"id": "def", "metadata": {"name": "web-12", "namespace": "default"}},
+            {"id": "ghi", "metadata": {"name": "web-1", "namespace": "staging"}}]}"#).unwrap();
+        assert_eq!(parse_pods(&pods, "web-1", None), vec!["abc", "ghi"]);
+        assert_eq!(parse_pods(&pods, "web-1", Some("staging")), vec!["ghi"]);
+        assert!(parse_pods(&Value::Null, "web-1", None).is_empty());
+
+        let containers: Value = serde_json::from_str(r#"{"containers": [
+            {"id": "111", "metadata": {"name": "web"}},
+            {"id": "222", "metadata": {"name": "sidecar"}}]}"#).unwrap();
+        assert_eq!(parse_containers(&containers, "web-1", Some("sidecar")).unwrap(), "222");
+        assert!(parse_containers(&containers, "web-1", Some("db")).is_err());
+        assert!(parse_containers(&containers, "web-1", None).is_err());
+
+        let status: Value = serde_json::from_str(r#"{"info": {"pid": 4242}}"#).unwrap();
+        assert_eq!(parse_container_pid(&status), Some(4242));
+        assert_eq!(parse_container_pid(&Value::Null), None);
+    }
+}
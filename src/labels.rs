@@ -0,0 +1,50 @@
+// Labels given with --label, like 'service=web', that are stored in the output so that recordings
+// can still be told apart once they're collected together
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Labels {
+    labels: Vec<(String, String)>,
+}
+
+impl Labels {
+    /// Sets a label, replacing any earlier value it had
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.labels.iter_mut().find(|(existing, _)| existing == key) {
+            Some(label) => label.1 = value.to_owned(),
+            None => self.labels.push((key.to_owned(), value.to_owned()))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&(String, String)> {
+        self.labels.iter()
+    }
+}
+
+impl std::fmt::Display for Labels {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, (key, value)) in self.labels.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels() {
+        let mut labels = Labels::default();
+        labels.set("service", "web");
+        labels.set("version", "1.2");
+        labels.set("service", "api");
+        assert_eq!(labels.to_string(), "service=api, version=1.2");
+    }
+}
@@ -6,7 +6,7 @@
 //! # Example:
 //!
 //! ```rust,no_run
-//! fn print_python_stacks(pid: remoteprocess::Pid) -> Result<(), failure::Error> {
+//! fn print_python_stacks(pid: remoteprocess::Pid) -> Result<(), py_spy::Error> {
 //!     // Create a new PythonSpy object with the default config options
 //!     let config = py_spy::Config::default();
 //!     let mut process = py_spy::PythonSpy::new(pid, &config)?;
@@ -24,12 +24,16 @@
 //!     Ok(())
 //! }
 //! ```
+//!
+//! Errors are a [`py_spy::Error`](enum.Error.html), which can be matched on to tell why sampling
+//! failed, like `Error::PermissionDenied` when py-spy isn't allowed to read the memory of the process.
 
 #[macro_use]
 extern crate clap;
 #[macro_use]
 extern crate failure;
 extern crate goblin;
+extern crate inferno;
 #[macro_use]
 extern crate lazy_static;
 extern crate libc;
@@ -40,15 +44,41 @@ extern crate lru;
 extern crate memmap;
 extern crate proc_maps;
 extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 #[cfg(windows)]
 extern crate winapi;
 extern crate cpp_demangle;
 extern crate rand;
 extern crate remoteprocess;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate tokio;
 
+mod alert;
+mod assertion;
+mod asyncio;
 mod config;
+mod config_builder;
+mod config_file;
+mod error;
+mod exceptions;
 mod binary_parser;
+mod flamegraph;
+mod greenlet;
+mod heap;
+mod idle_rules;
+#[cfg(target_os="linux")]
+mod jit_symbols;
+#[cfg(target_os="linux")]
+mod kernel_stack;
+mod labels;
+mod locals;
+mod profiler;
 #[cfg(unwind)]
 mod cython;
 #[cfg(unwind)]
@@ -56,12 +86,36 @@ mod native_stack_trace;
 mod python_bindings;
 mod python_interpreters;
 mod python_spy;
+mod sampler;
+#[cfg(feature = "async")]
+mod sampler_stream;
+mod speedscope;
 mod stack_trace;
+mod subscriber;
+mod threading;
+mod time_range;
+mod timer;
+mod tracemalloc;
+mod unwind_pool;
+mod trigger;
 mod utils;
 mod version;
+mod workers;
 
+pub use error::Error;
 pub use python_spy::PythonSpy;
-pub use config::Config;
+pub use config::{Config, FileFormat};
+pub use config_builder::ConfigBuilder;
+pub use profiler::Profiler;
+pub use sampler::{Sample, Sampler};
+#[cfg(feature = "async")]
+pub use sampler_stream::SamplerStream;
+pub use subscriber::{Backpressure, Subscription, TraceSink};
 pub use stack_trace::StackTrace;
 pub use stack_trace::Frame;
+pub use stack_trace::{ExceptionInfo, LocalVariable};
+pub use trigger::{Metric, Trigger, TriggerWatcher};
+pub use alert::{Condition, Measure};
+pub use time_range::{Bounds, TimeBound};
+pub use labels::Labels;
 
@@ -0,0 +1,267 @@
+// Reads the local variables of python frames, for dumping out what each thread was working on
+// (or recording the arguments of the innermost functions of each sample).
+// Only the fast locals of functions are read (module level code keeps its variables in a dict),
+// and only simple values like ints and strings are shown, other objects are described by their
+// type. The object headers used here are the same across every python version we support.
+use std::collections::HashMap;
+use std::mem::size_of;
+
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::python_interpreters::{CodeObject, FrameObject, StringObject};
+use crate::stack_trace::{LocalVariable, copy_string};
+use crate::version::Version;
+
+// sanity limit on the number of locals in a frame, in case we read garbage
+const MAX_LOCALS: usize = 4096;
+
+// strings longer than this are truncated
+const MAX_STRING_LENGTH: usize = 40;
+
+// python ints store 30 bits per digit, and we only read ints that fit in 128 bits
+const DIGIT_BITS: usize = 30;
+const MAX_DIGITS: usize = 4;
+
+#[repr(C)]
+struct ObjectHeader {
+    ob_refcnt: isize,
+    ob_type: usize,
+}
+
+#[repr(C)]
+struct VarObjectHeader {
+    ob_base: ObjectHeader,
+    ob_size: isize,
+}
+
+#[repr(C)]
+struct TypeHeader {
+    ob_base: VarObjectHeader,
+    tp_name: usize,
+}
+
+// the int type of python 2, which became the long type in python 3
+#[repr(C)]
+struct IntObject {
+    ob_base: ObjectHeader,
+    ob_ival: std::os::raw::c_long,
+}
+
+#[repr(C)]
+struct FloatObject {
+    ob_base: ObjectHeader,
+    ob_fval: f64,
+}
+
+/// Gets the local variables of the frame at the given address, with the positional arguments
+/// of the function first. Variables that haven't been assigned yet are skipped. If max_args is
+/// set, only (up to) that many of the positional arguments are returned
+pub fn get_locals<F, P>(process: &P, frame_ptr: usize, version: &Version, max_args: Option<usize>) -> Result<Vec<LocalVariable>, Error>
+        where F: FrameObject, P: ProcessMemory {
+    let frame: F = process.copy_struct(frame_ptr).context("Failed to copy PyFrameObject")?;
+    let code = process.copy_pointer(frame.code()).context("Failed to copy PyCodeObject")?;
+    if code.nlocals() < 0 || code.nlocals() as usize > MAX_LOCALS {
+        return Err(format_err!("Invalid number of locals {}", code.nlocals()));
+    }
+    let argcount = code.argcount().max(0) as usize;
+    let nlocals = match max_args {
+        Some(max_args) => (code.nlocals() as usize).min(argcount).min(max_args),
+        None => code.nlocals() as usize
+    };
+    let names = tuple_items(process, code.varnames()).context("Failed to copy local variable names")?;
+    let values = copy_pointers(process, frame.localsplus(frame_ptr), nlocals).context("Failed to copy local variables")?;
+
+    let mut locals = Vec::new();
+    for (i, (&name, &value)) in names.iter().zip(values.iter()).enumerate() {
+        if value == 0 {
+            continue;
+        }
+        let name = copy_string(name as *const <F::CodeObject as CodeObject>::StringObject, process)?;
+        let repr = format_value::<<F::CodeObject as CodeObject>::StringObject, P>(process, value, version)
+            .unwrap_or_else(|_| format!("<unknown at {:#x}>", value));
+        locals.push(LocalVariable{name, arg: i < argcount, repr});
+    }
+    Ok(locals)
+}
+
+/// How a method is passed the object it was called on, going by the name of its first argument
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Receiver {
+    /// An instance of the class, named 'self'
+    Instance,
+    /// The class itself in classmethods, named 'cls'
+    Class,
+}
+
+/// Gets the name of the class that the function running in the frame at the given address is a
+/// method of, for qualifying its name like python's __qualname__ does. This goes by the type of the
+/// first argument of functions whose first argument is self or cls, so it gives the class that the
+/// method was called on (which can be a subclass of the one that defined it). The receiver used
+/// by each code object is cached in receivers, since that doesn't change between samples
+pub fn get_class_name<F, P>(process: &P, frame_ptr: usize, receivers: &mut HashMap<usize, Option<Receiver>>) -> Result<Option<String>, Error>
+        where F: FrameObject, P: ProcessMemory {
+    let frame: F = process.copy_struct(frame_ptr).context("Failed to copy PyFrameObject")?;
+    let receiver = match receivers.get(&(frame.code() as usize)) {
+        Some(receiver) => *receiver,
+        None => {
+            let receiver = get_receiver(process, frame.code())?;
+            receivers.insert(frame.code() as usize, receiver);
+            receiver
+        }
+    };
+    let receiver = match receiver {
+        Some(receiver) => receiver,
+        None => return Ok(None)
+    };
+
+    // the argument is unset when the function has deleted it
+    let value = copy_pointers(process, frame.localsplus(frame_ptr), 1)?[0];
+    if value == 0 {
+        return Ok(None);
+    }
+    let type_name = match receiver {
+        Receiver::Instance => object_type_name(process, value)?,
+        Receiver::Class => copy_type_name(process, value)?
+    };
+    // builtin types are named with their module, like 'collections.OrderedDict'
+    Ok(Some(type_name.rsplit('.').next().unwrap_or_default().to_owned()))
+}
+
+fn get_receiver<C, P>(process: &P, code: *mut C) -> Result<Option<Receiver>, Error>
+        where C: CodeObject, P: ProcessMemory {
+    let code: C = process.copy_pointer(code).context("Failed to copy PyCodeObject")?;
+    if code.argcount() <= 0 {
+        return Ok(None);
+    }
+    let name = match tuple_items(process, code.varnames())?.first() {
+        Some(&name) => copy_string(name as *const C::StringObject, process)?,
+        None => return Ok(None)
+    };
+    Ok(match name.as_str() {
+        "self" => Some(Receiver::Instance),
+        "cls" => Some(Receiver::Class),
+        _ => None
+    })
+}
+
+/// Copies the value of a python int that fits in 128 bits (a long in python 2)
+pub fn copy_long<P: ProcessMemory>(process: &P, object: usize) -> Result<i128, Error> {
+    let header: VarObjectHeader = process.copy_struct(object)?;
+    // the sign of the int is stored in the sign of the size
+    let digits = header.ob_size.unsigned_abs();
+    if digits > MAX_DIGITS {
+        return Err(format_err!("Int of {} digits is too large to copy", digits));
+    }
+    let data = process.copy(object + size_of::<VarObjectHeader>(), digits * size_of::<u32>())?;
+    let mut value: i128 = 0;
+    for i in (0..digits).rev() {
+        let digit = unsafe { std::ptr::read_unaligned(data.as_ptr().add(i * size_of::<u32>()) as *const u32) };
+        value = (value << DIGIT_BITS) | i128::from(digit);
+    }
+    Ok(if header.ob_size < 0 { -value } else { value })
+}
+
+/// Describes a python object, like repr does for simple values
+pub fn format_value<S, P>(process: &P, object: usize, version: &Version) -> Result<String, Error>
+        where S: StringObject, P: ProcessMemory {
+    let type_name = object_type_name(process, object)?;
+    let value = match (type_name.as_str(), version.major) {
+        ("NoneType", _) => "None".to_owned(),
+        ("bool", 2) => bool_repr(process.copy_struct::<IntObject>(object)?.ob_ival != 0),
+        ("bool", _) => bool_repr(copy_long(process, object)? != 0),
+        ("int", 2) => process.copy_struct::<IntObject>(object)?.ob_ival.to_string(),
+        ("int", _) | ("long", 2) => match copy_long(process, object) {
+            Ok(value) => value.to_string(),
+            Err(_) => format!("<{} at {:#x}>", type_name, object)
+        },
+        ("float", _) => format!("{:?}", process.copy_struct::<FloatObject>(object)?.ob_fval),
+        ("str", _) => match copy_string(object as *const S, process) {
+            Ok(value) => string_repr(&value),
+            Err(_) => format!("<str at {:#x}>", object)
+        },
+        _ => format!("<{} at {:#x}>", type_name, object)
+    };
+    Ok(value)
+}
+
+fn bool_repr(value: bool) -> String {
+    if value { "True".to_owned() } else { "False".to_owned() }
+}
+
+// quotes a string, truncating it to MAX_STRING_LENGTH characters
+fn string_repr(value: &str) -> String {
+    let mut repr: String = value.chars().take(MAX_STRING_LENGTH).flat_map(char::escape_debug).collect();
+    if value.chars().count() > MAX_STRING_LENGTH {
+        repr.push_str("...");
+    }
+    format!("'{}'", repr)
+}
+
+/// Gets the name of the type of a python object
+pub fn object_type_name<P: ProcessMemory>(process: &P, object: usize) -> Result<String, Error> {
+    let header: ObjectHeader = process.copy_struct(object)?;
+    copy_type_name(process, header.ob_type)
+}
+
+fn copy_type_name<P: ProcessMemory>(process: &P, object_type: usize) -> Result<String, Error> {
+    let object_type: TypeHeader = process.copy_struct(object_type)?;
+    let name = process.copy(object_type.tp_name, 64)?;
+    let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Ok(String::from_utf8_lossy(&name[..end]).into_owned())
+}
+
+/// Gets the items of a tuple
+pub fn tuple_items<P: ProcessMemory>(process: &P, tuple: usize) -> Result<Vec<usize>, Error> {
+    let header: VarObjectHeader = process.copy_struct(tuple)?;
+    if header.ob_size < 0 || header.ob_size as usize > MAX_LOCALS {
+        return Err(format_err!("Invalid tuple size {}", header.ob_size));
+    }
+    copy_pointers(process, tuple + size_of::<VarObjectHeader>(), header.ob_size as usize)
+}
+
+fn copy_pointers<P: ProcessMemory>(process: &P, address: usize, count: usize) -> Result<Vec<usize>, Error> {
+    let data = process.copy(address, count * size_of::<usize>())?;
+    Ok((0..count).map(|i| unsafe { std::ptr::read_unaligned(data.as_ptr().add(i * size_of::<usize>()) as *const usize) })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+
+    #[repr(C)]
+    struct LongObject {
+        header: VarObjectHeader,
+        digits: [u32; 3],
+    }
+
+    fn long(ob_size: isize, digits: [u32; 3]) -> LongObject {
+        LongObject{header: VarObjectHeader{ob_base: ObjectHeader{ob_refcnt: 1, ob_type: 0}, ob_size}, digits}
+    }
+
+    #[test]
+    fn test_copy_long() {
+        let value: i128 = 0x7f12_3456_789a;
+        let digits = [(value & 0x3fff_ffff) as u32, ((value >> 30) & 0x3fff_ffff) as u32, 0];
+        let positive = long(2, digits);
+        assert_eq!(copy_long(&LocalProcess, &positive as *const LongObject as usize).unwrap(), value);
+
+        let negative = long(-2, digits);
+        assert_eq!(copy_long(&LocalProcess, &negative as *const LongObject as usize).unwrap(), -value);
+
+        let zero = long(0, digits);
+        assert_eq!(copy_long(&LocalProcess, &zero as *const LongObject as usize).unwrap(), 0);
+
+        let huge = long(100, digits);
+        assert!(copy_long(&LocalProcess, &huge as *const LongObject as usize).is_err());
+    }
+
+    #[test]
+    fn test_string_repr() {
+        assert_eq!(string_repr("hello"), "'hello'");
+        assert_eq!(string_repr("a\nb"), "'a\\nb'");
+        assert_eq!(string_repr(&"x".repeat(50)), format!("'{}...'", "x".repeat(40)));
+    }
+}
@@ -18,6 +18,10 @@ extern crate lru;
 extern crate memmap;
 extern crate proc_maps;
 extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate tempfile;
 #[cfg(unix)]
 extern crate termios;
@@ -27,7 +31,18 @@ extern crate cpp_demangle;
 extern crate rand;
 extern crate remoteprocess;
 
+mod adaptive_rate;
+mod agent;
+mod alert;
+mod alert_window;
+mod assertion;
+mod asyncio;
 mod config;
+mod config_file;
+#[cfg(target_os="linux")]
+mod core_dump;
+mod error;
+mod exceptions;
 mod binary_parser;
 #[cfg(unwind)]
 mod cython;
@@ -38,50 +53,333 @@ mod python_interpreters;
 mod python_spy;
 mod stack_trace;
 mod console_viewer;
+mod control;
+mod csv;
+mod dump_json;
+mod filename_template;
 mod flamegraph;
+mod frame_filter;
+mod function_stats;
+mod gate;
+mod greenlet;
+mod grouping;
+mod heap;
+mod idle_rules;
+#[cfg(target_os="linux")]
+mod jit_symbols;
+#[cfg(target_os="linux")]
+mod kernel_stack;
+#[cfg(target_os="linux")]
+mod kubernetes;
+mod labels;
+mod heap_report;
+mod html_report;
+mod locals;
+mod monitor;
+#[cfg(all(target_os="linux", target_arch="x86_64"))]
+mod offline_symbols;
+mod path_map;
+mod permissions;
+mod pprof;
+mod preview;
+mod process_group;
+mod ps;
+mod raw_capture;
+mod resources;
+mod respawn;
+mod sha256;
+mod signals;
+mod speedscope;
+mod status;
+mod summary;
 mod utils;
+mod threading;
+mod thread_events;
+mod time_range;
 mod timer;
+mod tracemalloc;
+mod unwind_pool;
+mod trigger;
 mod version;
+mod workers;
 
-use std::io::Read;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use failure::Error;
+use failure::{Error, ResultExt};
 
 use python_spy::PythonSpy;
-use stack_trace::StackTrace;
+use stack_trace::{LocalVariable, StackTrace};
 use console_viewer::ConsoleViewer;
+use adaptive_rate::AdaptiveRate;
+use config::FileFormat;
+use filename_template::FilenameTemplate;
+use frame_filter::FrameFilter;
+use preview::{Preview, Remaining};
+use process_group::ProcessGroup;
+use raw_capture::{RawCaptureReader, RawCaptureWriter};
+use time_range::TimeBound;
+use thread_events::{ThreadChange, ThreadTracker};
+use trigger::TriggerWatcher;
+
+// Applies the --gil and --idle filters to a sample. Output formats only include active threads,
+// so idle threads are included by marking them as active
+fn filter_traces(traces: &mut Vec<StackTrace>, gil_only: bool, include_idle: bool) {
+    if gil_only {
+        traces.retain(|trace| trace.owns_gil);
+    }
+    if include_idle {
+        for trace in traces.iter_mut() {
+            trace.active = true;
+        }
+    }
+}
+
+// the version of python, along with the library it was found in for programs that link to libpython
+// and how it was found when that wasn't from sys.version
+fn python_version(process: &PythonSpy) -> String {
+    let mut version = process.version.to_string();
+    if let Some(ref libpython) = process.libpython_filename {
+        version += &format!(" ({})", libpython);
+    }
+    if process.version_source != version::VersionSource::SysVersion {
+        version += &format!(" (from {}, {} confidence)", process.version_source, process.version_source.confidence());
+    }
+    version
+}
 
-fn print_traces(traces: &[StackTrace], show_idle: bool) {
+fn write_traces(w: &mut dyn Write, traces: &[StackTrace], show_idle: bool) -> std::io::Result<()> {
+    let gil_holder = traces.iter().find(|trace| trace.owns_gil);
     for trace in traces {
         if !show_idle && !trace.active {
             continue;
         }
 
+        let mut thread_name = match trace.thread_name.as_ref() {
+            Some(name) => format!(": \"{}\"", name),
+            None => "".to_owned()
+        };
+        if trace.interpreter_id != 0 {
+            thread_name.push_str(&format!(" (interpreter {})", trace.interpreter_id));
+        }
+        if let (true, Some(holder)) = (trace.waiting_for_gil(), gil_holder) {
+            thread_name.push_str(&format!(" (GIL held by thread {:#X})", holder.thread_id));
+        }
         if let Some(os_thread_id) = trace.os_thread_id {
-            println!("Thread {:#X}/{} ({})", trace.thread_id,  os_thread_id, trace.status_str());
+            writeln!(w, "Thread {:#X}/{} ({}){}", trace.thread_id,  os_thread_id, trace.status_str(), thread_name)?;
         } else {
-            println!("Thread {:#X} ({})", trace.thread_id, trace.status_str());
+            writeln!(w, "Thread {:#X} ({}){}", trace.thread_id, trace.status_str(), thread_name)?;
         }
-        for frame in &trace.frames {
+        for (i, frame) in trace.frames.iter().enumerate() {
             let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
             if frame.line != 0 {
-                println!("\t {} ({}:{})", frame.name, filename, frame.line);
+                writeln!(w, "\t {} ({}:{})", frame.name, filename, frame.line)?;
             } else {
-                println!("\t {} ({})", frame.name, filename);
+                writeln!(w, "\t {} ({})", frame.name, filename)?;
+            }
+            if let (0, Some(awaiting)) = (i, trace.awaiting.as_ref()) {
+                writeln!(w, "\t     Awaiting {}", awaiting)?;
             }
+            if let Some(locals) = frame.locals.as_ref() {
+                write_locals(w, locals)?;
+            }
+        }
+        if let Some(exception) = trace.exception.as_ref() {
+            writeln!(w, "\t Handling {}({}), raised at:", exception.type_name, exception.args.join(", "))?;
+            for frame in &exception.traceback {
+                let filename = match &frame.short_filename { Some(f) => f, None => &frame.filename };
+                writeln!(w, "\t     {} ({}:{})", frame.name, filename, frame.line)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// dumps the current stack traces of some processes, as text or JSON. With --interval or --watch this
+// repeats until Control-C is pressed, --count dumps have been taken or the processes have all ended
+fn dump_processes(mut processes: Vec<&mut PythonSpy>, show_pids: bool, config: &config::Config) -> Result<(), Error> {
+    let interval = match config.dump_interval {
+        Some(interval) => interval,
+        None => {
+            let mut traces = Vec::new();
+            for process in processes.iter_mut() {
+                traces.push(dump_traces(process, config)?);
+            }
+            return write_dump(&processes, &traces, show_pids, None, config);
+        }
+    };
+
+    install_ctrlc_handler()?;
+    let mut previous = None;
+    let mut dumps = 0;
+    while RUNNING.load(Ordering::SeqCst) {
+        let taken = Instant::now();
+        processes.retain(|process| {
+            let exited = process_exitted(&process.process);
+            if exited {
+                eprintln!("Process {} ended", process.pid);
+            }
+            !exited
+        });
+        if processes.is_empty() {
+            break;
+        }
+
+        let traces: Result<Vec<Vec<StackTrace>>, Error> = processes.iter_mut().map(|process| dump_traces(process, config)).collect();
+        match traces {
+            Ok(traces) => {
+                // the stacks of each thread, to tell whether anything has changed since the last dump
+                let stacks: Vec<Vec<_>> = traces.iter()
+                    .map(|traces| traces.iter().map(|trace| (trace.thread_id, trace.os_thread_id, trace.frames.clone())).collect())
+                    .collect();
+                if !(config.dump_changes_only && previous.as_ref() == Some(&stacks)) {
+                    write_dump(&processes, &traces, show_pids, Some(SystemTime::now()), config)?;
+                    previous = Some(stacks);
+                }
+            },
+            Err(err) => eprintln!("Failed to dump stacks: {}", err)
+        }
+
+        dumps += 1;
+        if config.dump_count.is_some_and(|count| dumps >= count) {
+            break;
+        }
+        while RUNNING.load(Ordering::SeqCst) && taken.elapsed() < interval {
+            std::thread::sleep(Duration::from_millis(100).min(interval - taken.elapsed()));
+        }
+    }
+    Ok(())
+}
+
+// gets the stacks of a process for a dump, including the threads that aren't running python if asked for
+fn dump_traces(process: &mut PythonSpy, config: &config::Config) -> Result<Vec<StackTrace>, Error> {
+    let mut traces = process.get_stack_traces()?;
+    if config.native_threads {
+        let native_threads = process.get_native_thread_traces(&traces)?;
+        traces.extend(native_threads);
+    }
+    // threads are still shown when all of their frames are left out
+    if let Some(frame_filter) = FrameFilter::new(config)? {
+        for trace in traces.iter_mut() {
+            frame_filter.filter_frames(trace);
+        }
+    }
+    Ok(traces)
+}
+
+// writes a dump to stdout, or to its own file when --output is given
+fn write_dump(processes: &[&mut PythonSpy], traces: &[Vec<StackTrace>], show_pids: bool,
+              timestamp: Option<SystemTime>, config: &config::Config) -> Result<(), Error> {
+    let (pid, exe) = match processes.first() {
+        Some(process) => (Some(process.pid), process.process.exe().ok()),
+        None => (None, None)
+    };
+    match dump_file(config, pid, exe.as_deref(), timestamp.unwrap_or_else(SystemTime::now)) {
+        Some(filename) => {
+            let mut out_file = std::fs::File::create(&filename).context(format!("Failed to create '{}'", filename))?;
+            format_dump(&mut out_file, processes, traces, show_pids, timestamp, true, config)?;
+            println!("Wrote dump to '{}'", filename);
+            Ok(())
+        },
+        // repeated JSON dumps on stdout get a line each
+        None => format_dump(&mut std::io::stdout(), processes, traces, show_pids, timestamp, timestamp.is_none(), config)
+    }
+}
+
+// the file to write a dump to with --output. Each dump gets its own file, so a timestamp is added
+// to the filename if it doesn't have one
+fn dump_file(config: &config::Config, pid: Option<remoteprocess::Pid>, exe: Option<&str>, time: SystemTime) -> Option<String> {
+    let filename = filename_template::with_placeholder(config.filename.as_ref()?, "timestamp");
+    let format = if config.dump_json { "json" } else { "txt" };
+    Some(FilenameTemplate{pid, exe, format, time}.expand(&filename))
+}
+
+fn format_dump(w: &mut dyn Write, processes: &[&mut PythonSpy], traces: &[Vec<StackTrace>], show_pids: bool,
+               timestamp: Option<SystemTime>, pretty: bool, config: &config::Config) -> Result<(), Error> {
+    if config.dump_json {
+        let mut exes = Vec::new();
+        for process in processes {
+            exes.push(process.process.exe()?);
+        }
+        let dumps: Vec<dump_json::ProcessDump> = processes.iter().zip(&exes).zip(traces).map(|((process, exe), traces)| {
+            let mut dump = dump_json::ProcessDump::new(Some(process.pid), exe, process.version.to_string(), traces);
+            dump.timestamp = timestamp.map(filename_template::format_time);
+            dump
+        }).collect();
+        return dump_json::write_dumps(w, &dumps, pretty);
+    }
+
+    if let Some(timestamp) = timestamp {
+        writeln!(w, "{}", filename_template::format_time(timestamp))?;
+    }
+    if let (false, [process], [traces]) = (show_pids, processes, traces) {
+        writeln!(w, "{}\nPython version {}", process.process.exe()?, python_version(process))?;
+        write_traces(w, traces, true)?;
+        if timestamp.is_some() {
+            writeln!(w)?;
+        }
+        return Ok(());
+    }
+    for (process, traces) in processes.iter().zip(traces) {
+        writeln!(w, "Process {}: {}\nPython version {}", process.pid, process.process.exe()?, python_version(process))?;
+        write_traces(w, traces, true)?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+fn write_locals(w: &mut dyn Write, locals: &[LocalVariable]) -> std::io::Result<()> {
+    let (args, locals): (Vec<&LocalVariable>, Vec<&LocalVariable>) = locals.iter().partition(|local| local.arg);
+    for (heading, variables) in &[("Arguments", args), ("Locals", locals)] {
+        if variables.is_empty() {
+            continue;
+        }
+        writeln!(w, "\t     {}:", heading)?;
+        for variable in variables {
+            writeln!(w, "\t         {}: {}", variable.name, variable.repr)?;
         }
     }
+    Ok(())
 }
 
 fn process_exitted(process: &remoteprocess::Process) -> bool {
     process.exe().is_err()
 }
 
-#[cfg(unix)]
+// Categorizes why a sample failed, so that failures can be counted by reason
+fn failure_reason(err: &Error) -> &'static str {
+    match spy_error(err) {
+        Some(error::Error::PauseTimeExceeded{..}) => return "timeout",
+        Some(error::Error::PermissionDenied) | Some(error::Error::ProcessExited) |
+        Some(error::Error::MemoryReadError{..}) => return "read_error",
+        Some(_) => return "inconsistent_state",
+        None => {}
+    }
+    let read_error = err.iter_chain().any(|cause| {
+        cause.downcast_ref::<remoteprocess::Error>().is_some() || cause.downcast_ref::<std::io::Error>().is_some()
+    });
+    // otherwise the memory we read didn't make sense, usually because the interpreter
+    // was changing it while we were reading (like when sampling with --nonblocking)
+    if read_error { "read_error" } else { "inconsistent_state" }
+}
+
+// CPU time used by py-spy itself (across all of its threads), to report on the overhead of profiling
+fn profiler_cpu_time() -> Option<Duration> {
+    remoteprocess::Process::new(std::process::id() as remoteprocess::Pid).and_then(|process| process.cpu_time()).ok()
+}
+
+// The error from the rust api behind an error, for errors that came from sampling a process
+fn spy_error(err: &Error) -> Option<&error::Error> {
+    err.iter_chain().find_map(|cause| cause.downcast_ref::<error::Error>())
+}
+
 fn permission_denied(err: &Error) -> bool {
+    if spy_error(err) == Some(&error::Error::PermissionDenied) {
+        return true;
+    }
     err.iter_chain().any(|cause| {
         if let Some(ioerror) = cause.downcast_ref::<std::io::Error>() {
             ioerror.kind() == std::io::ErrorKind::PermissionDenied
@@ -93,27 +391,113 @@ fn permission_denied(err: &Error) -> bool {
     })
 }
 
+// cleared when Control-C is pressed. This is a static since the handler can only be installed
+// once, and recording in windows calls record_samples repeatedly
+static RUNNING: AtomicBool = AtomicBool::new(true);
+static CTRLC_HANDLER: std::sync::Once = std::sync::Once::new();
+
+// set when the process being recorded ends before we were done with it, so that we can exit with
+// EXIT_TARGET_EXITED once the output has been written
+static TARGET_EXITED: AtomicBool = AtomicBool::new(false);
+const EXIT_TARGET_EXITED: i32 = 2;
+
+// set when an --assert didn't hold for the recording, which takes precedence over the target exiting
+static ASSERTION_FAILED: AtomicBool = AtomicBool::new(false);
+const EXIT_ASSERTION_FAILED: i32 = 3;
+
+fn install_ctrlc_handler() -> Result<(), Error> {
+    let mut result = Ok(());
+    CTRLC_HANDLER.call_once(|| {
+        result = ctrlc::set_handler(|| RUNNING.store(false, Ordering::SeqCst));
+    });
+    Ok(result?)
+}
+
+/// Creates a group for sampling other processes alongside the main one, if any are needed
+fn create_process_group(config: &config::Config, others: Vec<PythonSpy>) -> Option<ProcessGroup> {
+    if !config.subprocesses && !config.include_new && others.is_empty() {
+        return None;
+    }
+    let mut group = ProcessGroup::new(config);
+    for process in others {
+        group.add(process);
+    }
+    Some(group)
+}
+
 fn sample_console(process: &mut PythonSpy,
+                  mut group: Option<ProcessGroup>,
                   display: &str,
                   config: &config::Config) -> Result<(), Error> {
     let rate = config.sampling_rate;
-    let mut console = ConsoleViewer::new(config.show_line_numbers, display,
+    let mut console = ConsoleViewer::new(config.show_line_numbers, config.include_idle, config.gil_only, display,
                                          &format!("{}", process.version),
                                          1.0 / rate as f64)?;
 
-    for sleep in timer::Timer::new(rate as f64) {
+    // the samples are also kept in the snapshot format, so that a snapshot can be written at any time
+    let version = process.version.to_string();
+    let mut snapshot = create_recorder(config, rate, &version);
+    let mut resets = 0;
+    let template = match config.snapshot_filename {
+        Some(ref filename) => filename.clone(),
+        None => format!("snapshot-{{pid}}-{{timestamp}}.{}", snapshot_extension(&config.format))
+    };
+    if config.snapshot_on_exit {
+        install_ctrlc_handler()?;
+    }
+
+    let frame_filter = FrameFilter::new(config)?;
+    let start = Instant::now();
+    let mut samples = 0;
+    let mut image = respawn::Image::new(&process.process);
+    for sleep in timer::Timer::new(rate as f64, config.jitter) {
         if let Err(elapsed) = sleep {
             console.increment_late_sample(elapsed);
         }
 
-        match process.get_stack_traces() {
-            Ok(traces) => {
+        if config.duration.is_some_and(|duration| start.elapsed() >= duration) || !RUNNING.load(Ordering::SeqCst) {
+            break;
+        }
+
+        if console.take_snapshot_request() {
+            match write_snapshot(snapshot.as_mut(), &template, process, config) {
+                Ok(filename) => console.show_message(format!("Wrote snapshot to '{}'", filename)),
+                Err(err) => console.show_message(format!("Failed to write snapshot: {}", err))
+            }
+        }
+        if console.resets() != resets {
+            resets = console.resets();
+            snapshot = create_recorder(config, rate, &version);
+        }
+
+        let traces = match group.as_mut() {
+            Some(group) => group.get_stack_traces(process, config),
+            None => process.get_stack_traces().map_err(Error::from)
+        };
+        match traces {
+            Ok(mut traces) => {
+                if let Some(frame_filter) = frame_filter.as_ref() {
+                    frame_filter.filter_traces(&mut traces);
+                }
                 console.increment(&traces)?;
+                let (show_idle, gil_only) = console.filters();
+                filter_traces(&mut traces, gil_only, show_idle);
+                snapshot.increment(start.elapsed(), &traces)?;
+                samples += 1;
+                if config.samples.is_some_and(|max_samples| samples >= max_samples) {
+                    break;
+                }
             },
             Err(err) => {
                 if process_exitted(&process.process) {
+                    if let Some(next) = group.as_mut().and_then(|group| group.take_process()) {
+                        *process = next;
+                        continue;
+                    }
                     println!("\nprocess {} ended", process.pid);
                     break;
+                } else if let Some(attached) = respawn::follow_exec(process, &mut image, config) {
+                    *process = attached?;
                 } else {
                     console.increment_error(&err)?;
                 }
@@ -121,33 +505,792 @@ fn sample_console(process: &mut PythonSpy,
         }
 
     }
+
+    if config.snapshot_on_exit {
+        let filename = write_snapshot(snapshot.as_mut(), &template, process, config)?;
+        println!("\nWrote snapshot to '{}'", filename);
+    }
+    Ok(())
+}
+
+/// The extension for snapshots from the top view, when a filename isn't given
+fn snapshot_extension(format: &FileFormat) -> &'static str {
+    match format {
+        FileFormat::folded => "txt",
+        FileFormat::speedscope => "json",
+        _ => "svg"
+    }
+}
+
+fn write_snapshot(snapshot: &mut dyn Recorder, template: &str, process: &PythonSpy, config: &config::Config) -> Result<String, Error> {
+    let exe = process.process.exe().ok();
+    let filename = FilenameTemplate{pid: Some(process.pid), exe: exe.as_deref(),
+                                    format: &config.format.to_string(), time: SystemTime::now()}.expand(template);
+    let mut out_file = std::fs::File::create(&filename).context(format!("Failed to create '{}'", filename))?;
+    snapshot.write(&mut out_file)?;
+    Ok(filename)
+}
+
+
+// labels are passed to each output as metadata with this prefix on their key, which raw captures
+// then keep for 'py-spy convert'
+const LABEL_PREFIX: &str = "label.";
+
+/// Collects samples and writes them out to a file in one of the supported output formats
+trait Recorder {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error>;
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error>;
+    /// Writes the finished recording to 'filename'. Outputs that write to their file as they're
+    /// recorded only need to flush it
+    fn save(&mut self, filename: &str) -> Result<(), Error> {
+        let mut out_file = std::fs::File::create(filename).context(format!("Failed to create '{}'", filename))?;
+        self.write(&mut out_file)
+    }
+    /// Called when we fail to get a sample, so that formats can report on dropped samples
+    fn increment_error(&mut self, _timestamp: Duration, _err: &Error) {}
+    /// Adds information about the recording, for formats that can store it
+    fn metadata(&mut self, _key: &str, _value: &str) {}
+    /// Marks a point in the recording (like sampling being paused), for formats that can store it
+    fn epoch(&mut self, _timestamp: Duration, _label: &str) {}
+    /// Records a thread starting or exiting, for formats that can show when threads were around
+    fn thread_event(&mut self, _change: &ThreadChange) {}
+    /// Records the resources the process was using, with the resources option
+    fn resource_usage(&mut self, _timestamp: Duration, _usage: &resources::ResourceUsage) {}
+    /// The output for each process, when writing a separate file per process
+    fn per_process(&mut self) -> Option<&mut BTreeMap<remoteprocess::Pid, Box<dyn Recorder>>> { None }
+    /// Adds traces weighted by the bytes allocated from them, for formats that can record memory allocations
+    fn increment_weighted(&mut self, _timestamp: Duration, _traces: &[(StackTrace, u64)]) -> Result<(), Error> {
+        Err(format_err!("This format can't record memory allocations"))
+    }
+}
+
+impl Recorder for flamegraph::Flamegraph {
+    fn increment(&mut self, _timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        Ok(flamegraph::Flamegraph::increment(self, traces)?)
+    }
+    fn increment_weighted(&mut self, _timestamp: Duration, traces: &[(StackTrace, u64)]) -> Result<(), Error> {
+        Ok(flamegraph::Flamegraph::increment_weighted(self, traces)?)
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        flamegraph::Flamegraph::write(self, w)
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        if let Some(label) = key.strip_prefix(LABEL_PREFIX) {
+            self.labels.set(label, value);
+        }
+    }
+}
+
+/// Collapsed stacks, as consumed by the perl flamegraph scripts and most other flamegraph tools
+struct FoldedStacks(flamegraph::Flamegraph);
+
+impl Recorder for FoldedStacks {
+    fn increment(&mut self, _timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        Ok(self.0.increment(traces)?)
+    }
+    fn increment_weighted(&mut self, _timestamp: Duration, traces: &[(StackTrace, u64)]) -> Result<(), Error> {
+        Ok(self.0.increment_weighted(traces)?)
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        self.0.write_folded(w)
+    }
+}
+
+impl Recorder for speedscope::Stats {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        speedscope::Stats::increment(self, timestamp, traces);
+        Ok(())
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        speedscope::Stats::write(self, w)
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        match (key, value.parse()) {
+            ("effective_sampling_rate", Ok(rate)) => self.set_effective_rate(rate),
+            _ => if let Some(label) = key.strip_prefix(LABEL_PREFIX) { self.set_label(label, value) }
+        }
+    }
+}
+
+impl Recorder for csv::CsvStats {
+    fn increment(&mut self, _timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        csv::CsvStats::increment(self, traces);
+        Ok(())
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        csv::CsvStats::write(self, w)
+    }
+}
+
+impl Recorder for pprof::Profile {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        pprof::Profile::increment(self, timestamp, traces);
+        Ok(())
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        pprof::Profile::write(self, w)
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        match (key, value.parse::<f64>()) {
+            ("effective_sampling_rate", Ok(rate)) => self.set_effective_rate(rate),
+            ("start_time", Ok(time)) => self.set_start_time(time),
+            ("failed_samples", _) if !value.is_empty() => self.add_comment(&format!("failed samples: {}", value)),
+            ("target_exited", _) => self.add_comment(&format!("process exited during the recording with exit code {}", value)),
+            _ => if let Some(label) = key.strip_prefix(LABEL_PREFIX) { self.set_label(label, value) }
+        }
+    }
+}
+
+impl Recorder for html_report::HtmlReport {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        html_report::HtmlReport::increment(self, timestamp, traces)
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        html_report::HtmlReport::write(self, w)
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        match (key, value.parse::<f64>()) {
+            ("effective_sampling_rate", Ok(rate)) => self.set_effective_rate(rate),
+            ("failed_samples", _) => self.set_failed_samples(value),
+            _ => if let Some(label) = key.strip_prefix(LABEL_PREFIX) { self.set_label(label, value) }
+        }
+    }
+    fn thread_event(&mut self, change: &ThreadChange) {
+        html_report::HtmlReport::thread_event(self, change);
+    }
+}
+
+impl Recorder for summary::Summary {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        summary::Summary::increment(self, timestamp, traces);
+        Ok(())
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        summary::Summary::write(self, w)
+    }
+    fn increment_error(&mut self, timestamp: Duration, _err: &Error) {
+        summary::Summary::increment_error(self, timestamp);
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        match (key, value.parse::<f64>()) {
+            ("effective_sampling_rate", Ok(rate)) => self.set_effective_rate(rate),
+            ("missed_samples", Ok(missed)) => self.set_missed_samples(missed as u64),
+            ("jitter", _) => self.set_jitter(value),
+            ("failed_samples", _) => self.set_failed_samples(value),
+            ("profiler_cpu_time", Ok(time)) => self.set_profiler_cpu_time(Duration::from_secs_f64(time)),
+            ("suspended_time", Ok(time)) => self.set_suspended_time(Duration::from_secs_f64(time)),
+            ("target_exited", _) => self.set_target_exited(value),
+            _ => if let Some(label) = key.strip_prefix(LABEL_PREFIX) { self.set_label(label, value) }
+        }
+    }
+    fn resource_usage(&mut self, timestamp: Duration, usage: &resources::ResourceUsage) {
+        summary::Summary::resource_usage(self, timestamp, usage);
+    }
+}
+
+impl Recorder for RawCaptureWriter {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        RawCaptureWriter::increment(self, timestamp, traces);
+        Ok(())
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        RawCaptureWriter::write(self, w)
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        RawCaptureWriter::metadata(self, key, value);
+    }
+    fn epoch(&mut self, timestamp: Duration, label: &str) {
+        RawCaptureWriter::epoch(self, timestamp, label);
+    }
+    fn thread_event(&mut self, change: &ThreadChange) {
+        RawCaptureWriter::thread_event(self, change);
+    }
+    fn resource_usage(&mut self, timestamp: Duration, usage: &resources::ResourceUsage) {
+        RawCaptureWriter::resource_usage(self, timestamp, usage);
+    }
+}
+
+/// A raw capture that's written to its file as it's recorded
+type RawCaptureFile = RawCaptureWriter<std::io::BufWriter<std::fs::File>>;
+
+impl Recorder for RawCaptureFile {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        RawCaptureWriter::increment(self, timestamp, traces);
+        // flushed after every sample, so that the capture can be converted while it's being recorded
+        self.flush()
+    }
+    fn write(&mut self, _w: &mut dyn Write) -> Result<(), Error> {
+        Err(format_err!("Raw captures are written to their file as they're recorded"))
+    }
+    fn save(&mut self, _filename: &str) -> Result<(), Error> {
+        self.flush()
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        RawCaptureWriter::metadata(self, key, value);
+    }
+    fn epoch(&mut self, timestamp: Duration, label: &str) {
+        RawCaptureWriter::epoch(self, timestamp, label);
+    }
+    fn thread_event(&mut self, change: &ThreadChange) {
+        RawCaptureWriter::thread_event(self, change);
+    }
+    fn resource_usage(&mut self, timestamp: Duration, usage: &resources::ResourceUsage) {
+        RawCaptureWriter::resource_usage(self, timestamp, usage);
+    }
+}
+
+/// Rewrites the paths of each trace with --map-path and collapses its frames with --group-by,
+/// before passing them on to the output
+struct Grouped {
+    output: Box<dyn Recorder>,
+    group_by: config::GroupBy,
+    path_map: Option<path_map::PathMap>,
+}
+
+impl Grouped {
+    fn transform(&self, trace: &StackTrace) -> StackTrace {
+        match self.path_map.as_ref() {
+            Some(path_map) => grouping::group_trace(&path_map.map_trace(trace), self.group_by),
+            None => grouping::group_trace(trace, self.group_by)
+        }
+    }
+}
+
+impl Recorder for Grouped {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        let traces: Vec<StackTrace> = traces.iter().map(|trace| self.transform(trace)).collect();
+        self.output.increment(timestamp, &traces)
+    }
+    fn increment_weighted(&mut self, timestamp: Duration, traces: &[(StackTrace, u64)]) -> Result<(), Error> {
+        let traces: Vec<(StackTrace, u64)> = traces.iter()
+            .map(|(trace, weight)| (self.transform(trace), *weight))
+            .collect();
+        self.output.increment_weighted(timestamp, &traces)
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        self.output.write(w)
+    }
+    fn save(&mut self, filename: &str) -> Result<(), Error> {
+        self.output.save(filename)
+    }
+    fn increment_error(&mut self, timestamp: Duration, err: &Error) {
+        self.output.increment_error(timestamp, err);
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        self.output.metadata(key, value);
+    }
+    fn epoch(&mut self, timestamp: Duration, label: &str) {
+        self.output.epoch(timestamp, label);
+    }
+    fn thread_event(&mut self, change: &ThreadChange) {
+        self.output.thread_event(change);
+    }
+    fn resource_usage(&mut self, timestamp: Duration, usage: &resources::ResourceUsage) {
+        self.output.resource_usage(timestamp, usage);
+    }
+}
+
+/// Leaves the samples outside of --trim-start and --trim-end out of the output. Samples in the last
+/// --trim-end of the recording are held back until later samples show they aren't in it
+struct Trimmed {
+    output: Box<dyn Recorder>,
+    trim_start: Option<TimeBound>,
+    trim_end: Option<TimeBound>,
+    /// When recording started, from the start_time metadata
+    start_time: Option<SystemTime>,
+    pending: VecDeque<(Duration, TrimmedSample)>,
+}
+
+enum TrimmedSample {
+    Traces(Vec<StackTrace>),
+    Weighted(Vec<(StackTrace, u64)>),
+}
+
+impl Trimmed {
+    fn add(&mut self, timestamp: Duration, sample: TrimmedSample) -> Result<(), Error> {
+        let bounds = time_range::Bounds::new(self.trim_start, self.trim_end, self.start_time)?;
+        if !bounds.contains(timestamp) {
+            return Ok(());
+        }
+        self.pending.push_back((timestamp, sample));
+        while let Some(&(oldest, _)) = self.pending.front() {
+            if oldest + bounds.trailing > timestamp {
+                break;
+            }
+            match self.pending.pop_front() {
+                Some((oldest, TrimmedSample::Traces(traces))) => self.output.increment(oldest, &traces)?,
+                Some((oldest, TrimmedSample::Weighted(traces))) => self.output.increment_weighted(oldest, &traces)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Recorder for Trimmed {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        self.add(timestamp, TrimmedSample::Traces(traces.to_vec()))
+    }
+    fn increment_weighted(&mut self, timestamp: Duration, traces: &[(StackTrace, u64)]) -> Result<(), Error> {
+        self.add(timestamp, TrimmedSample::Weighted(traces.to_vec()))
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        // the samples left are the ones at the end that are trimmed
+        self.pending.clear();
+        self.output.write(w)
+    }
+    fn save(&mut self, filename: &str) -> Result<(), Error> {
+        self.pending.clear();
+        self.output.save(filename)
+    }
+    fn increment_error(&mut self, timestamp: Duration, err: &Error) {
+        if time_range::Bounds::new(self.trim_start, self.trim_end, self.start_time).is_ok_and(|bounds| bounds.contains(timestamp)) {
+            self.output.increment_error(timestamp, err);
+        }
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        if let ("start_time", Ok(time)) = (key, value.parse::<f64>()) {
+            self.start_time = Some(std::time::UNIX_EPOCH + Duration::from_secs_f64(time));
+        }
+        self.output.metadata(key, value);
+    }
+    fn epoch(&mut self, timestamp: Duration, label: &str) {
+        self.output.epoch(timestamp, label);
+    }
+    fn thread_event(&mut self, change: &ThreadChange) {
+        self.output.thread_event(change);
+    }
+    fn resource_usage(&mut self, timestamp: Duration, usage: &resources::ResourceUsage) {
+        self.output.resource_usage(timestamp, usage);
+    }
+}
+
+/// Records each process to an output of its own for --per-process, rather than to one output. Thread
+/// start and exit events aren't recorded, since the thread ids from different processes get mixed up
+struct PerProcess {
+    outputs: BTreeMap<remoteprocess::Pid, Box<dyn Recorder>>,
+    // stacks that don't say which process they came from, like those from a single process
+    root: remoteprocess::Pid,
+    config: config::Config,
+    sampling_rate: u64,
+    version: String,
+    // so that processes that are first seen partway through get the same metadata and epochs
+    metadata: Vec<(String, String)>,
+    epochs: Vec<(Duration, String)>,
+}
+
+impl PerProcess {
+    fn output(&mut self, pid: remoteprocess::Pid) -> &mut Box<dyn Recorder> {
+        let (config, sampling_rate, version) = (&self.config, self.sampling_rate, &self.version);
+        let (metadata, epochs) = (&self.metadata, &self.epochs);
+        self.outputs.entry(pid).or_insert_with(|| {
+            let mut output = create_recorder(config, sampling_rate, version);
+            for (key, value) in metadata {
+                output.metadata(key, value);
+            }
+            for (timestamp, label) in epochs {
+                output.epoch(*timestamp, label);
+            }
+            output
+        })
+    }
+}
+
+impl Recorder for PerProcess {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        let mut by_process: BTreeMap<remoteprocess::Pid, Vec<StackTrace>> = BTreeMap::new();
+        for trace in traces {
+            by_process.entry(trace.pid.unwrap_or(self.root)).or_default().push(trace.clone());
+        }
+        // every process gets counted as sampled, even if none of its threads were included
+        let pids: Vec<remoteprocess::Pid> = self.outputs.keys().chain(by_process.keys()).cloned().collect();
+        for pid in pids {
+            let traces = by_process.remove(&pid).unwrap_or_default();
+            self.output(pid).increment(timestamp, &traces)?;
+        }
+        Ok(())
+    }
+    fn increment_weighted(&mut self, timestamp: Duration, traces: &[(StackTrace, u64)]) -> Result<(), Error> {
+        let root = self.root;
+        self.output(root).increment_weighted(timestamp, traces)
+    }
+    fn write(&mut self, _w: &mut dyn Write) -> Result<(), Error> {
+        Err(format_err!("Each process is written to a file of its own"))
+    }
+    fn increment_error(&mut self, timestamp: Duration, err: &Error) {
+        for output in self.outputs.values_mut() {
+            output.increment_error(timestamp, err);
+        }
+    }
+    fn metadata(&mut self, key: &str, value: &str) {
+        for output in self.outputs.values_mut() {
+            output.metadata(key, value);
+        }
+        self.metadata.push((key.to_owned(), value.to_owned()));
+    }
+    fn epoch(&mut self, timestamp: Duration, label: &str) {
+        for output in self.outputs.values_mut() {
+            output.epoch(timestamp, label);
+        }
+        self.epochs.push((timestamp, label.to_owned()));
+    }
+    fn resource_usage(&mut self, timestamp: Duration, usage: &resources::ResourceUsage) {
+        // only the resources of the process that was originally profiled are recorded
+        let root = self.root;
+        self.output(root).resource_usage(timestamp, usage);
+    }
+    fn per_process(&mut self) -> Option<&mut BTreeMap<remoteprocess::Pid, Box<dyn Recorder>>> {
+        Some(&mut self.outputs)
+    }
+}
+
+fn create_recorder(config: &config::Config, sampling_rate: u64, version: &str) -> Box<dyn Recorder> {
+    let show_line_numbers = config.show_line_numbers;
+    let output: Box<dyn Recorder> = match config.format {
+        FileFormat::flamegraph => {
+            let mut flamegraph = flamegraph::Flamegraph::new(show_line_numbers, config.reverse);
+            if config.memory {
+                flamegraph.count_name = "bytes".to_owned();
+            }
+            flamegraph.min_percent = config.min_percent;
+            Box::new(flamegraph)
+        },
+        FileFormat::folded => {
+            let mut flamegraph = flamegraph::Flamegraph::new(show_line_numbers, config.reverse);
+            flamegraph.min_percent = config.min_percent;
+            Box::new(FoldedStacks(flamegraph))
+        },
+        FileFormat::speedscope => Box::new(speedscope::Stats::new(sampling_rate, show_line_numbers, config.reverse)),
+        FileFormat::pprof => Box::new(pprof::Profile::new(sampling_rate, show_line_numbers)),
+        FileFormat::html => Box::new(html_report::HtmlReport::new(sampling_rate, show_line_numbers, config.reverse, version)),
+        FileFormat::csv => Box::new(csv::CsvStats::new(show_line_numbers)),
+        FileFormat::summary => Box::new(summary::Summary::new(sampling_rate, show_line_numbers, version)),
+        FileFormat::raw => {
+            let mut raw = RawCaptureWriter::new();
+            raw.metadata("version", version);
+            raw.metadata("sampling_rate", &sampling_rate.to_string());
+            Box::new(raw)
+        }
+    };
+    group_output(config, output)
+}
+
+/// Creates the output for a recording to 'filename'. Raw captures are written to the file as they're
+/// recorded, rather than kept in memory until the recording is finished like the other formats
+fn file_recorder(config: &config::Config, filename: &str, sampling_rate: u64, version: &str) -> Result<Box<dyn Recorder>, Error> {
+    if config.format != FileFormat::raw {
+        return Ok(create_recorder(config, sampling_rate, version));
+    }
+    let out_file = std::fs::File::create(filename).context(format!("Failed to create '{}'", filename))?;
+    let mut raw = RawCaptureWriter::with_output(std::io::BufWriter::new(out_file));
+    raw.metadata("version", version);
+    raw.metadata("sampling_rate", &sampling_rate.to_string());
+    Ok(group_output(config, Box::new(raw)))
+}
+
+/// Continues the raw capture in 'filename' with --append, or starts a new one if there isn't one yet
+fn append_recorder(config: &config::Config, filename: &str, sampling_rate: u64, version: &str) -> Result<Box<dyn Recorder>, Error> {
+    let data = match std::fs::read(filename) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return file_recorder(config, filename, sampling_rate, version),
+        Err(err) => return Err(Error::from(err).context(format!("Failed to read '{}'", filename)).into())
+    };
+
+    // only append recordings of the same python, so that the capture can be converted as one
+    let mut reader = RawCaptureReader::new(&data).context(format!("Failed to append to '{}'", filename))?;
+    while let Some(event) = reader.next_event()? {
+        match event {
+            raw_capture::Event::Metadata{ref key, ref value} if key == "version" && value != version => {
+                return Err(format_err!("Can't append to '{}', which is a recording of python {} rather than {}",
+                                       filename, value, version));
+            },
+            _ => {}
+        }
+    }
+    // the existing capture is only read to carry on its string and frame tables, the new records are
+    // written to the end of the file as they're recorded
+    let out_file = std::fs::OpenOptions::new().append(true).open(filename).context(format!("Failed to open '{}'", filename))?;
+    let mut raw = RawCaptureWriter::append(&data, std::io::BufWriter::new(out_file))
+        .context(format!("Failed to append to '{}'", filename))?;
+    raw.metadata("sampling_rate", &sampling_rate.to_string());
+    raw.epoch(Duration::from_secs(0), &format!("appended at {}", filename_template::format_time(SystemTime::now())));
+    Ok(group_output(config, Box::new(raw)))
+}
+
+// groups frames by module or file, maps paths and trims the recording to --trim-start and --trim-end,
+// before passing samples on to the output
+fn group_output(config: &config::Config, output: Box<dyn Recorder>) -> Box<dyn Recorder> {
+    let output: Box<dyn Recorder> = match (config.group_by, config.path_maps.is_empty()) {
+        (config::GroupBy::function, true) => output,
+        (group_by, _) => {
+            let path_map = if config.path_maps.is_empty() { None } else { Some(path_map::PathMap::new(&config.path_maps)) };
+            Box::new(Grouped{output, group_by, path_map})
+        }
+    };
+    if config.trim_start.is_none() && config.trim_end.is_none() {
+        return output;
+    }
+    Box::new(Trimmed{output, trim_start: config.trim_start, trim_end: config.trim_end, start_time: None, pending: VecDeque::new()})
+}
+
+/// How often to check --start-when conditions
+const TRIGGER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records samples to a file, or to a file for each window when profiling in windows with --every
+fn record(process: &mut PythonSpy, mut group: Option<ProcessGroup>, filename: &str, config: &config::Config) -> Result<(), Error> {
+    // make sure that each process gets written to a different file
+    let filename = if config.per_process {
+        filename_template::with_placeholder(filename, "pid")
+    } else {
+        filename.to_owned()
+    };
+    let filename = filename.as_str();
+    status::emit(&status::Event::Attached{pid: process.pid, python_version: &process.version.to_string()});
+
+    let interval = match config.every {
+        Some(interval) => interval,
+        None => return record_samples(process, &mut group, filename, config).map(|_| ())
+    };
+
+    // make sure that each window gets written to a different file
+    let filename = filename_template::with_placeholder(filename, "timestamp");
+    if config.monitor {
+        return monitor(process, group, &filename, config, interval);
+    }
+
+    loop {
+        let window_start = Instant::now();
+        record_samples(process, &mut group, &filename, config)?;
+
+        // wait for the next window, stopping if Control-C is pressed or the process ends
+        while window_start.elapsed() < interval {
+            if !RUNNING.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            if process_exitted(&process.process) {
+                TARGET_EXITED.store(true, Ordering::SeqCst);
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(100).min(interval - window_start.elapsed()));
+        }
+        if !RUNNING.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if process_exitted(&process.process) {
+            TARGET_EXITED.store(true, Ordering::SeqCst);
+            return Ok(());
+        }
+    }
+}
+
+/// Records a window every 'interval' for 'py-spy monitor', which is meant to be left running for days:
+/// waiting for the process to come back when it restarts, deleting old files past --keep, reporting on
+/// its own overhead after each window and serving the latest profile and metrics with --listen
+fn monitor(process: &mut PythonSpy, mut group: Option<ProcessGroup>, filename: &str, config: &config::Config,
+           interval: Duration) -> Result<(), Error> {
+    let health = Arc::new(Mutex::new(monitor::Health::new(process.pid)));
+    if let Some(address) = config.monitor_listen.as_ref() {
+        monitor::serve(address, health.clone())?;
+        println!("Serving the latest profile on http://{}/ and metrics on http://{}/metrics", address, address);
+    }
+    install_ctrlc_handler()?;
+    let mut cmdline = process.process.cmdline()?;
+    let mut files = VecDeque::new();
+
+    while RUNNING.load(Ordering::SeqCst) {
+        let window_start = Instant::now();
+        let recording = record_samples(process, &mut group, filename, config)?;
+        {
+            let mut health = health.lock().unwrap();
+            health.windows += 1;
+            health.samples += recording.samples;
+            health.errors += recording.errors;
+            health.profiler_cpu_time += recording.profiler_cpu_time.unwrap_or_default();
+            health.last_window = Some(SystemTime::now());
+            health.latest = recording.filenames.clone();
+            let overhead = recording.profiler_cpu_time.map_or("unknown".to_owned(), |cpu_time| {
+                format!("{:.1}%", 100.0 * cpu_time.as_secs_f64() / recording.elapsed.as_secs_f64().max(0.001))
+            });
+            let rss = resources::get_resource_usage(std::process::id() as remoteprocess::Pid)
+                .map_or("unknown".to_owned(), |usage| format!("{}MB", usage.rss >> 20));
+            println!("Monitor health: {} windows, {} samples, {} errors, {} restarts. Overhead of the last window {} of a core, \
+                      py-spy is using {} of memory", health.windows, health.samples, health.errors, health.restarts, overhead, rss);
+        }
+        files.extend(recording.filenames);
+        if let Some(keep) = config.monitor_keep {
+            monitor::rotate(&mut files, keep);
+        }
+
+        // wait for the next window, following the process if it restarts
+        while RUNNING.load(Ordering::SeqCst) && window_start.elapsed() < interval {
+            if process_exitted(&process.process) {
+                health.lock().unwrap().pid = None;
+                println!("Process {} ended, waiting for it to restart", process.pid);
+                let timeout = config.respawn_wait.unwrap_or(Duration::MAX);
+                match respawn::wait_for_respawn(&cmdline, process.pid, timeout, &RUNNING, config)? {
+                    Some(respawned) => {
+                        println!("Process {} restarted as process {}", process.pid, respawned.pid);
+                        *process = respawned;
+                        cmdline = process.process.cmdline()?;
+                        TARGET_EXITED.store(false, Ordering::SeqCst);
+                        let mut health = health.lock().unwrap();
+                        health.pid = Some(process.pid);
+                        health.restarts += 1;
+                    },
+                    None => {
+                        if RUNNING.load(Ordering::SeqCst) {
+                            TARGET_EXITED.store(true, Ordering::SeqCst);
+                            println!("Stopped monitoring because the process didn't restart within {:?}", timeout);
+                        }
+                        return Ok(());
+                    }
+                }
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100).min(interval.saturating_sub(window_start.elapsed())));
+        }
+    }
     Ok(())
 }
 
+/// What record_samples wrote out, for 'py-spy monitor' to keep track of
+struct Recording {
+    filenames: Vec<String>,
+    samples: u64,
+    errors: u64,
+    profiler_cpu_time: Option<Duration>,
+    elapsed: Duration,
+}
+
+fn record_samples(process: &mut PythonSpy, group: &mut Option<ProcessGroup>, filename: &str, config: &config::Config) -> Result<Recording, Error> {
+    let exe = process.process.exe().ok();
+    let started = SystemTime::now();
+    let template = filename;
+    let filename = FilenameTemplate{pid: Some(process.pid), exe: exe.as_deref(),
+                                    format: &config.format.to_string(), time: started}.expand(filename);
+    // default to sampling for 2 seconds, unless told how many samples to collect
+    let duration = match (config.duration, config.samples) {
+        (None, None) => Some(Duration::from_secs(2)),
+        (duration, _) => duration
+    };
 
-fn sample_flame(process: &mut PythonSpy, filename: &str, config: &config::Config) -> Result<(), Error> {
-    let max_samples = config.duration * config.sampling_rate;
+    if config.memory && !process.tracemalloc_tracing()? {
+        return Err(format_err!("tracemalloc isn't tracing memory allocations in process {}. Run python with \
+                                PYTHONTRACEMALLOC=25 (or call tracemalloc.start(25)) to record where allocations come from",
+                               process.pid));
+    }
 
-    let mut flame = flamegraph::Flamegraph::new(config.show_line_numbers);
     use indicatif::ProgressBar;
-    let progress = ProgressBar::new(max_samples);
+    // the preview shows the progress itself, so the progress bar would only get in its way
+    let progress = if config.preview {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(match (config.samples, duration) {
+            (Some(samples), _) => samples,
+            (None, Some(duration)) => (duration.as_secs_f64() * config.sampling_rate as f64) as u64,
+            (None, None) => 0
+        })
+    };
+    let mut preview = if config.preview { Some(Preview::new()) } else { None };
+    let mut assertions = if config.assertions.is_empty() { None } else { Some(gate::AssertionStats::new(&config.assertions)) };
+    let frame_filter = FrameFilter::new(config)?;
 
-    println!("Sampling process {} times a second for {} seconds. Press Control-C to exit.",
-             config.sampling_rate, config.duration);
+    match (duration, config.samples) {
+        (Some(duration), Some(samples)) => println!("Sampling process {} times a second for {:?} or {} samples. Press Control-C to exit.",
+                                                    config.sampling_rate, duration, samples),
+        (Some(duration), None) => println!("Sampling process {} times a second for {:?}. Press Control-C to exit.",
+                                           config.sampling_rate, duration),
+        (None, Some(samples)) => println!("Sampling process {} times a second until {} samples are collected. Press Control-C to exit.",
+                                          config.sampling_rate, samples),
+        (None, None) => {}
+    }
 
     let mut errors = 0;
+    // samples abandoned because they would have paused the process for longer than --max-pause
+    let mut dropped = 0;
+    // every failed sample (including dropped ones), by the reason it failed
+    let mut failures: BTreeMap<&'static str, u64> = BTreeMap::new();
     let mut samples = 0;
     println!();
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })?;
+    install_ctrlc_handler()?;
+    let running = &RUNNING;
+
+    // hold off on sampling until the --start-when condition is met
+    let mut watcher = config.start_when.map(TriggerWatcher::new);
+    if let Some(watcher) = watcher.as_mut() {
+        println!("Waiting for {} before sampling", watcher.trigger);
+        while !watcher.check(&process.process)? {
+            if !running.load(Ordering::SeqCst) {
+                println!("Stopped waiting because Control-C pressed");
+                return Ok(Recording{filenames: Vec::new(), samples: 0, errors: 0, profiler_cpu_time: None, elapsed: Duration::from_secs(0)});
+            }
+            if process_exitted(&process.process) {
+                println!("Stopped waiting because the process ended");
+                return Ok(Recording{filenames: Vec::new(), samples: 0, errors: 0, profiler_cpu_time: None, elapsed: Duration::from_secs(0)});
+            }
+            std::thread::sleep(TRIGGER_INTERVAL);
+        }
+        println!("Condition {} met, starting to sample", watcher.trigger);
+    }
+
+    // raw captures are written as they're recorded, so this starts the file
+    let mut output = if config.per_process {
+        Box::new(PerProcess{outputs: BTreeMap::new(), root: process.pid, config: config.clone(), sampling_rate: config.sampling_rate,
+                            version: process.version.to_string(), metadata: Vec::new(), epochs: Vec::new()})
+    } else if config.append {
+        append_recorder(config, &filename, config.sampling_rate, &process.version.to_string())?
+    } else {
+        file_recorder(config, &filename, config.sampling_rate, &process.version.to_string())?
+    };
+    status::emit(&status::Event::SamplingStarted{pid: process.pid, rate: config.sampling_rate});
+    let mut last_trigger_check = Instant::now();
+    let mut last_resources: Option<Instant> = None;
 
-    let mut exit_message = "";
+    // SIGUSR1 pauses sampling and SIGUSR2 resumes it
+    signals::install(config.start_paused)?;
+    // and the filters can be changed by writing to the --control file
+    let mut control = match config.control_file.as_ref() {
+        Some(path) => Some(control::ControlFile::new(path)?),
+        None => None
+    };
+    let mut paused = false;
+    let mut gil_only = config.gil_only;
+    let mut include_idle = config.include_idle;
+    let mut paused_time = Duration::from_secs(0);
+    let mut paused_at = Instant::now();
 
-    for sleep in timer::Timer::new(config.sampling_rate as f64) {
+    let mut exit_message = String::new();
+    let start = Instant::now();
+    let start_cpu_time = profiler_cpu_time();
+    // time that the processes being profiled were suspended for while sampling them
+    let mut suspended_time = Duration::from_secs(0);
+    // thread stacks left out of samples for changing while they were read, with --dirty-reads
+    let mut torn_stacks = 0;
+    // sample timestamps are relative to this, so store the wall clock time for formats that can use it
+    if let Ok(since_epoch) = SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        output.metadata("start_time", &format!("{:.6}", since_epoch.as_secs_f64()));
+    }
+    output.metadata("jitter", &config.jitter.to_string());
+    // the filters that were in effect at the start, which epochs then mark changes to
+    output.metadata("gil_only", &config.gil_only.to_string());
+    output.metadata("include_idle", &config.include_idle.to_string());
+    for (key, value) in &config.labels {
+        output.metadata(&format!("{}{}", LABEL_PREFIX, key), value);
+    }
+
+    let mut threads = ThreadTracker::new();
+    let mut timer = timer::Timer::new(config.sampling_rate as f64, config.jitter);
+    let mut adaptive_rate = config.max_overhead.map(|overhead| AdaptiveRate::new(config.sampling_rate as f64, overhead / 100.0));
+    // the command line to look for when the process is restarted
+    let cmdline = match config.respawn_wait {
+        Some(_) => Some(process.process.cmdline()?),
+        None => None
+    };
+    // the program the process is running, to notice it exec()'ing a different one
+    let mut image = respawn::Image::new(&process.process);
+
+    while let Some(sleep) = timer.next() {
         if let Err(delay) = sleep {
             if delay > Duration::from_secs(1) {
                 // TODO: once this available on crates.io https://github.com/mitsuhiko/indicatif/pull/41
@@ -160,28 +1303,189 @@ fn sample_flame(process: &mut PythonSpy, filename: &str, config: &config::Config
         }
 
         if !running.load(Ordering::SeqCst) {
-            exit_message = "Stopped sampling because Control-C pressed";
+            exit_message = "Stopped sampling because Control-C pressed".to_owned();
             break;
         }
 
-        match process.get_stack_traces() {
-            Ok(traces) => {
-                flame.increment(&traces)?;
+        // the duration is how long to sample for, so time spent paused doesn't count towards it
+        let sampled_time = start.elapsed().saturating_sub(paused_time + if paused { paused_at.elapsed() } else { Duration::from_secs(0) });
+        if duration.is_some_and(|duration| sampled_time >= duration) {
+            break;
+        }
+
+        if config.stop_when_clear && last_trigger_check.elapsed() >= TRIGGER_INTERVAL {
+            last_trigger_check = Instant::now();
+            if let Some(watcher) = watcher.as_mut() {
+                match watcher.check(&process.process) {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        exit_message = "Stopped sampling because the --start-when condition cleared".to_owned();
+                        break;
+                    },
+                    // the next sample will notice that the process ended
+                    Err(_) if process_exitted(&process.process) => {},
+                    Err(err) => return Err(err.into())
+                }
+            }
+        }
+
+        if signals::paused() != paused {
+            paused = !paused;
+            if paused {
+                paused_at = Instant::now();
+            } else {
+                paused_time += paused_at.elapsed();
+            }
+            info!("Sampling {}", if paused { "paused" } else { "resumed" });
+            output.epoch(start.elapsed(), if paused { "paused" } else { "resumed" });
+        }
+
+        // mark where the filters were changed, so that post-processing can account for them
+        if let Some(filters) = control.as_mut().and_then(|control| control.check()) {
+            if filters.gil_only != gil_only {
+                gil_only = filters.gil_only;
+                info!("{} traces without the GIL", if gil_only { "Excluding" } else { "Including" });
+                output.epoch(start.elapsed(), if gil_only { "--gil enabled" } else { "--gil disabled" });
+            }
+            if filters.include_idle != include_idle {
+                include_idle = filters.include_idle;
+                info!("{} idle threads", if include_idle { "Including" } else { "Excluding" });
+                output.epoch(start.elapsed(), if include_idle { "--idle enabled" } else { "--idle disabled" });
+            }
+        }
+        if config.resources && last_resources.is_none_or(|last| last.elapsed() >= resources::RESOURCE_INTERVAL) {
+            last_resources = Some(Instant::now());
+            match resources::get_resource_usage(process.pid) {
+                Ok(usage) => output.resource_usage(start.elapsed(), &usage),
+                Err(err) => debug!("Failed to get the resource usage of process {}: {}", process.pid, err)
+            }
+        }
+        if paused {
+            continue;
+        }
+
+        let sample_start = Instant::now();
+        let suspended_before = process.suspended_time + group.as_ref().map(|g| g.suspended_time).unwrap_or_default();
+        let torn_before = process.torn_stacks + group.as_ref().map(|g| g.torn_stacks).unwrap_or_default();
+        let traces = if config.memory {
+            // the allocations made since the previous snapshot are recorded here, weighted by their size
+            match process.get_allocation_traces() {
+                Ok(allocations) => {
+                    output.increment_weighted(start.elapsed(), &allocations)?;
+                    Ok(Vec::new())
+                },
+                Err(err) => Err(Error::from(err))
+            }
+        } else {
+            match group.as_mut() {
+                Some(group) => group.get_stack_traces(process, config),
+                None => process.get_stack_traces().map_err(Error::from)
+            }
+        };
+        suspended_time += process.suspended_time + group.as_ref().map(|g| g.suspended_time).unwrap_or_default() - suspended_before;
+        torn_stacks += process.torn_stacks + group.as_ref().map(|g| g.torn_stacks).unwrap_or_default() - torn_before;
+        if let Some(adaptive_rate) = adaptive_rate.as_mut() {
+            if let Some(rate) = adaptive_rate.update(sample_start.elapsed()) {
+                info!("Changing sampling rate to {:.1} samples per second", rate);
+                status::emit(&status::Event::RateAdjusted{rate});
+                timer.set_rate(rate);
+            }
+        }
+
+        match traces {
+            Ok(mut traces) => {
+                // look for threads starting and exiting before idle threads are filtered out
+                if !config.memory {
+                    for change in threads.update(start.elapsed(), &traces) {
+                        output.thread_event(&change);
+                    }
+                }
+                filter_traces(&mut traces, gil_only, include_idle);
+                if config.lock_contention {
+                    traces.retain(|trace| trace.waiting_for_lock());
+                }
+                if let Some(frame_filter) = frame_filter.as_ref() {
+                    frame_filter.filter_traces(&mut traces);
+                }
+                output.increment(start.elapsed(), &traces)?;
+                if let Some(preview) = preview.as_mut() {
+                    preview.increment(&traces);
+                }
+                if let Some(assertions) = assertions.as_mut() {
+                    assertions.increment(&traces);
+                }
                 samples += 1;
-                if samples >= max_samples {
+                if config.samples.is_some_and(|max_samples| samples >= max_samples) {
                     break;
                 }
             },
-            Err(_) => {
+            Err(err) => {
                 if process_exitted(&process.process) {
-                    exit_message = "Stopped sampling because the process ended";
+                    // carry on with the remaining processes when sampling more than one
+                    if let Some(next) = group.as_mut().and_then(|group| group.take_process()) {
+                        info!("Process {} ended, continuing with process {}", process.pid, next.pid);
+                        *process = next;
+                        continue;
+                    }
+                    if let (Some(timeout), Some(cmdline)) = (config.respawn_wait, cmdline.as_ref()) {
+                        info!("Process {} ended, waiting up to {:?} for it to restart", process.pid, timeout);
+                        let wait_start = Instant::now();
+                        if let Some(respawned) = respawn::wait_for_respawn(cmdline, process.pid, timeout, running, config)? {
+                            *process = respawned;
+                            // time spent waiting doesn't count towards the effective sampling rate
+                            paused_time += wait_start.elapsed();
+                            timer.reschedule();
+                            output.epoch(start.elapsed(), &format!("respawned as pid {}", process.pid));
+                            continue;
+                        }
+                    }
+                    // mark the recording as cut short, so that it isn't mistaken for a complete one
+                    let exit_code = match process.process.exit_code() {
+                        Ok(Some(code)) => code.to_string(),
+                        _ => "unknown".to_owned()
+                    };
+                    output.metadata("target_exited", &exit_code);
+                    TARGET_EXITED.store(true, Ordering::SeqCst);
+                    exit_message = format!("Stopped sampling because the process ended (exit code {})", exit_code);
                     break;
+                } else if let Some(attached) = respawn::follow_exec(process, &mut image, config) {
+                    let exe = image.as_ref().map_or("unknown", |image| image.exe()).to_owned();
+                    match attached {
+                        Ok(attached) => {
+                            info!("Continuing with python {} in {}", attached.version, exe);
+                            *process = attached;
+                            output.epoch(start.elapsed(), &format!("exec'd {}", exe));
+                        },
+                        Err(err) => {
+                            output.metadata("target_exec", &exe);
+                            exit_message = format!("Stopped sampling because the process exec'd {}, which couldn't be \
+                                                    profiled: {}", exe, err);
+                            break;
+                        }
+                    }
                 } else {
-                    errors += 1;
+                    output.increment_error(start.elapsed(), &err);
+                    *failures.entry(failure_reason(&err)).or_default() += 1;
+                    if let Some(preview) = preview.as_mut() {
+                        preview.increment_error();
+                    }
+                    if let Some(error::Error::PauseTimeExceeded{..}) = spy_error(&err) {
+                        dropped += 1;
+                    } else {
+                        errors += 1;
+                    }
                 }
             }
         }
         progress.inc(1);
+        if let Some(preview) = preview.as_mut() {
+            let remaining = match (config.samples, duration) {
+                (Some(max_samples), _) => Some(Remaining::Samples(max_samples.saturating_sub(samples))),
+                (None, Some(duration)) => Some(Remaining::Time(duration.saturating_sub(sampled_time))),
+                (None, None) => None
+            };
+            preview.render(start.elapsed(), remaining)?;
+        }
     }
     progress.finish();
     // write out a message here (so as not to interfere with progress bar) if we ended earlier
@@ -189,21 +1493,454 @@ fn sample_flame(process: &mut PythonSpy, filename: &str, config: &config::Config
         println!("{}", exit_message);
     }
 
-    let out_file = std::fs::File::create(filename)?;
-    flame.write(out_file)?;
-    println!("Wrote flame graph '{}'. Samples: {} Errors: {}", filename, samples, errors);
+    // record the rate we actually achieved, so that formats can weight samples accurately
+    if paused {
+        paused_time += paused_at.elapsed();
+    }
+    let effective_rate = (samples + errors + dropped) as f64 / (start.elapsed() - paused_time).as_secs_f64();
+    if adaptive_rate.is_some() || timer.missed_ticks() > 0 {
+        println!("Effective sampling rate: {:.1} samples per second, missed {} samples from falling behind",
+                 effective_rate, timer.missed_ticks());
+    }
+    output.metadata("effective_sampling_rate", &format!("{:.3}", effective_rate));
+    output.metadata("missed_samples", &timer.missed_ticks().to_string());
+    let failed_samples: Vec<String> = failures.iter().map(|(reason, count)| format!("{}={}", reason, count)).collect();
+    output.metadata("failed_samples", &failed_samples.join(","));
+
+    // report on the observer effect: how much CPU py-spy used, and how long the program was stopped for
+    let elapsed = start.elapsed();
+    let cpu_time = match (start_cpu_time, profiler_cpu_time()) {
+        (Some(start_cpu_time), Some(end_cpu_time)) => end_cpu_time.checked_sub(start_cpu_time),
+        _ => None
+    };
+    if let Some(cpu_time) = cpu_time {
+        output.metadata("profiler_cpu_time", &format!("{:.6}", cpu_time.as_secs_f64()));
+    }
+    output.metadata("suspended_time", &format!("{:.6}", suspended_time.as_secs_f64()));
+    if config.dirty_reads {
+        output.metadata("torn_stacks", &torn_stacks.to_string());
+    }
+
+    let mut filenames = Vec::new();
+    match output.per_process() {
+        Some(outputs) => {
+            for (&pid, output) in outputs.iter_mut() {
+                let exe = remoteprocess::Process::new(pid).and_then(|process| process.exe()).ok();
+                let filename = FilenameTemplate{pid: Some(pid), exe: exe.as_deref(),
+                                                format: &config.format.to_string(), time: started}.expand(template);
+                output.save(&filename)?;
+                println!("Wrote {} file '{}' for process {}", config.format, filename, pid);
+                filenames.push(filename);
+            }
+            println!("Samples: {} Errors: {}", samples, errors);
+        },
+        None => {
+            output.save(&filename)?;
+            println!("Wrote {} file '{}'. Samples: {} Errors: {}", config.format, filename, samples, errors);
+            filenames.push(filename.clone());
+        }
+    }
+    if !failures.is_empty() {
+        let failed_samples: Vec<String> = failures.iter().map(|(reason, count)| format!("{} {}", reason, count)).collect();
+        println!("Failed samples by reason: {}", failed_samples.join(", "));
+    }
+    if let Some(max_pause) = config.max_pause {
+        println!("Dropped {} samples that would have paused the process for more than {:?}", dropped, max_pause);
+    }
+    if let Some(cpu_time) = cpu_time {
+        println!("py-spy used {:.2}s of CPU time ({:.1}% of a core)", cpu_time.as_secs_f64(),
+                 100.0 * cpu_time.as_secs_f64() / elapsed.as_secs_f64());
+    }
+    if config.dirty_reads {
+        println!("Dropped {} thread stacks that changed while they were being read", torn_stacks);
+    }
+    if !config.non_blocking {
+        println!("The process was suspended for {:.2}s ({:.2}% of the time) while sampling", suspended_time.as_secs_f64(),
+                 100.0 * suspended_time.as_secs_f64() / elapsed.as_secs_f64());
+    }
+    if let Some(assertions) = assertions {
+        check_assertions(&assertions.report(), config)?;
+    }
+    let stopped = if exit_message.is_empty() { None } else { Some(exit_message.as_str()) };
+    status::emit(&status::Event::Finished{files: &filenames, samples, errors, stopped});
 
     // open generated flame graph in the browser on OSX (theory being that on linux
     // you might be SSH'ed into a server somewhere and this isn't desired, but on
     // that is pretty unlikely for osx) (note to self: xdg-open will open on linux)
     #[cfg(target_os = "macos")]
-    std::process::Command::new("open").arg(filename).spawn()?;
+    {
+        if config.format == FileFormat::flamegraph || config.format == FileFormat::html {
+            std::process::Command::new("open").arg(&filename).spawn()?;
+        }
+    }
+
+    Ok(Recording{filenames, samples, errors, profiler_cpu_time: cpu_time, elapsed})
+}
+
+/// Prints how each --assert did and writes the --assert-report, exiting with EXIT_ASSERTION_FAILED
+/// at the end when any of them failed
+fn check_assertions(report: &gate::Report, config: &config::Config) -> Result<(), Error> {
+    for result in &report.assertions {
+        let value = result.value.map_or("nothing sampled".to_owned(), |value| format!("{:.2}", value));
+        println!("Assertion {}: {} (was {})", if result.passed { "passed" } else { "FAILED" }, result.assertion, value);
+    }
+    if let Some(filename) = config.assert_report.as_ref() {
+        let file = std::fs::File::create(filename).context(format!("Failed to create '{}'", filename))?;
+        serde_json::to_writer_pretty(file, report)?;
+        println!("Wrote assertion report to '{}'", filename);
+    }
+    if !report.passed {
+        ASSERTION_FAILED.store(true, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+/// Samples until stopped for 'py-spy alert', alerting when one of the conditions starts or stops holding
+fn run_alerts(process: &mut PythonSpy, mut group: Option<ProcessGroup>, config: &config::Config) -> Result<(), Error> {
+    let mut windows: Vec<alert_window::ConditionWindow> = config.alert_conditions.iter()
+        .map(|condition| alert_window::ConditionWindow::new(condition.clone(), config.alert_window))
+        .collect();
+    let conditions: Vec<String> = config.alert_conditions.iter().map(|condition| condition.to_string()).collect();
+    println!("Watching process {} for {} over {:?}. Press Control-C to exit.", process.pid, conditions.join(" or "), config.alert_window);
+
+    install_ctrlc_handler()?;
+    let start = Instant::now();
+    let mut timer = timer::Timer::new(config.sampling_rate as f64, config.jitter);
+    while timer.next().is_some() {
+        if !RUNNING.load(Ordering::SeqCst) {
+            break;
+        }
+        let traces = match group.as_mut() {
+            Some(group) => group.get_stack_traces(process, config),
+            None => process.get_stack_traces().map_err(Error::from)
+        };
+        let mut traces = match traces {
+            Ok(traces) => traces,
+            Err(_) if process_exitted(&process.process) => {
+                println!("Stopped watching because the process ended");
+                TARGET_EXITED.store(true, Ordering::SeqCst);
+                break;
+            },
+            Err(err) => {
+                debug!("Failed to sample process {}: {}", process.pid, err);
+                continue;
+            }
+        };
+        filter_traces(&mut traces, config.gil_only, config.include_idle);
+        for window in windows.iter_mut() {
+            if let Some(met) = window.add(start.elapsed(), &traces) {
+                fire_alert(&window.condition, met, window.value().unwrap_or(0.0), process.pid, config);
+            }
+        }
+    }
+    Ok(())
+}
+
+// logs an alert firing (or clearing), and runs the --exec command and posts to the --webhook for it
+fn fire_alert(condition: &alert::Condition, firing: bool, value: f64, pid: remoteprocess::Pid, config: &config::Config) {
+    let state = if firing { "firing" } else { "cleared" };
+    let time = filename_template::format_time(SystemTime::now());
+    println!("{} Alert {}: {} in process {} (currently {:.1}%)", time, state, condition, pid, value);
+
+    if let Some(command) = config.alert_exec.clone() {
+        let mut command = if cfg!(windows) {
+            let mut shell = std::process::Command::new("cmd");
+            shell.arg("/C").arg(command);
+            shell
+        } else {
+            let mut shell = std::process::Command::new("sh");
+            shell.arg("-c").arg(command);
+            shell
+        };
+        command.env("PYSPY_ALERT", condition.to_string())
+            .env("PYSPY_ALERT_STATE", state)
+            .env("PYSPY_ALERT_VALUE", format!("{:.1}", value))
+            .env("PYSPY_PID", pid.to_string());
+        // run actions in the background, so that a slow one doesn't hold up sampling
+        std::thread::spawn(move || match command.status() {
+            Ok(status) if !status.success() => warn!("Alert command failed with {}", status),
+            Ok(_) => {},
+            Err(err) => warn!("Failed to run alert command: {}", err)
+        });
+    }
+    if let Some(url) = config.alert_webhook.clone() {
+        let body = serde_json::json!({"condition": condition.to_string(), "state": state, "value": value,
+                                      "pid": pid, "time": time}).to_string();
+        std::thread::spawn(move || {
+            if let Err(err) = alert_window::post_webhook(&url, &body) {
+                warn!("Failed to post alert to {}: {}", url, err);
+            }
+        });
+    }
+}
+
+/// Reads in samples from a raw capture file, and writes them out in a different format
+fn convert_samples(input: &str, filename: &str, config: &config::Config) -> Result<(), Error> {
+    let filename = FilenameTemplate{pid: None, exe: None, format: &config.format.to_string(),
+                                    time: SystemTime::now()}.expand(filename);
+    let data = std::fs::read(input)?;
+
+    // metadata is written at the start of the capture, but we need the sampling rate
+    // before we can create the output, so read it in with a separate pass
+    let mut metadata = HashMap::new();
+    let mut reader = RawCaptureReader::new(&data)?;
+    while let Some(event) = reader.next_event()? {
+        if let raw_capture::Event::Metadata{key, value} = event {
+            metadata.insert(key, value);
+        }
+    }
+
+    let sampling_rate = metadata.get("sampling_rate").and_then(|rate| rate.parse().ok()).unwrap_or(config.sampling_rate);
+    let version = metadata.get("version").map(|v| v.as_str()).unwrap_or("");
+    let mut output = create_recorder(config, sampling_rate, version);
+
+    // native frames recorded with --unsymbolicated are symbolicated with 'py-spy symbolicate'
+    #[cfg(all(target_os="linux", target_arch="x86_64"))]
+    let mut symbols = if config.symbolicate {
+        Some(offline_symbols::OfflineSymbols::new(&config.symbol_paths, config.debuginfod))
+    } else {
+        None
+    };
+
+    let frame_filter = FrameFilter::new(config)?;
+    let mut samples = 0;
+    let mut reader = RawCaptureReader::new(&data)?;
+    while let Some(event) = reader.next_event()? {
+        match event {
+            raw_capture::Event::Sample{timestamp, mut traces} => {
+                #[cfg(all(target_os="linux", target_arch="x86_64"))]
+                {
+                    if let Some(symbols) = symbols.as_mut() {
+                        for trace in traces.iter_mut() {
+                            trace.frames = symbols.symbolicate(&trace.frames);
+                        }
+                    }
+                }
+                if let Some(frame_filter) = frame_filter.as_ref() {
+                    frame_filter.filter_traces(&mut traces);
+                }
+                output.increment(timestamp, &traces)?;
+                samples += 1;
+            },
+            // version and sampling_rate are already passed to create_recorder
+            raw_capture::Event::Metadata{key, value} => {
+                if key != "version" && key != "sampling_rate" {
+                    output.metadata(&key, &value);
+                }
+            },
+            raw_capture::Event::Epoch{timestamp, label} => output.epoch(timestamp, &label),
+            raw_capture::Event::Thread(change) => output.thread_event(&change),
+            raw_capture::Event::Resources{timestamp, usage} => output.resource_usage(timestamp, &usage)
+        }
+    }
+    // labels given when converting replace the ones the capture was recorded with
+    for (key, value) in &config.labels {
+        output.metadata(&format!("{}{}", LABEL_PREFIX, key), value);
+    }
+
+    #[cfg(all(target_os="linux", target_arch="x86_64"))]
+    {
+        if let Some(missing) = symbols.as_ref().map(|symbols| symbols.missing_binaries()).filter(|missing| !missing.is_empty()) {
+            println!("Couldn't find the binaries to symbolicate frames in: {}", missing.join(", "));
+        }
+    }
 
+    let mut out_file = std::fs::File::create(&filename)?;
+    output.write(&mut out_file)?;
+    println!("Wrote {} file '{}'. Samples: {}", config.format, filename, samples);
     Ok(())
 }
 
+/// Prints the python processes running on this machine, for 'py-spy ps'
+fn list_processes() -> Result<(), Error> {
+    let processes = ps::find_python_processes()?;
+    if processes.is_empty() {
+        println!("No python processes found");
+        return Ok(());
+    }
+    println!("{:>8}  {:<8} {:<10} {:<30} Command", "PID", "Python", "Profile", "Virtualenv");
+    for process in &processes {
+        let status = match process.error.as_ref() {
+            None => "yes",
+            Some(err) if permission_denied(err) => "denied",
+            Some(_) => "no"
+        };
+        println!("{:>8}  {:<8} {:<10} {:<30} {}", process.pid, process.version.as_deref().unwrap_or("?"), status,
+                 process.virtualenv.as_deref().unwrap_or("-"), process.cmdline);
+    }
+
+    let failed: Vec<&ps::PythonProcess> = processes.iter().filter(|process| process.error.is_some()).collect();
+    if !failed.is_empty() {
+        println!();
+        for process in &failed {
+            if let Some(err) = process.error.as_ref() {
+                println!("{}: {}", process.pid, err);
+            }
+        }
+    }
+    if failed.iter().any(|process| process.error.as_ref().is_some_and(permission_denied)) {
+        println!();
+        println!("{}", permissions::remediation(failed.first().map(|process| process.pid)));
+    }
+    Ok(())
+}
+
+/// Prints a summary of the objects tracked by the garbage collector, or how they've changed since an earlier snapshot
+fn inspect_heap(pid: remoteprocess::Pid, config: &config::Config) -> Result<(), Error> {
+    // read the earlier snapshot first, so that we don't pause the process just to fail on a bad file
+    let baseline = match config.heap_baseline.as_ref() {
+        Some(filename) => {
+            let contents = std::fs::read_to_string(filename).map_err(|e| format_err!("Failed to read '{}': {}", filename, e))?;
+            Some(heap_report::parse_snapshot(&contents)?)
+        },
+        None => None
+    };
+
+    let mut process = PythonSpy::retry_new(pid, config, 3)?;
+    let summary = process.get_heap_summary()?;
+    println!("{}\nPython version {}", process.process.exe()?, python_version(&process));
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    match baseline {
+        Some(baseline) => heap_report::write_diff(&mut stdout, &baseline, &summary)?,
+        None => heap_report::write_summary(&mut stdout, &summary)?
+    }
+
+    if let Some(filename) = config.filename.as_ref() {
+        heap_report::write_snapshot(&mut std::fs::File::create(filename)?, &summary)?;
+        println!("Wrote heap snapshot '{}'", filename);
+    }
+    Ok(())
+}
+
+#[cfg(target_os="linux")]
+fn dump_core(core: &str, config: &config::Config) -> Result<(), Error> {
+    let (exe, version, mut traces) = core_dump::get_stack_traces_from_core(core, config.core_exe.as_deref())?;
+    if !config.path_maps.is_empty() {
+        let path_map = path_map::PathMap::new(&config.path_maps);
+        traces = traces.iter().map(|trace| path_map.map_trace(trace)).collect();
+    }
+    if let Some(frame_filter) = FrameFilter::new(config)? {
+        for trace in traces.iter_mut() {
+            frame_filter.filter_frames(trace);
+        }
+    }
+    let filename = dump_file(config, None, Some(&exe), SystemTime::now());
+    let mut out: Box<dyn Write> = match filename.as_ref() {
+        Some(filename) => Box::new(std::fs::File::create(filename).context(format!("Failed to create '{}'", filename))?),
+        None => Box::new(std::io::stdout())
+    };
+    if config.dump_json {
+        let mut dump = dump_json::ProcessDump::new(None, &exe, version.to_string(), &traces);
+        dump.core_file = Some(core);
+        dump_json::write_dumps(&mut out, &[dump], true)?;
+    } else {
+        writeln!(out, "Core file {}: {}\nPython version {}", core, exe, version)?;
+        write_traces(&mut out, &traces, true)?;
+    }
+    if let Some(filename) = filename {
+        println!("Wrote dump to '{}'", filename);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os="linux"))]
+fn dump_core(_core: &str, _config: &config::Config) -> Result<(), Error> {
+    Err(format_err!("Reading core files is only supported on linux"))
+}
+
+// picks which of several processes to profile, out of the ones that are python. When there's more
+// than one this asks which to use if we're being run interactively
+fn pick_python_process(candidates: Vec<remoteprocess::Pid>, description: &str, config: &config::Config) -> Result<remoteprocess::Pid, Error> {
+    let mut pids = Vec::new();
+    for pid in candidates {
+        match PythonSpy::new(pid, config) {
+            Ok(_) => pids.push(pid),
+            Err(err) => info!("Skipping process {} {}: {}", pid, description, err)
+        }
+    }
+    let describe = |pid: remoteprocess::Pid| remoteprocess::Process::new(pid).and_then(|process| process.cmdline())
+        .map(|cmdline| cmdline.join(" ")).unwrap_or_default();
+    match pids.as_slice() {
+        [] => Err(format_err!("No python processes found {}", description)),
+        [pid] => Ok(*pid),
+        _ if !console::Term::stderr().is_term() => {
+            let found: Vec<String> = pids.iter().map(|&pid| format!("{} ({})", pid, describe(pid))).collect();
+            Err(format_err!("Found {} python processes {}, use --pid to pick one: {}", pids.len(), description, found.join(", ")))
+        },
+        _ => {
+            eprintln!("Found {} python processes {}:", pids.len(), description);
+            for (i, &pid) in pids.iter().enumerate() {
+                eprintln!("  [{}] {}  {}", i + 1, pid, describe(pid));
+            }
+            loop {
+                eprint!("Which one should be profiled? [1-{}] ", pids.len());
+                let mut line = String::new();
+                if std::io::stdin().read_line(&mut line)? == 0 {
+                    return Err(format_err!("No process was picked"));
+                }
+                match line.trim().parse::<usize>() {
+                    Ok(i) if i >= 1 && i <= pids.len() => return Ok(pids[i - 1]),
+                    _ => eprintln!("Enter a number from 1 to {}", pids.len())
+                }
+            }
+        }
+    }
+}
+
+// the main process of a container is often a shell or an init like tini, so this profiles the first
+// process in the container that looks like python
+#[cfg(target_os="linux")]
+fn find_pod_process(pod: &str, config: &config::Config) -> Result<remoteprocess::Pid, Error> {
+    let mut last_error = None;
+    for pid in kubernetes::container_processes(pod, config.pod_container.as_deref(), config.pod_namespace.as_deref())? {
+        match PythonSpy::new(pid, config) {
+            Ok(_) => return Ok(pid),
+            Err(err) => {
+                info!("Skipping process {} in pod {}: {}", pid, pod, err);
+                last_error = Some(err);
+            }
+        }
+    }
+    match last_error {
+        Some(err) => Err(format_err!("Failed to find a python process in pod {}: {}", pod, err)),
+        None => Err(format_err!("Failed to find a python process in pod {}", pod))
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+fn find_pod_process(_pod: &str, _config: &config::Config) -> Result<remoteprocess::Pid, Error> {
+    Err(format_err!("Finding kubernetes pods is only supported on linux"))
+}
+
 fn pyspy_main() -> Result<(), Error> {
     let config = config::Config::from_commandline()?;
+    if config.status_json {
+        status::enable();
+    }
+
+    #[cfg(target_os="linux")]
+    {
+        if config.sudo {
+            permissions::drop_privileges()?;
+        }
+    }
+
+    if let (Some(input), Some(filename)) = (&config.convert_file, &config.filename) {
+        return convert_samples(input, filename, &config);
+    }
+
+    if let Some(ref core) = config.core_file {
+        return dump_core(core, &config);
+    }
+
+    if let Some(ref address) = config.agent_listen {
+        return agent::run_agent(address, &config);
+    }
+
+    if let Some(ref address) = config.remote {
+        return agent::run_remote(address, &config.remote_args, &config);
+    }
 
     #[cfg(target_os="macos")]
     {
@@ -214,15 +1951,94 @@ fn pyspy_main() -> Result<(), Error> {
         }
     }
 
+    if config.list_processes {
+        return list_processes();
+    }
+
+    if let (true, Some(pid)) = (config.heap, config.pid) {
+        return inspect_heap(pid, &config);
+    }
+
+    let config = match config.auto {
+        Some(server) => {
+            let pid = process_group::find_master(server)?;
+            info!("Found {} master process {}", server, pid);
+            config::Config{pid: Some(pid), ..config}
+        },
+        None => config
+    };
+
+    let config = match config.cmdline.clone() {
+        Some(text) => {
+            let description = format!("with '{}' in their command line", text);
+            let pid = pick_python_process(process_group::find_cmdline(&text)?, &description, &config)?;
+            config::Config{pid: Some(pid), ..config}
+        },
+        None => config
+    };
+
+    let config = match config.port {
+        Some(port) => {
+            let description = format!("listening on port {}", port);
+            let pid = pick_python_process(process_group::find_listening(port)?, &description, &config)?;
+            config::Config{pid: Some(pid), ..config}
+        },
+        None => config
+    };
+
+    let config = match config.pod.clone() {
+        Some(pod) => {
+            let pid = find_pod_process(&pod, &config)?;
+            info!("Found python process {} in pod {}", pid, pod);
+            config::Config{pid: Some(pid), ..config}
+        },
+        None => config
+    };
+
     if let Some(pid) = config.pid {
         let mut process = PythonSpy::retry_new(pid, &config, 3)?;
+        let mut others = Vec::new();
+        for &pid in &config.other_pids {
+            others.push(PythonSpy::retry_new(pid, &config, 3).context(format!("Failed to attach to process {}", pid))?);
+        }
+        if config.dump {
+            let processes: Vec<&mut PythonSpy> = std::iter::once(&mut process).chain(others.iter_mut()).collect();
+            let show_pids = processes.len() > 1;
+            dump_processes(processes, show_pids, &config)?;
+        } else if !config.alert_conditions.is_empty() {
+            run_alerts(&mut process, create_process_group(&config, others), &config)?;
+        } else if let Some(ref filename) = config.filename {
+            record(&mut process, create_process_group(&config, others), filename, &config)?;
+        } else {
+            let pids: Vec<String> = std::iter::once(pid).chain(config.other_pids.iter().cloned()).map(|pid| pid.to_string()).collect();
+            let description = if pids.len() > 1 { format!("pids: {}", pids.join(", ")) } else { format!("pid: {}", pid) };
+            sample_console(&mut process, create_process_group(&config, others), &description, &config)?;
+        }
+    }
+
+    else if let Some(ref name) = config.name {
+        let mut processes = Vec::new();
+        for pid in process_group::find_matching(name)? {
+            match PythonSpy::retry_new(pid, &config, 3) {
+                Ok(process) => processes.push(process),
+                Err(err) => info!("Skipping process {} matching '{}': {}", pid, name, err)
+            }
+        }
+        if processes.is_empty() {
+            return Err(format_err!("No python processes found matching '{}'", name));
+        }
+
+        let mut process = processes.remove(0);
         if config.dump {
-            println!("{}\nPython version {}", process.process.exe()?, process.version);
-            print_traces(&process.get_stack_traces()?, true);
-        } else if let Some(ref flame_file) = config.flame_file_name {
-            sample_flame(&mut process, &flame_file, &config)?;
+            // processes found by name are always shown with their pid
+            let processes: Vec<&mut PythonSpy> = std::iter::once(&mut process).chain(processes.iter_mut()).collect();
+            dump_processes(processes, true, &config)?;
+        } else if !config.alert_conditions.is_empty() {
+            run_alerts(&mut process, create_process_group(&config, processes), &config)?;
+        } else if let Some(ref filename) = config.filename {
+            record(&mut process, create_process_group(&config, processes), filename, &config)?;
         } else {
-            sample_console(&mut process, &format!("pid: {}", pid), &config)?;
+            sample_console(&mut process, create_process_group(&config, processes), &format!("name: {}", name), &config)?;
         }
     }
 
@@ -243,10 +2059,26 @@ fn pyspy_main() -> Result<(), Error> {
             }
         }
 
-        let mut command = command.args(&subprocess[1..])
-            .stdin(std::process::Stdio::null())
-            .stdout(process_output.reopen()?)
-            .stderr(process_output.reopen()?)
+        command.args(&subprocess[1..]).envs(config.program_env.iter().cloned());
+        if let Some(ref cwd) = config.program_cwd {
+            command.current_dir(cwd);
+        }
+        let stdin = match config.program_stdin.as_deref() {
+            Some("-") => std::process::Stdio::inherit(),
+            Some(input) => std::fs::File::open(input).context(format!("Failed to open --stdin file '{}'", input))?.into(),
+            None => std::process::Stdio::null()
+        };
+        let output = |redirect: Option<&str>| -> Result<std::process::Stdio, Error> {
+            Ok(match redirect {
+                Some("-") => std::process::Stdio::inherit(),
+                Some(output) => std::fs::File::create(output).context(format!("Failed to create '{}'", output))?.into(),
+                None => process_output.reopen()?.into()
+            })
+        };
+        let mut command = command
+            .stdin(stdin)
+            .stdout(output(config.program_stdout.as_deref())?)
+            .stderr(output(config.program_stderr.as_deref())?)
             .spawn()
             .map_err(|e| format_err!("Failed to create process '{}': {}", subprocess[0], e))?;
 
@@ -255,12 +2087,21 @@ fn pyspy_main() -> Result<(), Error> {
             // sleep just in case: https://jvns.ca/blog/2018/01/28/mac-freeze/
             std::thread::sleep(Duration::from_millis(50));
         }
-        let result = match PythonSpy::retry_new(command.id() as remoteprocess::Pid, &config, 8) {
+        let pid = command.id() as remoteprocess::Pid;
+        // wrapper scripts (and the like) only exec python once they've set things up
+        let attached = match PythonSpy::retry_new(pid, &config, 8) {
+            Err(err) if command.try_wait()?.is_none() => {
+                info!("Failed to attach to process {} ({}), waiting for it to exec python", pid, err);
+                respawn::wait_for_exec(pid, &RUNNING, &config)
+            },
+            attached => attached.map_err(Error::from)
+        };
+        let result = match attached {
             Ok(mut process) => {
-                if let Some(ref flame_file) = config.flame_file_name {
-                    sample_flame(&mut process, &flame_file, &config)
+                if let Some(ref filename) = config.filename {
+                    record(&mut process, create_process_group(&config, Vec::new()), filename, &config)
                 } else {
-                    sample_console(&mut process, &subprocess.join(" "), &config)
+                    sample_console(&mut process, create_process_group(&config, Vec::new()), &subprocess.join(" "), &config)
                 }
             },
             Err(e) => Err(e)
@@ -288,6 +2129,8 @@ fn pyspy_main() -> Result<(), Error> {
             // I don't actually care if we failed to kill ... most times process is already done
             // eprintln!("Error killing child process {}", e);
         }
+        // programs that we started ending is how recording them normally finishes
+        TARGET_EXITED.store(false, Ordering::SeqCst);
         return result;
     }
 
@@ -298,13 +2141,20 @@ fn main() {
     env_logger::init();
 
     if let Err(err) = pyspy_main() {
-        #[cfg(unix)]
-        {
+        status::emit(&status::Event::Error{message: &err.to_string()});
         if permission_denied(&err) {
-            eprintln!("Permission Denied: Try running again with elevated permissions by going 'sudo env \"PATH=$PATH\" !!'");
+            // the arguments have already been parsed successfully, so this won't exit
+            let config = config::Config::from_commandline().ok();
+            #[cfg(unix)]
+            {
+                if config.as_ref().is_some_and(|config| config.sudo) && unsafe { libc::geteuid() } != 0 {
+                    eprintln!("Failed to run sudo: {}", permissions::rerun_with_sudo());
+                    std::process::exit(1);
+                }
+            }
+            eprintln!("{}", permissions::remediation(config.and_then(|config| config.pid)));
             std::process::exit(1);
         }
-        }
 
         eprintln!("Error: {}", err);
         for (i, suberror) in err.iter_chain().enumerate() {
@@ -315,4 +2165,11 @@ fn main() {
         eprintln!("{}", err.backtrace());
         std::process::exit(1);
     }
+
+    if ASSERTION_FAILED.load(Ordering::SeqCst) {
+        std::process::exit(EXIT_ASSERTION_FAILED);
+    }
+    if TARGET_EXITED.load(Ordering::SeqCst) {
+        std::process::exit(EXIT_TARGET_EXITED);
+    }
 }
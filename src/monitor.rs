@@ -0,0 +1,149 @@
+// Keeps track of how 'py-spy monitor' is doing, and serves the latest profile along with prometheus
+// metrics over http so that a long running monitor can be checked on (and alerted on) like any
+// other service
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use failure::{Error, ResultExt};
+use remoteprocess::Pid;
+
+/// The state of the monitor, shared with the http server
+pub struct Health {
+    /// The process being profiled, or None while waiting for it to restart
+    pub pid: Option<Pid>,
+    pub windows: u64,
+    pub samples: u64,
+    pub errors: u64,
+    pub restarts: u64,
+    /// CPU time py-spy has used while recording
+    pub profiler_cpu_time: Duration,
+    pub last_window: Option<SystemTime>,
+    /// The files written for the most recent window
+    pub latest: Vec<String>,
+}
+
+impl Health {
+    pub fn new(pid: Pid) -> Health {
+        Health{pid: Some(pid), windows: 0, samples: 0, errors: 0, restarts: 0, profiler_cpu_time: Duration::from_secs(0),
+               last_window: None, latest: Vec::new()}
+    }
+}
+
+/// Serves the latest profile at / and metrics at /metrics from a background thread
+pub fn serve(address: &str, health: Arc<Mutex<Health>>) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).context(format!("Failed to listen on {}", address))?;
+    let started = Instant::now();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.map_err(Error::from).and_then(|stream| respond(stream, &health, started.elapsed()));
+            if let Err(err) = result {
+                debug!("Failed to respond to http request: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, health: &Mutex<Health>, uptime: Duration) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", metrics(&health.lock().unwrap(), uptime).into_bytes()),
+        "/" => {
+            let latest = health.lock().unwrap().latest.first().cloned();
+            match latest.map(|filename| (std::fs::read(&filename), filename)) {
+                Some((Ok(data), filename)) => ("200 OK", content_type(&filename), data),
+                Some((Err(err), filename)) => ("500 Internal Server Error", "text/plain", format!("Failed to read '{}': {}\n", filename, err).into_bytes()),
+                None => ("404 Not Found", "text/plain", b"No profile has been written yet\n".to_vec())
+            }
+        },
+        _ => ("404 Not Found", "text/plain", b"Not found\n".to_vec())
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+           status, content_type, body.len())?;
+    stream.write_all(&body)?;
+    Ok(())
+}
+
+fn content_type(filename: &str) -> &'static str {
+    match Path::new(filename).extension().and_then(|extension| extension.to_str()) {
+        Some("svg") => "image/svg+xml",
+        Some("html") => "text/html",
+        Some("json") => "application/json",
+        Some("txt") | Some("csv") => "text/plain",
+        _ => "application/octet-stream"
+    }
+}
+
+/// Formats the health of the monitor in the prometheus text format
+fn metrics(health: &Health, uptime: Duration) -> String {
+    let mut metrics = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+        metrics.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+    };
+    metric("pyspy_up", "gauge", "Whether py-spy is attached to the process being monitored",
+           (health.pid.is_some() as u8).to_string());
+    if let Some(pid) = health.pid {
+        metric("pyspy_pid", "gauge", "The pid of the process being monitored", pid.to_string());
+    }
+    metric("pyspy_windows_total", "counter", "Recording windows written out", health.windows.to_string());
+    metric("pyspy_samples_total", "counter", "Samples recorded", health.samples.to_string());
+    metric("pyspy_sample_errors_total", "counter", "Samples that failed", health.errors.to_string());
+    metric("pyspy_target_restarts_total", "counter", "Times the process being monitored restarted", health.restarts.to_string());
+    metric("pyspy_cpu_seconds_total", "counter", "CPU time used by py-spy while recording",
+           format!("{:.3}", health.profiler_cpu_time.as_secs_f64()));
+    if let Some(since_epoch) = health.last_window.and_then(|last| last.duration_since(std::time::UNIX_EPOCH).ok()) {
+        metric("pyspy_last_window_timestamp_seconds", "gauge", "When the last window was written out",
+               since_epoch.as_secs().to_string());
+    }
+    metric("pyspy_uptime_seconds", "gauge", "How long py-spy has been monitoring for", uptime.as_secs().to_string());
+    metrics
+}
+
+/// Deletes the oldest files once there are more than 'keep' of them
+pub fn rotate(files: &mut VecDeque<String>, keep: usize) {
+    while files.len() > keep {
+        if let Some(filename) = files.pop_front() {
+            if let Err(err) = std::fs::remove_file(&filename) {
+                warn!("Failed to remove '{}': {}", filename, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let mut health = Health::new(4242);
+        health.windows = 3;
+        health.samples = 18000;
+        health.last_window = Some(std::time::UNIX_EPOCH + Duration::from_secs(1_560_000_000));
+        let metrics = metrics(&health, Duration::from_secs(1800));
+        assert!(metrics.contains("# TYPE pyspy_samples_total counter\npyspy_samples_total 18000\n"));
+        assert!(metrics.contains("\npyspy_up 1\n"));
+        assert!(metrics.contains("\npyspy_pid 4242\n"));
+        assert!(metrics.contains("\npyspy_last_window_timestamp_seconds 1560000000\n"));
+        assert!(metrics.contains("\npyspy_uptime_seconds 1800\n"));
+
+        health.pid = None;
+        let metrics = super::metrics(&health, Duration::from_secs(1800));
+        assert!(metrics.contains("\npyspy_up 0\n"));
+        assert!(!metrics.contains("pyspy_pid"));
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(content_type("profiles/4242-20190601-120000.svg"), "image/svg+xml");
+        assert_eq!(content_type("profile.raw"), "application/octet-stream");
+    }
+}
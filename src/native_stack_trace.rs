@@ -7,10 +7,26 @@ use lru::LruCache;
 
 use crate::binary_parser::BinaryInfo;
 use crate::cython;
+#[cfg(target_os="linux")]
+use crate::jit_symbols::JitSymbols;
 use crate::stack_trace::{Frame};
 use crate::utils::resolve_filename;
 use crate::cpp_demangle::{DemangleOptions, BorrowedSymbol};
 
+// number of frames (from the innermost) to look in for the functions that a thread is blocked in,
+// which are called just above the libc functions doing the wait
+const WAIT_FRAMES: usize = 8;
+
+// the functions in _threadmodule.c that acquire a threading.Lock or RLock. Condition.wait blocks
+// acquiring a plain lock, so this covers conditions too
+const LOCK_FUNCTIONS: &[&str] = &["acquire_timed", "lock_PyThread_acquire_lock", "rlock_acquire"];
+
+// the filename of native frames recorded with --unsymbolicated. These frames have the address
+// relative to their binary as the name, the binary as the module, and the build id of the binary
+// (when it has one) in place of the short filename
+#[cfg(target_os="linux")]
+pub const UNSYMBOLICATED: &str = "<unsymbolicated>";
+
 pub struct NativeStack {
     should_reload: bool,
     python: BinaryInfo,
@@ -23,7 +39,13 @@ pub struct NativeStack {
     // TODO: right now on windows if we don't hold on the process handle unwinding will fail
     #[allow(dead_code)]
     process: remoteprocess::Process,
-    symbol_cache: LruCache<u64, remoteprocess::StackFrame>,
+    // the frames that each address symbolicates to, which is more than one when functions are inlined
+    symbol_cache: LruCache<u64, Vec<remoteprocess::StackFrame>>,
+    #[cfg(target_os="linux")]
+    jit: JitSymbols,
+    // whether to leave native frames outside of python to be symbolicated later with 'py-spy symbolicate'
+    #[cfg(target_os="linux")]
+    unsymbolicated: bool,
 }
 
 impl NativeStack {
@@ -31,7 +53,10 @@ impl NativeStack {
         let cython_maps = cython::SourceMaps::new();
 
         let process = remoteprocess::Process::new(pid)?;
-        let unwinder = process.unwinder()?;
+        #[allow(unused_mut)]
+        let mut unwinder = process.unwinder()?;
+        #[cfg(target_os="linux")]
+        unwinder.set_symbol_cache(symbol_cache_directory().map(remoteprocess::SymbolCache::new));
 
         // Try to load up libunwind-ptrace on linux
         #[cfg(target_os="linux")]
@@ -43,10 +68,27 @@ impl NativeStack {
                               #[cfg(target_os="linux")]
                               libunwinder,
                               process,
-                              symbol_cache: LruCache::new(4096)
+                              symbol_cache: LruCache::new(4096),
+                              #[cfg(target_os="linux")]
+                              jit: JitSymbols::new(pid),
+                              #[cfg(target_os="linux")]
+                              unsymbolicated: false,
                               });
     }
 
+    /// Downloads the debug info of stripped binaries from the debuginfod servers in DEBUGINFOD_URLS
+    #[cfg(target_os="linux")]
+    pub fn set_debuginfod(&mut self, debuginfod: bool) {
+        self.unwinder.set_debuginfod(debuginfod);
+    }
+
+    /// Records the binary and relative address of native frames instead of symbolicating them, so that
+    /// they can be symbolicated on another machine that has the debug info
+    #[cfg(target_os="linux")]
+    pub fn set_unsymbolicated(&mut self, unsymbolicated: bool) {
+        self.unsymbolicated = unsymbolicated;
+    }
+
     pub fn merge_native_thread(&mut self, frames: &Vec<Frame>, thread: &remoteprocess::Thread) -> Result<Vec<Frame>, Error> {
         if self.should_reload {
             self.unwinder.reload()?;
@@ -83,16 +125,28 @@ impl NativeStack {
 
         // merge the native_stack and python stack together
         for addr in native_stack {
+            let is_python_addr = self.python.contains(addr) || self.libpython.as_ref().map_or(false, |m| m.contains(addr));
+
+            // frames in python itself are still symbolicated, since that's how we merge in the python frames
+            #[cfg(target_os="linux")]
+            {
+                if self.unsymbolicated && !is_python_addr {
+                    if let Some((module, build_id, address)) = self.unwinder.binary_address(addr) {
+                        merged.push(unsymbolicated_frame(module, build_id, address));
+                        continue;
+                    }
+                }
+            }
+
             // check in the symbol cache if we have looked up this symbol yet
-            let cached_symbol = self.symbol_cache.get(&addr).map(|f| f.clone());
+            let cached_frames = self.symbol_cache.get(&addr).cloned();
 
             // merges a remoteprocess::StackFrame into the current merged vec
-            let is_python_addr = self.python.contains(addr) || self.libpython.as_ref().map_or(false, |m| m.contains(addr));
             let merge_frame = &mut |frame: &remoteprocess::StackFrame| {
                 match self.get_merge_strategy(is_python_addr, frame) {
                     MergeType::Ignore => {},
                     MergeType::MergeNativeFrame => {
-                        if let Some(python_frame) = self.translate_native_frame(frame) {
+                        if let Some(python_frame) = translate_native_frame(frame) {
                             merged.push(python_frame);
                         }
                     },
@@ -108,25 +162,29 @@ impl NativeStack {
                 }
             };
 
-            if let Some(frame) = cached_symbol {
-                merge_frame(&frame);
+            if let Some(cached_frames) = cached_frames {
+                for frame in &cached_frames {
+                    merge_frame(frame);
+                }
                 continue;
             }
 
-            // Keep track of the first symbolicated frame for caching. We don't cache anything (yet) wheree
-            // symoblicationg returns multiple frames for an address, like in the case of inlined function calls.
-            // so track how many frames we get for the address, and only update cache in the happy case
-            // of 1 frame
-            let mut symbolicated_count = 0;
-            let mut first_frame = None;
-
-            self.unwinder.symbolicate(addr, !is_python_addr, &mut |frame: &remoteprocess::StackFrame| {
-                symbolicated_count += 1;
-                if symbolicated_count == 1 {
-                    first_frame = Some(frame.clone());
-                }
+            // an address can symbolicate to several frames when functions have been inlined into each other,
+            // which are all merged in (innermost first) and cached together
+            let mut symbolicated = Vec::new();
+            let result = self.unwinder.symbolicate(addr, !is_python_addr, &mut |frame: &remoteprocess::StackFrame| {
+                symbolicated.push(frame.clone());
                 merge_frame(frame);
-            }).unwrap_or_else(|e| {
+            });
+            result.unwrap_or_else(|e| {
+                // code generated by a JIT compiler isn't in any binary
+                #[cfg(target_os="linux")]
+                {
+                    if let Some(frame) = self.jit.frame(&self.process, addr) {
+                        merged.push(frame);
+                        return;
+                    }
+                }
                 if let remoteprocess::Error::NoBinaryForAddress(_) = e {
                     debug!("don't have a binary for symbols at 0x{:x} - reloading", addr);
                     self.should_reload = true;
@@ -134,11 +192,11 @@ impl NativeStack {
                 // if we can't symbolicate, just insert a stub here.
                 merged.push(Frame{filename: "?".to_owned(),
                                   name: format!("0x{:x}", addr),
-                                  line: 0, short_filename: None, module: None, frame_ptr: None});
+                                  line: 0, short_filename: None, module: None, frame_ptr: None, locals: None});
             });
 
-            if symbolicated_count == 1 {
-                self.symbol_cache.put(addr, first_frame.unwrap());
+            if !symbolicated.is_empty() {
+                self.symbol_cache.put(addr, symbolicated);
             }
         }
 
@@ -203,48 +261,45 @@ impl NativeStack {
         }
     }
 
-    /// translates a native frame into a optional frame. none indicates we should ignore this frame
-    fn translate_native_frame(&self, frame: &remoteprocess::StackFrame) -> Option<Frame> {
-        match &frame.function {
-            Some(func) =>  {
-                if ignore_frame(func, &frame.module) {
-                    return None;
-                }
+    /// Checks if a thread is blocked waiting to take the GIL, by looking for take_gil in its innermost
+    /// frames. This needs the symbols of libpython, since take_gil isn't exported
+    pub fn waiting_for_gil(&mut self, thread: &remoteprocess::Thread) -> Result<bool, Error> {
+        self.blocked_in(thread, &["take_gil"])
+    }
 
-                // Get the filename/line/function name here
-                let line = frame.line.unwrap_or(0) as i32;
+    /// Checks if a thread is blocked acquiring a lock from the threading module. Like waiting_for_gil,
+    /// this needs the symbols of libpython
+    pub fn waiting_for_lock(&mut self, thread: &remoteprocess::Thread) -> Result<bool, Error> {
+        self.blocked_in(thread, LOCK_FUNCTIONS)
+    }
 
-                // try to resolve the filename relative to the module if given
-                let filename = match frame.filename.as_ref() {
-                    Some(filename) => {
-                        resolve_filename(filename, &frame.module)
-                            .unwrap_or_else(|| filename.clone())
-                    },
-                    None => frame.module.clone()
-                };
-
-                let mut demangled = None;
-                if func.starts_with('_') {
-                    if let Ok((sym, _)) = BorrowedSymbol::with_tail(func.as_bytes()) {
-                        let options = DemangleOptions{no_params: true, ..Default::default()};
-                        if let Ok(sym) = sym.demangle(&options) {
-                            demangled = Some(sym);
-                        }
-                    }
-                }
-                let name = demangled.as_ref().unwrap_or_else(|| &func);
-                if cython::ignore_frame(name) {
-                    return None;
+    // checks if any of the innermost frames of a thread are in one of the python functions given
+    fn blocked_in(&mut self, thread: &remoteprocess::Thread, functions: &[&str]) -> Result<bool, Error> {
+        let is_match = |frame: &remoteprocess::StackFrame| frame.function.as_ref().is_some_and(|f| functions.contains(&c_symbol_name(f)));
+        let mut cursor = self.unwinder.cursor(thread)?;
+        for _ in 0..WAIT_FRAMES {
+            let addr = match cursor.next() {
+                Some(ip) => ip?,
+                None => break
+            };
+            if !(self.python.contains(addr) || self.libpython.as_ref().is_some_and(|m| m.contains(addr))) {
+                continue;
+            }
+            if let Some(frames) = self.symbol_cache.get(&addr) {
+                if frames.iter().any(is_match) {
+                    return Ok(true);
                 }
-                let name = cython::demangle(&name).to_owned();
-                Some(Frame{filename, line, name, short_filename: None, module: Some(frame.module.clone()), frame_ptr: None})
-            },
-            None => {
-                Some(Frame{filename: frame.module.clone(),
-                           name: format!("0x{:x}", frame.addr),
-                           line: 0, short_filename: None, module: Some(frame.module.clone()), frame_ptr: None})
+                continue;
+            }
+            let mut found = false;
+            self.unwinder.symbolicate(addr, false, &mut |frame: &remoteprocess::StackFrame| {
+                found = found || is_match(frame);
+            }).unwrap_or_else(|e| debug!("Failed to symbolicate 0x{:x}: {}", addr, e));
+            if found {
+                return Ok(true);
             }
         }
+        Ok(false)
     }
 
     fn get_thread(&mut self, thread: &remoteprocess::Thread) -> Result<Vec<u64>, Error> {
@@ -290,12 +345,95 @@ impl NativeStack {
     }
 }
 
+/// translates a native frame into a optional frame. none indicates we should ignore this frame
+pub fn translate_native_frame(frame: &remoteprocess::StackFrame) -> Option<Frame> {
+    match &frame.function {
+        Some(func) =>  {
+            if ignore_frame(func, &frame.module) {
+                return None;
+            }
+            // name native frames the same way on every OS, so that profiles can be compared
+            let func = c_symbol_name(func);
+
+            // Get the filename/line/function name here
+            let mut line = frame.line.unwrap_or(0) as i32;
+
+            // try to resolve the filename relative to the module if given
+            let source = frame.filename.as_ref().and_then(|filename| resolve_filename(filename, &frame.module));
+            let mut filename = match (source.as_ref(), frame.filename.as_ref()) {
+                (Some(source), _) => source.clone(),
+                (None, Some(filename)) => filename.clone(),
+                (None, None) => frame.module.clone()
+            };
+
+            // without the generated C source we can't map lines back to the cython source, but
+            // we can still point at the .pyx file that the extension module was built from
+            if source.is_none() && cython::is_cython_function(func) {
+                if let Some(pyx) = cython::module_source_file(&frame.module) {
+                    filename = pyx;
+                    line = 0;
+                }
+            }
+
+            let mut demangled = None;
+            if func.starts_with('_') {
+                if let Ok((sym, _)) = BorrowedSymbol::with_tail(func.as_bytes()) {
+                    let options = DemangleOptions{no_params: true, ..Default::default()};
+                    if let Ok(sym) = sym.demangle(&options) {
+                        demangled = Some(sym);
+                    }
+                }
+            }
+            let name = demangled.as_deref().unwrap_or(func);
+            if cython::ignore_frame(name) {
+                return None;
+            }
+            let name = cython::demangle(&name).to_owned();
+            Some(Frame{filename, line, name, short_filename: None, module: Some(frame.module.clone()), frame_ptr: None, locals: None})
+        },
+        None => {
+            Some(Frame{filename: frame.module.clone(),
+                       name: format!("0x{:x}", frame.addr),
+                       line: 0, short_filename: None, module: Some(frame.module.clone()), frame_ptr: None, locals: None})
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+fn unsymbolicated_frame(module: &str, build_id: Option<&[u8]>, address: u64) -> Frame {
+    let build_id = build_id.map(|id| id.iter().map(|b| format!("{:02x}", b)).collect());
+    Frame{filename: UNSYMBOLICATED.to_owned(), name: format!("0x{:x}", address), line: 0,
+          short_filename: build_id, module: Some(module.to_owned()), frame_ptr: None, locals: None}
+}
+
 enum MergeType {
     Ignore,
     MergePythonFrame,
     MergeNativeFrame
 }
 
+// symbol tables are cached in ~/.cache/py-spy/symbols (or under $XDG_CACHE_HOME)
+#[cfg(target_os="linux")]
+fn symbol_cache_directory() -> Option<std::path::PathBuf> {
+    let cache = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(cache) if !cache.is_empty() => std::path::PathBuf::from(cache),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".cache")
+    };
+    Some(cache.join("py-spy").join("symbols"))
+}
+
+// Mach-O symbols have an extra leading underscore compared to the names in the C source (and the
+// ELF symbols on linux), which counts mangled C++ names too: '__ZN3foo3barEv' is '_ZN3foo3barEv'
+#[cfg(target_os="macos")]
+fn c_symbol_name(function: &str) -> &str {
+    function.strip_prefix('_').unwrap_or(function)
+}
+
+#[cfg(not(target_os="macos"))]
+fn c_symbol_name(function: &str) -> &str {
+    function
+}
+
 // the intent here is to remove top-level libc or pthreads calls
 // from the stack traces. This almost certainly can be done better
 #[cfg(target_os="linux")]
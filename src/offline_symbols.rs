@@ -0,0 +1,167 @@
+// Symbolicates captures recorded with --unsymbolicated, which store the binary and relative
+// address of each native frame instead of its function name. This lets native code running in
+// slim containers (with stripped binaries and no debug info) be profiled, by symbolicating the
+// capture afterwards on a machine that has the debug info
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use remoteprocess;
+
+use crate::cython;
+use crate::native_stack_trace::{translate_native_frame, UNSYMBOLICATED};
+use crate::stack_trace::Frame;
+
+/// Symbolicates the native frames of a capture recorded with --unsymbolicated, using the binaries
+/// (or their separate debug info) on this machine
+pub struct OfflineSymbols {
+    search_paths: Vec<PathBuf>,
+    debuginfod: bool,
+    cython_maps: cython::SourceMaps,
+    // the symbols of each (binary, build id), or None if we couldn't find a matching copy of the binary
+    binaries: HashMap<(String, Option<String>), Option<remoteprocess::SymbolData>>,
+    frames: HashMap<Frame, Vec<Frame>>,
+}
+
+impl OfflineSymbols {
+    pub fn new(search_paths: &[String], debuginfod: bool) -> OfflineSymbols {
+        OfflineSymbols{search_paths: search_paths.iter().map(PathBuf::from).collect(), debuginfod,
+                       cython_maps: cython::SourceMaps::new(), binaries: HashMap::new(), frames: HashMap::new()}
+    }
+
+    /// Replaces each unsymbolicated frame with the frames it symbolicates to, leaving the other frames as is
+    pub fn symbolicate(&mut self, frames: &[Frame]) -> Vec<Frame> {
+        let mut symbolicated = Vec::with_capacity(frames.len());
+        for frame in frames {
+            if frame.filename != UNSYMBOLICATED {
+                symbolicated.push(frame.clone());
+                continue;
+            }
+            if !self.frames.contains_key(frame) {
+                let resolved = self.resolve(frame);
+                self.frames.insert(frame.clone(), resolved);
+            }
+            symbolicated.extend(self.frames[frame].iter().cloned());
+        }
+        symbolicated
+    }
+
+    /// The binaries that we couldn't find a copy of, which their frames have been left as addresses for
+    pub fn missing_binaries(&self) -> Vec<&str> {
+        let mut missing: Vec<&str> = self.binaries.iter()
+            .filter(|(_, symbols)| symbols.is_none())
+            .map(|((module, _), _)| module.as_str())
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        missing
+    }
+
+    fn resolve(&mut self, frame: &Frame) -> Vec<Frame> {
+        let module = frame.module.clone().unwrap_or_default();
+        let address = match u64::from_str_radix(frame.name.trim_start_matches("0x"), 16) {
+            Ok(address) => address,
+            Err(_) => return vec![frame.clone()]
+        };
+
+        let key = (module.clone(), frame.short_filename.clone());
+        if !self.binaries.contains_key(&key) {
+            let symbols = self.load(&module, frame.short_filename.as_deref());
+            self.binaries.insert(key.clone(), symbols);
+        }
+
+        let mut native_frames = Vec::new();
+        if let Some(symbols) = self.binaries[&key].as_ref() {
+            symbols.symbolicate(address, true, &mut |native: &remoteprocess::StackFrame| {
+                // report the frames as being in the binary that was profiled, rather than the copy we found
+                native_frames.push(remoteprocess::StackFrame{module: module.clone(), ..native.clone()});
+            }).unwrap_or_else(|e| warn!("Failed to symbolicate 0x{:x} in {}: {}", address, module, e));
+        }
+        if native_frames.is_empty() {
+            native_frames.push(remoteprocess::StackFrame{line: None, filename: None, function: None, addr: address, module});
+        }
+
+        let mut resolved: Vec<Frame> = native_frames.iter().filter_map(translate_native_frame).collect();
+        for frame in resolved.iter_mut() {
+            self.cython_maps.translate(frame);
+        }
+        resolved
+    }
+
+    // finds a copy of a binary with the same build id. This looks in the directories given with
+    // --symbols (by build id, by the original path under the directory, and by filename), then at
+    // the original path, and then for separate debug info in /usr/lib/debug or from debuginfod
+    fn load(&self, module: &str, build_id: Option<&str>) -> Option<remoteprocess::SymbolData> {
+        let mut candidates = Vec::new();
+        for directory in &self.search_paths {
+            if let Some(build_id) = build_id.filter(|id| id.len() > 2) {
+                candidates.push(directory.join(".build-id").join(&build_id[..2]).join(format!("{}.debug", &build_id[2..])));
+            }
+            candidates.push(directory.join(module.trim_start_matches('/')));
+            if let Some(filename) = Path::new(module).file_name() {
+                candidates.push(directory.join(filename));
+            }
+        }
+        candidates.push(PathBuf::from(module));
+        if let Some(path) = build_id.and_then(parse_build_id).and_then(|id| remoteprocess::find_debug_file(&id, self.debuginfod)) {
+            candidates.push(path);
+        }
+
+        for path in candidates {
+            let filename = match path.to_str() {
+                Some(filename) if path.is_file() => filename,
+                _ => continue
+            };
+            let symbols = match remoteprocess::SymbolData::new(filename, 0, None, self.debuginfod) {
+                Ok(symbols) => symbols,
+                Err(e) => {
+                    warn!("Failed to load symbols from {}: {}", filename, e);
+                    continue;
+                }
+            };
+            let found_id: Option<String> = symbols.build_id().map(|id| id.iter().map(|b| format!("{:02x}", b)).collect());
+            if build_id.is_some() && found_id.as_deref() != build_id {
+                info!("skipping {} for {}, since it's from a different build", filename, module);
+                continue;
+            }
+            info!("symbolicating {} with {}", module, filename);
+            return Some(symbols);
+        }
+        warn!("Failed to find a copy of {} to symbolicate it with", module);
+        None
+    }
+}
+
+fn parse_build_id(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes().chunks(2)
+        .map(|pair| std::str::from_utf8(pair).ok().filter(|pair| pair.len() == 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_binary() {
+        let python_frame = Frame{name: "run".to_owned(), filename: "test.py".to_owned(), module: None,
+                                 short_filename: Some("test.py".to_owned()), line: 10, frame_ptr: None, locals: None};
+        let native_frame = Frame{name: "0x1234".to_owned(), filename: UNSYMBOLICATED.to_owned(), module: Some("/missing/libfoo.so".to_owned()),
+                                 short_filename: Some("deadbeef".to_owned()), line: 0, frame_ptr: None, locals: None};
+
+        // frames in binaries we can't find are left as addresses, and python frames are passed through as is
+        let mut symbols = OfflineSymbols::new(&[], false);
+        let frames = symbols.symbolicate(&[native_frame, python_frame.clone()]);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].name, "0x1234");
+        assert_eq!(frames[0].filename, "/missing/libfoo.so");
+        assert_eq!(frames[1], python_frame);
+        assert_eq!(symbols.missing_binaries(), vec!["/missing/libfoo.so"]);
+    }
+
+    #[test]
+    fn test_parse_build_id() {
+        assert_eq!(parse_build_id("deadbeef"), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert_eq!(parse_build_id("dea"), None);
+        assert_eq!(parse_build_id("xyzw"), None);
+    }
+}
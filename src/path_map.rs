@@ -0,0 +1,65 @@
+// Rewrites the paths of the source files in stack traces with the --map-path rules, so that recordings
+// made in a container or on a CI machine point at copies of the source that exist on the machine the
+// recording is looked at on. Paths are matched by prefix, a whole path component at a time, and the
+// first rule that matches a path is the one used. Frames we add ourselves (like '<gc>') are left as is.
+use crate::stack_trace::{Frame, StackTrace};
+
+pub struct PathMap {
+    rules: Vec<(String, String)>,
+}
+
+impl PathMap {
+    pub fn new(rules: &[(String, String)]) -> PathMap {
+        PathMap{rules: rules.to_vec()}
+    }
+
+    /// Gets the path that a filename maps to, or None if no rule applies to it
+    pub fn map(&self, filename: &str) -> Option<String> {
+        for (from, to) in &self.rules {
+            let rest = match filename.strip_prefix(from.as_str()) {
+                Some(rest) => rest,
+                None => continue
+            };
+            // '/build/src' shouldn't match '/build/src2/main.py'
+            if rest.is_empty() || from.ends_with(['/', '\\']) || rest.starts_with(['/', '\\']) {
+                return Some(format!("{}{}", to, rest));
+            }
+        }
+        None
+    }
+
+    pub fn map_trace(&self, trace: &StackTrace) -> StackTrace {
+        let frames = trace.frames.iter().map(|frame| self.map_frame(frame)).collect();
+        StackTrace{frames, ..trace.clone()}
+    }
+
+    fn map_frame(&self, frame: &Frame) -> Frame {
+        if frame.filename.starts_with('<') {
+            return frame.clone();
+        }
+        match self.map(&frame.filename) {
+            Some(filename) => Frame{filename, ..frame.clone()},
+            None => frame.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_path() {
+        let rules = vec![("/build/src".to_owned(), "/home/me/src".to_owned()),
+                         ("/usr/lib/python3.7/".to_owned(), "/opt/python/lib/".to_owned()),
+                         ("/build".to_owned(), "/mnt/build".to_owned())];
+        let map = PathMap::new(&rules);
+        assert_eq!(map.map("/build/src/app/main.py").as_deref(), Some("/home/me/src/app/main.py"));
+        assert_eq!(map.map("/build/src").as_deref(), Some("/home/me/src"));
+        assert_eq!(map.map("/usr/lib/python3.7/threading.py").as_deref(), Some("/opt/python/lib/threading.py"));
+        // only whole path components are matched, and later rules apply if earlier ones don't
+        assert_eq!(map.map("/build/src2/main.py").as_deref(), Some("/mnt/build/src2/main.py"));
+        assert_eq!(map.map("/buildroot/main.py"), None);
+        assert_eq!(map.map("main.py"), None);
+    }
+}
@@ -0,0 +1,231 @@
+// Works out why attaching to a process was denied, so that we can say exactly how to fix it rather
+// than just suggesting sudo. On Linux the usual causes are the yama ptrace_scope setting, missing the
+// SYS_PTRACE capability in a container and the process belonging to another user. On OSX it's
+// either not being root or System Integrity Protection, and on Windows not being an administrator.
+//
+// This also implements --sudo: py-spy runs itself again with sudo when it's denied, and on Linux
+// the privileged copy then switches back to the user that ran sudo, keeping only the capabilities
+// needed to read other processes. That way programs it launches and the files it writes belong to
+// the user, rather than to root.
+use remoteprocess::Pid;
+
+/// The reasons we know about for being denied access to a process
+#[cfg(any(target_os="linux", test))]
+#[derive(Debug, Default)]
+struct Facts {
+    euid: u32,
+    // none when yama isn't enabled
+    ptrace_scope: Option<u32>,
+    cap_sys_ptrace: bool,
+    target_uid: Option<u32>,
+    container: bool,
+    seccomp: bool,
+}
+
+/// Explains why a process couldn't be attached to, and how to fix it
+pub fn remediation(pid: Option<Pid>) -> String {
+    #[cfg(target_os="linux")]
+    {
+        explain(&linux_facts(pid)).join("\n")
+    }
+    #[cfg(target_os="macos")]
+    {
+        macos_remediation(pid)
+    }
+    #[cfg(not(any(target_os="linux", target_os="macos")))]
+    {
+        let _ = pid;
+        if cfg!(windows) {
+            "Permission Denied: Run py-spy from an Administrator command prompt to profile processes started by \
+             other users or services".to_owned()
+        } else {
+            "Permission Denied: Try running again with elevated permissions by going 'sudo env \"PATH=$PATH\" !!'".to_owned()
+        }
+    }
+}
+
+#[cfg(any(target_os="linux", test))]
+fn explain(facts: &Facts) -> Vec<String> {
+    let sudo = "run py-spy as root ('sudo env \"PATH=$PATH\" !!'), or pass --sudo to have it re-run itself with sudo";
+    let mut lines = vec!["Permission Denied:".to_owned()];
+    if facts.ptrace_scope == Some(3) {
+        lines.push("  Attaching to processes is disabled on this machine (kernel.yama.ptrace_scope is 3), and this can \
+                    only be changed by rebooting".to_owned());
+        return lines;
+    }
+
+    let privileged = facts.euid == 0 || facts.cap_sys_ptrace;
+    if !privileged {
+        match (facts.target_uid, facts.ptrace_scope) {
+            (Some(uid), _) if uid != facts.euid => {
+                lines.push(format!("  The process belongs to another user (uid {}), so {}", uid, sudo));
+            },
+            (_, Some(1)) => {
+                lines.push(format!("  kernel.yama.ptrace_scope is 1, which only lets programs be profiled by the \
+                                    process that started them. Either {}, launch the program with py-spy \
+                                    ('py-spy -- python myprogram.py'), or allow attaching to your own processes \
+                                    with 'echo 0 | sudo tee /proc/sys/kernel/yama/ptrace_scope'", sudo));
+            },
+            (_, Some(2)) => {
+                lines.push(format!("  kernel.yama.ptrace_scope is 2, which only lets processes with the SYS_PTRACE \
+                                    capability attach to others, so {}", sudo));
+            },
+            _ => lines.push(format!("  Try running again with elevated permissions: {}", sudo))
+        }
+        return lines;
+    }
+
+    if facts.container && !facts.cap_sys_ptrace {
+        lines.push("  py-spy is running in a container without the SYS_PTRACE capability. Start the container with \
+                    'docker run --cap-add SYS_PTRACE', or add SYS_PTRACE to the container's \
+                    securityContext.capabilities in kubernetes".to_owned());
+    } else if facts.container && facts.seccomp {
+        lines.push("  The container's seccomp profile is probably blocking the system calls py-spy uses (this is the \
+                    default before Docker 19.03). Start the container with 'docker run --cap-add SYS_PTRACE' or \
+                    '--security-opt seccomp=unconfined'".to_owned());
+    } else {
+        lines.push("  py-spy has permission to attach to processes, so this is probably a security module like \
+                    AppArmor or SELinux denying it: check the audit log ('dmesg' or /var/log/audit/audit.log) for \
+                    denials of ptrace".to_owned());
+    }
+    lines
+}
+
+#[cfg(target_os="linux")]
+fn linux_facts(pid: Option<Pid>) -> Facts {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    Facts {
+        euid: unsafe { libc::geteuid() },
+        ptrace_scope: std::fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope").ok().and_then(|s| s.trim().parse().ok()),
+        cap_sys_ptrace: status_field(&status, "CapEff").and_then(|caps| u64::from_str_radix(caps, 16).ok())
+            .is_some_and(|caps| caps & (1 << CAP_SYS_PTRACE) != 0),
+        target_uid: pid.and_then(|pid| std::fs::read_to_string(format!("/proc/{}/status", pid)).ok())
+            .and_then(|status| status_field(&status, "Uid")?.split_whitespace().next()?.parse().ok()),
+        container: in_container(),
+        seccomp: status_field(&status, "Seccomp") == Some("2"),
+    }
+}
+
+#[cfg(target_os="linux")]
+fn in_container() -> bool {
+    if std::path::Path::new("/.dockerenv").exists() || std::path::Path::new("/run/.containerenv").exists() {
+        return true;
+    }
+    let cgroup = std::fs::read_to_string("/proc/1/cgroup").unwrap_or_default();
+    ["docker", "kubepods", "containerd", "libpod", "lxc"].iter().any(|runtime| cgroup.contains(runtime))
+}
+
+// gets the value of a field like 'CapEff:\t0000003fffffffff' from /proc/<pid>/status
+#[cfg(any(target_os="linux", test))]
+fn status_field<'a>(status: &'a str, name: &str) -> Option<&'a str> {
+    status.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .map(|value| value.trim())
+}
+
+#[cfg(target_os="macos")]
+fn macos_remediation(pid: Option<Pid>) -> String {
+    if unsafe { libc::geteuid() } != 0 {
+        return "Permission Denied: py-spy needs to run as root on OSX. Try running again with 'sudo env \
+                \"PATH=$PATH\" !!', or pass --sudo to have it re-run itself with sudo".to_owned();
+    }
+    let exe = pid.and_then(|pid| remoteprocess::Process::new(pid).ok()?.exe().ok());
+    let protected = ["/usr/bin/", "/bin/", "/usr/sbin/", "/sbin/", "/System/"];
+    let sip_enabled = std::process::Command::new("csrutil").arg("status").output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains("enabled")).unwrap_or(false);
+    match exe {
+        Some(exe) if sip_enabled && protected.iter().any(|dir| exe.starts_with(dir)) => format!(
+            "Permission Denied: {} is protected by System Integrity Protection, which stops even root from profiling \
+             it. Use a python installed from python.org, homebrew or pyenv instead", exe),
+        _ => "Permission Denied: the process couldn't be read even as root. If it's a python protected by System \
+              Integrity Protection, use a python installed from python.org, homebrew or pyenv instead".to_owned()
+    }
+}
+
+#[cfg(target_os="linux")]
+const CAP_DAC_READ_SEARCH: u32 = 2;
+#[cfg(any(target_os="linux", test))]
+const CAP_SYS_PTRACE: u32 = 19;
+
+/// Runs py-spy again with sudo, with the same arguments. This only returns if that couldn't be started
+#[cfg(unix)]
+pub fn rerun_with_sudo() -> std::io::Error {
+    use std::os::unix::process::CommandExt;
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return e
+    };
+    eprintln!("Permission denied, running py-spy again with sudo");
+    std::process::Command::new("sudo").arg(exe).args(std::env::args_os().skip(1)).exec()
+}
+
+/// When py-spy was re-run by --sudo, goes back to being the user that ran sudo while keeping the
+/// capabilities to read the memory and files of other processes
+#[cfg(target_os="linux")]
+pub fn drop_privileges() -> Result<(), failure::Error> {
+    let ids = (std::env::var("SUDO_UID").ok().and_then(|uid| uid.parse().ok()),
+               std::env::var("SUDO_GID").ok().and_then(|gid| gid.parse().ok()));
+    let (uid, gid): (libc::uid_t, libc::gid_t) = match ids {
+        (Some(uid), Some(gid)) if unsafe { libc::geteuid() } == 0 && uid != 0 => (uid, gid),
+        _ => return Ok(())
+    };
+
+    #[repr(C)]
+    struct CapHeader { version: u32, pid: libc::c_int }
+    #[repr(C)]
+    struct CapData { effective: u32, permitted: u32, inheritable: u32 }
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+    let check = |result: libc::c_long, call: &str| -> Result<(), failure::Error> {
+        if result != 0 {
+            return Err(format_err!("Failed to drop privileges ({}): {}", call, std::io::Error::last_os_error()));
+        }
+        Ok(())
+    };
+    unsafe {
+        check(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) as libc::c_long, "prctl")?;
+        check(libc::setgroups(0, std::ptr::null()) as libc::c_long, "setgroups")?;
+        check(libc::setresgid(gid, gid, gid) as libc::c_long, "setresgid")?;
+        check(libc::setresuid(uid, uid, uid) as libc::c_long, "setresuid")?;
+        let keep = (1 << CAP_SYS_PTRACE) | (1 << CAP_DAC_READ_SEARCH);
+        let header = CapHeader{version: LINUX_CAPABILITY_VERSION_3, pid: 0};
+        // nothing is inheritable, so programs we launch don't get these capabilities
+        let data = [CapData{effective: keep, permitted: keep, inheritable: 0},
+                    CapData{effective: 0, permitted: 0, inheritable: 0}];
+        check(libc::syscall(libc::SYS_capset, &header, data.as_ptr()), "capset")?;
+    }
+    info!("Dropped privileges to uid {}, keeping CAP_SYS_PTRACE and CAP_DAC_READ_SEARCH", uid);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_field() {
+        let status = "Name:\tpython\nUid:\t1000\t1000\t1000\t1000\nSeccomp:\t2\nCapEff:\t00000000a80425fb\n";
+        assert_eq!(status_field(status, "Uid"), Some("1000\t1000\t1000\t1000"));
+        assert_eq!(status_field(status, "Seccomp"), Some("2"));
+        assert_eq!(status_field(status, "Cap"), None);
+        // docker's default capabilities don't include SYS_PTRACE
+        let caps = u64::from_str_radix(status_field(status, "CapEff").unwrap(), 16).unwrap();
+        assert_eq!(caps & (1 << CAP_SYS_PTRACE), 0);
+    }
+
+    #[test]
+    fn test_explain() {
+        let explained = |facts: Facts| explain(&facts).join("\n");
+        let user = Facts{euid: 1000, ..Default::default()};
+
+        assert!(explained(Facts{ptrace_scope: Some(3), euid: 0, ..Default::default()}).contains("rebooting"));
+        assert!(explained(Facts{ptrace_scope: Some(1), target_uid: Some(1000), ..user}).contains("ptrace_scope is 1"));
+        assert!(explained(Facts{ptrace_scope: Some(1), target_uid: Some(0), euid: 1000, ..Default::default()})
+            .contains("another user (uid 0)"));
+        assert!(explained(Facts{ptrace_scope: Some(2), euid: 1000, ..Default::default()}).contains("ptrace_scope is 2"));
+        assert!(explained(Facts{container: true, ..Default::default()}).contains("--cap-add SYS_PTRACE"));
+        assert!(explained(Facts{container: true, cap_sys_ptrace: true, seccomp: true, ..Default::default()})
+            .contains("seccomp"));
+        assert!(explained(Facts{cap_sys_ptrace: true, ..Default::default()}).contains("AppArmor"));
+    }
+}
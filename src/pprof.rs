@@ -0,0 +1,212 @@
+// Writes out samples as a pprof profile, as described by
+// https://github.com/google/pprof/blob/master/proto/profile.proto
+//
+// Rather than pulling in a protobuf compiler for a single message type, this hand encodes the
+// couple of fields that we need. The output is left uncompressed, which 'go tool pprof' accepts.
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+
+use failure::Error;
+
+use crate::labels::Labels;
+use crate::stack_trace::StackTrace;
+
+pub struct Profile {
+    strings: Vec<String>,
+    string_ids: HashMap<String, u64>,
+    // function id is the index + 1, stored as (name, filename) string ids
+    functions: Vec<(u64, u64)>,
+    function_ids: HashMap<(u64, u64), u64>,
+    // location id is the index + 1, stored as (function id, line)
+    locations: Vec<(u64, i64)>,
+    location_ids: HashMap<(u64, i64), u64>,
+    counts: HashMap<Vec<u64>, u64>,
+    sample_period: u64,
+    show_linenumbers: bool,
+    // wall clock time the profile started at, in nanoseconds since the unix epoch
+    start_time: Option<u64>,
+    duration: Duration,
+    // string ids of free form comments about the profile
+    comments: Vec<u64>,
+    labels: Labels,
+}
+
+impl Profile {
+    pub fn new(sampling_rate: u64, show_linenumbers: bool) -> Profile {
+        let mut profile = Profile{strings: Vec::new(), string_ids: HashMap::new(),
+                                  functions: Vec::new(), function_ids: HashMap::new(),
+                                  locations: Vec::new(), location_ids: HashMap::new(),
+                                  counts: HashMap::new(),
+                                  sample_period: 1_000_000_000 / sampling_rate.max(1),
+                                  show_linenumbers, start_time: None, duration: Duration::from_secs(0),
+                                  comments: Vec::new(), labels: Labels::default()};
+        // the first entry in the string table is required to be the empty string
+        profile.string_id("");
+        profile
+    }
+
+    /// Weights samples by the sampling rate that was actually achieved, rather than the requested rate
+    pub fn set_effective_rate(&mut self, rate: f64) {
+        if rate > 0.0 {
+            self.sample_period = (1_000_000_000.0 / rate) as u64;
+        }
+    }
+
+    /// Sets when the profile was collected, in seconds since the unix epoch
+    pub fn set_start_time(&mut self, time: f64) {
+        self.start_time = Some((time * 1e9) as u64);
+    }
+
+    /// Sets a label given with --label, like 'service=web', which is written out as a comment
+    pub fn set_label(&mut self, key: &str, value: &str) {
+        self.labels.set(key, value);
+    }
+
+    /// Adds a comment to the profile, which 'go tool pprof -comments' shows
+    pub fn add_comment(&mut self, comment: &str) {
+        let id = self.string_id(comment);
+        self.comments.push(id);
+    }
+
+    pub fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) {
+        self.duration = self.duration.max(timestamp);
+        for trace in traces {
+            if !(trace.active) {
+                continue;
+            }
+
+            // pprof orders locations from the leaf of the stack to the root, same as we do
+            let mut locations = Vec::with_capacity(trace.frames.len());
+            for frame in &trace.frames {
+                let filename = match &frame.short_filename { Some(f) => f, None => &frame.filename };
+                let function = (self.string_id(&frame.name), self.string_id(filename));
+                let function = match self.function_ids.get(&function) {
+                    Some(&id) => id,
+                    None => {
+                        self.functions.push(function);
+                        let id = self.functions.len() as u64;
+                        self.function_ids.insert(function, id);
+                        id
+                    }
+                };
+
+                let line = if self.show_linenumbers { i64::from(frame.line) } else { 0 };
+                let location = match self.location_ids.get(&(function, line)) {
+                    Some(&id) => id,
+                    None => {
+                        self.locations.push((function, line));
+                        let id = self.locations.len() as u64;
+                        self.location_ids.insert((function, line), id);
+                        id
+                    }
+                };
+                locations.push(location);
+            }
+            *self.counts.entry(locations).or_insert(0) += 1;
+        }
+    }
+
+    pub fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        let samples = self.string_id("samples");
+        let count = self.string_id("count");
+        let wall = self.string_id("wall");
+        let nanoseconds = self.string_id("nanoseconds");
+        // labels can change until the profile is written, so only turn them into comments now
+        let labels: Vec<String> = self.labels.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        let mut comments = self.comments.clone();
+        comments.extend(labels.iter().map(|label| self.string_id(label)));
+
+        let mut profile = Vec::new();
+        write_message(&mut profile, 1, &value_type(samples, count));
+        write_message(&mut profile, 1, &value_type(wall, nanoseconds));
+
+        for (locations, &count) in &self.counts {
+            let mut sample = Vec::new();
+            write_packed(&mut sample, 1, locations.iter().cloned());
+            write_packed(&mut sample, 2, vec![count, count * self.sample_period].into_iter());
+            write_message(&mut profile, 2, &sample);
+        }
+
+        for (i, &(function, line)) in self.locations.iter().enumerate() {
+            let mut entry = Vec::new();
+            write_varint_field(&mut entry, 1, function);
+            write_varint_field(&mut entry, 2, line as u64);
+
+            let mut location = Vec::new();
+            write_varint_field(&mut location, 1, i as u64 + 1);
+            write_message(&mut location, 4, &entry);
+            write_message(&mut profile, 4, &location);
+        }
+
+        for (i, &(name, filename)) in self.functions.iter().enumerate() {
+            let mut function = Vec::new();
+            write_varint_field(&mut function, 1, i as u64 + 1);
+            write_varint_field(&mut function, 2, name);
+            write_varint_field(&mut function, 3, name);
+            write_varint_field(&mut function, 4, filename);
+            write_message(&mut profile, 5, &function);
+        }
+
+        for string in &self.strings {
+            write_message(&mut profile, 6, string.as_bytes());
+        }
+
+        if let Some(start_time) = self.start_time {
+            write_varint_field(&mut profile, 9, start_time);
+        }
+        write_varint_field(&mut profile, 10, self.duration.as_nanos() as u64);
+        write_message(&mut profile, 11, &value_type(wall, nanoseconds));
+        write_varint_field(&mut profile, 12, self.sample_period);
+        if !comments.is_empty() {
+            write_packed(&mut profile, 13, comments.into_iter());
+        }
+
+        w.write_all(&profile)?;
+        Ok(())
+    }
+
+    fn string_id(&mut self, value: &str) -> u64 {
+        if let Some(&id) = self.string_ids.get(value) {
+            return id;
+        }
+        let id = self.strings.len() as u64;
+        self.strings.push(value.to_owned());
+        self.string_ids.insert(value.to_owned(), id);
+        id
+    }
+}
+
+fn value_type(value_type: u64, unit: u64) -> Vec<u8> {
+    let mut message = Vec::new();
+    write_varint_field(&mut message, 1, value_type);
+    write_varint_field(&mut message, 2, unit);
+    message
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn write_varint_field(buffer: &mut Vec<u8>, field: u64, value: u64) {
+    write_varint(buffer, field << 3);
+    write_varint(buffer, value);
+}
+
+fn write_message(buffer: &mut Vec<u8>, field: u64, message: &[u8]) {
+    write_varint(buffer, field << 3 | 2);
+    write_varint(buffer, message.len() as u64);
+    buffer.extend_from_slice(message);
+}
+
+fn write_packed<I: Iterator<Item=u64>>(buffer: &mut Vec<u8>, field: u64, values: I) {
+    let mut packed = Vec::new();
+    for value in values {
+        write_varint(&mut packed, value);
+    }
+    write_message(buffer, field, &packed);
+}
@@ -0,0 +1,110 @@
+// Shows a summary of what's been recorded so far while 'py-spy record' runs (with --preview), so that
+// a long recording isn't a black box until the file is written out at the end
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use console::Term;
+
+use crate::stack_trace::StackTrace;
+
+/// How often the preview is redrawn
+const PREVIEW_INTERVAL: Duration = Duration::from_secs(1);
+/// How many functions are shown
+const PREVIEW_FUNCTIONS: usize = 5;
+
+/// What's left of the recording, given by --duration or --samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Remaining {
+    Time(Duration),
+    Samples(u64),
+}
+
+pub struct Preview {
+    term: Term,
+    /// Samples that each function was running in (at the top of the stack) for
+    own_counts: HashMap<String, u64>,
+    samples: u64,
+    errors: u64,
+    last_render: Option<Instant>,
+    /// Lines drawn last time, to clear before redrawing
+    lines: usize,
+}
+
+impl Preview {
+    pub fn new() -> Preview {
+        Preview{term: Term::stderr(), own_counts: HashMap::new(), samples: 0, errors: 0, last_render: None, lines: 0}
+    }
+
+    pub fn increment(&mut self, traces: &[StackTrace]) {
+        self.samples += 1;
+        for frame in traces.iter().filter_map(|trace| trace.frames.first()) {
+            let filename = frame.short_filename.as_ref().unwrap_or(&frame.filename);
+            *self.own_counts.entry(format!("{} ({})", frame.name, filename)).or_default() += 1;
+        }
+    }
+
+    pub fn increment_error(&mut self) {
+        self.errors += 1;
+    }
+
+    /// Redraws the preview, if it hasn't been drawn in the last second
+    pub fn render(&mut self, elapsed: Duration, remaining: Option<Remaining>) -> std::io::Result<()> {
+        if self.last_render.is_some_and(|last| last.elapsed() < PREVIEW_INTERVAL) {
+            return Ok(());
+        }
+        self.last_render = Some(Instant::now());
+        let lines = self.lines(elapsed, remaining);
+        self.term.clear_last_lines(self.lines)?;
+        for line in &lines {
+            self.term.write_line(line)?;
+        }
+        self.lines = lines.len();
+        Ok(())
+    }
+
+    fn lines(&self, elapsed: Duration, remaining: Option<Remaining>) -> Vec<String> {
+        let mut status = format!("{} samples, {} errors, {}s elapsed", self.samples, self.errors, elapsed.as_secs());
+        match remaining {
+            Some(Remaining::Time(time)) => status.push_str(&format!(", {}s remaining", time.as_secs())),
+            Some(Remaining::Samples(samples)) => status.push_str(&format!(", {} samples remaining", samples)),
+            None => {}
+        }
+
+        let mut functions: Vec<(&String, &u64)> = self.own_counts.iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let mut lines = vec![status];
+        for (function, &count) in functions.into_iter().take(PREVIEW_FUNCTIONS) {
+            lines.push(format!("  {:>5.1}%  {}", 100.0 * count as f64 / self.samples.max(1) as f64, function));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::Frame;
+
+    fn trace(function: &str) -> StackTrace {
+        let frame = Frame{name: function.to_owned(), filename: "/srv/app/server.py".to_owned(), module: None,
+                          short_filename: Some("server.py".to_owned()), line: 12, frame_ptr: None, locals: None};
+        StackTrace{thread_id: 1, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true, owns_gil: true,
+                   frames: vec![frame], exception: None, awaiting: None, pid: None}
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut preview = Preview::new();
+        preview.increment(&[trace("handle")]);
+        preview.increment(&[trace("handle"), trace("poll")]);
+        preview.increment(&[]);
+        preview.increment(&[trace("handle")]);
+        preview.increment_error();
+        assert_eq!(preview.lines(Duration::from_millis(4500), Some(Remaining::Time(Duration::from_secs(25)))),
+                   vec!["4 samples, 1 errors, 4s elapsed, 25s remaining",
+                        "   75.0%  handle (server.py)",
+                        "   25.0%  poll (server.py)"]);
+        assert_eq!(preview.lines(Duration::from_secs(1), Some(Remaining::Samples(96)))[0],
+                   "4 samples, 1 errors, 1s elapsed, 96 samples remaining");
+    }
+}
@@ -0,0 +1,359 @@
+// Samples several python processes into one recording: either the subprocesses of the process
+// being profiled (multiprocessing pools, gunicorn workers etc), every process matching a name, or
+// a list of pids. The processes are sampled one after another in each sample
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use remoteprocess::{Pid, Process};
+
+use crate::config::{Config, Server};
+use crate::python_spy::PythonSpy;
+use crate::stack_trace::{Frame, StackTrace};
+
+// how often to look for new processes
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+// how many scans a new process gets to start up the python interpreter before we give up on it
+const MAX_ATTACH_ATTEMPTS: u32 = 5;
+
+/// The processes being sampled alongside the 'root' process that was originally profiled
+pub struct ProcessGroup {
+    /// When set, stacks from different processes aren't told apart, so that identical
+    /// stacks from different workers get merged together
+    pub merge: bool,
+    /// Total time the other processes have been suspended for while taking samples
+    pub suspended_time: Duration,
+    /// Stacks of the other processes that were dropped for changing while they were read
+    pub torn_stacks: u64,
+    follow_children: bool,
+    // attach to new processes that match this name pattern
+    pattern: Option<String>,
+    others: HashMap<Pid, PythonSpy>,
+    labels: HashMap<Pid, Frame>,
+    // failed attach attempts for each new process that isn't (yet) a python process
+    failures: HashMap<Pid, u32>,
+    last_scan: Option<Instant>,
+}
+
+impl ProcessGroup {
+    pub fn new(config: &Config) -> ProcessGroup {
+        let pattern = if config.include_new { config.name.clone() } else { None };
+        // each process gets its own output with --per-process, so there's no need to tell them apart
+        let merge = config.merge_processes || config.per_process;
+        ProcessGroup{merge, suspended_time: Duration::from_secs(0), torn_stacks: 0, follow_children: config.subprocesses, pattern,
+                     others: HashMap::new(), labels: HashMap::new(), failures: HashMap::new(), last_scan: None}
+    }
+
+    /// Adds a process to be sampled along with the root process
+    pub fn add(&mut self, process: PythonSpy) {
+        self.others.insert(process.pid, process);
+    }
+
+    /// Removes one of the other processes, so that it can take over from a root process that has exited
+    pub fn take_process(&mut self) -> Option<PythonSpy> {
+        let pid = *self.others.keys().min()?;
+        self.others.remove(&pid)
+    }
+
+    /// Gets stack traces for the root process along with all the other processes in the group. Errors
+    /// from the root process are returned, while other processes that can't be sampled are dropped
+    pub fn get_stack_traces(&mut self, root: &mut PythonSpy, config: &Config) -> Result<Vec<StackTrace>, Error> {
+        if (self.follow_children || self.pattern.is_some()) &&
+           self.last_scan.is_none_or(|last| last.elapsed() >= SCAN_INTERVAL) {
+            self.last_scan = Some(Instant::now());
+            self.scan(root, config);
+        }
+
+        let mut traces = root.get_stack_traces()?;
+        set_pid(root.pid, &mut traces);
+        if !self.merge {
+            tag(&mut self.labels, root, &mut traces);
+        }
+
+        let mut exited = Vec::new();
+        for (&pid, other) in self.others.iter_mut() {
+            let (suspended_before, torn_before) = (other.suspended_time, other.torn_stacks);
+            let other_traces = other.get_stack_traces();
+            self.suspended_time += other.suspended_time - suspended_before;
+            self.torn_stacks += other.torn_stacks - torn_before;
+            match other_traces {
+                Ok(mut other_traces) => {
+                    set_pid(pid, &mut other_traces);
+                    if !self.merge {
+                        tag(&mut self.labels, other, &mut other_traces);
+                    }
+                    traces.extend(other_traces);
+                },
+                Err(err) => {
+                    if other.process.exe().is_err() {
+                        info!("Process {} ended", pid);
+                        exited.push(pid);
+                    } else {
+                        info!("Failed to sample process {}: {}", pid, err);
+                    }
+                }
+            }
+        }
+        for pid in exited {
+            self.others.remove(&pid);
+            self.labels.remove(&pid);
+        }
+        Ok(traces)
+    }
+
+    // attaches to any new python processes that should be part of the group
+    fn scan(&mut self, root: &PythonSpy, config: &Config) {
+        let mut pids = Vec::new();
+        if self.follow_children {
+            match root.process.child_processes() {
+                Ok(children) => pids.extend(children),
+                Err(err) => warn!("Failed to get subprocesses of {}: {}", root.pid, err)
+            }
+        }
+        if let Some(pattern) = self.pattern.as_ref() {
+            match find_matching(pattern) {
+                Ok(matching) => pids.extend(matching),
+                Err(err) => warn!("Failed to find processes matching '{}': {}", pattern, err)
+            }
+        }
+
+        for pid in pids {
+            if pid == root.pid || self.others.contains_key(&pid) ||
+               self.failures.get(&pid).is_some_and(|&f| f >= MAX_ATTACH_ATTEMPTS) {
+                continue;
+            }
+            match PythonSpy::new(pid, config) {
+                Ok(process) => {
+                    info!("Attached to process {} ({})", pid, process.version);
+                    self.failures.remove(&pid);
+                    self.others.insert(pid, process);
+                },
+                Err(err) => {
+                    debug!("Failed to attach to process {}: {}", pid, err);
+                    *self.failures.entry(pid).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+}
+
+fn set_pid(pid: Pid, traces: &mut [StackTrace]) {
+    for trace in traces.iter_mut() {
+        trace.pid = Some(pid);
+    }
+}
+
+// adds a frame identifying the process at the root of each stack trace
+fn tag(labels: &mut HashMap<Pid, Frame>, process: &PythonSpy, traces: &mut [StackTrace]) {
+    let label = labels.entry(process.pid)
+        .or_insert_with(|| process_label(process.pid, &process.process, process.worker_name()));
+    for trace in traces.iter_mut() {
+        trace.frames.push(label.clone());
+    }
+}
+
+/// Returns a frame that identifies a process by pid and command line, along with the name of
+/// the worker when it's a multiprocessing (or celery) worker. Servers like gunicorn and uwsgi set
+/// the command line of their workers to say what they are
+pub fn process_label(pid: Pid, process: &Process, worker_name: Option<String>) -> Frame {
+    let cmdline = process.cmdline().map(|args| args.join(" ")).unwrap_or_default();
+    let name = match worker_name {
+        Some(worker_name) => format!("{} (process {})", worker_name, pid),
+        None => format!("process {}", pid)
+    };
+    Frame{name, filename: cmdline, module: None, short_filename: None, line: 0, frame_ptr: None, locals: None}
+}
+
+/// Finds the master process of a server: the process matching the server's command line that
+/// isn't a descendant of another matching process. When there are several, the oldest is used
+pub fn find_master(server: Server) -> Result<Pid, Error> {
+    let mut matching = Vec::new();
+    for pattern in server_patterns(server) {
+        matching.extend(find_matching(pattern)?);
+    }
+    let mut workers = Vec::new();
+    for &pid in &matching {
+        if let Ok(children) = Process::new(pid).and_then(|process| process.child_processes()) {
+            workers.extend(children);
+        }
+    }
+    let mut masters: Vec<Pid> = matching.into_iter().filter(|pid| !workers.contains(pid)).collect();
+    masters.sort_unstable();
+    masters.dedup();
+    match masters.first() {
+        Some(&master) => {
+            if masters.len() > 1 {
+                warn!("Found {} {} master processes ({:?}), profiling {}", masters.len(), server, masters, master);
+            }
+            Ok(master)
+        },
+        None => Err(format_err!("Failed to find a running {} process", server))
+    }
+}
+
+// patterns matching the command lines of the master process of each server, either as
+// started or after the server has renamed it with setproctitle
+fn server_patterns(server: Server) -> &'static [&'static str] {
+    match server {
+        Server::gunicorn => &["gunicorn*"],
+        Server::celery => &["celery*"],
+        Server::uwsgi => &["uwsgi*", "uWSGI*"]
+    }
+}
+
+/// Returns the pids of the processes (other than py-spy itself) whose command line matches a pattern
+pub fn find_matching(pattern: &str) -> Result<Vec<Pid>, Error> {
+    find_processes(|cmdline| matches_name(pattern, cmdline))
+}
+
+/// Returns the pids of the processes (other than py-spy itself) whose command line contains some text
+pub fn find_cmdline(text: &str) -> Result<Vec<Pid>, Error> {
+    find_processes(|cmdline| !cmdline.is_empty() && cmdline.join(" ").contains(text))
+}
+
+fn find_processes<F: Fn(&[String]) -> bool>(matches: F) -> Result<Vec<Pid>, Error> {
+    let own_pid = std::process::id() as Pid;
+    let mut ret = Vec::new();
+    for pid in remoteprocess::processes()? {
+        if pid == own_pid {
+            continue;
+        }
+        // processes can exit at any time, or we might not have permission to inspect them
+        if let Ok(cmdline) = Process::new(pid).and_then(|process| process.cmdline()) {
+            if matches(&cmdline) {
+                ret.push(pid);
+            }
+        }
+    }
+    ret.sort_unstable();
+    Ok(ret)
+}
+
+/// Returns the pids of the processes that have a TCP socket listening on a port. Servers that fork
+/// workers (like gunicorn) share the listening socket, so this returns the master and the workers
+#[cfg(target_os="linux")]
+pub fn find_listening(port: u16) -> Result<Vec<Pid>, Error> {
+    // the sockets listed in /proc/<pid>/net/tcp are the ones in that process's network namespace, so
+    // these are read once for each namespace to also find processes listening inside containers
+    let mut listening: HashMap<std::path::PathBuf, Vec<u64>> = HashMap::new();
+    let mut ret = Vec::new();
+    for pid in remoteprocess::processes()? {
+        let namespace = match std::fs::read_link(format!("/proc/{}/ns/net", pid)) {
+            Ok(namespace) => namespace,
+            Err(_) => continue
+        };
+        let inodes = listening.entry(namespace).or_insert_with(|| {
+            ["tcp", "tcp6"].iter()
+                .filter_map(|table| std::fs::read_to_string(format!("/proc/{}/net/{}", pid, table)).ok())
+                .flat_map(|contents| parse_listening_inodes(&contents, port))
+                .collect()
+        });
+        if inodes.is_empty() {
+            continue;
+        }
+        let fds = match std::fs::read_dir(format!("/proc/{}/fd", pid)) {
+            Ok(fds) => fds,
+            Err(_) => continue
+        };
+        let has_socket = fds.filter_map(|fd| std::fs::read_link(fd.ok()?.path()).ok())
+            .filter_map(|link| link.to_str()?.strip_prefix("socket:[")?.strip_suffix(']')?.parse::<u64>().ok())
+            .any(|inode| inodes.contains(&inode));
+        if has_socket {
+            ret.push(pid);
+        }
+    }
+    Ok(ret)
+}
+
+/// Returns the pids of the processes that have a TCP socket listening on a port, using lsof
+#[cfg(not(target_os="linux"))]
+pub fn find_listening(port: u16) -> Result<Vec<Pid>, Error> {
+    let output = std::process::Command::new("lsof").arg("-nP").arg(format!("-iTCP:{}", port)).arg("-sTCP:LISTEN").arg("-t")
+        .output().map_err(|e| format_err!("Failed to run lsof to find the process listening on port {}: {}", port, e))?;
+    let mut ret: Vec<Pid> = String::from_utf8_lossy(&output.stdout).lines().filter_map(|line| line.trim().parse().ok()).collect();
+    ret.sort_unstable();
+    ret.dedup();
+    Ok(ret)
+}
+
+// gets the inodes of the sockets in /proc/net/tcp (or tcp6) that are listening on a port
+#[cfg(any(target_os="linux", test))]
+fn parse_listening_inodes(contents: &str, port: u16) -> Vec<u64> {
+    // columns are: sl local_address rem_address st ... inode, with addresses as hex ip:port and st 0A for LISTEN
+    contents.lines().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let local_port = u16::from_str_radix(fields.get(1)?.rsplit(':').next()?, 16).ok()?;
+        if local_port != port || *fields.get(3)? != "0A" {
+            return None;
+        }
+        fields.get(9)?.parse().ok()
+    }).collect()
+}
+
+/// Whether a glob pattern like 'celery*' matches a command line. The pattern can either match the
+/// filename of any of the arguments (so that 'celery*' matches 'python /usr/bin/celery worker'),
+/// or the whole command line
+pub fn matches_name(pattern: &str, cmdline: &[String]) -> bool {
+    if cmdline.is_empty() {
+        return false;
+    }
+    cmdline.iter().any(|arg| glob_match(pattern, arg.rsplit('/').next().unwrap_or(arg))) ||
+        glob_match(pattern, &cmdline.join(" "))
+}
+
+// matches text against a pattern where '*' matches any sequence of characters and '?' any single one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last '*' seen in the pattern, and the position in the text it's matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // backtrack, letting the last '*' swallow one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listening_inodes() {
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:1F40 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 31337 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:1F40 0100007F:C350 01 00000000:00000000 00:00000000 00000000  1000        0 4242 1 0000000000000000 20 4 30 10 -1
+   2: 0100007F:0CEA 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1234 1 0000000000000000 100 0 0 10 0";
+        assert_eq!(parse_listening_inodes(tcp, 8000), vec![31337]);
+        assert_eq!(parse_listening_inodes(tcp, 3306), vec![1234]);
+        assert!(parse_listening_inodes(tcp, 50000).is_empty());
+    }
+
+    #[test]
+    fn test_matches_name() {
+        let cmdline: Vec<String> = vec!["/usr/bin/python3".into(), "/usr/local/bin/celery".into(), "worker".into()];
+        assert!(matches_name("celery*", &cmdline));
+        assert!(matches_name("python?", &cmdline));
+        assert!(matches_name("*celery worker", &cmdline));
+        assert!(!matches_name("gunicorn*", &cmdline));
+        assert!(!matches_name("*", &[]));
+
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a*b", "aXXbc"));
+    }
+}
@@ -0,0 +1,176 @@
+// Profiles the process that py-spy is running in, for the python extension module. Memory is read
+// from our own process, which doesn't need ptrace or the SYS_PTRACE capability, so this works in
+// containers that don't allow attaching to other processes. Sampling happens on a native thread
+// that never takes the GIL, the same as when py-spy is run with --nonblocking.
+use std::fs::File;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use failure::{Error, ResultExt};
+use remoteprocess::Pid;
+
+use crate::config::{Config, FileFormat};
+use crate::error;
+use crate::flamegraph::Flamegraph;
+use crate::python_spy::PythonSpy;
+use crate::speedscope::Stats;
+use crate::stack_trace::StackTrace;
+use crate::timer::Timer;
+
+/// Samples the current process in the background until stopped, then writes out what it recorded
+pub struct Profiler {
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<Result<(), Error>>,
+}
+
+enum Output {
+    Flamegraph(Flamegraph),
+    Folded(Flamegraph),
+    Speedscope(Stats),
+}
+
+impl Profiler {
+    /// Starts sampling this process, returning once the python interpreter has been found in it.
+    /// Only the flamegraph, folded and speedscope formats are supported.
+    pub fn start(filename: &str, config: &Config) -> Result<Profiler, error::Error> {
+        Profiler::_start(filename, config).map_err(error::Error::from)
+    }
+
+    fn _start(filename: &str, config: &Config) -> Result<Profiler, Error> {
+        let output = match config.format {
+            FileFormat::flamegraph => {
+                let mut flamegraph = Flamegraph::new(config.show_line_numbers, config.reverse);
+                if config.memory {
+                    flamegraph.count_name = "bytes".to_owned();
+                }
+                for (key, value) in &config.labels {
+                    flamegraph.labels.set(key, value);
+                }
+                Output::Flamegraph(flamegraph)
+            },
+            FileFormat::folded => Output::Folded(Flamegraph::new(config.show_line_numbers, config.reverse)),
+            FileFormat::speedscope => {
+                let mut stats = Stats::new(config.sampling_rate, config.show_line_numbers, config.reverse);
+                for (key, value) in &config.labels {
+                    stats.set_label(key, value);
+                }
+                Output::Speedscope(stats)
+            },
+            _ => return Err(error::Error::InvalidConfig(
+                format!("{} can't be recorded in-process, use flamegraph, folded or speedscope", config.format)).into())
+        };
+        if config.memory && config.format == FileFormat::speedscope {
+            return Err(error::Error::InvalidConfig("speedscope can't record memory allocations, use flamegraph or folded".to_owned()).into());
+        }
+        // pausing the process would also pause the thread doing the sampling
+        let config = Config{non_blocking: true, native: false, ..config.clone()};
+        let filename = filename.to_owned();
+        let running = Arc::new(AtomicBool::new(true));
+        let (started, result) = mpsc::channel();
+
+        let thread_running = running.clone();
+        let thread = thread::spawn(move || {
+            let spy = PythonSpy::new(std::process::id() as Pid, &config).map_err(Error::from).and_then(|spy| {
+                if config.memory && !spy.tracemalloc_tracing()? {
+                    return Err(format_err!("tracemalloc isn't tracing memory allocations, call tracemalloc.start(25) first"));
+                }
+                Ok(spy)
+            });
+            let spy = match spy {
+                Ok(spy) => { let _ = started.send(Ok(())); spy },
+                Err(e) => { let _ = started.send(Err(e)); return Ok(()); }
+            };
+            sample(spy, output, &filename, &config, &thread_running)
+        });
+
+        result.recv()?.context("Failed to find python in this process")?;
+        Ok(Profiler{running, thread})
+    }
+
+    /// Stops sampling and writes out the recording
+    pub fn stop(self) -> Result<(), error::Error> {
+        self.running.store(false, Ordering::SeqCst);
+        match self.thread.join() {
+            Ok(result) => result.map_err(error::Error::from),
+            Err(_) => Err(error::Error::Other("py-spy sampling thread panicked".to_owned()))
+        }
+    }
+}
+
+fn sample(mut spy: PythonSpy, mut output: Output, filename: &str, config: &Config, running: &AtomicBool) -> Result<(), Error> {
+    let start = Instant::now();
+    let mut errors = 0;
+    let mut samples = 0;
+    let mut timer = Timer::new(config.sampling_rate as f64, config.jitter);
+    while timer.next().is_some() && running.load(Ordering::SeqCst) {
+        let result = if config.memory {
+            spy.get_allocation_traces().map_err(Error::from).and_then(|allocations| output.increment_weighted(&allocations))
+        } else {
+            spy.get_stack_traces().map_err(Error::from).and_then(|mut traces| {
+                filter_traces(&mut traces, config);
+                output.increment(start.elapsed(), &traces)
+            })
+        };
+        match result {
+            Ok(()) => samples += 1,
+            Err(e) => {
+                errors += 1;
+                debug!("Failed to sample: {}", e);
+            }
+        }
+    }
+
+    if let Output::Speedscope(ref mut stats) = output {
+        stats.set_effective_rate(samples as f64 / start.elapsed().as_secs_f64().max(1e-9));
+    }
+    if errors > 0 {
+        info!("{} of {} samples failed", errors, errors + samples);
+    }
+    if timer.missed_ticks() > 0 {
+        info!("Fell behind and skipped {} samples, try reducing the sampling rate", timer.missed_ticks());
+    }
+    if samples == 0 {
+        return Err(format_err!("No samples were collected"));
+    }
+    let mut file = File::create(filename).context(format!("Failed to create {}", filename))?;
+    output.write(&mut file)
+}
+
+fn filter_traces(traces: &mut Vec<StackTrace>, config: &Config) {
+    if config.gil_only {
+        traces.retain(|trace| trace.owns_gil);
+    }
+    if config.include_idle {
+        for trace in traces.iter_mut() {
+            trace.active = true;
+        }
+    }
+}
+
+impl Output {
+    fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Result<(), Error> {
+        match self {
+            Output::Flamegraph(flamegraph) | Output::Folded(flamegraph) => flamegraph.increment(traces)?,
+            Output::Speedscope(stats) => stats.increment(timestamp, traces)
+        }
+        Ok(())
+    }
+
+    fn increment_weighted(&mut self, traces: &[(StackTrace, u64)]) -> Result<(), Error> {
+        match self {
+            Output::Flamegraph(flamegraph) | Output::Folded(flamegraph) => flamegraph.increment_weighted(traces)?,
+            Output::Speedscope(_) => {}
+        }
+        Ok(())
+    }
+
+    fn write(&self, w: &mut dyn std::io::Write) -> Result<(), Error> {
+        match self {
+            Output::Flamegraph(flamegraph) => flamegraph.write(w),
+            Output::Folded(flamegraph) => flamegraph.write_folded(w),
+            Output::Speedscope(stats) => stats.write(w)
+        }
+    }
+}
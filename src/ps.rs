@@ -0,0 +1,138 @@
+// Finds the python processes running on this machine for 'py-spy ps', along with what version of
+// python they're running, the virtualenv they're in and whether py-spy can attach to them
+use std::path::Path;
+
+use failure::Error;
+use remoteprocess::{Pid, Process};
+
+use crate::config::Config;
+use crate::python_spy::PythonSpy;
+
+pub struct PythonProcess {
+    pub pid: Pid,
+    pub cmdline: String,
+    pub version: Option<String>,
+    pub virtualenv: Option<String>,
+    /// Why py-spy couldn't sample the process, if it can't
+    pub error: Option<Error>,
+}
+
+/// Returns the processes (other than py-spy itself) that look like they're running python
+pub fn find_python_processes() -> Result<Vec<PythonProcess>, Error> {
+    let own_pid = std::process::id() as Pid;
+    // only read the process's memory, without pausing it
+    let config = Config{non_blocking: true, ..Default::default()};
+    let mut ret = Vec::new();
+    for pid in remoteprocess::processes()? {
+        if pid == own_pid {
+            continue;
+        }
+        let process = match Process::new(pid) {
+            Ok(process) => process,
+            Err(_) => continue
+        };
+        let cmdline = process.cmdline().unwrap_or_default();
+        let exe = process.exe().unwrap_or_default();
+        if !is_python(&exe, &cmdline) && !loads_libpython(pid) {
+            continue;
+        }
+
+        let (version, error) = match PythonSpy::new(pid, &config) {
+            Ok(mut spy) => (Some(spy.version.to_string()), spy.get_stack_traces().err().map(Error::from)),
+            Err(err) => (version_from_filename(&exe), Some(Error::from(err)))
+        };
+        // scripts passed with -c can span several lines, so collapse those onto one
+        let description = cmdline.iter().flat_map(|arg| arg.split_whitespace()).collect::<Vec<&str>>().join(" ");
+        ret.push(PythonProcess{pid, cmdline: description, version, virtualenv: virtualenv(pid, &cmdline), error});
+    }
+    ret.sort_by_key(|process| process.pid);
+    Ok(ret)
+}
+
+// whether the executable (or the program in the command line, since virtualenvs and pyenv run python
+// through symlinks and shims) is python
+fn is_python(exe: &str, cmdline: &[String]) -> bool {
+    let program = |path: &str| Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or("").to_owned();
+    std::iter::once(exe).chain(cmdline.first().map(|arg| arg.as_str()))
+        .any(|path| program(path).starts_with("python"))
+}
+
+// programs like uwsgi embed python, which shows up as libpython in their memory maps
+#[cfg(target_os="linux")]
+fn loads_libpython(pid: Pid) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/maps", pid)).is_ok_and(|maps| maps.contains("/libpython"))
+}
+
+#[cfg(not(target_os="linux"))]
+fn loads_libpython(_pid: Pid) -> bool {
+    false
+}
+
+// gets the version from a filename like /usr/bin/python3.7, for processes that can't be read
+fn version_from_filename(exe: &str) -> Option<String> {
+    let name = Path::new(exe).file_name()?.to_str()?;
+    let version = name.strip_prefix("python")?;
+    let mut parts = version.splitn(3, '.');
+    match (parts.next()?.parse::<u64>(), parts.next().map(|minor| minor.trim_end_matches(|c: char| !c.is_ascii_digit()))) {
+        (Ok(major), Some(minor)) if minor.parse::<u64>().is_ok() => Some(format!("{}.{}", major, minor)),
+        _ => None
+    }
+}
+
+// finds the virtualenv (or conda environment) from the process's environment, or else from the
+// location of the python it was started with
+fn virtualenv(pid: Pid, cmdline: &[String]) -> Option<String> {
+    #[cfg(target_os="linux")]
+    {
+        if let Some(env) = std::fs::read(format!("/proc/{}/environ", pid)).ok().and_then(|environ| environment_prefix(&environ)) {
+            return Some(env);
+        }
+    }
+    #[cfg(not(target_os="linux"))]
+    let _ = pid;
+
+    // virtualenvs have a pyvenv.cfg file in the directory above bin/python
+    let prefix = Path::new(cmdline.first()?).parent()?.parent()?;
+    if prefix.is_absolute() && prefix.join("pyvenv.cfg").exists() {
+        return Some(prefix.to_string_lossy().into_owned());
+    }
+    None
+}
+
+// gets VIRTUAL_ENV or CONDA_PREFIX from the contents of /proc/<pid>/environ
+#[cfg(any(target_os="linux", test))]
+fn environment_prefix(environ: &[u8]) -> Option<String> {
+    let variables: Vec<&[u8]> = environ.split(|&b| b == 0).collect();
+    ["VIRTUAL_ENV=", "CONDA_PREFIX="].iter().find_map(|name| {
+        let value = variables.iter().find_map(|variable| variable.strip_prefix(name.as_bytes()))?;
+        Some(String::from_utf8_lossy(value).into_owned()).filter(|value| !value.is_empty())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_python() {
+        assert!(is_python("/usr/bin/python3.7", &[]));
+        assert!(is_python("/usr/local/bin/uwsgi", &["/home/me/venv/bin/python".to_owned(), "app.py".to_owned()]));
+        assert!(!is_python("/usr/bin/bash", &["bash".to_owned(), "python.sh".to_owned()]));
+        assert!(!is_python("", &[]));
+    }
+
+    #[test]
+    fn test_version_from_filename() {
+        assert_eq!(version_from_filename("/usr/bin/python3.7"), Some("3.7".to_owned()));
+        assert_eq!(version_from_filename("/usr/bin/python3.8m"), Some("3.8".to_owned()));
+        assert_eq!(version_from_filename("/usr/bin/python3"), None);
+        assert_eq!(version_from_filename("/usr/bin/uwsgi"), None);
+    }
+
+    #[test]
+    fn test_environment_prefix() {
+        assert_eq!(environment_prefix(b"HOME=/root\0VIRTUAL_ENV=/srv/app/venv\0PATH=/usr/bin\0"), Some("/srv/app/venv".to_owned()));
+        assert_eq!(environment_prefix(b"CONDA_PREFIX=/opt/conda\0VIRTUAL_ENV=\0"), Some("/opt/conda".to_owned()));
+        assert_eq!(environment_prefix(b"HOME=/root\0"), None);
+    }
+}
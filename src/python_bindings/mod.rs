@@ -42,6 +42,36 @@ pub mod pyruntime {
         }
     }
 
+    /// Where the lists of objects tracked by the garbage collector are in PyRuntime
+    pub struct GcLayout {
+        /// Offset of the list head of the first generation
+        pub generations: usize,
+        /// Distance between the list heads of each generation
+        pub stride: usize,
+        /// Size of the PyGC_Head that comes before each tracked object
+        pub head_size: usize,
+        /// Offset of the flag that is set while a collection is running
+        pub collecting: usize,
+    }
+
+    // Python 3.7.4 aligned PyGC_Head to a long double, which is 16 bytes everywhere but windows.
+    // Like interpreters.head, nothing before the gc state is OS specific. The collecting flag
+    // comes after generation0, the permanent generation and the stats of each generation
+    #[cfg(target_pointer_width = "64")]
+    pub fn get_gc_layout(version: &Version) -> Option<GcLayout> {
+        match version {
+            Version{major: 3, minor: 7, patch, ..} if *patch >= 4 && !cfg!(windows) =>
+                Some(GcLayout{generations: 352, stride: 48, head_size: 32, collecting: 632}),
+            Version{major: 3, minor: 7, ..} => Some(GcLayout{generations: 336, stride: 32, head_size: 24, collecting: 544}),
+            _ => None
+        }
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    pub fn get_gc_layout(_version: &Version) -> Option<GcLayout> {
+        None
+    }
+
     // getting gilstate.tstate_current is different for all OS
     // and is also different for each python version, and even
     // between v3.8.0a1 and v3.8.0a2 =(
@@ -13,8 +13,12 @@ use crate::python_bindings::{v2_7_15, v3_3_7, v3_5_5, v3_6_6, v3_7_0};
 use std;
 
 pub trait InterpreterState {
-    type ThreadState: ThreadState;
+    // 'static so that the jobs for the threads unwinding stacks in parallel can copy it
+    type ThreadState: ThreadState + 'static;
     fn head(&self) -> * mut Self::ThreadState;
+    fn next(&self) -> * mut Self;
+    /// The id of the interpreter, which is only stored from python 3.7 on
+    fn id(&self) -> Option<i64>;
 }
 
 pub trait ThreadState {
@@ -25,6 +29,11 @@ pub trait ThreadState {
     fn frame(&self) -> * mut Self::FrameObject;
     fn thread_id(&self) -> u64;
     fn next(&self) -> * mut Self;
+    /// Address of the per thread state dict, which greenlet uses to tell which thread a greenlet belongs to
+    fn dict(&self) -> usize;
+    /// Address of the type, value and traceback of the exception being handled, given the
+    /// address of the thread state itself
+    fn exc_info(&self, base: usize) -> usize;
 }
 
 pub trait FrameObject {
@@ -32,6 +41,8 @@ pub trait FrameObject {
     fn code(&self) -> * mut Self::CodeObject;
     fn lasti(&self) -> i32;
     fn back(&self) -> * mut Self;
+    /// Address of the fast locals of the frame, given the address of the frame itself
+    fn localsplus(&self, base: usize) -> usize;
 }
 
 pub trait CodeObject {
@@ -42,6 +53,11 @@ pub trait CodeObject {
     fn filename(&self) -> * mut Self::StringObject;
     fn lnotab(&self) -> * mut Self::BytesObject;
     fn first_lineno(&self) -> i32;
+    /// Number of positional arguments, which come first in the fast locals of a frame
+    fn argcount(&self) -> i32;
+    fn nlocals(&self) -> i32;
+    /// Address of the tuple of local variable names
+    fn varnames(&self) -> usize;
 }
 
 pub trait BytesObject {
@@ -64,10 +80,18 @@ fn offset_of<T, M>(object: *const T, member: *const M) -> usize {
 /// (this code is identical across python versions, we are only abstracting the struct layouts here).
 /// String handling changes substantially between python versions, and is handled separately.
 macro_rules! PythonCommonImpl {
+    // before python 3.7 the exception being handled is stored in the thread state, and interpreters don't have ids
     ($py: ident, $bytesobject: ident, $stringobject: ident) => (
+        PythonCommonImpl!($py, $bytesobject, $stringobject,
+                          |thread: &$py::PyThreadState, base| base + offset_of(thread, &thread.exc_type),
+                          |_: &$py::PyInterpreterState| None);
+    );
+    ($py: ident, $bytesobject: ident, $stringobject: ident, $exc_info: expr, $interpreter_id: expr) => (
         impl InterpreterState for $py::PyInterpreterState {
             type ThreadState = $py::PyThreadState;
             fn head(&self) -> * mut Self::ThreadState { self.tstate_head }
+            fn next(&self) -> * mut Self { self.next }
+            fn id(&self) -> Option<i64> { ($interpreter_id)(self) }
         }
 
         impl ThreadState for $py::PyThreadState {
@@ -77,6 +101,8 @@ macro_rules! PythonCommonImpl {
             fn thread_id(&self) -> u64 { self.thread_id as u64 }
             fn next(&self) -> * mut Self { self.next }
             fn interp(&self) -> *mut Self::InterpreterState { self.interp }
+            fn dict(&self) -> usize { self.dict as usize }
+            fn exc_info(&self, base: usize) -> usize { ($exc_info)(self, base) }
         }
 
         impl FrameObject for $py::PyFrameObject {
@@ -84,6 +110,7 @@ macro_rules! PythonCommonImpl {
             fn code(&self) -> * mut Self::CodeObject { self.f_code }
             fn lasti(&self) -> i32 { self.f_lasti }
             fn back(&self) -> * mut Self { self.f_back }
+            fn localsplus(&self, base: usize) -> usize { base + offset_of(self, &self.f_localsplus) }
         }
 
         impl CodeObject for $py::PyCodeObject {
@@ -93,6 +120,9 @@ macro_rules! PythonCommonImpl {
             fn filename(&self) -> * mut Self::StringObject { self.co_filename as * mut Self::StringObject }
             fn lnotab(&self) -> * mut Self::BytesObject { self.co_lnotab as * mut Self::BytesObject }
             fn first_lineno(&self) -> i32 { self.co_firstlineno }
+            fn argcount(&self) -> i32 { self.co_argcount }
+            fn nlocals(&self) -> i32 { self.co_nlocals }
+            fn varnames(&self) -> usize { self.co_varnames as usize }
         }
     )
 }
@@ -144,8 +174,10 @@ macro_rules! Python2StringImpl {
     )
 }
 
-// Python 3.7
-PythonCommonImpl!(v3_7_0, PyBytesObject, PyUnicodeObject);
+// Python 3.7, which keeps a stack of the exceptions being handled by generators and the thread,
+// and gives each interpreter an id
+PythonCommonImpl!(v3_7_0, PyBytesObject, PyUnicodeObject, |thread: &v3_7_0::PyThreadState, _| thread.exc_info as usize,
+                  |interp: &v3_7_0::PyInterpreterState| Some(interp.id));
 Python3StringImpl!(v3_7_0);
 
 // Python 3.6
@@ -1,10 +1,10 @@
 use std;
-use std::collections::HashMap;
-#[cfg(all(target_os="linux", unwind))]
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::mem::size_of;
 use std::slice;
 use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 #[cfg(all(target_os="linux", unwind))]
 use std::iter::FromIterator;
 use regex::Regex;
@@ -14,42 +14,113 @@ use remoteprocess::{Process, ProcessMemory, Pid, Tid};
 use proc_maps::{get_process_maps, MapRange};
 
 
+use crate::asyncio;
+use crate::exceptions;
+use crate::greenlet::{self, Greenlet};
+use crate::heap::{self, TypeCounts};
+use crate::idle_rules::IdleRules;
+#[cfg(target_os="linux")]
+use crate::kernel_stack::{self, KernelStacks};
+use crate::locals::{self, Receiver};
 use crate::binary_parser::{parse_binary, BinaryInfo};
 use crate::config::Config;
+use crate::error;
 #[cfg(unwind)]
 use crate::native_stack_trace::NativeStack;
 use crate::python_bindings::{pyruntime, v2_7_15, v3_3_7, v3_5_5, v3_6_6, v3_7_0};
 use crate::python_interpreters::{self, InterpreterState, ThreadState};
-use crate::stack_trace::{StackTrace, get_stack_traces, get_stack_trace};
-use crate::version::Version;
+use crate::stack_trace::{Frame, StackTrace, gc_frame, get_stack_traces, get_stack_trace, get_checked_stack_trace, is_native_gc_frame};
+use crate::threading;
+use crate::workers;
+use crate::tracemalloc::{Allocation, Traceback, Tracemalloc, get_traceback};
+use crate::unwind_pool::UnwindPool;
+use crate::version::{Version, VersionSource};
+
+// how often to look for new greenlets, which means walking every object tracked by the gc
+const GREENLET_SCAN_INTERVAL: Duration = Duration::from_secs(5);
+// number of python frames (from the innermost) to copy arguments for, with the capture_args option
+const CAPTURE_ARGS_FRAMES: usize = 3;
+// how often to re-read the names that the threading module gives threads, in case they get renamed
+const THREAD_NAME_SCAN_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Lets you retrieve stack traces of a running python program
 pub struct PythonSpy {
     pub pid: Pid,
     pub process: Process,
     pub version: Version,
+    /// Where the version came from, for reporting how far it can be trusted
+    pub version_source: VersionSource,
     pub interpreter_address: usize,
+    /// Address of the pointer to the head of the list of interpreters, if we found it from the symbols
+    pub interp_head_address: Option<usize>,
     pub threadstate_address: usize,
     pub python_filename: String,
+    /// The libpython that the interpreter was found in, when it isn't part of the executable
+    pub libpython_filename: Option<String>,
     pub version_string: String,
     pub config: Config,
     #[cfg(unwind)]
     pub native: Option<NativeStack>,
+    /// Samples the kernel stacks of each thread, used with the kernel option
+    #[cfg(target_os="linux")]
+    pub kernel: Option<KernelStacks>,
     pub short_filenames: HashMap<String, Option<String>>,
     pub python_thread_ids: HashMap<u64, Tid>,
+    /// CPU time used by each OS thread as of the previous sample, used with the cpu_time option
+    pub thread_cpu_times: HashMap<Tid, Duration>,
+    pub thread_name_regex: Option<Regex>,
+    pub native_thread_name_regex: Option<Regex>,
+    /// What threads are doing when they're idle, for when the OS can't tell us
+    pub idle_rules: IdleRules,
+    /// Total time the process has been suspended for while taking samples
+    pub suspended_time: Duration,
+    /// Number of thread stacks dropped for having changed while they were read, with the dirty_reads option
+    pub torn_stacks: u64,
+    pub runtime_address: Option<usize>,
+    /// Greenlets found in the process as of the last scan, used with the gevent option
+    pub greenlets: Vec<Greenlet>,
+    pub last_greenlet_scan: Option<Instant>,
+    /// Names from the threading module for each python thread id, as of the last scan
+    pub python_thread_names: HashMap<u64, Option<String>>,
+    pub last_thread_name_scan: Option<Instant>,
+    /// Where tracemalloc keeps its state, used with the memory option
+    pub tracemalloc: Option<Tracemalloc>,
+    /// The allocations that tracemalloc was tracking as of the previous snapshot
+    pub allocations: Option<HashSet<Allocation>>,
+    /// How the function of each code object is passed its class, used to qualify method names
+    pub method_receivers: HashMap<usize, Option<Receiver>>,
+    /// The threads that unwind python stacks in parallel, with the unwind_workers option
+    unwind_pool: Option<UnwindPool>,
 }
 
 impl PythonSpy {
     /// Constructs a new PythonSpy object.
-    pub fn new(pid: Pid, config: &Config) -> Result<PythonSpy, Error> {
+    pub fn new(pid: Pid, config: &Config) -> Result<PythonSpy, error::Error> {
+        PythonSpy::attach(pid, config).map_err(|err| error::Error::from_process(err, pid))
+    }
+
+    fn attach(pid: Pid, config: &Config) -> Result<PythonSpy, Error> {
         let process = remoteprocess::Process::new(pid)
             .context("Failed to open process - check if it is running.")?;
 
+        // attaching to a process in a frozen cgroup (like a paused container) would hang until it's resumed
+        #[cfg(target_os="linux")]
+        {
+            if process.frozen().unwrap_or(false) {
+                return Err(format_err!("Process {} is frozen (is it in a paused container?). Resume it to sample it", pid));
+            }
+        }
+
         // get basic process information (memory maps/symbols etc)
         let python_info = PythonProcessInfo::new(&process)?;
 
-        let version = get_python_version(&python_info, &process)?;
-        info!("python version {} detected", version);
+        let (version, version_source) = get_python_version(&python_info, &process)?;
+        info!("python version {} detected from {} ({} confidence)", version, version_source, version_source.confidence());
+        if version_source == VersionSource::Layout {
+            warn!("Failed to find the version of python, guessed {}.{} from the layout of the interpreter. \
+                   Stack traces might not make sense if this is wrong", version.major, version.minor);
+        }
+        let libpython_filename = python_info.libpython_binary.as_ref().map(|binary| binary.filename.clone());
 
         let interpreter_address = get_interpreter_address(&python_info, &process, &version)?;
         info!("Found interpreter at 0x{:016x}", interpreter_address);
@@ -88,32 +159,101 @@ impl PythonSpy {
              }
          };
 
+        let interp_head_address = match version {
+            Version{major: 3, minor: 7, ..} => python_info.get_symbol("_PyRuntime")
+                .map(|&addr| addr as usize + pyruntime::get_interp_head_offset(&version)),
+            _ => python_info.get_symbol("interp_head").map(|&addr| addr as usize)
+        };
+
         let version_string = format!("python{}.{}", version.major, version.minor);
+        let runtime_address = python_info.get_symbol("_PyRuntime").map(|&addr| addr as usize);
+        if config.gevent && (runtime_address.is_none() || pyruntime::get_gc_layout(&version).is_none()) {
+            warn!("greenlet stacks are only supported for 64 bit builds of python 3.7, and won't be collected for python {}", version);
+        }
+        if config.asyncio && !(version.major == 3 && version.minor == 7) {
+            warn!("asyncio task stacks are only supported for python 3.7, and won't be collected for python {}", version);
+        }
 
-        #[cfg(all(unwind, not(target_os="linux")))]
-        let native = if config.native {
-            Some(NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary)?)
+        let tracemalloc = if config.memory {
+            if version.major < 3 || (version.major == 3 && version.minor < 4) {
+                return Err(format_err!("Recording memory allocations needs tracemalloc, which isn't available before python 3.4"));
+            }
+            let tracemalloc_config = python_info.get_symbol("_Py_tracemalloc_config")
+                .or_else(|| python_info.get_symbol("tracemalloc_config"));
+            match (tracemalloc_config, python_info.get_symbol("tracemalloc_traces")) {
+                (Some(&config), Some(&traces)) => Some(Tracemalloc{config: config as usize, traces: traces as usize}),
+                _ => return Err(format_err!("Failed to find the tracemalloc symbols in {}, which are needed to record memory allocations",
+                                            python_info.python_filename))
+            }
         } else {
             None
         };
 
+        let thread_name_regex = match config.thread_name_regex.as_ref() {
+            Some(regex) => Some(Regex::new(regex).context("Invalid thread name regex")?),
+            None => None
+        };
+        let native_thread_name_regex = match config.native_thread_name_regex.as_ref() {
+            Some(regex) => Some(Regex::new(regex).context("Invalid native thread name regex")?),
+            None => None
+        };
+
+        // without any options that need it, the unwinder is still used to show the native stack of a thread
+        // holding the GIL outside of python code, but failing to create it isn't an error
+        #[cfg(all(unwind, not(target_os="linux")))]
+        let native = match NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary) {
+            Ok(native) => Some(native),
+            Err(err) if !(config.native || config.native_threads || config.gil_wait || config.lock_contention) => {
+                debug!("Failed to create native unwinder: {}", err);
+                None
+            },
+            Err(err) => return Err(err)
+        };
+
         #[cfg(all(unwind, target_os="linux"))]
-        let native = Some(NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary)?);
+        let native = {
+            let mut native = NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary)?;
+            native.set_debuginfod(config.debuginfod);
+            native.set_unsymbolicated(config.unsymbolicated);
+            Some(native)
+        };
+
+        #[cfg(target_os="linux")]
+        let kernel = if config.kernel {
+            Some(KernelStacks::new(pid, config.sampling_rate)?)
+        } else {
+            None
+        };
 
-        Ok(PythonSpy{pid, process, version, interpreter_address, threadstate_address,
-                     python_filename: python_info.python_filename,
+        // the workers open the process once here, rather than on every sample
+        let unwind_pool = if uses_unwind_pool(config) {
+            Some(UnwindPool::new(pid, config.unwind_workers)?)
+        } else {
+            None
+        };
+
+        Ok(PythonSpy{pid, process, version, version_source, interpreter_address, interp_head_address, threadstate_address,
+                     python_filename: python_info.python_filename, libpython_filename,
                      version_string,
                      #[cfg(unwind)]
                      native,
+                     #[cfg(target_os="linux")]
+                     kernel,
                      config: config.clone(),
                      short_filenames: HashMap::new(),
-                     python_thread_ids: HashMap::new()})
+                     python_thread_ids: HashMap::new(),
+                     thread_cpu_times: HashMap::new(),
+                     thread_name_regex, native_thread_name_regex,
+                     idle_rules: IdleRules::new(&config.idle_rules, &config.removed_idle_rules), suspended_time: Duration::from_secs(0), torn_stacks: 0,
+                     runtime_address, greenlets: Vec::new(), last_greenlet_scan: None,
+                     python_thread_names: HashMap::new(), last_thread_name_scan: None,
+                     tracemalloc, allocations: None, method_receivers: HashMap::new(), unwind_pool})
     }
 
     /// Creates a PythonSpy object, retrying up to max_retries times.
     /// Mainly useful for the case where the process is just started and
     /// symbols or the python interpreter might not be loaded yet.
-    pub fn retry_new(pid: Pid, config: &Config, max_retries:u64) -> Result<PythonSpy, Error> {
+    pub fn retry_new(pid: Pid, config: &Config, max_retries:u64) -> Result<PythonSpy, error::Error> {
         let mut retries = 0;
         loop {
             let err = match PythonSpy::new(pid, config) {
@@ -138,8 +278,8 @@ impl PythonSpy {
     }
 
     /// Gets a StackTrace for each thread in the current process
-    pub fn get_stack_traces(&mut self) -> Result<Vec<StackTrace>, Error> {
-        match self.version {
+    pub fn get_stack_traces(&mut self) -> Result<Vec<StackTrace>, error::Error> {
+        let traces = match self.version {
             // Currently 3.7.x and 3.8.0a0 have the same ABI, but this might change
             // as 3.8 evolves
             Version{major: 3, minor: 8, ..} => self._get_stack_traces::<v3_7_0::_is>(),
@@ -151,17 +291,164 @@ impl PythonSpy {
             Version{major: 3, minor: 3, ..} => self._get_stack_traces::<v3_3_7::_is>(),
             // ABI for 2.3/2.4/2.5/2.6/2.7 is also compatible
             Version{major: 2, minor: 3...7, ..} => self._get_stack_traces::<v2_7_15::_is>(),
-            _ => Err(format_err!("Unsupported version of Python: {}", self.version)),
+            _ => Err(error::Error::UnsupportedPythonVersion{found: self.version.to_string()}.into()),
+        };
+        traces.map_err(|err| error::Error::from_process(err, self.pid))
+    }
+
+    /// Gets the native stacks of the threads that aren't running python (like threads started by C
+    /// extensions), given the stack traces of the python threads. These have a python thread id of 0
+    pub fn get_native_thread_traces(&mut self, python_traces: &[StackTrace]) -> Result<Vec<StackTrace>, error::Error> {
+        self._get_native_thread_traces(python_traces).map_err(|err| error::Error::from_process(err, self.pid))
+    }
+
+    #[cfg(unwind)]
+    fn _get_native_thread_traces(&mut self, python_traces: &[StackTrace]) -> Result<Vec<StackTrace>, Error> {
+        let python_threads: HashSet<u64> = python_traces.iter().filter_map(|trace| trace.os_thread_id).collect();
+        let native = self.native.as_mut().ok_or_else(|| format_err!("Native stack traces aren't available"))?;
+        let _lock = self.process.lock().context("Failed to suspend process")?;
+
+        let mut traces = Vec::new();
+        for thread in self.process.threads()?.iter() {
+            let os_thread_id = thread.id()? as u64;
+            if python_threads.contains(&os_thread_id) {
+                continue;
+            }
+            let frames = match native.merge_native_thread(&Vec::new(), thread) {
+                Ok(frames) => frames,
+                Err(err) => {
+                    warn!("Failed to get the native stack of thread {}: {}", os_thread_id, err);
+                    continue;
+                }
+            };
+            traces.push(StackTrace{thread_id: 0, os_thread_id: Some(os_thread_id), thread_name: thread.name().ok(),
+                                   interpreter_id: 0, active: thread.active()?, owns_gil: false, frames,
+                                   exception: None, awaiting: None, pid: None});
+        }
+        Ok(traces)
+    }
+
+    #[cfg(not(unwind))]
+    fn _get_native_thread_traces(&mut self, _python_traces: &[StackTrace]) -> Result<Vec<StackTrace>, Error> {
+        Err(format_err!("Native stack traces aren't supported on this platform"))
+    }
+
+    /// Counts the objects tracked by the garbage collector by type, along with their approximate size
+    pub fn get_heap_summary(&mut self) -> Result<HashMap<String, TypeCounts>, error::Error> {
+        self._get_heap_summary().map_err(|err| error::Error::from_process(err, self.pid))
+    }
+
+    fn _get_heap_summary(&mut self) -> Result<HashMap<String, TypeCounts>, Error> {
+        let (runtime, layout) = match (self.runtime_address, pyruntime::get_gc_layout(&self.version)) {
+            (Some(runtime), Some(layout)) => (runtime, layout),
+            _ => return Err(format_err!("Inspecting the heap is only supported for 64 bit builds of python 3.7, not python {}",
+                                        self.version))
+        };
+
+        let _lock = if self.config.non_blocking {
+            None
+        } else {
+            Some(if self.config.freeze { self.process.freeze() } else { self.process.lock() }
+                     .context("Failed to suspend process")?)
+        };
+        let locked_at = Instant::now();
+        let summary = heap::get_heap_summary(&self.process, runtime, &layout);
+        if let Some(lock) = _lock {
+            drop(lock);
+            self.suspended_time += locked_at.elapsed();
+        }
+        summary
+    }
+
+    /// Returns whether tracemalloc is tracing allocations, which is needed for get_allocation_traces
+    pub fn tracemalloc_tracing(&self) -> Result<bool, error::Error> {
+        match self.tracemalloc.as_ref() {
+            Some(tracemalloc) => tracemalloc.is_tracing(&self.process).map_err(|err| error::Error::from_process(err, self.pid)),
+            None => Ok(false)
+        }
+    }
+
+    /// Gets the stacks that memory was allocated from since the previous call, along with the
+    /// number of bytes allocated from each stack. Only allocations tracked by tracemalloc that are
+    /// still alive are counted, and the first call just records the allocations that already exist
+    pub fn get_allocation_traces(&mut self) -> Result<Vec<(StackTrace, u64)>, error::Error> {
+        self._get_allocation_traces().map_err(|err| error::Error::from_process(err, self.pid))
+    }
+
+    fn _get_allocation_traces(&mut self) -> Result<Vec<(StackTrace, u64)>, Error> {
+        let tracemalloc = match self.tracemalloc {
+            Some(tracemalloc) => tracemalloc,
+            None => return Err(format_err!("Memory allocations aren't being recorded"))
+        };
+
+        let _lock = if self.config.non_blocking {
+            None
+        } else {
+            Some(if self.config.freeze { self.process.freeze() } else { self.process.lock() }
+                     .context("Failed to suspend process")?)
+        };
+        let locked_at = Instant::now();
+        let tracebacks = self._get_new_allocations(&tracemalloc);
+        if let Some(lock) = _lock {
+            drop(lock);
+            self.suspended_time += locked_at.elapsed();
+        }
+
+        let mut traces = Vec::new();
+        for (frames, size) in tracebacks? {
+            let frames = frames.into_iter().map(|(filename, line)| {
+                let name = Path::new(&filename).file_name().map(|f| f.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| filename.clone());
+                Frame{name, short_filename: self.shorten_filename(&filename), filename, module: None,
+                      line: line as i32, frame_ptr: None, locals: None}
+            }).collect();
+            traces.push((StackTrace{thread_id: 0, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true,
+                                    owns_gil: false, frames, exception: None, awaiting: None, pid: None}, size));
+        }
+        Ok(traces)
+    }
+
+    // reads the tracebacks of allocations that weren't there in the previous snapshot (once the
+    // process has been locked), along with the bytes allocated from each
+    fn _get_new_allocations(&mut self, tracemalloc: &Tracemalloc) -> Result<Vec<(Traceback, u64)>, Error> {
+        let allocations: HashSet<Allocation> = tracemalloc.get_allocations(&self.process)?.into_iter().collect();
+        let previous = match self.allocations.replace(allocations) {
+            Some(previous) => previous,
+            None => return Ok(Vec::new())
+        };
+
+        let mut sizes: HashMap<usize, u64> = HashMap::new();
+        for allocation in self.allocations.iter().flatten().filter(|allocation| !previous.contains(allocation)) {
+            *sizes.entry(allocation.traceback).or_default() += allocation.size as u64;
         }
+        let mut tracebacks = Vec::new();
+        for (traceback, size) in sizes {
+            tracebacks.push((get_traceback(&self.process, traceback)?, size));
+        }
+        Ok(tracebacks)
     }
 
     // implementation of get_stack_traces, where we have a type for the InterpreterState
     fn _get_stack_traces<I: InterpreterState>(&mut self) -> Result<Vec<StackTrace>, Error> {
         // Query the OS to get if each thread in the process is running or not
         let mut thread_activity = HashMap::new();
+        let mut thread_names = HashMap::new();
         for thread in self.process.threads()?.iter() {
             let threadid: Tid = thread.id()?;
-            thread_activity.insert(threadid, thread.active()?);
+            if let Ok(name) = thread.name() {
+                thread_names.insert(threadid, name);
+            }
+            let active = if self.config.cpu_time {
+                // only count the thread as active if it has been running on a CPU since the last sample
+                let cpu_time = thread.cpu_time()?;
+                match self.thread_cpu_times.insert(threadid, cpu_time) {
+                    Some(previous) => cpu_time > previous,
+                    None => thread.active()?
+                }
+            } else {
+                thread.active()?
+            };
+            thread_activity.insert(threadid, active);
         }
 
         // Lock the process if appropiate. Note we have to lock AFTER getting the thread
@@ -171,73 +458,411 @@ impl PythonSpy {
         let _lock = if self.config.non_blocking {
             None
         } else {
-            Some(self.process.lock().context("Failed to suspend process")?)
+            Some(if self.config.freeze { self.process.freeze() } else { self.process.lock() }
+                     .context("Failed to suspend process")?)
         };
+        let locked_at = Instant::now();
+
+        let traces = self._get_locked_stack_traces::<I>(&thread_activity, &thread_names, locked_at, _lock.is_some());
+        if let Some(lock) = _lock {
+            drop(lock);
+            self.suspended_time += locked_at.elapsed();
+        }
+        traces
+    }
 
+    // gets the stack traces once the process has been locked (if appropiate), so that the caller
+    // can measure how long the process was suspended for on every return path
+    fn _get_locked_stack_traces<I: InterpreterState>(&mut self, thread_activity: &HashMap<Tid, bool>,
+                                                     thread_names: &HashMap<Tid, String>,
+                                                     locked_at: Instant, locked: bool) -> Result<Vec<StackTrace>, Error> {
         let gil_thread_id = self._get_gil_threadid::<I>()?;
 
-        // Get the python interpreter, and loop over all the python threads
-        let interp: I = self.process.copy_struct(self.interpreter_address)
-           .context("Failed to copy PyInterpreterState from process")?;
+        // walk the list of python threads of every interpreter first, so that their stacks can be unwound in parallel
+        let mut thread_states = Vec::new();
+        let mut interpreter_ids = HashMap::new();
+        for (interp, interpreter_id) in self._get_interpreters::<I>()? {
+            let mut threads = interp.head();
+            while !threads.is_null() {
+                let thread = self.process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
+
+                // Try getting the native thread id
+                let python_thread_id = thread.thread_id();
+                let os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
+
+                // skip unwinding threads that we've been told to ignore
+                let next = thread.next();
+                if self._include_thread(python_thread_id, os_thread_id, thread_names) {
+                    interpreter_ids.insert(python_thread_id, interpreter_id);
+                    thread_states.push((threads as usize, thread, os_thread_id));
+                }
+
+                // This seems to happen occasionally when scanning BSS addresses for valid interpeters
+                if thread_states.len() > 4096 {
+                    return Err(format_err!("Max thread recursion depth reached"));
+                }
+                threads = next;
+            }
+        }
+
+        // threads that have appeared since the last scan are likely to have been named by now
+        if self.version.major == 3 && self.version.minor == 7 &&
+            (self.last_thread_name_scan.is_none_or(|last| last.elapsed() >= THREAD_NAME_SCAN_INTERVAL) ||
+             thread_states.iter().any(|(_, thread, _)| !self.python_thread_names.contains_key(&thread.thread_id()))) {
+            self.last_thread_name_scan = Some(Instant::now());
+            let thread_ids: Vec<u64> = thread_states.iter().map(|(_, thread, _)| thread.thread_id()).collect();
+            self._scan_python_thread_names(&thread_ids);
+        }
+
+        let mut parallel_traces = if unwinds_in_parallel(&self.config, thread_states.len()) {
+            let addresses: Vec<usize> = thread_states.iter().map(|t| t.0).collect();
+            Some(self._get_stack_traces_parallel::<I::ThreadState>(&addresses, locked_at, locked)?.into_iter())
+        } else {
+            None
+        };
+
+        // the collector runs on whichever thread holds the GIL, so this tells us which stack is in a collection
+        let gc_collecting = self._gc_collecting();
 
         let mut traces = Vec::new();
-        let mut threads = interp.head();
-        while !threads.is_null() {
+        // greenlets are matched up with their threads by the thread state dict
+        let thread_dicts: Vec<(usize, u64, Option<u64>)> = thread_states.iter()
+            .map(|(_, thread, os_thread_id)| (thread.dict(), thread.thread_id(), os_thread_id.map(|id| id as u64)))
+            .collect();
+
+        #[cfg(target_os="linux")]
+        let kernel_time = kernel_stack::monotonic_time();
+        #[cfg(target_os="linux")]
+        {
+            if let Some(kernel) = self.kernel.as_mut() {
+                kernel.update();
+            }
+        }
+
+        for (address, thread, os_thread_id) in thread_states {
             // Get the stack trace of the python thread
-            let thread = self.process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
-            let mut trace = get_stack_trace(&thread, &self.process)?;
+            let trace = match parallel_traces.as_mut() {
+                Some(parallel_traces) => parallel_traces.next().ok_or_else(|| format_err!("Missing stack trace"))?,
+                None if self.config.dirty_reads => get_checked_stack_trace(address, &thread, &self.process),
+                None => Some(get_stack_trace(&thread, &self.process)?)
+            };
+            // without pausing the process, stacks that were torn by the thread running are left out of the sample
+            let mut trace = match trace {
+                Some(trace) => trace,
+                None => {
+                    self.torn_stacks += 1;
+                    continue;
+                }
+            };
 
-            // Try getting the native thread id
-            let python_thread_id = thread.thread_id();
-            let os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
+            trace.os_thread_id = os_thread_id.map(|id| id as u64);
+            trace.owns_gil = trace.thread_id == gil_thread_id;
+
+            // native unwinding can be limited to some threads, since it's much slower than getting the python stack
+            #[cfg(unwind)]
+            let native_thread = self.config.native && self._native_thread(trace.thread_id, os_thread_id, thread_names);
 
             #[cfg(unwind)]
             {
-                if self.config.native {
+                if native_thread {
                     if let Some(native) = self.native.as_mut() {
                         let os_thread = remoteprocess::Thread::new(os_thread_id.unwrap())?;
                         trace.frames = native.merge_native_thread(&trace.frames, &os_thread)?;
                     }
+                } else if trace.owns_gil && trace.frames.is_empty() {
+                    // a thread holding the GIL without any python frames is in C code that took the GIL itself
+                    // (like a callback from a thread that an extension started), so show its native stack instead
+                    if let (Some(native), Some(os_thread_id)) = (self.native.as_mut(), os_thread_id) {
+                        match native.merge_native_thread(&trace.frames, &remoteprocess::Thread::new(os_thread_id)?) {
+                            Ok(frames) => trace.frames = frames,
+                            Err(err) => debug!("Failed to get native stack of GIL holder {:#X}: {}", trace.thread_id, err)
+                        }
+                    }
                 }
             }
+            if trace.owns_gil && trace.frames.is_empty() {
+                trace.frames.push(crate::stack_trace::native_gil_frame());
+            }
 
-            trace.os_thread_id = os_thread_id.map(|id| id as u64);
-            trace.owns_gil = trace.thread_id == gil_thread_id;
+            if self.config.capture_exceptions {
+                match exceptions::get_exception(&thread, address, &self.process, &self.version) {
+                    Ok(exception) => trace.exception = exception,
+                    Err(err) => debug!("Failed to get exception for thread {:#X}: {}", trace.thread_id, err)
+                }
+            }
+            if let Some(exception) = trace.exception.as_mut() {
+                for frame in &mut exception.traceback {
+                    frame.short_filename = self.shorten_filename(&frame.filename);
+                }
+            }
 
             trace.active = match os_thread_id.map(|id| thread_activity.get(&id)) {
-                Some(Some(active)) => *active,
-                _ => self._heuristic_thread_activity(&trace)
+                Some(Some(active)) => *active && !self.idle_rules.is_waiting(&trace.frames),
+                _ => !self.idle_rules.is_idle(&trace.frames)
             };
 
+            let mut python_frames = 0;
             for frame in &mut trace.frames {
                 frame.short_filename = self.shorten_filename(&frame.filename);
+                if let (true, Some(address)) = (self.config.qualified_names, frame.address()) {
+                    match locals::get_class_name::<<I::ThreadState as ThreadState>::FrameObject, _>(&self.process, address, &mut self.method_receivers) {
+                        Ok(Some(class_name)) => frame.name = format!("{}.{}", class_name, frame.name),
+                        Ok(None) => {},
+                        Err(err) => debug!("Failed to get class of {}: {}", frame.name, err)
+                    }
+                }
+                if let Some(address) = frame.address() {
+                    let max_args = match (self.config.dump_locals, self.config.capture_args) {
+                        (true, _) => None,
+                        (false, Some(max_args)) if python_frames < CAPTURE_ARGS_FRAMES => Some(max_args),
+                        _ => continue
+                    };
+                    python_frames += 1;
+                    match locals::get_locals::<<I::ThreadState as ThreadState>::FrameObject, _>(&self.process, address, &self.version, max_args) {
+                        Ok(locals) => frame.locals = Some(locals),
+                        Err(err) => debug!("Failed to get locals for {}: {}", frame.name, err)
+                    }
+                }
+            }
+            if (gc_collecting && trace.owns_gil) || trace.frames.iter().any(is_native_gc_frame) {
+                trace.frames.insert(0, gc_frame());
             }
 
-            // This seems to happen occasionally when scanning BSS addresses for valid interpeters
+            // threads waiting on the GIL want to run, so they're counted as active
+            #[cfg(unwind)]
+            {
+                if let (true, false, false, Some(native), Some(os_thread_id)) =
+                        (self.config.gil_wait, trace.active, trace.owns_gil, self.native.as_mut(), os_thread_id) {
+                    match native.waiting_for_gil(&remoteprocess::Thread::new(os_thread_id)?) {
+                        Ok(true) => {
+                            trace.active = true;
+                            trace.frames.insert(0, crate::stack_trace::gil_wait_frame());
+                        },
+                        Ok(false) => {},
+                        Err(err) => debug!("Failed to check if thread {:#X} is waiting on the GIL: {}", trace.thread_id, err)
+                    }
+                }
+                if let (true, false, Some(native), Some(os_thread_id)) =
+                        (self.config.lock_contention, trace.active, self.native.as_mut(), os_thread_id) {
+                    match native.waiting_for_lock(&remoteprocess::Thread::new(os_thread_id)?) {
+                        Ok(true) => {
+                            trace.active = true;
+                            trace.frames.insert(0, crate::stack_trace::lock_wait_frame());
+                        },
+                        Ok(false) => {},
+                        Err(err) => debug!("Failed to check if thread {:#X} is waiting on a lock: {}", trace.thread_id, err)
+                    }
+                }
+                // separate native code that is running without the GIL from python code that is blocked. This
+                // needs to know which thread holds the GIL, which isn't possible without the threadstate address
+                if native_thread && self.threadstate_address != 0 && trace.active && !trace.owns_gil &&
+                        !trace.waiting_for_gil() && !trace.waiting_for_lock() {
+                    crate::stack_trace::mark_gil_released(&mut trace.frames);
+                }
+            }
+            #[cfg(target_os="linux")]
+            {
+                if let (Some(kernel), Some(os_thread_id)) = (self.kernel.as_ref(), os_thread_id) {
+                    if let Some(frames) = kernel.frames(os_thread_id, trace.active, kernel_time) {
+                        trace.frames.splice(0..0, frames);
+                    }
+                }
+            }
             traces.push(trace);
-            if traces.len() > 4096 {
-                return Err(format_err!("Max thread recursion depth reached"));
+
+            // give up on this sample (and resume the process by dropping the lock) if it's taking too long
+            if let (Some(max_pause), true) = (self.config.max_pause, locked) {
+                if locked_at.elapsed() > max_pause {
+                    return Err(error::Error::PauseTimeExceeded{max_pause}.into());
+                }
             }
+        }
 
-            threads = thread.next();
+        // stacks of coroutines and greenlets that are waiting, rather than running on a thread
+        let mut waiting_traces = Vec::new();
+        if self.config.asyncio && self.version.major == 3 && self.version.minor == 7 {
+            for trace in &traces {
+                match asyncio::get_task_traces(trace, &self.process) {
+                    Ok(tasks) => waiting_traces.extend(tasks),
+                    Err(err) => debug!("Failed to get asyncio task stacks for thread {:#X}: {}", trace.thread_id, err)
+                }
+            }
+        }
+        if let (true, Some(runtime), Some(layout)) = (self.config.gevent, self.runtime_address, pyruntime::get_gc_layout(&self.version)) {
+            if self.last_greenlet_scan.is_none_or(|last| last.elapsed() >= GREENLET_SCAN_INTERVAL) {
+                self.last_greenlet_scan = Some(Instant::now());
+                match greenlet::find_greenlets(&self.process, runtime, &layout) {
+                    Ok(greenlets) => self.greenlets = greenlets,
+                    Err(err) => warn!("Failed to find greenlets: {}", err)
+                }
+            }
+            waiting_traces.extend(greenlet::get_greenlet_traces(&self.process, &self.greenlets, &thread_dicts));
+        }
+        for trace in &mut waiting_traces {
+            for frame in &mut trace.frames {
+                if frame.short_filename.is_none() {
+                    frame.short_filename = self.shorten_filename(&frame.filename);
+                }
+            }
+        }
+        traces.extend(waiting_traces);
+
+        // prefer the name from the threading module, since the OS truncates names to 15 characters
+        for trace in &mut traces {
+            trace.interpreter_id = interpreter_ids.get(&trace.thread_id).cloned().unwrap_or(0);
+            if self.config.group_by_interpreter {
+                trace.frames.push(Frame{name: format!("interpreter {}", trace.interpreter_id), filename: "<interpreter>".to_owned(),
+                                        module: None, short_filename: None, line: 0, frame_ptr: None, locals: None});
+            }
+            trace.thread_name = match self.python_thread_names.get(&trace.thread_id) {
+                Some(Some(name)) => Some(name.clone()),
+                _ => trace.os_thread_id.and_then(|id| thread_names.get(&(id as Tid)).cloned())
+            };
         }
         Ok(traces)
     }
 
-    // heuristic fallback for determining if a thread is active, used
-    // when we don't have the ability to get the thread information from the OS
-    fn _heuristic_thread_activity(&self, trace: &StackTrace) -> bool {
-        let frames = &trace.frames;
-        if frames.is_empty() {
-            true
-        } else {
-            let frame = &frames[0];
-            (frame.name == "wait" && frame.filename.ends_with("threading.py")) ||
-            (frame.name == "select" && frame.filename.ends_with("selectors.py")) ||
-            (frame.name == "poll" && (frame.filename.ends_with("asyncore.py") ||
-                                    frame.filename.contains("zmq") ||
-                                    frame.filename.contains("gevent") ||
-                                    frame.filename.contains("tornado")))
+    // gets every interpreter in the process along with its id. Interpreters are added to the head of
+    // the list as they're created, so when the ids aren't stored they're numbered from the tail,
+    // which is the main interpreter
+    fn _get_interpreters<I: InterpreterState>(&self) -> Result<Vec<(I, i64)>, Error> {
+        // subinterpreters created since we attached are ahead of the interpreter we found then
+        let mut address = match self.interp_head_address.map(|head| self.process.copy_struct::<usize>(head)) {
+            Some(Ok(head)) if head != 0 => head,
+            _ => self.interpreter_address
+        };
+
+        let mut interpreters = Vec::new();
+        while address != 0 {
+            let interp: I = self.process.copy_struct(address).context("Failed to copy PyInterpreterState from process")?;
+            address = interp.next() as usize;
+            interpreters.push(interp);
+            if interpreters.len() > 4096 {
+                return Err(format_err!("Max interpreter recursion depth reached"));
+            }
+        }
+
+        let count = interpreters.len();
+        Ok(interpreters.into_iter().enumerate()
+           .map(|(i, interp)| { let id = interp.id().unwrap_or((count - 1 - i) as i64); (interp, id) })
+           .collect())
+    }
+
+    /// Gets the name that multiprocessing gave this process, if it's one of its workers (python 3.7 only)
+    pub fn worker_name(&self) -> Option<String> {
+        if !(self.version.major == 3 && self.version.minor == 7) {
+            return None;
+        }
+        self.process.copy_struct(self.interpreter_address)
+            .map_err(Error::from)
+            .and_then(|interp: v3_7_0::_is| workers::get_worker_name(&self.process, interp.modules as usize))
+            .unwrap_or_else(|err| { debug!("Failed to get the name of process {}: {}", self.pid, err); None })
+    }
+
+    // reads the names of the threads from threading._active. Every thread gets an entry, even
+    // if it doesn't have a name, so that we don't rescan on every sample for unnamed threads
+    fn _scan_python_thread_names(&mut self, thread_ids: &[u64]) {
+        let names = self.process.copy_struct(self.interpreter_address)
+            .map_err(Error::from)
+            .and_then(|interp: v3_7_0::_is| threading::get_thread_names(&self.process, interp.modules as usize));
+        self.python_thread_names = match names {
+            Ok(names) => names.into_iter().map(|(thread_id, name)| (thread_id, Some(name))).collect(),
+            Err(err) => {
+                debug!("Failed to get the names of python threads: {}", err);
+                HashMap::new()
+            }
+        };
+        for &thread_id in thread_ids {
+            self.python_thread_names.entry(thread_id).or_insert(None);
+        }
+    }
+
+    // checks if the garbage collector is running a collection, which we can only tell for python 3.7
+    fn _gc_collecting(&self) -> bool {
+        let (runtime, layout) = match (self.runtime_address, pyruntime::get_gc_layout(&self.version)) {
+            (Some(runtime), Some(layout)) => (runtime, layout),
+            _ => return false
+        };
+        match self.process.copy_struct::<i32>(runtime + layout.collecting) {
+            Ok(collecting) => collecting != 0,
+            Err(err) => {
+                debug!("Failed to check if the garbage collector is running: {}", err);
+                false
+            }
+        }
+    }
+
+    // unwinds the python stacks of the threads at the given addresses, splitting the threads between
+    // the workers of the unwind pool. The caller is responsible for keeping the process paused so that
+    // this gets a consistent snapshot, and each worker gives up once the process has been paused for
+    // longer than max_pause
+    fn _get_stack_traces_parallel<T: ThreadState + 'static>(&self, addresses: &[usize], locked_at: Instant, locked: bool)
+            -> Result<Vec<Option<StackTrace>>, Error> {
+        let pool = self.unwind_pool.as_ref().ok_or_else(|| format_err!("Stack unwinding threads weren't started"))?;
+        let chunk_size = addresses.len().div_ceil(pool.workers());
+        let dirty_reads = self.config.dirty_reads;
+        let max_pause = self.config.max_pause.filter(|_| locked);
+
+        let (sender, receiver) = mpsc::channel();
+        let mut results = Vec::new();
+        for (worker, chunk) in addresses.chunks(chunk_size).enumerate() {
+            let chunk = chunk.to_vec();
+            let sender = sender.clone();
+            pool.run(worker, Box::new(move |process| {
+                let _ = sender.send((worker, unwind_threads::<T>(process, &chunk, dirty_reads, locked_at, max_pause)));
+            }))?;
+            results.push(None);
+        }
+        drop(sender);
+
+        // wait for every worker, so that none are still reading once the process is resumed, and
+        // put their traces back in the order of the threads
+        for (worker, worker_traces) in receiver {
+            results[worker] = Some(worker_traces);
+        }
+        let mut traces = Vec::with_capacity(addresses.len());
+        for worker_traces in results {
+            match worker_traces {
+                Some(worker_traces) => traces.extend(worker_traces?),
+                None => return Err(format_err!("Stack unwinding thread panicked"))
+            }
+        }
+        Ok(traces)
+    }
+
+    // whether a thread matches the thread id and thread name filters from the config
+    fn _include_thread(&self, python_thread_id: u64, os_thread_id: Option<Tid>, thread_names: &HashMap<Tid, String>) -> bool {
+        if !self.config.thread_ids.is_empty() &&
+           !self.config.thread_ids.iter().any(|&tid| tid == python_thread_id || Some(tid) == os_thread_id.map(|id| id as u64)) {
+            return false;
+        }
+
+        if let Some(regex) = self.thread_name_regex.as_ref() {
+            return match os_thread_id.and_then(|id| thread_names.get(&id)) {
+                Some(name) => regex.is_match(name),
+                None => false
+            };
+        }
+        true
+    }
+
+    // whether to get the native stack of a thread, with the native thread id and name filters from the config
+    fn _native_thread(&self, python_thread_id: u64, os_thread_id: Option<Tid>, thread_names: &HashMap<Tid, String>) -> bool {
+        if self.config.native_thread_ids.is_empty() && self.native_thread_name_regex.is_none() {
+            return true;
+        }
+
+        if self.config.native_thread_ids.iter().any(|&tid| tid == python_thread_id || Some(tid) == os_thread_id.map(|id| id as u64)) {
+            return true;
+        }
+
+        match self.native_thread_name_regex.as_ref() {
+            Some(regex) => {
+                let python_name = self.python_thread_names.get(&python_thread_id).and_then(|name| name.as_ref());
+                let os_name = os_thread_id.and_then(|id| thread_names.get(&id));
+                python_name.into_iter().chain(os_name).any(|name| regex.is_match(name))
+            },
+            None => false
         }
     }
 
@@ -367,7 +992,7 @@ impl PythonSpy {
         let mut path = Path::new(filename);
         while let Some(parent) = path.parent() {
             path = parent;
-            if !parent.join("__init__.py").exists() {
+            if !process_file_exists(self.pid, &parent.join("__init__.py")) {
                 break;
             }
         }
@@ -382,13 +1007,45 @@ impl PythonSpy {
         shortened
     }
 }
-/// Returns the version of python running in the process.
+
+/// Whether the python stacks of 'threads' threads get unwound on the --parallel workers
+fn unwinds_in_parallel(config: &Config, threads: usize) -> bool {
+    uses_unwind_pool(config) && threads > 1
+}
+
+/// Whether to start workers for --parallel. Native stacks are always unwound one thread at a time,
+/// since the native unwinder can't be shared
+fn uses_unwind_pool(config: &Config) -> bool {
+    config.unwind_workers > 1 && !config.native
+}
+
+// unwinds the stacks of the threads at 'addresses' on a worker of the unwind pool, giving up
+// once the process has been paused for longer than max_pause
+fn unwind_threads<T: ThreadState>(process: &Process, addresses: &[usize], dirty_reads: bool, locked_at: Instant,
+                                  max_pause: Option<Duration>) -> Result<Vec<Option<StackTrace>>, Error> {
+    let mut traces = Vec::with_capacity(addresses.len());
+    for &address in addresses {
+        let thread: T = process.copy_struct(address).context("Failed to copy PyThreadState")?;
+        traces.push(match dirty_reads {
+            true => get_checked_stack_trace(address, &thread, process),
+            false => Some(get_stack_trace(&thread, process)?)
+        });
+        if let Some(max_pause) = max_pause {
+            if locked_at.elapsed() > max_pause {
+                return Err(error::Error::PauseTimeExceeded{max_pause}.into());
+            }
+        }
+    }
+    Ok(traces)
+}
+
+/// Returns the version of python running in the process, along with how it was found
 fn get_python_version(python_info: &PythonProcessInfo, process: &remoteprocess::Process)
-        -> Result<Version, Error> {
+        -> Result<(Version, VersionSource), Error> {
     // If possible, grab the sys.version string from the processes memory (mac osx).
     if let Some(&addr) = python_info.get_symbol("Py_GetVersion.version") {
         info!("Getting version from symbol address");
-        return Ok(Version::scan_bytes(&process.copy(addr as usize, 128)?)?);
+        return Ok((Version::scan_bytes(&process.copy(addr as usize, 128)?)?, VersionSource::SysVersion));
     }
 
     // otherwise get version info from scanning BSS section for sys.version string
@@ -396,7 +1053,7 @@ fn get_python_version(python_info: &PythonProcessInfo, process: &remoteprocess::
     let bss = process.copy(python_info.python_binary.bss_addr as usize,
                            python_info.python_binary.bss_size as usize)?;
     match Version::scan_bytes(&bss) {
-        Ok(version) => return Ok(version),
+        Ok(version) => return Ok((version, VersionSource::SysVersion)),
         Err(err) => {
             info!("Failed to get version from BSS section: {}", err);
             // try again if there is a libpython.so
@@ -405,13 +1062,24 @@ fn get_python_version(python_info: &PythonProcessInfo, process: &remoteprocess::
                 let bss = process.copy(libpython.bss_addr as usize,
                                        libpython.bss_size as usize)?;
                 match Version::scan_bytes(&bss) {
-                    Ok(version) => return Ok(version),
+                    Ok(version) => return Ok((version, VersionSource::SysVersion)),
                     Err(err) => info!("Failed to get version from libpython BSS section: {}", err)
                 }
             }
         }
     }
 
+    // stripped binaries might not have section headers for us to find the BSS section from, so
+    // look through all the writable memory that they have mapped in
+    info!("Getting version from the writable memory of the python binaries");
+    for binary in python_info.binaries() {
+        for data in copy_binary_memory(process, &python_info.maps, binary, true) {
+            if let Ok(version) = Version::scan_bytes(&data) {
+                return Ok((version, VersionSource::SysVersion));
+            }
+        }
+    }
+
     // the python_filename might have the version encoded in it (/usr/bin/python3.5 etc).
     // try reading that in (will miss patch level on python, but that shouldn't matter)
     info!("Trying to get version from path: {}", python_info.python_filename);
@@ -422,12 +1090,33 @@ fn get_python_version(python_info: &PythonProcessInfo, process: &remoteprocess::
                 let tokens: Vec<&str> = python[6..].split('.').collect();
                 if tokens.len() >= 2 {
                     if let (Ok(major), Ok(minor)) = (tokens[0].parse::<u64>(), tokens[1].parse::<u64>()) {
-                        return Ok(Version{major, minor, patch:0, release_flags: "".to_owned()})
+                        return Ok((Version{major, minor, patch:0, release_flags: "".to_owned()}, VersionSource::Filename))
                     }
                 }
             }
         }
     }
+
+    // look for the version string that is compiled into the read only data of python
+    info!("Trying to get version from the read only memory of the python binaries");
+    for binary in python_info.binaries() {
+        for data in copy_binary_memory(process, &python_info.maps, binary, false) {
+            if let Some(version) = Version::scan_signature(&data) {
+                return Ok((version, VersionSource::Signature));
+            }
+        }
+    }
+
+    // as a last resort, see which layout of the interpreter state matches what is in memory. Newer
+    // versions are tried first, and versions that share a layout can't be told apart (so python 3.8
+    // shows up as 3.7, and 3.4 as 3.5)
+    info!("Trying to guess version from the layout of the interpreter");
+    for &(major, minor) in &[(3, 7), (3, 6), (3, 5), (3, 3), (2, 7)] {
+        let version = Version{major, minor, patch: 0, release_flags: "".to_owned()};
+        if scan_for_interpreter(python_info, process, &version).is_ok() {
+            return Ok((version, VersionSource::Layout));
+        }
+    }
     Err(format_err!("Failed to find python version from target process"))
 }
 
@@ -459,21 +1148,76 @@ fn get_interpreter_address(python_info: &PythonProcessInfo,
         }
     };
     info!("Failed to get interp_head from symbols, scanning BSS section from main binary");
+    scan_for_interpreter(python_info, process, version)
+}
 
+// Looks for the interpreter in memory, for when it can't be found from symbols
+fn scan_for_interpreter(python_info: &PythonProcessInfo,
+                        process: &remoteprocess::Process,
+                        version: &Version) -> Result<usize, Error> {
     // try scanning the BSS section of the binary for things that might be the interpreterstate
-    match get_interpreter_address_from_binary(&python_info.python_binary, &python_info.maps, process, version) {
-        Ok(addr) => Ok(addr),
+    let err = match get_interpreter_address_from_binary(&python_info.python_binary, &python_info.maps, process, version) {
+        Ok(addr) => return Ok(addr),
         // Before giving up, try again if there is a libpython.so
         Err(err) => {
             match python_info.libpython_binary {
                 Some(ref libpython) => {
                     info!("Failed to get interpreter from binary BSS, scanning libpython BSS");
-                    Ok(get_interpreter_address_from_binary(libpython, &python_info.maps, process, version)?)
+                    match get_interpreter_address_from_binary(libpython, &python_info.maps, process, version) {
+                        Ok(addr) => return Ok(addr),
+                        Err(err) => err
+                    }
                 },
-                None => Err(err)
+                None => err
+            }
+        }
+    };
+
+    // static builds keep _PyRuntime in the .data section, and stripped binaries might not have
+    // section headers for finding the BSS section, so try all the writable memory of the binaries
+    info!("Failed to get interpreter from BSS, scanning writable memory of the python binaries");
+    for binary in python_info.binaries() {
+        for data in copy_binary_memory(process, &python_info.maps, binary, true) {
+            #[allow(clippy::cast_ptr_alignment)]
+            let addrs = unsafe { slice::from_raw_parts(data.as_ptr() as *const usize, data.len() / size_of::<usize>()) };
+            if let Ok(addr) = check_interpreter_addresses(addrs, &python_info.maps, process, version) {
+                info!("Found interpreter by scanning the writable memory of {}", binary.filename);
+                return Ok(addr);
             }
         }
     }
+    Err(err)
+}
+
+// Copies the memory mapped in from a binary. This is either the writable memory (holding the .data
+// and .bss sections, including the anonymous mapping that follows with the rest of the .bss) or
+// the read only memory (holding the code and constants)
+fn copy_binary_memory(process: &remoteprocess::Process, maps: &[MapRange], binary: &BinaryInfo, writable: bool) -> Vec<Vec<u8>> {
+    let filename = match maps.iter().find(|m| m.start() as u64 == binary.addr) {
+        Some(map) => map.filename(),
+        None => return Vec::new()
+    };
+
+    let mut ret = Vec::new();
+    let mut previous: Option<&MapRange> = None;
+    for map in maps {
+        let in_binary = map.filename().is_some() && map.filename() == filename;
+        let rest_of_bss = writable && map.filename().is_none() && previous.is_some_and(|p| p.start() + p.size() == map.start());
+        previous = if in_binary && map.is_write() { Some(map) } else { None };
+
+        if !map.is_read() || !((in_binary && map.is_write() == writable) || rest_of_bss) {
+            continue;
+        }
+        if map.size() > MAX_SCAN_SIZE {
+            info!("Skipping scanning {} bytes at {:016x}", map.size(), map.start());
+            continue;
+        }
+        match process.copy(map.start(), map.size()) {
+            Ok(data) => ret.push(data),
+            Err(err) => debug!("Failed to copy memory at {:016x}: {}", map.start(), err)
+        }
+    }
+    ret
 }
 
 fn get_interpreter_address_from_binary(binary: &BinaryInfo,
@@ -547,8 +1291,94 @@ fn check_interpreter_addresses(addrs: &[usize],
         Version{major: 3, minor: 4, ..} => check::<v3_5_5::_is>(addrs, maps, process),
         Version{major: 3, minor: 3, ..} => check::<v3_3_7::_is>(addrs, maps, process),
         Version{major: 2, minor: 3...7, ..} => check::<v2_7_15::_is>(addrs, maps, process),
-        _ => Err(format_err!("Unsupported version of Python: {}", version))
+        _ => Err(error::Error::UnsupportedPythonVersion{found: version.to_string()}.into())
+    }
+}
+
+// largest region of memory to copy when scanning binaries for the interpreter or version
+const MAX_SCAN_SIZE: usize = 64 * 1024 * 1024;
+
+// symbols that are only defined by python itself, for recognizing it in libraries that embed it
+const PYTHON_SYMBOLS: &[&str] = &["_PyRuntime", "interp_head", "_PyThreadState_Current", "Py_GetVersion"];
+
+// parses a binary that the process has mapped in. Processes running in containers see their own
+// filesystem, so on linux binaries are opened through /proc/<pid>/root to profile them from the host
+#[cfg(target_os="linux")]
+fn parse_process_binary(pid: Pid, filename: &str, addr: u64, size: u64) -> Result<BinaryInfo, Error> {
+    let path = remoteprocess::namespace_path(pid, filename);
+    let mut binary = parse_binary(&path.to_string_lossy(), addr, size)?;
+    binary.filename = filename.to_owned();
+    Ok(binary)
+}
+
+#[cfg(not(target_os="linux"))]
+fn parse_process_binary(_pid: Pid, filename: &str, addr: u64, size: u64) -> Result<BinaryInfo, Error> {
+    parse_binary(filename, addr, size)
+}
+
+// checks if a file exists in the filesystem that the process sees
+#[cfg(target_os="linux")]
+fn process_file_exists(pid: Pid, path: &Path) -> bool {
+    path.to_str().is_some_and(|path| remoteprocess::namespace_path(pid, path).exists())
+}
+
+#[cfg(not(target_os="linux"))]
+fn process_file_exists(_pid: Pid, path: &Path) -> bool {
+    path.exists()
+}
+
+// checks if a binary defines python's symbols. Undefined symbols (like the references to python
+// from extension modules) end up at the load address of the binary, so those are skipped
+fn has_python_symbols(binary: &BinaryInfo) -> bool {
+    PYTHON_SYMBOLS.iter().any(|symbol| binary.symbols.get(*symbol).is_some_and(|&addr| addr != binary.offset))
+}
+
+// parses every library loaded into the process, returning the ones that define python's symbols
+fn find_embedded_python(pid: Pid, maps: &[MapRange], exe: &str) -> Vec<BinaryInfo> {
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    for map in maps.iter().filter(|m| m.is_exec()) {
+        let filename = match &map.filename() {
+            Some(filename) if filename != exe && !filename.starts_with('[') => filename.to_string(),
+            _ => continue
+        };
+        if !seen.insert(filename.clone()) {
+            continue;
+        }
+        match parse_process_binary(pid, &filename, map.start() as u64, map.size() as u64) {
+            Ok(binary) if has_python_symbols(&binary) => {
+                info!("Found python symbols in {}", filename);
+                candidates.push(binary);
+            },
+            Ok(_) => {},
+            Err(err) => debug!("Failed to parse {}: {}", filename, err)
+        }
     }
+    candidates
+}
+
+// picks which copy of python to profile when more than one is loaded, preferring the first one
+// whose runtime has been initialized (since programs can load python without ever starting it)
+fn choose_libpython(process: &remoteprocess::Process, mut candidates: Vec<BinaryInfo>) -> Option<BinaryInfo> {
+    if candidates.len() <= 1 {
+        return candidates.pop();
+    }
+    let chosen = candidates.iter().position(|binary| is_initialized(process, binary)).unwrap_or(0);
+    let filenames: Vec<&str> = candidates.iter().map(|binary| binary.filename.as_str()).collect();
+    warn!("Found {} copies of python loaded ({}), using {}", candidates.len(), filenames.join(", "), filenames[chosen]);
+    Some(candidates.swap_remove(chosen))
+}
+
+// checks if a copy of python has been initialized. The initialized flags come first in
+// _PyRuntime from python 3.7, and before that the list of interpreters is empty until startup
+fn is_initialized(process: &remoteprocess::Process, binary: &BinaryInfo) -> bool {
+    if let Some(&runtime) = binary.symbols.get("_PyRuntime") {
+        return process.copy_struct::<[i32; 4]>(runtime as usize).is_ok_and(|flags| flags.iter().any(|&flag| flag != 0));
+    }
+    if let Some(&interp_head) = binary.symbols.get("interp_head") {
+        return process.copy_struct::<usize>(interp_head as usize).is_ok_and(|head| head != 0);
+    }
+    false
 }
 
 /// Holds information about the python process: memory map layout, parsed binary info
@@ -584,17 +1414,6 @@ impl PythonProcessInfo {
                 map.filename().as_ref().unwrap_or(&"".to_owned()));
         }
 
-        // on linux, support profiling processes running in docker containers by setting
-        // the namespace to match that of the target process when reading in binaries
-        #[cfg(target_os="linux")]
-        let _namespace = match remoteprocess::Namespace::new(process.pid) {
-            Ok(ns) => Some(ns),
-            Err(e) => {
-                warn!("Failed to set namespace: {}", e);
-                None
-            }
-        };
-
         // parse the main python binary
         let (python_binary, python_filename) = {
             // Get the memory address for the executable by matching against virtual memory maps
@@ -618,7 +1437,7 @@ impl PythonProcessInfo {
 
             // TODO: consistent types? u64 -> usize? for map.start etc
             #[allow(unused_mut)]
-            let mut python_binary = parse_binary(&filename, map.start() as u64, map.size() as u64)?;
+            let mut python_binary = parse_process_binary(process.pid, &filename, map.start() as u64, map.size() as u64)?;
 
             // windows symbols are stored in separate files (.pdb), load
             #[cfg(windows)]
@@ -640,27 +1459,33 @@ impl PythonProcessInfo {
             (python_binary, filename.clone())
         };
 
-        // likewise handle libpython for python versions compiled with --enabled-shared
-         let libpython_binary = {
-            let libmap = maps.iter()
-                .find(|m| if let Some(ref pathname) = &m.filename() {
-                    is_python_lib(pathname) && m.is_exec()
-                } else {
-                    false
-                });
-
-            let mut libpython_binary: Option<BinaryInfo> = None;
-            if let Some(libpython) = libmap {
+        // likewise handle libpython for python versions compiled with --enabled-shared, and for
+        // programs that embed python (like blender or uwsgi). These can have more than one loaded
+        let libpython_binary = {
+            let mut candidates: Vec<BinaryInfo> = Vec::new();
+            for libpython in maps.iter().filter(|m| m.is_exec()) {
                 if let Some(filename) = &libpython.filename() {
+                    if !is_python_lib(filename) || candidates.iter().any(|c| &c.filename == filename) {
+                        continue;
+                    }
                     info!("Found libpython binary @ {}", filename);
                     #[allow(unused_mut)]
-                    let mut parsed = parse_binary(filename, libpython.start() as u64, libpython.size() as u64)?;
+                    let mut parsed = parse_process_binary(process.pid, filename, libpython.start() as u64, libpython.size() as u64)?;
                     #[cfg(windows)]
                     parsed.symbols.extend(get_windows_python_symbols(process.pid, filename, libpython.start() as u64)?);
-                    libpython_binary = Some(parsed);
+                    candidates.push(parsed);
                 }
             }
 
+            // embedded interpreters can be linked in under any name, so look for one by its symbols
+            if candidates.is_empty() && !has_python_symbols(&python_binary) {
+                info!("Failed to find libpython by name, looking for python symbols in every loaded library");
+                candidates = find_embedded_python(process.pid, &maps, &filename);
+            }
+
+            #[allow(unused_mut)]
+            let mut libpython_binary = choose_libpython(process, candidates);
+
             // On OSX, it's possible that the Python library is a dylib loaded up from the system
             // framework (like /System/Library/Frameworks/Python.framework/Versions/2.7/Python)
             // In this case read in the dyld_info information and figure out the filename from there
@@ -703,6 +1528,11 @@ impl PythonProcessInfo {
         Ok(PythonProcessInfo{python_binary, libpython_binary, maps, python_filename})
     }
 
+    /// The python binary, followed by libpython if there is one
+    pub fn binaries(&self) -> impl Iterator<Item=&BinaryInfo> {
+        std::iter::once(&self.python_binary).chain(self.libpython_binary.iter())
+    }
+
     pub fn get_symbol(&self, symbol: &str) -> Option<&u64> {
         if let Some(addr) = self.python_binary.symbols.get(symbol) {
             info!("got symbol {} (0x{:016x}) from python binary", symbol, addr);
@@ -817,6 +1647,15 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_unwinds_in_parallel() {
+        let config = Config{unwind_workers: 4, ..Default::default()};
+        assert!(unwinds_in_parallel(&config, 8));
+        assert!(!unwinds_in_parallel(&config, 1));
+        assert!(!unwinds_in_parallel(&Config{native: true, ..config.clone()}, 8));
+        assert!(!unwinds_in_parallel(&Config{unwind_workers: 1, ..config}, 8));
+    }
+
     #[cfg(target_os="macos")]
     #[test]
     fn test_python_frameworks() {
@@ -0,0 +1,631 @@
+/* Compact binary format for storing the raw samples collected from a python process.
+
+Unlike the other output formats, this doesn't aggregate samples together, so that a recording
+can be converted into any of the other formats after the fact (with 'py-spy convert').
+
+The file starts with an 8 byte magic number and a single byte format version, followed by a
+stream of records. Version 1 files are the same, except that they don't store thread names or
+interpreter ids, version 2 files don't have thread records and version 3 files don't have resource
+records. Each record starts with a tag byte:
+
+    TAG_STRING:   varint length, utf8 bytes. Strings are implicitly numbered in the order they
+                  are written, starting at 0
+    TAG_FRAME:    varint name id, varint filename id, varint (short filename id + 1, or 0 if none),
+                  varint (module id + 1, or 0 if none), zigzag varint line number. Frames are also
+                  implicitly numbered in the order they are written
+    TAG_SAMPLE:   varint microseconds since the previous sample or epoch, varint number of traces, and then
+                  for each trace: varint python thread id, flags byte, optional varint os thread id,
+                  optional varint thread name string id, optional varint interpreter id, varint number
+                  of frames, and a varint frame id for each frame
+    TAG_METADATA: varint key string id, varint value string id
+    TAG_EPOCH:    varint microseconds since the previous sample or epoch, varint label string id.
+                  Marks a point in the recording, like sampling being paused or resumed
+    TAG_THREAD:   varint microseconds since the previous sample or epoch, varint python thread id, event
+                  byte (0 for started, 1 for exited), varint (thread name string id + 1, or 0 if none)
+    TAG_RESOURCES: varint microseconds since the previous sample or epoch, varint resident set size in
+                  bytes, varint microseconds of CPU time used, and then varint (count + 1, or 0 if unknown)
+                  for each of the open file descriptors, bytes read and bytes written
+
+Strings and frames are written out the first time they are seen, so that each sample only
+needs to store a couple bytes per frame.
+
+Records are written out as they're made rather than held until the recording ends, so a long
+recording doesn't build up in memory and the file can be converted while it's still being written.
+Recordings can be appended to an existing capture (with 'py-spy record --append'), which adds
+their records after the existing ones with timestamps carrying on from the end of the capture.
+*/
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+
+use failure::Error;
+
+use crate::resources::ResourceUsage;
+use crate::stack_trace::{StackTrace, Frame};
+use crate::thread_events::{ThreadChange, ThreadEvent};
+
+const MAGIC: &[u8] = b"PYSPYRAW";
+const FORMAT_VERSION: u8 = 4;
+
+const TAG_STRING: u8 = 1;
+const TAG_FRAME: u8 = 2;
+const TAG_SAMPLE: u8 = 3;
+const TAG_METADATA: u8 = 4;
+const TAG_EPOCH: u8 = 5;
+const TAG_THREAD: u8 = 6;
+const TAG_RESOURCES: u8 = 7;
+
+const FLAG_ACTIVE: u8 = 1;
+const FLAG_OWNS_GIL: u8 = 2;
+const FLAG_OS_THREAD_ID: u8 = 4;
+const FLAG_THREAD_NAME: u8 = 8;
+const FLAG_INTERPRETER_ID: u8 = 16;
+
+/// Returns true if the data looks like it was written by RawCaptureWriter
+pub fn is_raw_capture(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encodes stack traces into the raw capture format, writing each record to 'out' as it's added.
+/// By default the capture is kept in memory, and written out with 'write'
+pub struct RawCaptureWriter<W: Write = Vec<u8>> {
+    out: W,
+    /// The record being encoded, before it's written to 'out'
+    buffer: Vec<u8>,
+    /// The first error writing to 'out', which is returned by 'flush'
+    error: Option<std::io::Error>,
+    strings: HashMap<String, u64>,
+    frames: HashMap<Frame, u64>,
+    last_timestamp: Duration,
+    /// Added to timestamps, so that a recording appended to a capture starts where the capture ended
+    time_offset: Duration,
+    /// Whether the capture already has a start time, which is kept when appending to it
+    has_start_time: bool,
+}
+
+impl RawCaptureWriter {
+    pub fn new() -> RawCaptureWriter {
+        RawCaptureWriter::with_output(Vec::new())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        w.write_all(&self.out)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> RawCaptureWriter<W> {
+    /// Starts a new capture, which is written to 'out' as it's recorded
+    pub fn with_output(out: W) -> RawCaptureWriter<W> {
+        let mut writer = RawCaptureWriter{out, buffer: Vec::new(), error: None, strings: HashMap::new(), frames: HashMap::new(),
+                                          last_timestamp: Duration::from_secs(0), time_offset: Duration::from_secs(0),
+                                          has_start_time: false};
+        writer.buffer.extend_from_slice(MAGIC);
+        writer.buffer.push(FORMAT_VERSION);
+        writer.write_record();
+        writer
+    }
+
+    /// Continues the existing capture in 'data', writing the new records to 'out' (which should add
+    /// them to the end of 'data'). Timestamps passed in afterwards are relative to the
+    /// start of the new recording, and are stored as carrying on from the end of the existing one
+    pub fn append(data: &[u8], out: W) -> Result<RawCaptureWriter<W>, Error> {
+        let mut reader = RawCaptureReader::new(data)?;
+        if data[MAGIC.len()] != FORMAT_VERSION {
+            return Err(format_err!("Can't append to a raw capture written by an older version of py-spy"));
+        }
+        let mut has_start_time = false;
+        while let Some(event) = reader.next_event()? {
+            if let Event::Metadata{key, ..} = event {
+                has_start_time |= key == "start_time";
+            }
+        }
+        let strings = reader.strings.into_iter().enumerate().map(|(id, string)| (string, id as u64)).collect();
+        let frames = reader.frames.into_iter().enumerate().map(|(id, frame)| (frame, id as u64)).collect();
+        let end = reader.timestamp;
+        Ok(RawCaptureWriter{out, buffer: Vec::new(), error: None, strings, frames, last_timestamp: end, time_offset: end,
+                            has_start_time})
+    }
+
+    /// Flushes the capture to 'out', returning the first error from writing it
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err.into());
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Adds a key/value pair describing the recording (python version, sampling rate etc)
+    pub fn metadata(&mut self, key: &str, value: &str) {
+        // timestamps are relative to the start of the first recording in the capture
+        if key == "start_time" && std::mem::replace(&mut self.has_start_time, true) {
+            return;
+        }
+        let key = self.intern_string(key);
+        let value = self.intern_string(value);
+        self.buffer.push(TAG_METADATA);
+        write_varint(&mut self.buffer, key);
+        write_varint(&mut self.buffer, value);
+        self.write_record();
+    }
+
+    /// Adds a marker at 'timestamp' since the start of the recording
+    pub fn epoch(&mut self, timestamp: Duration, label: &str) {
+        let label = self.intern_string(label);
+        self.buffer.push(TAG_EPOCH);
+        self.write_timestamp(timestamp);
+        write_varint(&mut self.buffer, label);
+        self.write_record();
+    }
+
+    /// Records a thread starting or exiting
+    pub fn thread_event(&mut self, change: &ThreadChange) {
+        let name = change.thread_name.as_ref().map_or(0, |name| self.intern_string(name) + 1);
+        self.buffer.push(TAG_THREAD);
+        self.write_timestamp(change.timestamp);
+        write_varint(&mut self.buffer, change.thread_id);
+        self.buffer.push(match change.event { ThreadEvent::Started => 0, ThreadEvent::Exited => 1 });
+        write_varint(&mut self.buffer, name);
+        self.write_record();
+    }
+
+    /// Records the resources the process was using at 'timestamp'
+    pub fn resource_usage(&mut self, timestamp: Duration, usage: &ResourceUsage) {
+        self.buffer.push(TAG_RESOURCES);
+        self.write_timestamp(timestamp);
+        write_varint(&mut self.buffer, usage.rss);
+        write_varint(&mut self.buffer, usage.cpu_time.as_micros() as u64);
+        for count in &[usage.open_fds, usage.read_bytes, usage.write_bytes] {
+            write_varint(&mut self.buffer, count.map_or(0, |count| count + 1));
+        }
+        self.write_record();
+    }
+
+    /// Adds the stack traces from a single sample, taken at 'timestamp' since the start of the recording
+    pub fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) {
+        // make sure every frame has been written out before the sample references it
+        let frame_ids: Vec<Vec<u64>> = traces.iter()
+            .map(|trace| trace.frames.iter().map(|frame| self.intern_frame(frame)).collect())
+            .collect();
+        let thread_names: Vec<Option<u64>> = traces.iter()
+            .map(|trace| trace.thread_name.as_ref().map(|name| self.intern_string(name)))
+            .collect();
+
+        self.buffer.push(TAG_SAMPLE);
+        self.write_timestamp(timestamp);
+        write_varint(&mut self.buffer, traces.len() as u64);
+        for ((trace, frames), thread_name) in traces.iter().zip(frame_ids.iter()).zip(thread_names) {
+            let mut flags = 0;
+            if trace.active { flags |= FLAG_ACTIVE; }
+            if trace.owns_gil { flags |= FLAG_OWNS_GIL; }
+            if trace.os_thread_id.is_some() { flags |= FLAG_OS_THREAD_ID; }
+            if thread_name.is_some() { flags |= FLAG_THREAD_NAME; }
+            if trace.interpreter_id != 0 { flags |= FLAG_INTERPRETER_ID; }
+
+            write_varint(&mut self.buffer, trace.thread_id);
+            self.buffer.push(flags);
+            if let Some(os_thread_id) = trace.os_thread_id {
+                write_varint(&mut self.buffer, os_thread_id);
+            }
+            if let Some(thread_name) = thread_name {
+                write_varint(&mut self.buffer, thread_name);
+            }
+            if trace.interpreter_id != 0 {
+                write_varint(&mut self.buffer, trace.interpreter_id as u64);
+            }
+            write_varint(&mut self.buffer, frames.len() as u64);
+            for &id in frames {
+                write_varint(&mut self.buffer, id);
+            }
+        }
+        self.write_record();
+    }
+
+    // writes out the records in the buffer, which includes any strings and frames they introduced
+    fn write_record(&mut self) {
+        if self.error.is_none() {
+            self.error = self.out.write_all(&self.buffer).err();
+        }
+        self.buffer.clear();
+    }
+
+    fn write_timestamp(&mut self, timestamp: Duration) {
+        let timestamp = timestamp + self.time_offset;
+        // timestamps shouldn't go backwards, but saturate to 0 if they do
+        let delta = if timestamp > self.last_timestamp { timestamp - self.last_timestamp } else { Duration::from_secs(0) };
+        self.last_timestamp = std::cmp::max(timestamp, self.last_timestamp);
+        write_varint(&mut self.buffer, delta.as_secs() * 1_000_000 + u64::from(delta.subsec_micros()));
+    }
+
+    fn intern_string(&mut self, value: &str) -> u64 {
+        if let Some(&id) = self.strings.get(value) {
+            return id;
+        }
+        let id = self.strings.len() as u64;
+        self.buffer.push(TAG_STRING);
+        write_varint(&mut self.buffer, value.len() as u64);
+        self.buffer.extend_from_slice(value.as_bytes());
+        self.strings.insert(value.to_owned(), id);
+        id
+    }
+
+    fn intern_frame(&mut self, frame: &Frame) -> u64 {
+        // frame pointers aren't meaningful after the fact and locals are only used by dumps, don't store them
+        let key = Frame{frame_ptr: None, locals: None, ..frame.clone()};
+        if let Some(&id) = self.frames.get(&key) {
+            return id;
+        }
+
+        let name = self.intern_string(&frame.name);
+        let filename = self.intern_string(&frame.filename);
+        let short_filename = frame.short_filename.as_ref().map_or(0, |f| self.intern_string(f) + 1);
+        let module = frame.module.as_ref().map_or(0, |m| self.intern_string(m) + 1);
+
+        let id = self.frames.len() as u64;
+        self.buffer.push(TAG_FRAME);
+        write_varint(&mut self.buffer, name);
+        write_varint(&mut self.buffer, filename);
+        write_varint(&mut self.buffer, short_filename);
+        write_varint(&mut self.buffer, module);
+        write_varint(&mut self.buffer, zigzag_encode(frame.line));
+        self.frames.insert(key, id);
+        id
+    }
+}
+
+/// An event read back from a raw capture
+#[derive(Debug)]
+pub enum Event {
+    Sample{timestamp: Duration, traces: Vec<StackTrace>},
+    Metadata{key: String, value: String},
+    Epoch{timestamp: Duration, label: String},
+    Thread(ThreadChange),
+    Resources{timestamp: Duration, usage: ResourceUsage},
+}
+
+/// Decodes a raw capture file, returning each sample or metadata record in the order written
+pub struct RawCaptureReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    strings: Vec<String>,
+    frames: Vec<Frame>,
+    timestamp: Duration,
+}
+
+impl<'a> RawCaptureReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<RawCaptureReader<'a>, Error> {
+        if !is_raw_capture(data) {
+            return Err(format_err!("Not a py-spy raw capture file"));
+        }
+
+        let version = data.get(MAGIC.len()).cloned().unwrap_or(0);
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(format_err!("Unsupported raw capture version {}", version));
+        }
+
+        Ok(RawCaptureReader{data, position: MAGIC.len() + 1, strings: Vec::new(), frames: Vec::new(),
+                            timestamp: Duration::from_secs(0)})
+    }
+
+    pub fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        while self.position < self.data.len() {
+            let tag = self.read_byte()?;
+            match tag {
+                TAG_STRING => {
+                    let length = self.read_varint()? as usize;
+                    let bytes = self.read_bytes(length)?;
+                    self.strings.push(String::from_utf8(bytes.to_vec())?);
+                },
+                TAG_FRAME => {
+                    let name = self.read_string()?;
+                    let filename = self.read_string()?;
+                    let short_filename = self.read_optional_string()?;
+                    let module = self.read_optional_string()?;
+                    let line = zigzag_decode(self.read_varint()?);
+                    self.frames.push(Frame{name, filename, short_filename, module, line, frame_ptr: None, locals: None});
+                },
+                TAG_METADATA => {
+                    let key = self.read_string()?;
+                    let value = self.read_string()?;
+                    return Ok(Some(Event::Metadata{key, value}));
+                },
+                TAG_EPOCH => {
+                    let delta = self.read_varint()?;
+                    self.timestamp += Duration::from_micros(delta);
+                    let label = self.read_string()?;
+                    return Ok(Some(Event::Epoch{timestamp: self.timestamp, label}));
+                },
+                TAG_THREAD => {
+                    let delta = self.read_varint()?;
+                    self.timestamp += Duration::from_micros(delta);
+                    let thread_id = self.read_varint()?;
+                    let event = match self.read_byte()? {
+                        0 => ThreadEvent::Started,
+                        1 => ThreadEvent::Exited,
+                        other => return Err(format_err!("Invalid thread event {} in raw capture", other))
+                    };
+                    let thread_name = self.read_optional_string()?;
+                    return Ok(Some(Event::Thread(ThreadChange{timestamp: self.timestamp, thread_id, thread_name, event})));
+                },
+                TAG_RESOURCES => {
+                    let delta = self.read_varint()?;
+                    self.timestamp += Duration::from_micros(delta);
+                    let rss = self.read_varint()?;
+                    let cpu_time = Duration::from_micros(self.read_varint()?);
+                    let mut read_count = || -> Result<Option<u64>, Error> { Ok(self.read_varint()?.checked_sub(1)) };
+                    let (open_fds, read_bytes, write_bytes) = (read_count()?, read_count()?, read_count()?);
+                    let usage = ResourceUsage{rss, cpu_time, open_fds, read_bytes, write_bytes};
+                    return Ok(Some(Event::Resources{timestamp: self.timestamp, usage}));
+                },
+                TAG_SAMPLE => {
+                    let delta = self.read_varint()?;
+                    self.timestamp += Duration::from_micros(delta);
+                    let count = self.read_varint()? as usize;
+                    let mut traces = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let thread_id = self.read_varint()?;
+                        let flags = self.read_byte()?;
+                        let os_thread_id = if flags & FLAG_OS_THREAD_ID != 0 { Some(self.read_varint()?) } else { None };
+                        let thread_name = if flags & FLAG_THREAD_NAME != 0 { Some(self.read_string()?) } else { None };
+                        let interpreter_id = if flags & FLAG_INTERPRETER_ID != 0 { self.read_varint()? as i64 } else { 0 };
+                        let frame_count = self.read_varint()? as usize;
+                        let mut frames = Vec::with_capacity(frame_count);
+                        for _ in 0..frame_count {
+                            let id = self.read_varint()? as usize;
+                            match self.frames.get(id) {
+                                Some(frame) => frames.push(frame.clone()),
+                                None => return Err(format_err!("Invalid frame id {} in raw capture", id))
+                            }
+                        }
+                        traces.push(StackTrace{thread_id, os_thread_id, thread_name, interpreter_id, frames,
+                                               active: flags & FLAG_ACTIVE != 0,
+                                               owns_gil: flags & FLAG_OWNS_GIL != 0, exception: None, awaiting: None, pid: None});
+                    }
+                    return Ok(Some(Event::Sample{timestamp: self.timestamp, traces}));
+                },
+                _ => return Err(format_err!("Unknown record type {} in raw capture at offset {}", tag, self.position - 1))
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if self.position + length > self.data.len() {
+            return Err(format_err!("Unexpected end of raw capture file"));
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn read_varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_byte()?;
+            if shift >= 64 {
+                return Err(format_err!("Invalid varint in raw capture"));
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_string(&mut self) -> Result<String, Error> {
+        let id = self.read_varint()? as usize;
+        match self.strings.get(id) {
+            Some(s) => Ok(s.clone()),
+            None => Err(format_err!("Invalid string id {} in raw capture", id))
+        }
+    }
+
+    fn read_optional_string(&mut self) -> Result<Option<String>, Error> {
+        match self.read_varint()? as usize {
+            0 => Ok(None),
+            id => match self.strings.get(id - 1) {
+                Some(s) => Ok(Some(s.clone())),
+                None => Err(format_err!("Invalid string id {} in raw capture", id - 1))
+            }
+        }
+    }
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn zigzag_encode(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+fn zigzag_decode(value: u64) -> i32 {
+    let value = value as u32;
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, line: i32) -> Frame {
+        Frame{name: name.to_owned(), filename: "test.py".to_owned(), module: None,
+              short_filename: Some("test.py".to_owned()), line, frame_ptr: Some("0x1234".to_owned()), locals: None}
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let traces = vec![StackTrace{thread_id: 0x7f00_0000_1234, os_thread_id: Some(1000),
+                                     thread_name: Some("MainThread".to_owned()), interpreter_id: 2, active: true, owns_gil: true,
+                                     frames: vec![frame("inner", 10), frame("outer", -1)], exception: None, awaiting: None, pid: None},
+                          StackTrace{thread_id: 12, os_thread_id: None, thread_name: None, interpreter_id: 0, active: false, owns_gil: false,
+                                     frames: vec![frame("outer", -1)], exception: None, awaiting: None, pid: None}];
+
+        let mut writer = RawCaptureWriter::new();
+        writer.metadata("version", "3.7.3");
+        writer.increment(Duration::from_millis(10), &traces);
+        writer.epoch(Duration::from_millis(20), "paused");
+        writer.thread_event(&ThreadChange{timestamp: Duration::from_millis(20), thread_id: 12, thread_name: None,
+                                          event: ThreadEvent::Exited});
+        let usage = ResourceUsage{rss: 1 << 30, cpu_time: Duration::from_millis(1500), open_fds: Some(0), read_bytes: None,
+                                  write_bytes: Some(4096)};
+        writer.resource_usage(Duration::from_millis(22), &usage);
+        writer.increment(Duration::from_millis(25), &traces[1..]);
+
+        let mut data = Vec::new();
+        writer.write(&mut data).unwrap();
+
+        let mut reader = RawCaptureReader::new(&data).unwrap();
+        match reader.next_event().unwrap() {
+            Some(Event::Metadata{key, value}) => { assert_eq!(key, "version"); assert_eq!(value, "3.7.3"); },
+            other => panic!("unexpected event {:?}", other)
+        }
+
+        match reader.next_event().unwrap() {
+            Some(Event::Sample{timestamp, traces}) => {
+                assert_eq!(timestamp, Duration::from_millis(10));
+                assert_eq!(traces.len(), 2);
+                assert_eq!(traces[0].thread_id, 0x7f00_0000_1234);
+                assert_eq!(traces[0].os_thread_id, Some(1000));
+                assert_eq!(traces[0].thread_name.as_deref(), Some("MainThread"));
+                assert_eq!(traces[1].thread_name, None);
+                assert_eq!(traces[0].interpreter_id, 2);
+                assert_eq!(traces[1].interpreter_id, 0);
+                assert!(traces[0].active && traces[0].owns_gil);
+                assert_eq!(traces[0].frames[0].name, "inner");
+                assert_eq!(traces[0].frames[1].line, -1);
+                assert_eq!(traces[0].frames[1].frame_ptr, None);
+                assert!(!traces[1].active && !traces[1].owns_gil);
+            },
+            other => panic!("unexpected event {:?}", other)
+        }
+
+        match reader.next_event().unwrap() {
+            Some(Event::Epoch{timestamp, label}) => {
+                assert_eq!(timestamp, Duration::from_millis(20));
+                assert_eq!(label, "paused");
+            },
+            other => panic!("unexpected event {:?}", other)
+        }
+
+        match reader.next_event().unwrap() {
+            Some(Event::Thread(change)) => {
+                assert_eq!(change, ThreadChange{timestamp: Duration::from_millis(20), thread_id: 12, thread_name: None,
+                                                event: ThreadEvent::Exited});
+            },
+            other => panic!("unexpected event {:?}", other)
+        }
+
+        match reader.next_event().unwrap() {
+            Some(Event::Resources{timestamp, usage: read}) => {
+                assert_eq!(timestamp, Duration::from_millis(22));
+                assert_eq!(read, usage);
+            },
+            other => panic!("unexpected event {:?}", other)
+        }
+
+        match reader.next_event().unwrap() {
+            Some(Event::Sample{timestamp, traces}) => {
+                assert_eq!(timestamp, Duration::from_millis(25));
+                assert_eq!(traces.len(), 1);
+                assert_eq!(traces[0].frames[0].name, "outer");
+            },
+            other => panic!("unexpected event {:?}", other)
+        }
+        assert!(reader.next_event().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_append() {
+        let traces = vec![StackTrace{thread_id: 1, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true,
+                                     owns_gil: false, frames: vec![frame("inner", 10)], exception: None, awaiting: None, pid: None}];
+        let mut writer = RawCaptureWriter::new();
+        writer.metadata("start_time", "1000.0");
+        writer.increment(Duration::from_secs(5), &traces);
+        let mut data = Vec::new();
+        writer.write(&mut data).unwrap();
+
+        let mut writer = RawCaptureWriter::append(&data, data.clone()).unwrap();
+        writer.metadata("start_time", "2000.0");
+        writer.epoch(Duration::from_secs(0), "appended");
+        writer.increment(Duration::from_secs(2), &traces);
+        let mut data = Vec::new();
+        writer.write(&mut data).unwrap();
+
+        let mut reader = RawCaptureReader::new(&data).unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = reader.next_event().unwrap() {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 4);
+        match &events[2] {
+            Event::Epoch{timestamp, label} => { assert_eq!(*timestamp, Duration::from_secs(5)); assert_eq!(label, "appended"); },
+            other => panic!("unexpected event {:?}", other)
+        }
+        match &events[3] {
+            Event::Sample{timestamp, traces} => {
+                assert_eq!(*timestamp, Duration::from_secs(7));
+                assert_eq!(traces[0].frames[0].name, "inner");
+            },
+            other => panic!("unexpected event {:?}", other)
+        }
+        // the frame was written out in the first recording, and is reused rather than written again
+        assert_eq!(reader.frames.len(), 1);
+    }
+
+    #[test]
+    fn test_streaming() {
+        let traces = vec![StackTrace{thread_id: 1, os_thread_id: None, thread_name: None, interpreter_id: 0, active: true,
+                                     owns_gil: false, frames: vec![frame("inner", 10)], exception: None, awaiting: None, pid: None}];
+        let mut out = Vec::new();
+        let mut writer = RawCaptureWriter::with_output(&mut out);
+        writer.increment(Duration::from_secs(1), &traces);
+        writer.increment(Duration::from_secs(2), &traces);
+        // records are written as they're added, so the capture is complete without writing it out at the end
+        drop(writer);
+
+        let mut reader = RawCaptureReader::new(&out).unwrap();
+        let mut samples = 0;
+        while let Some(event) = reader.next_event().unwrap() {
+            assert!(matches!(event, Event::Sample{..}));
+            samples += 1;
+        }
+        assert_eq!(samples, 2);
+        assert_eq!(reader.frames.len(), 1);
+
+        // appending only writes the new records
+        let mut appended = Vec::new();
+        let mut writer = RawCaptureWriter::append(&out, &mut appended).unwrap();
+        writer.increment(Duration::from_secs(1), &traces);
+        writer.flush().unwrap();
+        assert_eq!(appended[0], TAG_SAMPLE);
+    }
+
+    #[test]
+    fn test_varint() {
+        for &value in &[0, 1, 127, 128, 300, u64::from(u32::MAX), u64::MAX] {
+            let mut buffer = MAGIC.to_vec();
+            buffer.push(FORMAT_VERSION);
+            buffer.push(TAG_STRING);
+            write_varint(&mut buffer, value);
+            let mut reader = RawCaptureReader::new(&buffer).unwrap();
+            reader.position += 1;
+            assert_eq!(reader.read_varint().unwrap(), value);
+        }
+
+        for &value in &[0, 1, -1, 1000, -1000, i32::MAX, i32::MIN] {
+            assert_eq!(zigzag_decode(zigzag_encode(value)), value);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Reads how much memory, CPU, open files and IO the profiled process is using, so that this can be
+// recorded alongside the samples and a profile read in context (like a slowdown lining up with
+// the process running out of memory)
+use std::time::Duration;
+
+use failure::Error;
+use remoteprocess::Pid;
+
+/// How often to record the resource usage of the process, with the resources option
+pub const RESOURCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The resources a process is using at a point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// Resident set size in bytes
+    pub rss: u64,
+    /// Total CPU time (user and system) used by the process so far
+    pub cpu_time: Duration,
+    pub open_fds: Option<u64>,
+    /// Total bytes read from and written to storage so far
+    pub read_bytes: Option<u64>,
+    pub write_bytes: Option<u64>,
+}
+
+#[cfg(target_os="linux")]
+pub fn get_resource_usage(pid: Pid) -> Result<ResourceUsage, Error> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let (cpu_ticks, rss_pages) = parse_stat(&stat).ok_or_else(|| format_err!("Failed to parse /proc/{}/stat", pid))?;
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+
+    let open_fds = std::fs::read_dir(format!("/proc/{}/fd", pid)).ok().map(|fds| fds.count() as u64);
+    // reading the io counters of another user's process needs the same permissions as reading its memory
+    let (read_bytes, write_bytes) = match std::fs::read_to_string(format!("/proc/{}/io", pid)) {
+        Ok(io) => parse_io(&io),
+        Err(_) => (None, None)
+    };
+    Ok(ResourceUsage{rss: rss_pages * page_size,
+                     cpu_time: Duration::from_micros(cpu_ticks * 1_000_000 / ticks_per_second),
+                     open_fds, read_bytes, write_bytes})
+}
+
+#[cfg(not(target_os="linux"))]
+pub fn get_resource_usage(_pid: Pid) -> Result<ResourceUsage, Error> {
+    Err(format_err!("Recording resource usage is only supported on Linux"))
+}
+
+// gets the user + system CPU time in clock ticks and the resident set size in pages from /proc/<pid>/stat
+#[cfg(any(target_os="linux", test))]
+fn parse_stat(stat: &str) -> Option<(u64, u64)> {
+    // the command name can contain spaces and brackets, so the fields are counted from the last ')'.
+    // After it come state (field 3 in the man page) onwards, so utime (14) is at index 11
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let rss: i64 = fields.get(21)?.parse().ok()?;
+    Some((utime + stime, rss.max(0) as u64))
+}
+
+// gets read_bytes and write_bytes from /proc/<pid>/io
+#[cfg(any(target_os="linux", test))]
+fn parse_io(io: &str) -> (Option<u64>, Option<u64>) {
+    let field = |name: &str| io.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .and_then(|value| value.trim().parse().ok());
+    (field("read_bytes"), field("write_bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (python (worker) 1) S 1 4242 4242 0 -1 4194560 12345 0 3 0 250 50 0 0 20 0 3 0 \
+                    987654 1073741824 25600 18446744073709551615 1 1 0 0 0 0 0 16781312 2 0 0 0 17 2 0 0 0 0 0";
+        assert_eq!(parse_stat(stat), Some((300, 25600)));
+        assert_eq!(parse_stat("4242 (python) S 1"), None);
+    }
+
+    #[test]
+    fn test_parse_io() {
+        let io = "rchar: 1948\nwchar: 12\nsyscr: 7\nsyscw: 1\nread_bytes: 4096\nwrite_bytes: 0\ncancelled_write_bytes: 0\n";
+        assert_eq!(parse_io(io), (Some(4096), Some(0)));
+        assert_eq!(parse_io(""), (None, None));
+    }
+}
@@ -0,0 +1,112 @@
+// Waits for a profiled process to be restarted (by a supervisor, systemd etc), or notices it
+// exec()'ing a new program (like a wrapper script starting python, or a server reloading itself),
+// so that a recording can carry on with the new process
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use remoteprocess::{Pid, Process};
+
+use crate::config::Config;
+use crate::python_spy::PythonSpy;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// how long a program we launched gets to exec() python, when it isn't python itself
+const EXEC_WAIT: Duration = Duration::from_secs(10);
+
+/// Returns the pid of a process other than 'previous' running the given command line
+pub fn find_process(cmdline: &[String], previous: Pid) -> Result<Option<Pid>, Error> {
+    for pid in remoteprocess::processes()? {
+        if pid == previous {
+            continue;
+        }
+        let matches = Process::new(pid).and_then(|process| process.cmdline()).is_ok_and(|args| args == cmdline);
+        if matches {
+            return Ok(Some(pid));
+        }
+    }
+    Ok(None)
+}
+
+/// Waits up to 'timeout' for a new process with the given command line to show up, and attaches
+/// to it. Returns None if no process appeared in time, or if waiting was interrupted
+pub fn wait_for_respawn(cmdline: &[String], previous: Pid, timeout: Duration,
+                        running: &AtomicBool, config: &Config) -> Result<Option<PythonSpy>, Error> {
+    let start = Instant::now();
+    while start.elapsed() < timeout && running.load(Ordering::SeqCst) {
+        if let Some(pid) = find_process(cmdline, previous)? {
+            // the new process might not have loaded the python interpreter yet
+            return match PythonSpy::retry_new(pid, config, 8) {
+                Ok(process) => Ok(Some(process)),
+                Err(err) => {
+                    warn!("Failed to attach to restarted process {}: {}", pid, err);
+                    Ok(None)
+                }
+            };
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Ok(None)
+}
+
+/// Identifies the program that a process is running, which changes when it calls exec() even
+/// though the pid stays the same
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    exe: Option<String>,
+    // the auxiliary vector the kernel passes to each new program on Linux, which has
+    // (randomized) addresses from the new program's memory
+    id: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(process: &Process) -> Option<Image> {
+        #[cfg(target_os="linux")]
+        let id = std::fs::read(format!("/proc/{}/auxv", process.pid)).ok()?;
+        // elsewhere, only exec'ing a different command line can be noticed
+        #[cfg(not(target_os="linux"))]
+        let id = process.cmdline().ok()?.join("\0").into_bytes();
+        Some(Image{exe: process.exe().ok(), id})
+    }
+
+    /// The filename of the program
+    pub fn exe(&self) -> &str {
+        self.exe.as_deref().unwrap_or("unknown")
+    }
+}
+
+/// When the process is running a different program than 'image', attaches to the python interpreter in
+/// the new program and updates 'image'. Returns None when it's still running the same program
+pub fn follow_exec(process: &PythonSpy, image: &mut Option<Image>, config: &Config) -> Option<Result<PythonSpy, Error>> {
+    let current = Image::new(&process.process)?;
+    if image.as_ref()? == &current {
+        return None;
+    }
+    info!("Process {} exec'd {}", process.pid, current.exe());
+    *image = Some(current);
+    Some(PythonSpy::retry_new(process.pid, config, 8).map_err(Error::from))
+}
+
+/// Waits for a program we launched that isn't python (like a shell script) to exec() python, and
+/// attaches to it. Gives up when the program exits, or hasn't started python after a while
+pub fn wait_for_exec(pid: Pid, running: &AtomicBool, config: &Config) -> Result<PythonSpy, Error> {
+    let process = Process::new(pid)?;
+    let mut image = Image::new(&process);
+    let start = Instant::now();
+    let mut last_err = format_err!("Process {} didn't start python within {:?}", pid, EXEC_WAIT);
+    while start.elapsed() < EXEC_WAIT && running.load(Ordering::SeqCst) && process.exe().is_ok() {
+        std::thread::sleep(POLL_INTERVAL / 10);
+        let current = Image::new(&process);
+        if current == image {
+            continue;
+        }
+        info!("Process {} exec'd {}", pid, current.as_ref().map_or("unknown", |image| image.exe()));
+        image = current;
+        match PythonSpy::retry_new(pid, config, 8) {
+            Ok(process) => return Ok(process),
+            Err(err) => last_err = Error::from(err)
+        }
+    }
+    Err(last_err)
+}
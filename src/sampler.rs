@@ -0,0 +1,104 @@
+// Samples a python process at the configured rate, for library users that want to do something
+// with each sample themselves rather than having py-spy write out a profile
+use std::time::{Duration, Instant};
+
+use remoteprocess::Pid;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::python_spy::PythonSpy;
+use crate::stack_trace::StackTrace;
+use crate::timer::Timer;
+
+/// The stack traces of a process at one point in time, from a Sampler
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// When the sample was taken, relative to when sampling started
+    pub timestamp: Duration,
+    /// The stack trace of each thread
+    pub traces: Vec<StackTrace>,
+    /// How far behind schedule the sample was taken, if sampling isn't keeping up with the rate
+    pub late: Option<Duration>,
+    /// The number of times getting a sample failed since the previous sample
+    pub errors: u64,
+}
+
+/// An iterator over samples of a python process, taken `config.sampling_rate` times a second. This
+/// ends once the process exits.
+///
+/// ```rust,no_run
+/// # fn count(pid: remoteprocess::Pid) -> Result<(), py_spy::Error> {
+/// let config = py_spy::ConfigBuilder::new().sampling_rate(10).build()?;
+/// for sample in py_spy::Sampler::new(pid, &config)?.take(100) {
+///     let active = sample.traces.iter().filter(|trace| trace.active).count();
+///     println!("{:.2?}: {} of {} threads active", sample.timestamp, active, sample.traces.len());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Sampler {
+    spy: PythonSpy,
+    pub(crate) timer: Timer,
+    start: Instant,
+    done: bool,
+}
+
+// what came of trying to take a sample
+pub(crate) enum Attempt {
+    Sampled(Sample),
+    Failed,
+    Exited,
+}
+
+impl Sampler {
+    /// Attaches to the python process, retrying a few times while it starts up
+    pub fn new(pid: Pid, config: &Config) -> Result<Sampler, Error> {
+        let spy = PythonSpy::retry_new(pid, config, 3)?;
+        Ok(Sampler{spy, timer: Timer::new(config.sampling_rate as f64, config.jitter), start: Instant::now(), done: false})
+    }
+
+    /// The process being sampled
+    pub fn spy(&mut self) -> &mut PythonSpy {
+        &mut self.spy
+    }
+
+    /// The number of samples that were skipped because sampling fell behind schedule
+    pub fn missed_samples(&self) -> u64 {
+        self.timer.missed_ticks()
+    }
+
+    /// Takes a sample now, rather than waiting for when it's scheduled
+    pub(crate) fn attempt(&mut self, late: Option<Duration>, errors: u64) -> Attempt {
+        if self.done {
+            return Attempt::Exited;
+        }
+        match self.spy.get_stack_traces() {
+            Ok(traces) => Attempt::Sampled(Sample{timestamp: self.start.elapsed(), traces, late, errors}),
+            Err(Error::ProcessExited) => {
+                self.done = true;
+                Attempt::Exited
+            },
+            Err(e) => {
+                debug!("Failed to sample: {}", e);
+                Attempt::Failed
+            }
+        }
+    }
+}
+
+impl Iterator for Sampler {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        let mut errors = 0;
+        while !self.done {
+            let late = self.timer.next()?.err();
+            match self.attempt(late, errors) {
+                Attempt::Sampled(sample) => return Some(sample),
+                Attempt::Failed => errors += 1,
+                Attempt::Exited => {}
+            }
+        }
+        None
+    }
+}
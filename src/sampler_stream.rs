@@ -0,0 +1,159 @@
+// Samples a python process from async code, waiting for each sample with a tokio timer rather than by
+// sleeping. Taking a sample still happens on the task polling the stream, which suspends the process
+// being sampled for as long as it takes unless non_blocking is set, but that's usually well under a
+// millisecond.
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use remoteprocess::Pid;
+use tokio::time::{Instant, Sleep};
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::sampler::{Attempt, Sample, Sampler};
+
+/// A stream of samples of a python process, taken `config.sampling_rate` times a second, that ends
+/// once the process exits. This needs the 'async' feature, and a tokio runtime with timers enabled.
+///
+/// The stream isn't Send, since the PythonSpy it samples with isn't, so it can't be run with
+/// tokio::spawn. Poll it on the task that created it, or spawn it with spawn_local on a LocalSet.
+pub struct SamplerStream<S = Sampler> {
+    sampler: S,
+    sleep: Pin<Box<Sleep>>,
+    // how long until the next sample, or how far behind it is, once it's been scheduled
+    delay: Option<Result<Duration, Duration>>,
+    // samples that failed since the last sample
+    errors: u64,
+}
+
+// what the stream takes samples with: a Sampler, or something standing in for one in tests
+pub(crate) trait Attempts {
+    fn next_delay(&mut self) -> Result<Duration, Duration>;
+    fn attempt(&mut self, late: Option<Duration>, errors: u64) -> Attempt;
+}
+
+impl Attempts for Sampler {
+    fn next_delay(&mut self) -> Result<Duration, Duration> {
+        self.timer.next_delay()
+    }
+
+    fn attempt(&mut self, late: Option<Duration>, errors: u64) -> Attempt {
+        Sampler::attempt(self, late, errors)
+    }
+}
+
+impl SamplerStream {
+    /// Attaches to the python process, like Sampler::new. This blocks while retrying to attach
+    pub fn new(pid: Pid, config: &Config) -> Result<SamplerStream, Error> {
+        Ok(SamplerStream::from(Sampler::new(pid, config)?))
+    }
+
+    /// The sampler that the stream takes samples with
+    pub fn sampler(&mut self) -> &mut Sampler {
+        &mut self.sampler
+    }
+}
+
+impl<S: Attempts> From<S> for SamplerStream<S> {
+    fn from(sampler: S) -> SamplerStream<S> {
+        SamplerStream{sampler, sleep: Box::pin(tokio::time::sleep(Duration::from_secs(0))), delay: None, errors: 0}
+    }
+}
+
+impl<S: Attempts + Unpin> Stream for SamplerStream<S> {
+    type Item = Sample;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Sample>> {
+        let stream = self.get_mut();
+        loop {
+            if stream.delay.is_none() {
+                let delay = stream.sampler.next_delay();
+                stream.sleep.as_mut().reset(Instant::now() + delay.unwrap_or_default());
+                stream.delay = Some(delay);
+            }
+            if stream.sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            let late = stream.delay.take().and_then(|delay| delay.err());
+            match stream.sampler.attempt(late, stream.errors) {
+                Attempt::Sampled(sample) => {
+                    stream.errors = 0;
+                    return Poll::Ready(Some(sample));
+                },
+                Attempt::Failed => stream.errors += 1,
+                Attempt::Exited => return Poll::Ready(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // gives out a fixed list of delays and attempts, recording how late each attempt was and the
+    // errors before it, like a Sampler would put in its sample
+    struct Scripted {
+        delays: VecDeque<Result<Duration, Duration>>,
+        attempts: VecDeque<Attempt>,
+        calls: Vec<(Option<Duration>, u64)>,
+    }
+
+    impl Attempts for Scripted {
+        fn next_delay(&mut self) -> Result<Duration, Duration> {
+            self.delays.pop_front().unwrap()
+        }
+
+        fn attempt(&mut self, late: Option<Duration>, errors: u64) -> Attempt {
+            self.calls.push((late, errors));
+            match self.attempts.pop_front() {
+                Some(Attempt::Sampled(sample)) => Attempt::Sampled(Sample{late, errors, ..sample}),
+                Some(attempt) => attempt,
+                None => Attempt::Exited
+            }
+        }
+    }
+
+    fn sampled() -> Attempt {
+        Attempt::Sampled(Sample{timestamp: Duration::from_secs(0), traces: Vec::new(), late: None, errors: 0})
+    }
+
+    #[test]
+    fn test_sampler_stream() {
+        let delay = Duration::from_millis(5);
+        let late = Duration::from_millis(20);
+        let scripted = Scripted{
+            delays: vec![Ok(delay), Ok(delay), Ok(delay), Err(late), Ok(delay)].into_iter().collect(),
+            attempts: vec![Attempt::Failed, Attempt::Failed, sampled(), sampled(), Attempt::Exited].into_iter().collect(),
+            calls: Vec::new(),
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        let _runtime = runtime.enter();
+        let mut stream = SamplerStream::from(scripted);
+        let mut next = || runtime.block_on(std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)));
+
+        // failed samples are retried at the next scheduled time, waiting for each one
+        let start = Instant::now();
+        let sample = next().unwrap();
+        assert!(start.elapsed() >= delay * 3);
+        assert_eq!(sample.errors, 2);
+        assert_eq!(sample.late, None);
+
+        // a sample that's behind schedule is taken straight away, and says how late it is
+        let start = Instant::now();
+        let sample = next().unwrap();
+        assert!(start.elapsed() < late);
+        assert_eq!(sample.late, Some(late));
+        assert_eq!(sample.errors, 0);
+
+        // and the stream ends once the process exits
+        assert!(next().is_none());
+        assert_eq!(stream.sampler.calls, vec![(None, 0), (None, 1), (None, 2), (Some(late), 0), (None, 0)]);
+    }
+}
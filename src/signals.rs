@@ -0,0 +1,38 @@
+// Lets sampling be paused with SIGUSR1 and resumed with SIGUSR2, so that only the interesting
+// part of a long running process ends up in the recording. The filters are changed with a
+// control file instead (see control.rs), since the other signals already mean something else.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use failure::Error;
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    // only async signal safe operations are allowed here
+    match signal {
+        libc::SIGUSR1 => PAUSED.store(true, Ordering::SeqCst),
+        libc::SIGUSR2 => PAUSED.store(false, Ordering::SeqCst),
+        _ => {}
+    }
+}
+
+/// Installs the signal handlers, with sampling starting out paused or not
+pub fn install(paused: bool) -> Result<(), Error> {
+    PAUSED.store(paused, Ordering::SeqCst);
+
+    #[cfg(unix)]
+    unsafe {
+        for &signal in &[libc::SIGUSR1, libc::SIGUSR2] {
+            if libc::signal(signal, handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t) == libc::SIG_ERR {
+                return Err(format_err!("Failed to install signal handler: {}", std::io::Error::last_os_error()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether sampling is currently paused
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,224 @@
+// Writes out samples in the speedscope file format: https://www.speedscope.app/
+// The schema is described at https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources
+//
+// Samples are written as 'evented' profiles, with frames being opened and closed at the time
+// that they were sampled, so that the time order view in speedscope lines up with wall clock time.
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+
+use failure::Error;
+
+use crate::labels::Labels;
+use crate::stack_trace::StackTrace;
+
+#[derive(Serialize)]
+struct SpeedscopeFile {
+    #[serde(rename = "$schema")]
+    schema: String,
+    profiles: Vec<Profile>,
+    shared: Shared,
+    #[serde(rename = "activeProfileIndex")]
+    active_profile_index: Option<u64>,
+    exporter: String,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct Profile {
+    #[serde(rename = "type")]
+    profile_type: String,
+    name: String,
+    unit: String,
+    #[serde(rename = "startValue")]
+    start_value: f64,
+    #[serde(rename = "endValue")]
+    end_value: f64,
+    events: Vec<Event>,
+}
+
+#[derive(Serialize)]
+struct Event {
+    #[serde(rename = "type")]
+    event_type: &'static str,
+    frame: usize,
+    at: f64,
+}
+
+#[derive(Serialize)]
+struct Shared {
+    frames: Vec<Frame>,
+}
+
+#[derive(Serialize, Clone, Hash, Eq, PartialEq)]
+struct Frame {
+    name: String,
+    file: Option<String>,
+    line: Option<i32>,
+}
+
+pub struct Stats {
+    // for each thread, the time in seconds of each sample along with its frame indices
+    samples: HashMap<u64, Vec<(f64, Vec<usize>)>>,
+    thread_names: HashMap<u64, String>,
+    frames: Vec<Frame>,
+    frame_to_index: HashMap<Frame, usize>,
+    sample_period: f64,
+    show_linenumbers: bool,
+    reverse: bool,
+    labels: Labels,
+}
+
+impl Stats {
+    pub fn new(sampling_rate: u64, show_linenumbers: bool, reverse: bool) -> Stats {
+        Stats{samples: HashMap::new(), thread_names: HashMap::new(), frames: Vec::new(), frame_to_index: HashMap::new(),
+              sample_period: 1.0 / sampling_rate as f64, show_linenumbers, reverse, labels: Labels::default()}
+    }
+
+    /// Weights samples by the sampling rate that was actually achieved, rather than the requested rate
+    pub fn set_effective_rate(&mut self, rate: f64) {
+        if rate > 0.0 {
+            self.sample_period = 1.0 / rate;
+        }
+    }
+
+    /// Sets a label given with --label, like 'service=web', which is added to the name of the profile
+    pub fn set_label(&mut self, key: &str, value: &str) {
+        self.labels.set(key, value);
+    }
+
+    pub fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) {
+        let timestamp = timestamp.as_secs_f64();
+        for trace in traces {
+            if !(trace.active) {
+                continue;
+            }
+
+            // speedscope wants the frames ordered from the root of the stack to the leaf,
+            // when reversed we pretend that the leaf is the root instead
+            let mut sample = Vec::with_capacity(trace.frames.len());
+            let frames: Box<dyn Iterator<Item=_>> = if self.reverse {
+                Box::new(trace.frames.iter())
+            } else {
+                Box::new(trace.frames.iter().rev())
+            };
+            for frame in frames {
+                let filename = match &frame.short_filename { Some(f) => f, None => &frame.filename };
+                let line = if self.show_linenumbers && frame.line != 0 { Some(frame.line) } else { None };
+                let name = match frame.locals.as_ref() {
+                    // tell calls apart by their arguments, when they were captured
+                    Some(locals) => {
+                        let args: Vec<String> = locals.iter().filter(|local| local.arg)
+                            .map(|local| format!("{}={}", local.name, local.repr))
+                            .collect();
+                        format!("{}({})", frame.name, args.join(", "))
+                    },
+                    None => frame.name.clone()
+                };
+                let key = Frame{name, file: Some(filename.clone()), line};
+
+                let index = match self.frame_to_index.get(&key) {
+                    Some(&index) => index,
+                    None => {
+                        let index = self.frames.len();
+                        self.frames.push(key.clone());
+                        self.frame_to_index.insert(key, index);
+                        index
+                    }
+                };
+                sample.push(index);
+            }
+            self.samples.entry(trace.thread_id).or_default().push((timestamp, sample));
+            if let Some(name) = trace.thread_name.as_ref() {
+                self.thread_names.insert(trace.thread_id, name.clone());
+            }
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let mut thread_ids: Vec<&u64> = self.samples.keys().collect();
+        thread_ids.sort();
+
+        let profiles = thread_ids.into_iter().map(|thread_id| {
+            let samples = &self.samples[thread_id];
+            let events = get_events(samples, self.sample_period);
+            Profile{profile_type: "evented".to_owned(),
+                    name: match self.thread_names.get(thread_id) {
+                        Some(name) => format!("Thread {:#X} ({})", thread_id, name),
+                        None => format!("Thread {:#X}", thread_id)
+                    },
+                    unit: "seconds".to_owned(),
+                    start_value: samples.first().map(|s| s.0).unwrap_or(0.0),
+                    end_value: events.last().map(|e| e.at).unwrap_or(0.0),
+                    events}
+        }).collect();
+
+        let file = SpeedscopeFile{schema: "https://www.speedscope.app/file-format-schema.json".to_owned(),
+                                  active_profile_index: None,
+                                  name: if self.labels.is_empty() {
+                                      "py-spy profile".to_owned()
+                                  } else {
+                                      format!("py-spy profile ({})", self.labels)
+                                  },
+                                  exporter: format!("py-spy@{}", env!("CARGO_PKG_VERSION")),
+                                  profiles,
+                                  shared: Shared{frames: self.frames.clone()}};
+        serde_json::to_writer(w, &file)?;
+        Ok(())
+    }
+}
+
+// converts a series of samples into open/close events. Each sample is assumed to last for a
+// sample period, or until the next sample if that comes sooner
+fn get_events(samples: &[(f64, Vec<usize>)], sample_period: f64) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    let mut last_end = 0.0;
+
+    for (i, (start, frames)) in samples.iter().enumerate() {
+        // close everything if the thread wasn't seen for a while (idle, or sampling was paused)
+        if *start > last_end {
+            close_frames(&mut events, &mut open, 0, last_end);
+        }
+
+        let common = open.iter().zip(frames.iter()).take_while(|(a, b)| a == b).count();
+        close_frames(&mut events, &mut open, common, *start);
+        for &frame in &frames[common..] {
+            events.push(Event{event_type: "O", frame, at: *start});
+            open.push(frame);
+        }
+
+        last_end = start + sample_period;
+        if let Some((next, _)) = samples.get(i + 1) {
+            last_end = last_end.min(*next);
+        }
+    }
+    close_frames(&mut events, &mut open, 0, last_end);
+    events
+}
+
+// closes all the open frames past a stack depth, innermost first
+fn close_frames(events: &mut Vec<Event>, open: &mut Vec<usize>, depth: usize, at: f64) {
+    while open.len() > depth {
+        let frame = open.pop().unwrap();
+        events.push(Event{event_type: "C", frame, at});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_events() {
+        let samples = vec![(0.0, vec![0, 1]), (0.1, vec![0, 2]), (0.5, vec![0, 2])];
+        let events: Vec<(&str, usize, f64)> = get_events(&samples, 0.1).iter()
+            .map(|e| (e.event_type, e.frame, e.at)).collect();
+        assert_eq!(events, vec![("O", 0, 0.0), ("O", 1, 0.0),
+                                ("C", 1, 0.1), ("O", 2, 0.1),
+                                // the gap between 0.2 and 0.5 closes all the frames
+                                ("C", 2, 0.2), ("C", 0, 0.2),
+                                ("O", 0, 0.5), ("O", 2, 0.5),
+                                ("C", 2, 0.6), ("C", 0, 0.6)]);
+    }
+}
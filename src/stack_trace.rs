@@ -6,22 +6,43 @@ use remoteprocess::ProcessMemory;
 use crate::python_interpreters::{InterpreterState, ThreadState, FrameObject, CodeObject, StringObject, BytesObject};
 
 /// Call stack for a single python thread
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct StackTrace {
     /// The python thread id for this stack trace
     pub thread_id: u64,
     /// The OS thread id for this stack tracee
     pub os_thread_id: Option<u64>,
+    /// The name of the thread, from python's threading module or the OS
+    pub thread_name: Option<String>,
+    /// The id of the interpreter that the thread belongs to, which is 0 unless subinterpreters are used
+    pub interpreter_id: i64,
     /// Whether or not the thread was active
     pub active: bool,
     /// Whether or not the thread held the GIL
     pub owns_gil: bool,
     /// The frames
-    pub frames: Vec<Frame>
+    pub frames: Vec<Frame>,
+    /// The exception that the thread was handling, if it was requested
+    pub exception: Option<ExceptionInfo>,
+    /// What the innermost coroutine is awaiting, for the stacks of asyncio tasks
+    pub awaiting: Option<String>,
+    /// The process the stack came from, when sampling several processes together
+    pub pid: Option<remoteprocess::Pid>,
+}
+
+/// An exception being handled by a thread, in an except or finally block
+#[derive(Debug, Clone, Serialize)]
+pub struct ExceptionInfo {
+    /// The name of the type of the exception, like 'ValueError'
+    pub type_name: String,
+    /// The arguments the exception was raised with, described the same way as local variables
+    pub args: Vec<String>,
+    /// Where the exception was raised, innermost frame first
+    pub traceback: Vec<Frame>,
 }
 
 /// Information about a single function call in a stack trace
-#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone)]
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize)]
 pub struct Frame {
     /// The function name
     pub name: String,
@@ -34,9 +55,21 @@ pub struct Frame {
     /// The line number inside the file (or 0 for native frames without line information)
     pub line: i32,
     /// Address of the current frame
-    pub frame_ptr: Option<String>
-
+    #[serde(skip)]
+    pub frame_ptr: Option<String>,
+    /// The local variables of the frame, if they were requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locals: Option<Vec<LocalVariable>>,
+}
 
+/// A local variable (or argument) of a python function, along with a short description of its value
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize)]
+pub struct LocalVariable {
+    pub name: String,
+    /// Whether this is one of the positional arguments of the function
+    pub arg: bool,
+    /// The value for ints, floats, bools, None and (truncated) strings, or the type name otherwise
+    pub repr: String,
 }
 
 /// Given an InterpreterState, this function returns a vector of stack traces for each thread
@@ -65,13 +98,7 @@ pub fn get_stack_trace<T, P >(thread: &T, process: &P) -> Result<StackTrace, Err
     let mut frame_ptr = thread.frame();
     while !frame_ptr.is_null() {
         let frame = process.copy_pointer(frame_ptr).context("Failed to copy PyFrameObject")?;
-        let code = process.copy_pointer(frame.code()).context("Failed to copy PyCodeObject")?;
-
-        let filename = copy_string(code.filename(), process).context("Failed to copy filename")?;
-        let name = copy_string(code.name(), process).context("Failed to copy function name")?;
-        let line = get_line_number(&code, frame.lasti(), process).context("Failed to get line number")?;
-
-        frames.push(Frame{name, filename, line, short_filename: None, module: None, frame_ptr: Some(format!("{:?}", frame_ptr))});
+        frames.push(get_frame(frame_ptr, &frame, process)?);
         if frames.len() > 4096 {
             return Err(format_err!("Max frame recursion depth reached"));
         }
@@ -79,11 +106,163 @@ pub fn get_stack_trace<T, P >(thread: &T, process: &P) -> Result<StackTrace, Err
         frame_ptr = frame.back();
     }
 
-    Ok(StackTrace{frames, thread_id: thread.thread_id(), owns_gil: false, active: true, os_thread_id: None})
+    Ok(StackTrace{frames, thread_id: thread.thread_id(), owns_gil: false, active: true, os_thread_id: None, thread_name: None, interpreter_id: 0,
+                  exception: None, awaiting: None, pid: None})
+}
+
+/// Gets the stack trace of a thread that is running while it's read, returning None if its stack
+/// changed partway through. The thread's innermost frame is read again once the whole stack has
+/// been walked: the frames that called it can't change without it returning, so if the same frame
+/// object is still running the same code, the rest of the stack is from a single point in time.
+pub fn get_checked_stack_trace<T, P>(address: usize, thread: &T, process: &P) -> Option<StackTrace>
+        where T: ThreadState, P: ProcessMemory {
+    let code = |frame: *mut T::FrameObject| -> Option<Option<usize>> {
+        if frame.is_null() {
+            return Some(None);
+        }
+        process.copy_pointer(frame).ok().map(|frame| Some(frame.code() as usize))
+    };
+    let code_before = code(thread.frame())?;
+    let trace = match get_stack_trace(thread, process) {
+        Ok(trace) => trace,
+        Err(err) => {
+            debug!("Dropping stack of thread {:#X} that couldn't be read: {}", thread.thread_id(), err);
+            return None;
+        }
+    };
+    let current: T = process.copy_struct(address).ok()?;
+    let unchanged = current.frame() == thread.frame() && code(current.frame())? == code_before;
+    if !unchanged || !valid_frames(&trace.frames) {
+        debug!("Dropping stack of thread {:#X} that changed while it was read", thread.thread_id());
+        return None;
+    }
+    Some(trace)
+}
+
+// whether frames look like they came from code objects, rather than memory that was being reused
+fn valid_frames(frames: &[Frame]) -> bool {
+    frames.iter().all(|frame| !frame.name.is_empty() && !frame.filename.is_empty() && frame.line >= 0 &&
+                      !frame.name.chars().chain(frame.filename.chars()).any(char::is_control))
+}
+
+/// Gets the function name, filename and line number for a frame that has been copied from the process
+pub fn get_frame<F, P>(frame_ptr: *mut F, frame: &F, process: &P) -> Result<Frame, Error>
+        where F: FrameObject, P: ProcessMemory {
+    let code = process.copy_pointer(frame.code()).context("Failed to copy PyCodeObject")?;
+
+    let (filename, name, lnotab) = match copy_code_strings(&code, process) {
+        Ok(strings) => strings,
+        // copy each object separately, so that any error says which one couldn't be copied
+        Err(_) => (copy_string(code.filename(), process).context("Failed to copy filename")?,
+                   copy_string(code.name(), process).context("Failed to copy function name")?,
+                   copy_bytes(code.lnotab(), process).context("Failed to copy line number table")?)
+    };
+    let line = get_line_number(&code, frame.lasti(), &lnotab);
+
+    Ok(Frame{name, filename, line, short_filename: None, module: None, frame_ptr: Some(format!("{:?}", frame_ptr)),
+             locals: None})
+}
+
+impl Frame {
+    /// Gets the address of the python frame object in the target process, if this is a python frame
+    pub fn address(&self) -> Option<usize> {
+        self.frame_ptr.as_ref().and_then(|ptr| usize::from_str_radix(ptr.trim_start_matches("0x"), 16).ok())
+    }
+}
+
+/// The filename of the frame added to the top of stacks that were running the garbage collector
+pub const GC_FILENAME: &str = "<gc>";
+
+// the functions in gcmodule.c that run a collection, for recognizing the collector in native stacks.
+// The innermost one is just called 'collect', which is only matched along with the filename
+const GC_FUNCTIONS: &[&str] = &["collect_with_callback", "collect_generations", "gc_collect_main"];
+
+/// Returns a frame to mark a stack as running the garbage collector
+pub fn gc_frame() -> Frame {
+    Frame{name: "garbage collection".to_owned(), filename: GC_FILENAME.to_owned(), module: None,
+          short_filename: None, line: 0, frame_ptr: None, locals: None}
+}
+
+/// The filename of the frame added to the top of stacks that were waiting to take the GIL
+pub const GIL_WAIT_FILENAME: &str = "<gil>";
+
+/// Returns a frame to mark a stack as waiting to take the GIL
+pub fn gil_wait_frame() -> Frame {
+    Frame{name: "waiting for the GIL".to_owned(), filename: GIL_WAIT_FILENAME.to_owned(), module: None,
+          short_filename: None, line: 0, frame_ptr: None, locals: None}
+}
+
+/// The filename of the frame added to the top of stacks that were blocked acquiring a lock
+pub const LOCK_WAIT_FILENAME: &str = "<lock>";
+
+/// Returns a frame to mark a stack as blocked acquiring a threading.Lock, RLock or Condition
+pub fn lock_wait_frame() -> Frame {
+    Frame{name: "waiting for lock".to_owned(), filename: LOCK_WAIT_FILENAME.to_owned(), module: None,
+          short_filename: None, line: 0, frame_ptr: None, locals: None}
+}
+
+/// The filename of the frame that marks where native code was called from after it released the GIL
+pub const GIL_RELEASED_FILENAME: &str = "<nogil>";
+
+/// Returns a frame to mark native code that was running without holding the GIL
+pub fn gil_released_frame() -> Frame {
+    Frame{name: "GIL released".to_owned(), filename: GIL_RELEASED_FILENAME.to_owned(), module: None,
+          short_filename: None, line: 0, frame_ptr: None, locals: None}
+}
+
+/// The filename of the frame shown for a thread that holds the GIL without running any python code
+pub const NATIVE_GIL_FILENAME: &str = "<native>";
+
+/// Returns a frame for a thread that holds the GIL in native code, when we can't get its native stack
+pub fn native_gil_frame() -> Frame {
+    Frame{name: "holding the GIL in native code".to_owned(), filename: NATIVE_GIL_FILENAME.to_owned(), module: None,
+          short_filename: None, line: 0, frame_ptr: None, locals: None}
+}
+
+/// Marks the native frames of a merged stack as running without the GIL, by adding a frame from
+/// gil_released_frame between them and the innermost python frame. Extensions (like numpy or
+/// blocking IO) release the GIL in the native code that python calls into, so everything above
+/// that python frame ran without it. Does nothing if there aren't any native frames on top
+pub fn mark_gil_released(frames: &mut Vec<Frame>) {
+    let python_frame = frames.iter().position(|frame| frame.module.is_none() && !frame.filename.starts_with('<'));
+    if let Some(index) = python_frame.filter(|&index| index > 0) {
+        frames.insert(index, gil_released_frame());
+    }
+}
+
+/// Checks if a native frame is the garbage collector running a collection
+pub fn is_native_gc_frame(frame: &Frame) -> bool {
+    GC_FUNCTIONS.contains(&frame.name.as_str()) || (frame.name == "collect" && frame.filename.ends_with("gcmodule.c"))
 }
 
 impl StackTrace {
+    /// Whether the thread was running the garbage collector, which marks it with a frame from gc_frame
+    pub fn in_gc(&self) -> bool {
+        self.frames.first().is_some_and(|frame| frame.filename == GC_FILENAME)
+    }
+
+    /// Whether the thread was waiting to take the GIL, which marks it with a frame from gil_wait_frame
+    pub fn waiting_for_gil(&self) -> bool {
+        self.frames.first().is_some_and(|frame| frame.filename == GIL_WAIT_FILENAME)
+    }
+
+    /// Whether the thread was running native code that had released the GIL, from mark_gil_released
+    pub fn released_gil(&self) -> bool {
+        self.frames.iter().any(|frame| frame.filename == GIL_RELEASED_FILENAME)
+    }
+
+    /// Whether the thread was blocked acquiring a lock, which marks it with a frame from lock_wait_frame
+    pub fn waiting_for_lock(&self) -> bool {
+        self.frames.first().is_some_and(|frame| frame.filename == LOCK_WAIT_FILENAME)
+    }
+
     pub fn status_str(&self) -> &str {
+        if self.waiting_for_gil() {
+            return "waiting for gil";
+        }
+        if self.waiting_for_lock() {
+            return "waiting for lock";
+        }
         match (self.owns_gil, self.active) {
             (_, false) => "idle",
             (true, true) => "active+gil",
@@ -92,10 +271,9 @@ impl StackTrace {
     }
 }
 
-/// Returns the line number from a PyCodeObject (given the lasti index from a PyFrameObject)
-fn get_line_number<C: CodeObject, P: ProcessMemory>(code: &C, lasti: i32, process: &P) -> Result<i32, Error> {
-    let table = copy_bytes(code.lnotab(), process).context("Failed to copy line number table")?;
-
+/// Returns the line number from a PyCodeObject (given the lasti index from a PyFrameObject,
+/// and the contents of the line number table of the code object)
+fn get_line_number<C: CodeObject>(code: &C, lasti: i32, table: &[u8]) -> i32 {
     // unpack the line table. format is specified here:
     // https://github.com/python/cpython/blob/master/Objects/lnotab_notes.txt
     let size = table.len();
@@ -112,20 +290,51 @@ fn get_line_number<C: CodeObject, P: ProcessMemory>(code: &C, lasti: i32, proces
         i += 2;
     }
 
-    Ok(line_number)
+    line_number
+}
+
+/// Copies the filename, function name and line number table of a code object. Rather than taking
+/// two reads for each object, this copies the three object headers and then their contents in
+/// a batch each, which the OS can do in a single call on some platforms
+fn copy_code_strings<C: CodeObject, P: ProcessMemory>(code: &C, process: &P) -> Result<(String, String, Vec<u8>), Error> {
+    let mut filename = vec![0; std::mem::size_of::<C::StringObject>()];
+    let mut name = vec![0; std::mem::size_of::<C::StringObject>()];
+    let mut lnotab = vec![0; std::mem::size_of::<C::BytesObject>()];
+    process.read_many(&mut [(code.filename() as usize, &mut filename),
+                            (code.name() as usize, &mut name),
+                            (code.lnotab() as usize, &mut lnotab)])?;
+    let filename_obj: C::StringObject = unsafe { std::ptr::read_unaligned(filename.as_ptr() as *const _) };
+    let name_obj: C::StringObject = unsafe { std::ptr::read_unaligned(name.as_ptr() as *const _) };
+    let lnotab_obj: C::BytesObject = unsafe { std::ptr::read_unaligned(lnotab.as_ptr() as *const _) };
+
+    let mut filename = vec![0; string_length(&filename_obj)?];
+    let mut name = vec![0; string_length(&name_obj)?];
+    let mut lnotab = vec![0; bytes_length(&lnotab_obj)?];
+    process.read_many(&mut [(filename_obj.address(code.filename() as usize), &mut filename),
+                            (name_obj.address(code.name() as usize), &mut name),
+                            (lnotab_obj.address(code.lnotab() as usize), &mut lnotab)])?;
+
+    Ok((decode_string(&filename_obj, filename)?, decode_string(&name_obj, name)?, lnotab))
 }
 
 /// Copies a string from a target process. Attempts to handle unicode differences, which mostly seems to be working
 pub fn copy_string<T: StringObject, P: ProcessMemory>(ptr: * const T, process: &P) -> Result<String, Error> {
     let obj = process.copy_pointer(ptr)?;
+    let bytes = process.copy(obj.address(ptr as usize), string_length(&obj)?)?;
+    decode_string(&obj, bytes)
+}
+
+// returns the number of bytes of character data in a string object
+fn string_length<T: StringObject>(obj: &T) -> Result<usize, Error> {
     if obj.size() >= 4096 {
         return Err(format_err!("Refusing to copy {} chars of a string", obj.size()));
     }
+    Ok(obj.size() * obj.kind() as usize)
+}
 
+// converts the character data copied from a string object into a String
+fn decode_string<T: StringObject>(obj: &T, bytes: Vec<u8>) -> Result<String, Error> {
     let kind = obj.kind();
-
-    let bytes = process.copy(obj.address(ptr as usize), obj.size() * kind as usize)?;
-
     match (kind, obj.ascii()) {
         (4, _) => {
             #[allow(clippy::cast_ptr_alignment)]
@@ -147,11 +356,16 @@ pub fn copy_string<T: StringObject, P: ProcessMemory>(ptr: * const T, process: &
 /// Copies data from a PyBytesObject (currently only lnotab object)
 pub fn copy_bytes<T: BytesObject, P: ProcessMemory>(ptr: * const T, process: &P) -> Result<Vec<u8>, Error> {
     let obj = process.copy_pointer(ptr)?;
+    Ok(process.copy(obj.address(ptr as usize), bytes_length(&obj)?)?)
+}
+
+// returns the size of the data in a bytes object
+fn bytes_length<T: BytesObject>(obj: &T) -> Result<usize, Error> {
     let size = obj.size();
     if size >= 8192 {
         return Err(format_err!("Refusing to copy {} bytes", size));
     }
-    Ok(process.copy(obj.address(ptr as usize), size as usize)?)
+    Ok(size)
 }
 
 #[cfg(test)]
@@ -200,13 +414,53 @@ mod tests {
         ret
     }
 
+    #[test]
+    fn test_is_native_gc_frame() {
+        let frame = |name: &str, filename: &str| Frame{name: name.to_owned(), filename: filename.to_owned(), module: None,
+                                                       short_filename: None, line: 0, frame_ptr: None, locals: None};
+        assert!(is_native_gc_frame(&frame("collect", "Modules/gcmodule.c")));
+        assert!(is_native_gc_frame(&frame("collect_with_callback", "?")));
+        assert!(!is_native_gc_frame(&frame("collect", "src/app.c")));
+        assert!(!is_native_gc_frame(&frame("PyObject_Malloc", "Modules/gcmodule.c")));
+    }
+
+    #[test]
+    fn test_valid_frames() {
+        let frame = |name: &str, filename: &str, line: i32| Frame{name: name.to_owned(), filename: filename.to_owned(),
+                                                                  module: None, short_filename: None, line, frame_ptr: None,
+                                                                  locals: None};
+        assert!(valid_frames(&[frame("handle", "/app/server.py", 12), frame("<module>", "/app/server.py", 0)]));
+        assert!(valid_frames(&[]));
+        assert!(!valid_frames(&[frame("", "/app/server.py", 12)]));
+        assert!(!valid_frames(&[frame("handle", "/app/server.py", -1)]));
+        assert!(!valid_frames(&[frame("handle", "/app/\u{1}\u{7f}", 12)]));
+    }
+
+    #[test]
+    fn test_mark_gil_released() {
+        let frame = |name: &str, module: Option<&str>| Frame{name: name.to_owned(), filename: "?".to_owned(),
+                                                            module: module.map(|m| m.to_owned()), short_filename: None,
+                                                            line: 0, frame_ptr: None, locals: None};
+        let mut frames = vec![frame("cblas_dgemm", Some("libopenblas.so")), frame("matmul", Some("numpy.so")),
+                              frame("train", None), frame("<module>", None)];
+        mark_gil_released(&mut frames);
+        let names: Vec<&str> = frames.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["cblas_dgemm", "matmul", "GIL released", "train", "<module>"]);
+
+        // nothing to mark if python is on top of the stack
+        let mut frames = vec![frame("train", None), frame("<module>", None)];
+        mark_gil_released(&mut frames);
+        assert_eq!(frames.len(), 2);
+    }
+
     #[test]
     fn test_get_line_number() {
         let mut lnotab = to_byteobject(&[0u8, 1, 10, 1, 8, 1, 4, 1]);
         let code = PyCodeObject{co_firstlineno: 3,
                                 co_lnotab: &mut lnotab.base.ob_base.ob_base,
                                 ..Default::default()};
-        let lineno = get_line_number(&code, 30, &LocalProcess).unwrap();
+        let table = copy_bytes(code.co_lnotab as *const PyBytesObject, &LocalProcess).unwrap();
+        let lineno = get_line_number(&code, 30, &table);
         assert_eq!(lineno, 7);
     }
 
@@ -0,0 +1,59 @@
+// Events about the progress of a recording, written to stderr as a line of JSON each with --status-json
+// so that tools running py-spy can follow along without parsing the messages meant for people
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    Attached { pid: remoteprocess::Pid, python_version: &'a str },
+    SamplingStarted { pid: remoteprocess::Pid, rate: u64 },
+    RateAdjusted { rate: f64 },
+    Error { message: &'a str },
+    /// A recording was written out, which happens once for each window with --every
+    Finished { files: &'a [String], samples: u64, errors: u64, stopped: Option<&'a str> },
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+pub fn emit(event: &Event) {
+    if !enabled() {
+        return;
+    }
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs_f64()).unwrap_or(0.0);
+    match format_event(event, time) {
+        Ok(line) => eprintln!("{}", line),
+        Err(e) => warn!("Failed to write status event: {}", e)
+    }
+}
+
+// the event as a single line of JSON, with the time it happened at in seconds since the epoch
+fn format_event(event: &Event, time: f64) -> Result<String, serde_json::Error> {
+    let mut value = serde_json::to_value(event)?;
+    if let Some(object) = value.as_object_mut() {
+        object.insert("time".to_owned(), time.into());
+    }
+    serde_json::to_string(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_event() {
+        let files = vec!["profile.svg".to_owned()];
+        let event = Event::Finished{files: &files, samples: 200, errors: 1, stopped: None};
+        assert_eq!(format_event(&event, 1.5).unwrap(),
+                   r#"{"errors":1,"event":"finished","files":["profile.svg"],"samples":200,"stopped":null,"time":1.5}"#);
+        assert_eq!(format_event(&Event::RateAdjusted{rate: 50.0}, 2.0).unwrap(), r#"{"event":"rate-adjusted","rate":50.0,"time":2.0}"#);
+    }
+}
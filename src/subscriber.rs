@@ -0,0 +1,210 @@
+// Passes samples of a process to a handler on its own thread, so that a handler that's slow (like one
+// sending samples over the network) doesn't hold up sampling. What happens once the handler falls
+// behind by more than the capacity of the queue between the two threads is up to the Backpressure.
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use remoteprocess::Pid;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::sampler::{Sample, Sampler};
+
+/// Handles the samples of a process, for Subscription
+pub trait TraceSink: Send {
+    /// Called with each sample, in the order they were taken. Returning an error stops sampling
+    fn handle(&mut self, sample: Sample) -> Result<(), Error>;
+    /// Called once sampling has stopped and every queued sample has been handled
+    fn finish(&mut self) -> Result<(), Error> { Ok(()) }
+}
+
+/// What to do with a new sample when the queue of samples waiting for the TraceSink is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait for the sink to catch up before taking the next sample, which lowers the sampling rate
+    Block,
+    /// Drop the oldest sample in the queue to make room
+    DropOldest,
+    /// Drop the new sample
+    DropNewest,
+}
+
+/// Samples a process in the background, passing each sample to a TraceSink until stopped
+pub struct Subscription {
+    running: Arc<AtomicBool>,
+    queue: Arc<Queue>,
+    sampler: JoinHandle<()>,
+    sink: JoinHandle<Result<(), Error>>,
+}
+
+impl Subscription {
+    /// Attaches to the process and starts sampling it, queueing up to 'capacity' samples for the sink
+    pub fn start<S: TraceSink + 'static>(pid: Pid, config: &Config, sink: S, capacity: usize,
+                                         backpressure: Backpressure) -> Result<Subscription, Error> {
+        let running = Arc::new(AtomicBool::new(true));
+        let queue = Arc::new(Queue::new(capacity.max(1), backpressure));
+        let (started, result) = mpsc::channel();
+
+        let (config, sampler_running, sampler_queue) = (config.clone(), running.clone(), queue.clone());
+        let sampler = thread::spawn(move || {
+            let sampler = match Sampler::new(pid, &config) {
+                Ok(sampler) => { let _ = started.send(Ok(())); sampler },
+                Err(e) => { let _ = started.send(Err(e)); return; }
+            };
+            for sample in sampler {
+                if !sampler_running.load(Ordering::SeqCst) {
+                    break;
+                }
+                sampler_queue.push(sample);
+            }
+            sampler_queue.close();
+        });
+
+        match result.recv() {
+            Ok(attached) => attached?,
+            Err(_) => return Err(Error::Other("py-spy sampling thread panicked".to_owned()))
+        }
+        let (sink_running, sink_queue) = (running.clone(), queue.clone());
+        let sink = thread::spawn(move || handle_samples(sink, &sink_queue, &sink_running));
+        Ok(Subscription{running, queue, sampler, sink})
+    }
+
+    /// The number of samples that were dropped because the sink couldn't keep up
+    pub fn dropped(&self) -> u64 {
+        self.queue.state.lock().unwrap().dropped
+    }
+
+    /// Stops sampling, and waits for the sink to handle the samples that were already queued
+    pub fn stop(self) -> Result<(), Error> {
+        self.running.store(false, Ordering::SeqCst);
+        self.queue.close();
+        self.wait()
+    }
+
+    /// Waits for the process to exit (or the sink to fail), and for the sink to handle every sample
+    pub fn wait(self) -> Result<(), Error> {
+        if self.sampler.join().is_err() {
+            return Err(Error::Other("py-spy sampling thread panicked".to_owned()));
+        }
+        match self.sink.join() {
+            Ok(result) => result,
+            Err(_) => Err(Error::Other("py-spy trace sink thread panicked".to_owned()))
+        }
+    }
+}
+
+fn handle_samples<S: TraceSink>(mut sink: S, queue: &Queue, running: &AtomicBool) -> Result<(), Error> {
+    while let Some(sample) = queue.pop() {
+        if let Err(e) = sink.handle(sample) {
+            running.store(false, Ordering::SeqCst);
+            queue.close();
+            return Err(e);
+        }
+    }
+    sink.finish()
+}
+
+struct QueueState {
+    samples: VecDeque<Sample>,
+    closed: bool,
+    dropped: u64,
+}
+
+// the samples waiting to be handled by the sink
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    capacity: usize,
+    backpressure: Backpressure,
+}
+
+impl Queue {
+    fn new(capacity: usize, backpressure: Backpressure) -> Queue {
+        Queue{state: Mutex::new(QueueState{samples: VecDeque::new(), closed: false, dropped: 0}),
+              changed: Condvar::new(), capacity, backpressure}
+    }
+
+    fn push(&self, sample: Sample) {
+        let mut state = self.state.lock().unwrap();
+        if state.samples.len() >= self.capacity {
+            match self.backpressure {
+                Backpressure::Block => {
+                    while state.samples.len() >= self.capacity && !state.closed {
+                        state = self.changed.wait(state).unwrap();
+                    }
+                },
+                Backpressure::DropOldest => {
+                    state.samples.pop_front();
+                    state.dropped += 1;
+                },
+                Backpressure::DropNewest => {
+                    state.dropped += 1;
+                    return;
+                }
+            }
+        }
+        if state.closed {
+            return;
+        }
+        state.samples.push_back(sample);
+        self.changed.notify_all();
+    }
+
+    /// The next sample, or None once the queue is closed and every sample has been taken
+    fn pop(&self) -> Option<Sample> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(sample) = state.samples.pop_front() {
+                self.changed.notify_all();
+                return Some(sample);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn sample(errors: u64) -> Sample {
+        Sample{timestamp: Duration::from_secs(0), traces: Vec::new(), late: None, errors}
+    }
+
+    fn drain(queue: &Queue) -> Vec<u64> {
+        queue.close();
+        std::iter::from_fn(|| queue.pop()).map(|sample| sample.errors).collect()
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let queue = Queue::new(2, Backpressure::DropOldest);
+        (0..4).for_each(|i| queue.push(sample(i)));
+        assert_eq!(queue.state.lock().unwrap().dropped, 2);
+        assert_eq!(drain(&queue), vec![2, 3]);
+
+        let queue = Queue::new(2, Backpressure::DropNewest);
+        (0..4).for_each(|i| queue.push(sample(i)));
+        assert_eq!(drain(&queue), vec![0, 1]);
+
+        // blocking waits for the sink to take a sample
+        let queue = Arc::new(Queue::new(1, Backpressure::Block));
+        queue.push(sample(0));
+        let pusher = queue.clone();
+        let thread = thread::spawn(move || pusher.push(sample(1)));
+        assert_eq!(queue.pop().map(|sample| sample.errors), Some(0));
+        thread.join().unwrap();
+        assert_eq!(drain(&queue), vec![1]);
+    }
+}
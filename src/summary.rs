@@ -0,0 +1,304 @@
+// Writes a plain text report of a recording, meant to be read in a terminal or pasted into an issue.
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+use failure::Error;
+
+use crate::function_stats::{FunctionStats, FunctionCounts};
+use crate::heap_report::format_size;
+use crate::labels::Labels;
+use crate::resources::ResourceUsage;
+use crate::stack_trace::StackTrace;
+
+// number of functions to list in each of the tables
+const MAX_FUNCTIONS: usize = 20;
+
+// number of functions to show the hottest lines of, and how many lines to show for each
+const MAX_LINE_FUNCTIONS: usize = 10;
+const MAX_LINES: usize = 5;
+
+#[derive(Default)]
+struct ThreadCounts {
+    os_thread_id: Option<u64>,
+    name: Option<String>,
+    samples: u64,
+    active: u64,
+    gil: u64,
+}
+
+// what the process used over the recording, from its resource usage recorded every so often
+struct ResourceStats {
+    first: (Duration, ResourceUsage),
+    last: (Duration, ResourceUsage),
+    peak_rss: (Duration, u64),
+    // the highest CPU usage between two readings, as a fraction of a core
+    peak_cpu: f64,
+    max_fds: Option<u64>,
+}
+
+pub struct Summary {
+    functions: FunctionStats,
+    threads: BTreeMap<u64, ThreadCounts>,
+    samples: u64,
+    errors: u64,
+    // number of traces from active threads, and how many of them were running the garbage collector
+    active_traces: u64,
+    gc_traces: u64,
+    gil_wait_traces: u64,
+    lock_wait_traces: u64,
+    gil_released_traces: u64,
+    duration: Duration,
+    sampling_rate: u64,
+    effective_rate: Option<f64>,
+    missed: u64,
+    jitter: Option<String>,
+    failed_samples: Vec<(String, u64)>,
+    profiler_cpu_time: Option<Duration>,
+    suspended_time: Option<Duration>,
+    // the exit code of the process, if it ended before the recording did
+    target_exited: Option<String>,
+    resources: Option<ResourceStats>,
+    labels: Labels,
+    version: String,
+}
+
+impl Summary {
+    pub fn new(sampling_rate: u64, show_linenumbers: bool, version: &str) -> Summary {
+        Summary{functions: FunctionStats::new(show_linenumbers), threads: BTreeMap::new(),
+                samples: 0, errors: 0, active_traces: 0, gc_traces: 0, gil_wait_traces: 0, lock_wait_traces: 0, gil_released_traces: 0, duration: Duration::from_secs(0),
+                sampling_rate, effective_rate: None, missed: 0, jitter: None, failed_samples: Vec::new(),
+                profiler_cpu_time: None, suspended_time: None, target_exited: None, resources: None, labels: Labels::default(),
+                version: version.to_owned()}
+    }
+
+    pub fn increment(&mut self, timestamp: Duration, traces: &[StackTrace]) {
+        self.samples += 1;
+        self.duration = std::cmp::max(self.duration, timestamp);
+
+        for trace in traces {
+            let thread = self.threads.entry(trace.thread_id).or_default();
+            thread.samples += 1;
+            if trace.os_thread_id.is_some() { thread.os_thread_id = trace.os_thread_id; }
+            if trace.thread_name.is_some() { thread.name = trace.thread_name.clone(); }
+            if trace.owns_gil { thread.gil += 1; }
+            if trace.active {
+                thread.active += 1;
+                self.active_traces += 1;
+                if trace.in_gc() { self.gc_traces += 1; }
+                if trace.waiting_for_gil() { self.gil_wait_traces += 1; }
+                if trace.waiting_for_lock() { self.lock_wait_traces += 1; }
+                if trace.released_gil() { self.gil_released_traces += 1; }
+                self.functions.increment(trace);
+            }
+        }
+    }
+
+    pub fn increment_error(&mut self, timestamp: Duration) {
+        self.errors += 1;
+        self.duration = std::cmp::max(self.duration, timestamp);
+    }
+
+    /// Sets the sampling rate that was actually achieved, when the rate was adjusted while recording
+    pub fn set_effective_rate(&mut self, rate: f64) {
+        self.effective_rate = Some(rate);
+    }
+
+    /// Sets the number of samples that weren't taken because the sampler fell behind
+    pub fn set_missed_samples(&mut self, missed: u64) {
+        self.missed = missed;
+    }
+
+    /// Sets how the time between samples was randomized
+    pub fn set_jitter(&mut self, jitter: &str) {
+        self.jitter = Some(jitter.to_owned());
+    }
+
+    /// Sets the number of samples that failed for each reason, as formatted in the 'failed_samples' metadata
+    pub fn set_failed_samples(&mut self, failed_samples: &str) {
+        self.failed_samples = parse_failed_samples(failed_samples);
+        // raw captures don't store failed samples, so this is the only way to know about them after converting
+        self.errors = self.errors.max(self.failed_samples.iter().map(|(_, count)| count).sum());
+    }
+
+    /// Sets the CPU time that py-spy itself used while recording
+    pub fn set_profiler_cpu_time(&mut self, time: Duration) {
+        self.profiler_cpu_time = Some(time);
+    }
+
+    /// Sets how long the profiled program was suspended for while taking samples
+    pub fn set_suspended_time(&mut self, time: Duration) {
+        self.suspended_time = Some(time);
+    }
+
+    /// Marks the recording as ending because the process exited, with its exit code (or 'unknown')
+    pub fn set_target_exited(&mut self, exit_code: &str) {
+        self.target_exited = Some(exit_code.to_owned());
+    }
+
+    /// Sets a label given with --label, like 'service=web'
+    pub fn set_label(&mut self, key: &str, value: &str) {
+        self.labels.set(key, value);
+    }
+
+    /// Adds a reading of the resources the process was using
+    pub fn resource_usage(&mut self, timestamp: Duration, usage: &ResourceUsage) {
+        let stats = match self.resources.as_mut() {
+            Some(stats) => stats,
+            None => {
+                self.resources = Some(ResourceStats{first: (timestamp, usage.clone()), last: (timestamp, usage.clone()),
+                                                    peak_rss: (timestamp, usage.rss), peak_cpu: 0.0, max_fds: usage.open_fds});
+                return;
+            }
+        };
+        let elapsed = timestamp.saturating_sub(stats.last.0).as_secs_f64();
+        if elapsed > 0.0 {
+            let cpu = usage.cpu_time.saturating_sub(stats.last.1.cpu_time).as_secs_f64() / elapsed;
+            stats.peak_cpu = stats.peak_cpu.max(cpu);
+        }
+        if usage.rss > stats.peak_rss.1 {
+            stats.peak_rss = (timestamp, usage.rss);
+        }
+        stats.max_fds = stats.max_fds.max(usage.open_fds);
+        stats.last = (timestamp, usage.clone());
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        writeln!(w, "py-spy summary")?;
+        match self.effective_rate {
+            Some(rate) => writeln!(w, "Python {}, sampled at {:.1} Hz (target {} Hz) for {:.1}s", self.version, rate,
+                                   self.sampling_rate, self.duration.as_secs_f64())?,
+            None => writeln!(w, "Python {}, sampled at {} Hz for {:.1}s", self.version, self.sampling_rate, self.duration.as_secs_f64())?
+        }
+        if !self.labels.is_empty() {
+            writeln!(w, "Labels: {}", self.labels)?;
+        }
+        writeln!(w, "Samples: {}, dropped: {} ({:.2}%), missed from falling behind: {}", self.samples, self.errors,
+                 percent(self.errors, self.samples + self.errors), self.missed)?;
+        if let Some(jitter) = self.jitter.as_ref() {
+            writeln!(w, "Sampling interval jitter: {}", jitter)?;
+        }
+        if let Some(exit_code) = self.target_exited.as_ref() {
+            writeln!(w, "The process exited during the recording, with exit code {}", exit_code)?;
+        }
+        if self.gc_traces > 0 {
+            writeln!(w, "Time in garbage collection: {:.2}% of active samples", percent(self.gc_traces, self.active_traces))?;
+        }
+        if self.gil_wait_traces > 0 {
+            writeln!(w, "Time waiting for the GIL: {:.2}% of active samples", percent(self.gil_wait_traces, self.active_traces))?;
+        }
+        if self.lock_wait_traces > 0 {
+            writeln!(w, "Time waiting for locks: {:.2}% of active samples", percent(self.lock_wait_traces, self.active_traces))?;
+        }
+        if self.gil_released_traces > 0 {
+            writeln!(w, "Time in native code without the GIL: {:.2}% of active samples",
+                     percent(self.gil_released_traces, self.active_traces))?;
+        }
+        if !self.failed_samples.is_empty() {
+            let failed: Vec<String> = self.failed_samples.iter().map(|(reason, count)| format!("{} {}", reason, count)).collect();
+            writeln!(w, "Failed samples by reason: {}", failed.join(", "))?;
+        }
+        let elapsed = self.duration.as_secs_f64();
+        if let Some(time) = self.profiler_cpu_time {
+            writeln!(w, "Profiler CPU time: {:.2}s ({:.1}% of a core)", time.as_secs_f64(), percent_of(time, elapsed))?;
+        }
+        if let Some(time) = self.suspended_time {
+            writeln!(w, "Time the process was suspended: {:.2}s ({:.2}%)", time.as_secs_f64(), percent_of(time, elapsed))?;
+        }
+
+        if let Some(stats) = self.resources.as_ref() {
+            let ((start, first), (end, last)) = (&stats.first, &stats.last);
+            writeln!(w, "Memory: {} resident at the start, {} at the end, peaking at {} after {:.1}s",
+                     format_size(first.rss as i64), format_size(last.rss as i64), format_size(stats.peak_rss.1 as i64),
+                     stats.peak_rss.0.as_secs_f64())?;
+            let elapsed = end.saturating_sub(*start);
+            if elapsed > Duration::from_secs(0) {
+                let cpu = last.cpu_time.saturating_sub(first.cpu_time);
+                writeln!(w, "CPU: {:.1}% of a core on average, peaking at {:.1}%", percent_of(cpu, elapsed.as_secs_f64()),
+                         100.0 * stats.peak_cpu)?;
+            }
+            if let Some(fds) = stats.max_fds {
+                writeln!(w, "Open files: up to {}", fds)?;
+            }
+            if let (Some(first_read), Some(read), Some(first_written), Some(written)) =
+                   (first.read_bytes, last.read_bytes, first.write_bytes, last.write_bytes) {
+                writeln!(w, "IO: read {}, wrote {}", format_size(read.saturating_sub(first_read) as i64),
+                         format_size(written.saturating_sub(first_written) as i64))?;
+            }
+        }
+
+        writeln!(w)?;
+        writeln!(w, "Threads:")?;
+        writeln!(w, "  {:>18} {:>10} {:>9} {:>8} {:>8}  Name", "Thread", "OS Thread", "Samples", "%Active", "%GIL")?;
+        for (thread_id, counts) in &self.threads {
+            let os_thread_id = counts.os_thread_id.map(|tid| tid.to_string()).unwrap_or_else(|| "-".to_owned());
+            writeln!(w, "  {:>#18X} {:>10} {:>9} {:>7.2}% {:>7.2}%  {}", thread_id, os_thread_id, counts.samples,
+                     percent(counts.active, counts.samples), percent(counts.gil, counts.samples),
+                     counts.name.as_deref().unwrap_or("-"))?;
+        }
+
+        writeln!(w)?;
+        writeln!(w, "Top {} functions by own time:", MAX_FUNCTIONS)?;
+        self.write_functions(w, &self.functions.top_own(MAX_FUNCTIONS))?;
+
+        writeln!(w)?;
+        writeln!(w, "Top {} functions by total time:", MAX_FUNCTIONS)?;
+        self.write_functions(w, &self.functions.top_total(MAX_FUNCTIONS))?;
+
+        let hottest = self.functions.hottest_lines(MAX_LINE_FUNCTIONS, MAX_LINES);
+        if !hottest.is_empty() {
+            let period = 1.0 / self.effective_rate.unwrap_or(self.sampling_rate as f64);
+            writeln!(w)?;
+            writeln!(w, "Hottest lines of the top {} functions by own time:", MAX_LINE_FUNCTIONS)?;
+            for (name, own, lines) in hottest {
+                writeln!(w, "  {:>6.2}% {:>8.2}s  {}", percent(own, self.samples), own as f64 * period, name)?;
+                for (line, count) in lines {
+                    writeln!(w, "      {:>6.2}% {:>8.2}s  line {}", percent(count, self.samples), count as f64 * period, line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_functions(&self, w: &mut dyn Write, functions: &[(&str, FunctionCounts)]) -> Result<(), Error> {
+        let period = 1.0 / self.effective_rate.unwrap_or(self.sampling_rate as f64);
+        writeln!(w, "  {:>7} {:>7} {:>9} {:>9}  Function", "%Own", "%Total", "OwnTime", "TotalTime")?;
+        for (name, counts) in functions {
+            writeln!(w, "  {:>6.2}% {:>6.2}% {:>8.2}s {:>8.2}s  {}",
+                     percent(counts.own, self.samples), percent(counts.total, self.samples),
+                     counts.own as f64 * period, counts.total as f64 * period, name)?;
+        }
+        Ok(())
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { 100.0 * count as f64 / total as f64 }
+}
+
+/// Parses the 'failed_samples' metadata written when recording, like 'read_error=3,timeout=1'
+pub fn parse_failed_samples(value: &str) -> Vec<(String, u64)> {
+    value.split(',').filter_map(|entry| {
+        let mut parts = entry.splitn(2, '=');
+        let reason = parts.next()?.trim();
+        let count = parts.next()?.trim().parse().ok()?;
+        Some((reason.to_owned(), count))
+    }).collect()
+}
+
+fn percent_of(time: Duration, total_secs: f64) -> f64 {
+    if total_secs <= 0.0 { 0.0 } else { 100.0 * time.as_secs_f64() / total_secs }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_failed_samples() {
+        assert_eq!(parse_failed_samples("read_error=3,timeout=1"),
+                   vec![("read_error".to_owned(), 3), ("timeout".to_owned(), 1)]);
+        assert_eq!(parse_failed_samples(""), vec![]);
+        assert_eq!(parse_failed_samples("read_error=lots,timeout=2"), vec![("timeout".to_owned(), 2)]);
+    }
+}
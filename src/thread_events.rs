@@ -0,0 +1,100 @@
+// Tracks when threads first and last show up in the samples, so that recordings can mark threads
+// starting and exiting. Otherwise short lived threads (like the workers of a thread pool) only show
+// up as a few samples mixed in with everything else. Threads are identified by their python thread
+// id, and are only noticed when sampled, so the times are only as accurate as the sampling rate.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::stack_trace::StackTrace;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ThreadEvent {
+    Started,
+    Exited,
+}
+
+/// A thread starting or exiting, with the name it had (if it had one)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ThreadChange {
+    pub timestamp: Duration,
+    pub thread_id: u64,
+    pub thread_name: Option<String>,
+    pub event: ThreadEvent,
+}
+
+#[derive(Default)]
+pub struct ThreadTracker {
+    // the name of each thread in the last sample, and the time of that sample
+    threads: HashMap<u64, Option<String>>,
+    last_sample: Duration,
+}
+
+impl ThreadTracker {
+    pub fn new() -> ThreadTracker {
+        ThreadTracker::default()
+    }
+
+    /// Compares the threads in a sample against the previous one. Threads that have exited are
+    /// reported at the last time they were seen, and new threads at the time of this sample
+    pub fn update(&mut self, timestamp: Duration, traces: &[StackTrace]) -> Vec<ThreadChange> {
+        let mut threads: HashMap<u64, Option<String>> = HashMap::new();
+        for trace in traces {
+            let name = threads.entry(trace.thread_id).or_default();
+            if name.is_none() {
+                *name = trace.thread_name.clone();
+            }
+        }
+
+        let mut changes: Vec<ThreadChange> = self.threads.iter()
+            .filter(|(thread_id, _)| !threads.contains_key(thread_id))
+            .map(|(&thread_id, thread_name)| ThreadChange{timestamp: self.last_sample, thread_id,
+                                                          thread_name: thread_name.clone(), event: ThreadEvent::Exited})
+            .collect();
+        let mut started: Vec<ThreadChange> = threads.iter()
+            .filter(|(thread_id, _)| !self.threads.contains_key(thread_id))
+            .map(|(&thread_id, thread_name)| ThreadChange{timestamp, thread_id, thread_name: thread_name.clone(),
+                                                          event: ThreadEvent::Started})
+            .collect();
+        changes.sort_unstable_by_key(|change| change.thread_id);
+        started.sort_unstable_by_key(|change| change.thread_id);
+        changes.extend(started);
+
+        // hold on to names that were looked up after the thread started
+        for (thread_id, name) in threads.iter_mut() {
+            if name.is_none() {
+                *name = self.threads.get(thread_id).cloned().unwrap_or_default();
+            }
+        }
+        self.threads = threads;
+        self.last_sample = timestamp;
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(thread_id: u64, thread_name: Option<&str>) -> StackTrace {
+        StackTrace{thread_id, os_thread_id: None, thread_name: thread_name.map(|name| name.to_owned()), interpreter_id: 0,
+                   active: true, owns_gil: false, frames: Vec::new(), exception: None, awaiting: None, pid: None}
+    }
+
+    #[test]
+    fn test_thread_tracker() {
+        let mut tracker = ThreadTracker::new();
+        let started = tracker.update(Duration::from_millis(10), &[trace(1, Some("MainThread"))]);
+        assert_eq!(started, vec![ThreadChange{timestamp: Duration::from_millis(10), thread_id: 1,
+                                              thread_name: Some("MainThread".to_owned()), event: ThreadEvent::Started}]);
+
+        // the name of the worker isn't known in the first sample it shows up in
+        let started = tracker.update(Duration::from_millis(20), &[trace(1, Some("MainThread")), trace(2, None)]);
+        assert_eq!(started.len(), 1);
+        assert_eq!((started[0].thread_id, started[0].event), (2, ThreadEvent::Started));
+        assert!(tracker.update(Duration::from_millis(30), &[trace(1, None), trace(2, Some("worker"))]).is_empty());
+
+        let exited = tracker.update(Duration::from_millis(40), &[trace(1, None)]);
+        assert_eq!(exited, vec![ThreadChange{timestamp: Duration::from_millis(30), thread_id: 2,
+                                             thread_name: Some("worker".to_owned()), event: ThreadEvent::Exited}]);
+    }
+}
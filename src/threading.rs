@@ -0,0 +1,47 @@
+// Gets the names that python's threading module gives threads, like 'MainThread' or 'Thread-3'.
+// These don't get passed on to the OS, so the only place to find them is threading._active,
+// which maps the ident of each running thread to its Thread object. Only python 3.7 is supported,
+// since this uses the object layouts copied into the asyncio module.
+use std::collections::HashMap;
+
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::asyncio::{dict_get, dict_items, instance_dict, module_dict};
+use crate::locals::copy_long;
+use crate::python_bindings::v3_7_0::PyUnicodeObject;
+use crate::stack_trace::copy_string;
+
+/// Gets the name of each thread started through the threading module, keyed by python thread id,
+/// given the address of sys.modules. Returns nothing if the threading module hasn't been imported
+pub fn get_thread_names<P: ProcessMemory>(process: &P, modules: usize) -> Result<HashMap<u64, String>, Error> {
+    let mut names = HashMap::new();
+    let threading = match dict_get(process, modules, "threading")? {
+        Some(threading) => threading,
+        None => return Ok(names)
+    };
+    let active = dict_get(process, module_dict(process, threading)?, "_active")?
+        .ok_or_else(|| format_err!("Failed to find threading._active"))?;
+
+    for (ident, thread) in dict_items(process, active).context("Failed to read threading._active")? {
+        match get_thread_name(process, ident, thread) {
+            Ok(Some((ident, name))) => { names.insert(ident, name); },
+            Ok(None) => {},
+            // threads can exit while we're reading them when not pausing the process
+            Err(err) => debug!("Failed to get name of thread: {}", err)
+        }
+    }
+    Ok(names)
+}
+
+fn get_thread_name<P: ProcessMemory>(process: &P, ident: usize, thread: usize) -> Result<Option<(u64, String)>, Error> {
+    let name = match dict_get(process, instance_dict(process, thread)?, "_name")? {
+        Some(name) => name,
+        None => return Ok(None)
+    };
+    let ident = copy_long(process, ident)?;
+    if ident < 0 || ident > i128::from(u64::MAX) {
+        return Err(format_err!("Invalid thread ident {}", ident));
+    }
+    Ok(Some((ident as u64, copy_string(name as *const PyUnicodeObject, process)?)))
+}
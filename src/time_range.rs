@@ -0,0 +1,139 @@
+// The part of a recording to keep with --trim-start and --trim-end, so that the warm up and shutdown of a
+// program can be left out of the output without recording it again. Bounds are either offsets from the
+// start (or end) of the recording, or times in UTC that are compared against the time recording started.
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeBound {
+    /// From the start of the recording for --trim-start, or from its end for --trim-end
+    Offset(Duration),
+    /// A date and time, like '2019-06-01 12:00:10'
+    At(SystemTime),
+    /// A time on the day the recording started, in seconds since midnight
+    TimeOfDay(u64),
+}
+
+impl TimeBound {
+    /// Parses a UTC time like '2019-06-01 12:00:10', '2019-06-01T12:00:10Z' or '12:00:10'. Offsets
+    /// are parsed with the other durations
+    pub fn parse_time(value: &str) -> Option<TimeBound> {
+        let value = value.trim();
+        let value = value.strip_suffix("UTC").or_else(|| value.strip_suffix('Z')).unwrap_or(value).trim();
+        let (date, time) = match value.split_once([' ', 'T']) {
+            Some((date, time)) => (Some(date), time),
+            None => (None, value)
+        };
+
+        let time: Vec<u64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<Vec<u64>>>()?;
+        let secs = match *time.as_slice() {
+            [hours, minutes, seconds] if hours < 24 && minutes < 60 && seconds < 60 => hours * 3600 + minutes * 60 + seconds,
+            [hours, minutes] if hours < 24 && minutes < 60 => hours * 3600 + minutes * 60,
+            _ => return None
+        };
+        let date = match date {
+            Some(date) => date,
+            None => return Some(TimeBound::TimeOfDay(secs))
+        };
+
+        let date: Vec<i64> = date.split('-').map(|part| part.parse().ok()).collect::<Option<Vec<i64>>>()?;
+        let days = match *date.as_slice() {
+            [year, month, day] if (1..=12).contains(&month) && (1..=31).contains(&day) => days_from_civil(year, month, day),
+            _ => return None
+        };
+        let since_epoch = u64::try_from(days * 86400).ok()? + secs;
+        Some(TimeBound::At(UNIX_EPOCH + Duration::from_secs(since_epoch)))
+    }
+
+    // the offset from the start of the recording of a time, when we know when recording started
+    fn since_start(&self, start_time: Option<SystemTime>) -> Result<Duration, Error> {
+        let time = match (*self, start_time) {
+            (TimeBound::Offset(offset), _) => return Ok(offset),
+            (TimeBound::At(time), Some(_)) => time,
+            (TimeBound::TimeOfDay(secs), Some(start_time)) => {
+                let start = start_time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+                UNIX_EPOCH + Duration::from_secs(start - start % 86400 + secs)
+            },
+            (_, None) => return Err(Error::InvalidConfig("Can't trim to a time of day, since the recording doesn't say when it started".to_owned()))
+        };
+        Ok(time.duration_since(start_time.unwrap_or(UNIX_EPOCH)).unwrap_or_default())
+    }
+}
+
+/// The part of the recording that is kept: samples from 'start' up to 'end', other than the samples
+/// in the last 'trailing' of the recording
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub start: Duration,
+    pub end: Option<Duration>,
+    pub trailing: Duration,
+}
+
+impl Bounds {
+    pub fn new(trim_start: Option<TimeBound>, trim_end: Option<TimeBound>, start_time: Option<SystemTime>) -> Result<Bounds, Error> {
+        let start = match trim_start {
+            Some(bound) => bound.since_start(start_time)?,
+            None => Duration::from_secs(0)
+        };
+        let (end, trailing) = match trim_end {
+            Some(TimeBound::Offset(trailing)) => (None, trailing),
+            Some(bound) => (Some(bound.since_start(start_time)?), Duration::from_secs(0)),
+            None => (None, Duration::from_secs(0))
+        };
+        Ok(Bounds{start, end, trailing})
+    }
+
+    /// Whether a sample is between the start and end, not counting the trailing part
+    pub fn contains(&self, timestamp: Duration) -> bool {
+        timestamp >= self.start && self.end.is_none_or(|end| timestamp <= end)
+    }
+}
+
+// days since the epoch of a civil date, from http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        let at = |secs| Some(TimeBound::At(UNIX_EPOCH + Duration::from_secs(secs)));
+        assert_eq!(TimeBound::parse_time("2019-10-02 07:06:40"), at(1_570_000_000));
+        assert_eq!(TimeBound::parse_time("2019-10-02T07:06:40Z"), at(1_570_000_000));
+        assert_eq!(TimeBound::parse_time("2019-10-02 07:06:40 UTC"), at(1_570_000_000));
+        assert_eq!(TimeBound::parse_time("1970-01-01 00:00"), at(0));
+        assert_eq!(TimeBound::parse_time("07:06:40"), Some(TimeBound::TimeOfDay(25600)));
+        assert_eq!(TimeBound::parse_time("25:00"), None);
+        assert_eq!(TimeBound::parse_time("2019-13-02 07:06:40"), None);
+        assert_eq!(TimeBound::parse_time("10s"), None);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let started = Some(UNIX_EPOCH + Duration::from_secs(1_570_000_000));
+        let bounds = Bounds::new(Some(TimeBound::Offset(Duration::from_secs(10))), Some(TimeBound::Offset(Duration::from_secs(5))),
+                                 None).unwrap();
+        assert_eq!(bounds, Bounds{start: Duration::from_secs(10), end: None, trailing: Duration::from_secs(5)});
+        assert!(!bounds.contains(Duration::from_secs(9)));
+
+        let bounds = Bounds::new(TimeBound::parse_time("07:06:50"), TimeBound::parse_time("2019-10-02 07:07:40"), started).unwrap();
+        assert_eq!(bounds, Bounds{start: Duration::from_secs(10), end: Some(Duration::from_secs(60)), trailing: Duration::from_secs(0)});
+        assert!(bounds.contains(Duration::from_secs(60)));
+        assert!(!bounds.contains(Duration::from_secs(61)));
+
+        // times before recording started don't trim anything
+        let bounds = Bounds::new(TimeBound::parse_time("2019-10-01 00:00:00"), None, started).unwrap();
+        assert_eq!(bounds.start, Duration::from_secs(0));
+        assert!(Bounds::new(TimeBound::parse_time("07:06:50"), None, None).is_err());
+    }
+}
@@ -2,21 +2,31 @@ use std::time::{Instant, Duration};
 #[cfg(windows)]
 use winapi::um::timeapi;
 
-use rand::{self, distributions::{Exp, Distribution}};
+use rand::{self, Rng, distributions::{Exp, Distribution}};
+
+use crate::config::Jitter;
 
 /// Timer is an iterator that sleeps an appropiate amount of time between iterations
 /// so that we can sample the process a certain number of times a second.
-/// We're using an irregular sampling strategy to avoid aliasing effects that can happen
+/// By default we're using an irregular sampling strategy to avoid aliasing effects that can happen
 /// if the target process runs code at a similar schedule as the profiler:
 /// https://github.com/benfred/py-spy/issues/94
+///
+/// Samples are scheduled against absolute deadlines, so that time spent collecting
+/// a sample doesn't cause the rate to drift. If we fall more than a sampling interval
+/// behind, the missed deadlines are skipped (and counted) rather than taking a burst
+/// of back to back samples to catch up.
 pub struct Timer {
     start: Instant,
     desired: Duration,
     exp: Exp,
+    rate: f64,
+    jitter: Jitter,
+    missed: u64,
 }
 
 impl Timer {
-    pub fn new(rate: f64) -> Timer {
+    pub fn new(rate: f64, jitter: Jitter) -> Timer {
         // This changes a system-wide setting on Windows so that the OS wakes up every 1ms
         // instead of the default 15.6ms. This is required to have a sleep call
         // take less than 15ms, which we need since we usually profile at more than 64hz.
@@ -27,32 +37,72 @@ impl Timer {
         unsafe { timeapi::timeBeginPeriod(1); }
 
         let start = Instant::now();
-        Timer{start, desired: Duration::from_secs(0), exp: Exp::new(rate)}
+        Timer{start, desired: Duration::from_secs(0), exp: Exp::new(rate), rate, jitter, missed: 0}
     }
-}
 
-impl Iterator for Timer {
-    type Item = Result<Duration, Duration>;
+    /// Changes the number of samples per second, starting at the next iteration
+    #[allow(dead_code)]
+    pub fn set_rate(&mut self, rate: f64) {
+        self.exp = Exp::new(rate);
+        self.rate = rate;
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Restarts the schedule from the current time, so that a deliberate gap in sampling
+    /// isn't counted as missed samples
+    #[allow(dead_code)]
+    pub fn reschedule(&mut self) {
+        self.desired = self.start.elapsed();
+    }
+
+    /// Returns the number of samples that were skipped because we fell behind schedule
+    pub fn missed_ticks(&self) -> u64 {
+        self.missed
+    }
+
+    /// Schedules the next sample without sleeping, returning how long to wait for it, or how far
+    /// behind schedule we are if it's already due
+    pub fn next_delay(&mut self) -> Result<Duration, Duration> {
         let elapsed = self.start.elapsed();
 
         // figure out how many nanoseconds should come between the previous and
-        // the next sample using an exponential distribution to avoid aliasing
-        let nanos = 1_000_000_000.0 * self.exp.sample(&mut rand::thread_rng());
+        // the next sample, randomizing the interval to avoid aliasing
+        let interval = match self.jitter {
+            Jitter::exponential => self.exp.sample(&mut rand::thread_rng()),
+            // anywhere from half to one and a half times the mean interval
+            Jitter::uniform => (0.5 + rand::thread_rng().gen::<f64>()) / self.rate,
+            Jitter::none => 1.0 / self.rate,
+        };
+        let nanos = 1_000_000_000.0 * interval;
 
         // since we want to account for the amount of time the sampling takes
         // we keep track of when we should sleep to (rather than just sleeping
         // the amount of time from the previous line).
         self.desired += Duration::from_nanos(nanos as u64);
 
-        // sleep if appropiate, or warn if we are behind in sampling
         if self.desired > elapsed {
-            std::thread::sleep(self.desired - elapsed);
-            Some(Ok(self.desired - elapsed))
+            Ok(self.desired - elapsed)
         } else {
-            Some(Err(elapsed - self.desired))
+            let behind = elapsed - self.desired;
+            let missed = (behind.as_secs_f64() * self.rate) as u64;
+            if missed > 0 {
+                self.missed += missed;
+                self.desired = elapsed;
+            }
+            Err(behind)
+        }
+    }
+}
+
+impl Iterator for Timer {
+    type Item = Result<Duration, Duration>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // sleep if appropiate, or warn if we are behind in sampling
+        let delay = self.next_delay();
+        if let Ok(delay) = delay {
+            std::thread::sleep(delay);
         }
+        Some(delay)
     }
 }
 
@@ -0,0 +1,165 @@
+// Reads the allocations that tracemalloc is tracking in the target, for recording where memory is
+// being allocated. pymalloc doesn't keep any record of where its blocks were allocated from, so
+// this only works when the program is running with tracemalloc enabled (like PYTHONTRACEMALLOC=25).
+//
+// tracemalloc keeps a hashtable from the address of each live allocation to its size and the
+// traceback of where it was allocated. Tracebacks only store the filename and line of each frame,
+// with the innermost frame first. The layouts here are copied from Modules/hashtable.h and
+// Modules/_tracemalloc.c, which are the same from python 3.4 through 3.8.
+use std::mem::size_of;
+
+use failure::{Error, ResultExt};
+use remoteprocess::ProcessMemory;
+
+use crate::python_bindings::v3_7_0::PyUnicodeObject;
+use crate::stack_trace::copy_string;
+
+// sanity limits, in case we end up reading garbage
+const MAX_ALLOCATIONS: usize = 50_000_000;
+const MAX_FRAMES: i32 = 4096;
+
+// the size of the header of each hashtable entry (the next pointer and the hash of the key),
+// which is followed by the key and then the data
+const ENTRY_HEADER_SIZE: usize = 2 * size_of::<usize>();
+
+// the frames of a traceback follow its hash and frame count, and are packed to 4 bytes
+const TRACEBACK_FRAMES_OFFSET: usize = size_of::<usize>() + size_of::<i32>();
+const FRAME_SIZE: usize = size_of::<usize>() + size_of::<u32>();
+
+#[repr(C)]
+struct TracemallocConfig {
+    initialized: i32,
+    tracing: i32,
+    max_nframe: i32,
+    use_domain: i32,
+}
+
+#[repr(C)]
+struct Hashtable {
+    num_buckets: usize,
+    entries: usize,
+    buckets: usize,
+    key_size: usize,
+    data_size: usize,
+}
+
+#[repr(C)]
+struct Trace {
+    size: usize,
+    traceback: usize,
+}
+
+/// The addresses of tracemalloc's state in the target, found from the symbols of the python binary
+#[derive(Debug, Clone, Copy)]
+pub struct Tracemalloc {
+    pub config: usize,
+    /// Address of the pointer to the hashtable of traces
+    pub traces: usize,
+}
+
+/// An allocation tracked by tracemalloc, along with the address of the traceback it was allocated from
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Allocation {
+    pub address: usize,
+    pub size: usize,
+    pub traceback: usize,
+}
+
+impl Tracemalloc {
+    /// Returns whether tracemalloc is currently tracing allocations
+    pub fn is_tracing<P: ProcessMemory>(&self, process: &P) -> Result<bool, Error> {
+        let config: TracemallocConfig = process.copy_struct(self.config).context("Failed to copy tracemalloc config")?;
+        Ok(config.tracing != 0)
+    }
+
+    /// Gets every allocation that tracemalloc is tracking
+    pub fn get_allocations<P: ProcessMemory>(&self, process: &P) -> Result<Vec<Allocation>, Error> {
+        let table: usize = process.copy_struct(self.traces).context("Failed to copy tracemalloc traces")?;
+        if table == 0 {
+            return Ok(Vec::new());
+        }
+        let table: Hashtable = process.copy_struct(table).context("Failed to copy tracemalloc hashtable")?;
+        if table.entries > MAX_ALLOCATIONS || table.num_buckets > MAX_ALLOCATIONS || table.data_size < size_of::<Trace>() {
+            return Err(format_err!("Invalid tracemalloc hashtable"));
+        }
+
+        let buckets = process.copy(table.buckets, table.num_buckets * size_of::<usize>())
+            .context("Failed to copy tracemalloc hashtable buckets")?;
+        let entry_size = ENTRY_HEADER_SIZE + table.key_size + size_of::<Trace>();
+        let mut allocations = Vec::with_capacity(table.entries);
+        for bucket in 0..table.num_buckets {
+            let mut entry = unsafe { std::ptr::read_unaligned(buckets.as_ptr().add(bucket * size_of::<usize>()) as *const usize) };
+            while entry != 0 {
+                // the key starts with the address of the allocation, followed by the domain if there is one
+                let data = process.copy(entry, entry_size)?;
+                let address = unsafe { std::ptr::read_unaligned(data.as_ptr().add(ENTRY_HEADER_SIZE) as *const usize) };
+                let trace: Trace = unsafe { std::ptr::read_unaligned(data.as_ptr().add(ENTRY_HEADER_SIZE + table.key_size) as *const _) };
+                allocations.push(Allocation{address, size: trace.size, traceback: trace.traceback});
+                if allocations.len() > MAX_ALLOCATIONS {
+                    return Err(format_err!("Failed to walk the tracemalloc hashtable"));
+                }
+                entry = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const usize) };
+            }
+        }
+        Ok(allocations)
+    }
+}
+
+/// The filename and line of each frame of a traceback, starting with the innermost frame
+pub type Traceback = Vec<(String, u32)>;
+
+/// Gets the frames of a traceback, given its address
+pub fn get_traceback<P: ProcessMemory>(process: &P, traceback: usize) -> Result<Traceback, Error> {
+    let nframe: i32 = process.copy_struct(traceback + size_of::<usize>()).context("Failed to copy traceback")?;
+    if !(0..=MAX_FRAMES).contains(&nframe) {
+        return Err(format_err!("Invalid number of frames {} in traceback", nframe));
+    }
+    let data = process.copy(traceback + TRACEBACK_FRAMES_OFFSET, nframe as usize * FRAME_SIZE)
+        .context("Failed to copy traceback frames")?;
+    let mut frames = Vec::with_capacity(nframe as usize);
+    for i in 0..nframe as usize {
+        let frame = unsafe { data.as_ptr().add(i * FRAME_SIZE) };
+        let filename = unsafe { std::ptr::read_unaligned(frame as *const usize) };
+        let line = unsafe { std::ptr::read_unaligned(frame.add(size_of::<usize>()) as *const u32) };
+        frames.push((copy_string(filename as *const PyUnicodeObject, process)?, line));
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+
+    #[repr(C)]
+    struct Entry {
+        next: usize,
+        hash: usize,
+        address: usize,
+        trace: Trace,
+    }
+
+    #[test]
+    fn test_get_allocations() {
+        let config = TracemallocConfig{initialized: 1, tracing: 1, max_nframe: 1, use_domain: 0};
+
+        // a table with two buckets, the first holding two entries and the second empty
+        let second = Entry{next: 0, hash: 0, address: 0x2000, trace: Trace{size: 64, traceback: 0xbeef}};
+        let first = Entry{next: &second as *const Entry as usize, hash: 0, address: 0x1000, trace: Trace{size: 32, traceback: 0xf00d}};
+        let buckets = [&first as *const Entry as usize, 0];
+        let table = Hashtable{num_buckets: 2, entries: 2, buckets: buckets.as_ptr() as usize,
+                              key_size: size_of::<usize>(), data_size: size_of::<Trace>()};
+        let traces = &table as *const Hashtable as usize;
+
+        let tracemalloc = Tracemalloc{config: &config as *const TracemallocConfig as usize, traces: &traces as *const usize as usize};
+        assert!(tracemalloc.is_tracing(&LocalProcess).unwrap());
+        assert_eq!(tracemalloc.get_allocations(&LocalProcess).unwrap(),
+                   vec![Allocation{address: 0x1000, size: 32, traceback: 0xf00d},
+                        Allocation{address: 0x2000, size: 64, traceback: 0xbeef}]);
+
+        // tracemalloc hasn't allocated the table until it starts
+        let traces = 0usize;
+        let tracemalloc = Tracemalloc{traces: &traces as *const usize as usize, ..tracemalloc};
+        assert!(tracemalloc.get_allocations(&LocalProcess).unwrap().is_empty());
+    }
+}
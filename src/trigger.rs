@@ -0,0 +1,123 @@
+// Conditions on cheap OS level metrics like 'cpu>80%' or 'rss>2G', used to hold off on sampling
+// until something interesting is happening in the target process
+use std::time::{Duration, Instant};
+
+use remoteprocess::Process;
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Metric {
+    /// CPU usage of the whole process, in percent of a single core
+    Cpu,
+    /// Resident set size, in bytes
+    Rss,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trigger {
+    pub metric: Metric,
+    pub greater: bool,
+    pub threshold: f64,
+}
+
+impl Trigger {
+    /// Parses a trigger like 'cpu>80%', 'cpu<5', 'rss>2G' or 'rss>512M'
+    pub fn parse(value: &str) -> Result<Trigger, Error> {
+        let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+        let position = value.find(&['>', '<'][..])
+            .ok_or_else(|| Error::InvalidConfig(format!("Invalid trigger '{}': expected something like 'cpu>80%' or 'rss>2G'", value)))?;
+        let greater = &value[position..=position] == ">";
+        let (name, threshold) = (&value[..position], &value[position + 1..]);
+
+        let invalid = || Error::InvalidConfig(format!("Invalid threshold '{}' in trigger '{}'", threshold, value));
+        match name.to_lowercase().as_str() {
+            "cpu" => {
+                let threshold = threshold.trim_end_matches('%').parse().map_err(|_| invalid())?;
+                Ok(Trigger{metric: Metric::Cpu, greater, threshold})
+            },
+            "rss" | "mem" | "memory" => {
+                let upper = threshold.to_uppercase();
+                let upper = upper.trim_end_matches('B');
+                let (number, multiplier) = match upper.chars().last() {
+                    Some('K') => (&upper[..upper.len() - 1], 1024.0),
+                    Some('M') => (&upper[..upper.len() - 1], 1024.0 * 1024.0),
+                    Some('G') => (&upper[..upper.len() - 1], 1024.0 * 1024.0 * 1024.0),
+                    _ => (upper, 1.0)
+                };
+                let number: f64 = number.parse().map_err(|_| invalid())?;
+                Ok(Trigger{metric: Metric::Rss, greater, threshold: number * multiplier})
+            },
+            _ => Err(Error::InvalidConfig(format!("Unknown metric '{}' in trigger '{}', expected 'cpu' or 'rss'", name, value)))
+        }
+    }
+
+    /// Returns whether a measured value of this trigger's metric meets the condition
+    pub fn is_met(&self, value: f64) -> bool {
+        if self.greater { value > self.threshold } else { value < self.threshold }
+    }
+}
+
+impl std::fmt::Display for Trigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let op = if self.greater { ">" } else { "<" };
+        match self.metric {
+            Metric::Cpu => write!(f, "cpu{}{}%", op, self.threshold),
+            Metric::Rss => write!(f, "rss{}{:.0}M", op, self.threshold / (1024.0 * 1024.0)),
+        }
+    }
+}
+
+/// Periodically measures the metric for a trigger
+pub struct TriggerWatcher {
+    pub trigger: Trigger,
+    // previous cpu time measurement, used to figure out the cpu usage over an interval
+    last_cpu: Option<(Instant, Duration)>,
+}
+
+impl TriggerWatcher {
+    pub fn new(trigger: Trigger) -> TriggerWatcher {
+        TriggerWatcher{trigger, last_cpu: None}
+    }
+
+    /// Measures the process, and returns whether the trigger condition currently holds. CPU usage
+    /// is measured since the previous call, so the first call for a cpu trigger always returns false
+    pub fn check(&mut self, process: &Process) -> Result<bool, Error> {
+        match self.trigger.metric {
+            Metric::Rss => Ok(self.trigger.is_met(process.memory_usage()? as f64)),
+            Metric::Cpu => {
+                let now = Instant::now();
+                let cpu_time = process.cpu_time()?;
+                let previous = self.last_cpu.replace((now, cpu_time));
+                match previous {
+                    Some((last_time, last_cpu)) if now > last_time => {
+                        let used = cpu_time.checked_sub(last_cpu).unwrap_or_default();
+                        let usage = 100.0 * used.as_secs_f64() / (now - last_time).as_secs_f64();
+                        Ok(self.trigger.is_met(usage))
+                    },
+                    _ => Ok(false)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trigger() {
+        assert_eq!(Trigger::parse("cpu>80%").unwrap(), Trigger{metric: Metric::Cpu, greater: true, threshold: 80.0});
+        assert_eq!(Trigger::parse("cpu < 5").unwrap(), Trigger{metric: Metric::Cpu, greater: false, threshold: 5.0});
+        assert_eq!(Trigger::parse("rss>2G").unwrap().threshold, 2.0 * 1024.0 * 1024.0 * 1024.0);
+        assert_eq!(Trigger::parse("rss>512mb").unwrap().threshold, 512.0 * 1024.0 * 1024.0);
+        assert!(Trigger::parse("cpu=80").is_err());
+        assert!(Trigger::parse("disk>80").is_err());
+        assert!(Trigger::parse("rss>lots").is_err());
+
+        let trigger = Trigger::parse("cpu>80").unwrap();
+        assert!(trigger.is_met(90.0));
+        assert!(!trigger.is_met(80.0));
+    }
+}
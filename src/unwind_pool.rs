@@ -0,0 +1,91 @@
+// Long running worker threads for unwinding python stacks in parallel with --parallel. Each worker
+// opens its own handle to the process once when py-spy attaches, rather than on every sample, since
+// spawning threads and opening the process (task_for_pid on OSX, OpenProcess on Windows) a few hundred
+// times a second would add to the time the process is paused for.
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use failure::{Error, ResultExt};
+use remoteprocess::{Pid, Process};
+
+/// Something for a worker to do with its handle to the process
+pub type Job = Box<dyn FnOnce(&Process) + Send>;
+
+/// A fixed set of threads that run jobs against the process
+pub struct UnwindPool {
+    jobs: Vec<mpsc::Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl UnwindPool {
+    /// Starts 'workers' threads, returning once each of them has opened the process
+    pub fn new(pid: Pid, workers: usize) -> Result<UnwindPool, Error> {
+        let mut pool = UnwindPool{jobs: Vec::with_capacity(workers), threads: Vec::with_capacity(workers)};
+        for index in 0..workers {
+            let (jobs, receiver) = mpsc::channel::<Job>();
+            let (started, result) = mpsc::channel();
+            let thread = thread::Builder::new().name(format!("py-spy-unwind-{}", index)).spawn(move || {
+                let process = match Process::new(pid) {
+                    Ok(process) => { let _ = started.send(Ok(())); process },
+                    Err(e) => { let _ = started.send(Err(Error::from(e))); return; }
+                };
+                for job in receiver {
+                    job(&process);
+                }
+            }).context("Failed to start stack unwinding thread")?;
+            pool.jobs.push(jobs);
+            pool.threads.push(thread);
+            result.recv().context("Stack unwinding thread exited")?.context("Failed to open process for unwinding")?;
+        }
+        Ok(pool)
+    }
+
+    /// The number of worker threads
+    pub fn workers(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Runs a job on the worker with this index
+    pub fn run(&self, worker: usize, job: Job) -> Result<(), Error> {
+        self.jobs[worker].send(job).map_err(|_| format_err!("Stack unwinding thread panicked"))
+    }
+}
+
+impl Drop for UnwindPool {
+    fn drop(&mut self) {
+        // closing the channels ends each worker once it's finished its current job
+        self.jobs.clear();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let pool = UnwindPool::new(std::process::id() as Pid, 3).unwrap();
+        assert_eq!(pool.workers(), 3);
+
+        // each job runs on the worker it was given to, keeping the same thread between samples
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..2 {
+            for worker in 0..pool.workers() {
+                let sender = sender.clone();
+                pool.run(worker, Box::new(move |_process| {
+                    let _ = sender.send((worker, thread::current().name().map(|name| name.to_owned())));
+                })).unwrap();
+            }
+        }
+        drop(sender);
+        let mut names: Vec<(usize, Option<String>)> = receiver.iter().collect();
+        names.sort();
+        assert_eq!(names.len(), 6);
+        for (worker, name) in names {
+            assert_eq!(name, Some(format!("py-spy-unwind-{}", worker)));
+        }
+    }
+}
@@ -44,6 +44,53 @@ impl Version {
         }
         Err(format_err!("failed to find version string"))
     }
+
+    /// Looks for the PY_VERSION string that is compiled into the read only data of python (like
+    /// '3.7.3'), for when the sys.version string can't be found. Unlike sys.version, this is just the
+    /// version on its own, so it has to be a whole null terminated string to count
+    pub fn scan_signature(data: &[u8]) -> Option<Version> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"(?-u)\x00(2|3)\.(3|4|5|6|7|8)\.(\d{1,2})((a|b|c|rc)\d{1,2})?\+?\x00").unwrap();
+        }
+        let cap = RE.captures(data)?;
+        let number = |i| std::str::from_utf8(&cap[i]).ok()?.parse::<u64>().ok();
+        let release = cap.get(4).and_then(|x| std::str::from_utf8(x.as_bytes()).ok()).unwrap_or("");
+        Some(Version{major: number(1)?, minor: number(2)?, patch: number(3)?, release_flags: release.to_owned()})
+    }
+}
+
+/// How the version of python was found, which decides how far it can be trusted
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum VersionSource {
+    /// the sys.version string, read out of memory
+    SysVersion,
+    /// the name of the python executable, which doesn't include the patch level
+    Filename,
+    /// the version string compiled into the python binary
+    Signature,
+    /// the first layout of the interpreter state that matched the memory of the process
+    Layout,
+}
+
+impl VersionSource {
+    pub fn confidence(self) -> &'static str {
+        match self {
+            VersionSource::SysVersion => "high",
+            VersionSource::Filename | VersionSource::Signature => "medium",
+            VersionSource::Layout => "low"
+        }
+    }
+}
+
+impl std::fmt::Display for VersionSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            VersionSource::SysVersion => "sys.version",
+            VersionSource::Filename => "the executable name",
+            VersionSource::Signature => "the version string in the binary",
+            VersionSource::Layout => "the layout of the interpreter"
+        })
+    }
 }
 
 impl std::fmt::Display for Version {
@@ -79,4 +126,16 @@ mod tests {
         let version = Version::scan_bytes(b"2.7.15+ (default, Oct  2 2018, 22:12:08)").unwrap();
         assert_eq!(version, Version{major: 2, minor: 7, patch: 15, release_flags: "".to_owned()});
     }
+
+    #[test]
+    fn test_scan_signature() {
+        let version = Version::scan_signature(b"%.80s (%.80s) %.80s\x003.7.3\x00default\x00").unwrap();
+        assert_eq!(version, Version{major: 3, minor: 7, patch: 3, release_flags: "".to_owned()});
+
+        let version = Version::scan_signature(b"\x003.8.0b4\x00").unwrap();
+        assert_eq!(version, Version{major: 3, minor: 8, patch: 0, release_flags: "b4".to_owned()});
+
+        assert!(Version::scan_signature(b"\x00libpython3.7.3\x00").is_none(), "needs a whole string");
+        assert!(Version::scan_signature(b"\x003.7.3 (default)\x00").is_none(), "needs a whole string");
+    }
 }
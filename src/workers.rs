@@ -0,0 +1,38 @@
+// Gets the name that multiprocessing gives a worker process, like 'ForkPoolWorker-3', for
+// labelling the stacks of each process when following subprocesses. Each process keeps its own
+// Process object in multiprocessing.process._current_process (or in billiard.process, the fork of
+// multiprocessing used by celery). Servers like gunicorn and uwsgi name their workers with
+// setproctitle instead, which already shows up in the command line of the process.
+// Only python 3.7 is supported, since this uses the object layouts copied into the asyncio module.
+use failure::Error;
+use remoteprocess::ProcessMemory;
+
+use crate::asyncio::{dict_get, instance_dict, module_dict};
+use crate::python_bindings::v3_7_0::PyUnicodeObject;
+use crate::stack_trace::copy_string;
+
+// the modules that keep track of the current process, in the order to check them
+const PROCESS_MODULES: &[&str] = &["billiard.process", "multiprocessing.process"];
+
+/// Gets the name of the current process from multiprocessing, given the address of sys.modules.
+/// Returns nothing for the main process, or if multiprocessing hasn't been imported
+pub fn get_worker_name<P: ProcessMemory>(process: &P, modules: usize) -> Result<Option<String>, Error> {
+    for &name in PROCESS_MODULES {
+        let module = match dict_get(process, modules, name)? {
+            Some(module) => module,
+            None => continue
+        };
+        let current = match dict_get(process, module_dict(process, module)?, "_current_process")? {
+            Some(current) => current,
+            None => continue
+        };
+        let name = match dict_get(process, instance_dict(process, current)?, "_name")? {
+            Some(name) => copy_string(name as *const PyUnicodeObject, process)?,
+            None => continue
+        };
+        if name != "MainProcess" {
+            return Ok(Some(name));
+        }
+    }
+    Ok(None)
+}
@@ -1,7 +1,10 @@
 import os
 import re
+import shutil
 import subprocess
 import sys
+import sysconfig
+import tempfile
 import time
 import unittest
 from collections import namedtuple
@@ -11,10 +14,10 @@ Thread = namedtuple("Thread", ("id", "status"))
 
 
 class IntegrationTest(unittest.TestCase):
-    def _profile_python_file(self, filename):
+    def _profile_python_file(self, filename, args=(), spy_args=()):
         # Run the python command in a subprocess
         python_process = subprocess.Popen(
-            [sys.executable, os.path.join("scripts", filename)]
+            [sys.executable, os.path.join("scripts", filename)] + list(args)
         )
         try:
             # hack: give it some time to get running
@@ -23,7 +26,7 @@ class IntegrationTest(unittest.TestCase):
             # Run py-spy on the pid of the process we just created
             # TODO: get built py-spy here (rather than globally installed)
             output = subprocess.check_output(
-                ["py-spy", "--pid", str(python_process.pid), "--dump"]
+                ["py-spy", "--pid", str(python_process.pid), "--dump"] + list(spy_args)
             )
 
             if sys.version_info[0] >= 3:
@@ -68,6 +71,58 @@ class IntegrationTest(unittest.TestCase):
         self.assertTrue("gil" in thread.status)
         self.assertTrue("active" in thread.status)
 
+    @unittest.skipIf(sys.platform == "win32", "needs pthreads")
+    def test_gil_holder_without_python_frames(self):
+        # build the extension that holds the GIL from a thread of its own
+        build_dir = tempfile.mkdtemp()
+        try:
+            library = os.path.join(build_dir, "gilholder.so")
+            compiler = (sysconfig.get_config_var("CC") or "cc").split()
+            try:
+                subprocess.check_call(
+                    compiler
+                    + ["-shared", "-fPIC", "-g", "-I" + sysconfig.get_paths()["include"]]
+                    + [os.path.join("scripts", "gilholder.c"), "-o", library, "-lpthread"]
+                )
+            except (OSError, subprocess.CalledProcessError):
+                self.skipTest("couldn't build the extension")
+
+            traces = self._profile_python_file("gilholder.py", [library])
+        finally:
+            shutil.rmtree(build_dir)
+
+        # the thread holding the GIL has no python frames, so its native stack is shown instead
+        holders = [frames for thread, frames in traces if "gil" in thread.status]
+        self.assertEqual(len(holders), 1)
+        self.assertIn(
+            Frame(function="spin_holding_gil", file="gilholder.c", line=10), holders[0]
+        )
+
+    def test_parallel_unwinding(self):
+        def python_stacks(traces):
+            return [
+                [frame for frame in frames if frame and frame.file.endswith(".py")]
+                for thread, frames in traces
+            ]
+
+        serial = python_stacks(self._profile_python_file("threads.py"))
+        self.assertEqual(len(serial), 8)
+        self.assertEqual(len(set(len(frames) for frames in serial)), 8)
+
+        # the threads are unwound on different workers, but come back in the same order
+        parallel = python_stacks(
+            self._profile_python_file("threads.py", spy_args=["--parallel", "4"])
+        )
+        self.assertEqual(parallel, serial)
+
+        # native stacks are unwound one thread at a time, even with --parallel
+        native = python_stacks(
+            self._profile_python_file(
+                "threads.py", spy_args=["--native", "--parallel", "4"]
+            )
+        )
+        self.assertEqual(native, serial)
+
 
 def parse_frame(frame_line):
     matches = re.match(
@@ -0,0 +1,23 @@
+// A C extension thread that takes the GIL through the C API, and then keeps it without running any
+// python code
+#include <Python.h>
+#include <pthread.h>
+
+static volatile long counter;
+
+static void spin_holding_gil(void) {
+    for (;;) {
+        counter++;
+    }
+}
+
+static void *run(void *arg) {
+    PyGILState_Ensure();
+    spin_holding_gil();
+    return NULL;
+}
+
+void start(void) {
+    pthread_t thread;
+    pthread_create(&thread, NULL, run, NULL);
+}
@@ -0,0 +1,14 @@
+import ctypes
+import sys
+import time
+
+
+def wait():
+    # the thread started here takes the GIL and never gives it back
+    ctypes.CDLL(sys.argv[1]).start()
+    while True:
+        time.sleep(0.01)
+
+
+if __name__ == "__main__":
+    wait()
@@ -0,0 +1,18 @@
+import threading
+import time
+
+
+def nested(depth):
+    if depth:
+        nested(depth - 1)
+    else:
+        time.sleep(100000)
+
+
+if __name__ == "__main__":
+    # each thread sleeps at a different depth, so that their stacks can be told apart
+    for depth in range(1, 8):
+        thread = threading.Thread(target=nested, args=(depth,))
+        thread.daemon = True
+        thread.start()
+    nested(0)